@@ -0,0 +1,56 @@
+/*
+ * C interface for embedding vrchat-light-sync in other programs. Link against
+ * the cdylib built by `cargo build --release`, libvrchat_light_sync.so,
+ * vrchat_light_sync.dll or libvrchat_light_sync.dylib.
+ *
+ * Functions returning int return 0 on success and a negative number when
+ * something went wrong, like a null handle.
+ */
+#ifndef VRCHAT_LIGHT_SYNC_H
+#define VRCHAT_LIGHT_SYNC_H
+
+#include <stdbool.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct LightSync LightSync;
+
+/* Called from a background thread with every event as JSON, in the same
+ * format as the websocket stream. The string is only valid during the call. */
+typedef void (*lightsync_event_callback)(const char *event, void *user_data);
+
+/* Creates a sync from the YAML text of a settings file, returns NULL when the
+ * settings can't be used. */
+LightSync *lightsync_new(const char *settings);
+
+/* Starts syncing in a background thread. */
+int lightsync_start(LightSync *sync);
+
+/* Returns 1 while the sync thread is running and 0 once it stopped or failed,
+ * after which lightsync_start can start it again. Why it couldn't start, like
+ * a port that's already taken, is printed to stderr. */
+int lightsync_is_running(LightSync *sync);
+
+/* Stops syncing and waits for the sync thread to end. */
+int lightsync_stop(LightSync *sync);
+
+/* Sets the state of every light using the push bulb service with this name,
+ * hue and brightness go from 0 to 1. */
+int lightsync_push_state(LightSync *sync, const char *name, bool on, float hue,
+                         float brightness);
+
+/* Calls callback with every event from now on, subscribing before starting
+ * works as well. user_data has to be usable from another thread. */
+int lightsync_subscribe(LightSync *sync, lightsync_event_callback callback,
+                        void *user_data);
+
+/* Stops syncing and frees the sync, it can't be used afterwards. */
+void lightsync_free(LightSync *sync);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
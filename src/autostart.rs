@@ -0,0 +1,236 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const NAME: &str = "vrchat-light-sync";
+
+// What to start when the user logs in
+struct CommandLine {
+    exe: PathBuf,
+    args: Vec<String>,
+    // The folder the settings are in, so paths in them work the same as when
+    // the program is started from there
+    dir: PathBuf,
+}
+
+// The command line that starts syncing with the given settings and the other
+// flags it was given, like --preview
+fn command_line(config: &Path, flags: &[&str]) -> Result<CommandLine, String> {
+    let exe = env::current_exe().map_err(|err| format!("Couldn't find this program: {}", err))?;
+    let config = fs::canonicalize(config)
+        .map_err(|err| format!("Couldn't find {}: {}", config.display(), err))?;
+    let dir = config.parent().unwrap_or(Path::new("/")).to_owned();
+    let mut args = vec!["--config".to_owned(), config.display().to_string()];
+    args.extend(flags.iter().map(|flag| flag.to_string()));
+    Ok(CommandLine { exe, args, dir })
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|err| format!("Couldn't run {}: {}", program, err))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} failed with {}", program, status))
+    }
+}
+
+#[cfg(not(windows))]
+fn home() -> Result<PathBuf, String> {
+    env::var("HOME")
+        .map(PathBuf::from)
+        .map_err(|_| "HOME isn't set".to_owned())
+}
+
+#[cfg(windows)]
+const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+
+// Registers the program to start syncing when the user logs in. The Run key
+// can't set the working folder, so cmd changes to it first.
+#[cfg(windows)]
+pub fn enable(config: &Path, flags: &[&str]) -> Result<(), String> {
+    let command = command_line(config, flags)?;
+    let mut line = format!("\"{}\"", command.exe.display());
+    for arg in command.args {
+        line += &format!(" \"{}\"", arg);
+    }
+    // cd doesn't take the \\?\ paths canonicalize gives
+    let dir = command.dir.display().to_string();
+    let dir = dir.strip_prefix(r"\\?\").unwrap_or(&dir);
+    // cmd takes off the outer quotes and runs the rest as it is
+    let line = format!("cmd /c \"cd /d \"{}\" && {}\"", dir, line);
+    run(
+        "reg",
+        &[
+            "add", RUN_KEY, "/v", NAME, "/t", "REG_SZ", "/d", &line, "/f",
+        ],
+    )
+}
+
+#[cfg(windows)]
+pub fn disable() -> Result<(), String> {
+    run("reg", &["delete", RUN_KEY, "/v", NAME, "/f"])
+}
+
+#[cfg(target_os = "macos")]
+const LABEL: &str = "com.github.hrolfurgylfa.vrchat-light-sync";
+
+#[cfg(target_os = "macos")]
+fn agent_path() -> Result<PathBuf, String> {
+    Ok(home()?
+        .join("Library/LaunchAgents")
+        .join(LABEL.to_owned() + ".plist"))
+}
+
+// Escapes text to go between XML tags
+#[cfg(target_os = "macos")]
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(target_os = "macos")]
+pub fn enable(config: &Path, flags: &[&str]) -> Result<(), String> {
+    let command = command_line(config, flags)?;
+    let mut arguments = format!(
+        "        <string>{}</string>\n",
+        xml_escape(&command.exe.display().to_string())
+    );
+    for arg in command.args {
+        arguments += &format!("        <string>{}</string>\n", xml_escape(&arg));
+    }
+    let plist = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>WorkingDirectory</key>
+    <string>{}</string>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+        LABEL,
+        arguments,
+        xml_escape(&command.dir.display().to_string())
+    );
+    let path = agent_path()?;
+    fs::create_dir_all(path.parent().unwrap()).map_err(|err| err.to_string())?;
+    fs::write(&path, plist).map_err(|err| format!("Couldn't write {}: {}", path.display(), err))?;
+    run("launchctl", &["load", "-w", &path.display().to_string()])
+}
+
+#[cfg(target_os = "macos")]
+pub fn disable() -> Result<(), String> {
+    let path = agent_path()?;
+    run("launchctl", &["unload", "-w", &path.display().to_string()]).ok();
+    fs::remove_file(&path).map_err(|err| format!("Couldn't remove {}: {}", path.display(), err))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn unit_path() -> Result<PathBuf, String> {
+    let config_dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home()?.join(".config"),
+    };
+    Ok(config_dir
+        .join("systemd/user")
+        .join(NAME.to_owned() + ".service"))
+}
+
+// Keeps systemd from reading % as the start of a specifier like %h
+#[cfg(not(any(windows, target_os = "macos")))]
+fn systemd_escape(text: &str) -> String {
+    text.replace('%', "%%")
+}
+
+// Quotes an argument for a systemd ExecStart line
+#[cfg(not(any(windows, target_os = "macos")))]
+fn systemd_quote(arg: &str) -> String {
+    format!(
+        "\"{}\"",
+        systemd_escape(arg)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn unit(command: &CommandLine) -> String {
+    let mut exec = systemd_quote(&command.exe.display().to_string());
+    for arg in &command.args {
+        exec += " ";
+        exec += &systemd_quote(arg);
+    }
+    format!(
+        "[Unit]\n\
+         Description=Sync smart lights to VRChat\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         WorkingDirectory={}\n\
+         Restart=on-failure\n\
+         # Broken settings won't fix themselves by restarting\n\
+         RestartPreventExitStatus=2\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        exec,
+        systemd_escape(&command.dir.display().to_string())
+    )
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn enable(config: &Path, flags: &[&str]) -> Result<(), String> {
+    let unit = unit(&command_line(config, flags)?);
+    let path = unit_path()?;
+    fs::create_dir_all(path.parent().unwrap()).map_err(|err| err.to_string())?;
+    fs::write(&path, unit).map_err(|err| format!("Couldn't write {}: {}", path.display(), err))?;
+    run("systemctl", &["--user", "daemon-reload"])?;
+    run("systemctl", &["--user", "enable", "--now", NAME])
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn disable() -> Result<(), String> {
+    run("systemctl", &["--user", "disable", "--now", NAME]).ok();
+    let path = unit_path()?;
+    fs::remove_file(&path).map_err(|err| format!("Couldn't remove {}: {}", path.display(), err))?;
+    run("systemctl", &["--user", "daemon-reload"])
+}
+
+#[cfg(all(test, not(any(windows, target_os = "macos"))))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_the_systemd_unit() {
+        let command = CommandLine {
+            exe: PathBuf::from("/opt/light sync/vrchat-light-sync"),
+            args: vec![
+                "--config".to_owned(),
+                "/home/me/100% \"lights\"/settings.yaml".to_owned(),
+                "--preview".to_owned(),
+            ],
+            dir: PathBuf::from("/home/me/100% \"lights\""),
+        };
+        let unit = unit(&command);
+        assert!(unit.contains(
+            "ExecStart=\"/opt/light sync/vrchat-light-sync\" \"--config\" \
+             \"/home/me/100%% \\\"lights\\\"/settings.yaml\" \"--preview\"\n"
+        ));
+        assert!(unit.contains("WorkingDirectory=/home/me/100%% \"lights\"\n"));
+    }
+}
@@ -0,0 +1,288 @@
+use super::push::PushStore;
+use super::{create_backend, BackendError, BulbBackend};
+use crate::clock;
+use crate::config::SourceConfig;
+use crate::logging::{self, Category};
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::f32::consts::TAU;
+use std::time::Instant;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFunction {
+    // On when any of the sources is on
+    #[default]
+    Any,
+    // Only on when all of the sources are on
+    All,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HueFunction {
+    // The hue of the source whose state changed last
+    #[default]
+    MostRecent,
+    // The average hue of the sources that are on, going the short way around
+    Average,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BrightnessFunction {
+    #[default]
+    Max,
+    Min,
+    Average,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AggregateConfig {
+    pub sources: Vec<SourceConfig>,
+    #[serde(default)]
+    pub on: OnFunction,
+    #[serde(default)]
+    pub hue: HueFunction,
+    #[serde(default)]
+    pub brightness: BrightnessFunction,
+}
+
+impl AggregateConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sources.is_empty() {
+            return Err("The aggregate bulb service needs at least one source.".into());
+        }
+        self.sources.iter().try_for_each(SourceConfig::validate)
+    }
+}
+
+struct AggregateSource {
+    backend: Box<dyn BulbBackend>,
+    last_state: Option<BulbState>,
+    last_change: Option<Instant>,
+}
+
+// A virtual light whose state is worked out from several sources
+pub struct AggregateBackend {
+    sources: Vec<AggregateSource>,
+    on: OnFunction,
+    hue: HueFunction,
+    brightness: BrightnessFunction,
+}
+
+impl AggregateBackend {
+    pub fn new(config: &AggregateConfig, pushed: &PushStore) -> AggregateBackend {
+        AggregateBackend {
+            sources: config
+                .sources
+                .iter()
+                .map(|source| AggregateSource {
+                    backend: create_backend(source, pushed),
+                    last_state: None,
+                    last_change: None,
+                })
+                .collect(),
+            on: config.on,
+            hue: config.hue,
+            brightness: config.brightness,
+        }
+    }
+}
+
+fn average_hue(hues: &[f32]) -> f32 {
+    let (sin, cos) = hues.iter().fold((0.0, 0.0), |(sin, cos), hue| {
+        (sin + (hue * TAU).sin(), cos + (hue * TAU).cos())
+    });
+    (sin.atan2(cos) / TAU).rem_euclid(1.0)
+}
+
+impl BulbBackend for AggregateBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        let now = clock::now();
+        let mut first_error = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.backend.get_state() {
+                Ok(state) => {
+                    if source.last_state.is_some_and(|last| last != state) {
+                        source.last_change = Some(now);
+                    }
+                    source.last_state = Some(state);
+                }
+                Err(err) => {
+                    // Leave failing sources out until they come back
+                    logging::error(
+                        Category::Source,
+                        format!("Aggregate source {} failed: {}", i + 1, err),
+                    );
+                    source.last_state = None;
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+
+        let states: Vec<BulbState> = self
+            .sources
+            .iter()
+            .filter_map(|source| source.last_state)
+            .collect();
+        if states.is_empty() {
+            return Err(first_error.unwrap());
+        }
+        // Lights that are off don't have a meaningful color
+        let lit: Vec<BulbState> = states.iter().copied().filter(|s| s.on).collect();
+        let colored = if lit.is_empty() { &states } else { &lit };
+
+        let on = match self.on {
+            OnFunction::Any => states.iter().any(|s| s.on),
+            OnFunction::All => states.iter().all(|s| s.on),
+        };
+        // The saturation and color temperature go along with the hue
+        let color = match self.hue {
+            HueFunction::MostRecent => self
+                .sources
+                .iter()
+                .filter(|source| source.last_state.is_some())
+                .max_by_key(|source| source.last_change)
+                .and_then(|source| source.last_state)
+                .map_or((0.0, 1.0, None), |s| (s.hue, s.saturation, s.color_temp)),
+            HueFunction::Average => {
+                let count = colored.len() as f32;
+                // Only a white while all of them are
+                let color_temp = colored
+                    .iter()
+                    .map(|s| s.color_temp)
+                    .sum::<Option<f32>>()
+                    .map(|sum| sum / count);
+                (
+                    average_hue(&colored.iter().map(|s| s.hue).collect::<Vec<f32>>()),
+                    colored.iter().map(|s| s.saturation).sum::<f32>() / count,
+                    color_temp,
+                )
+            }
+        };
+        let brightnesses = colored.iter().map(|s| s.brightness);
+        let brightness = match self.brightness {
+            BrightnessFunction::Max => brightnesses.fold(0.0, f32::max),
+            BrightnessFunction::Min => brightnesses.fold(1.0, f32::min),
+            BrightnessFunction::Average => brightnesses.sum::<f32>() / colored.len() as f32,
+        };
+        Ok(BulbState::new(on, color, brightness))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn aggregate(functions: &str) -> (AggregateBackend, PushStore) {
+        let settings = format!(
+            "
+sources:
+    - bulb_service: push
+      push:
+          name: first
+    - bulb_service: push
+      push:
+          name: second
+{}",
+            functions
+        );
+        let config: AggregateConfig = serde_yaml::from_str(&settings).unwrap();
+        config.validate().unwrap();
+        let pushed = PushStore::default();
+        (AggregateBackend::new(&config, &pushed), pushed)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    #[test]
+    fn is_on_when_any_or_all_of_the_sources_are() {
+        let (mut any, pushed) = aggregate("on: any");
+        pushed.push("first", BulbState::color(true, 0.0, 1.0));
+        pushed.push("second", BulbState::color(false, 0.0, 0.0));
+        assert!(any.get_state().unwrap().on);
+
+        let (mut all, pushed) = aggregate("on: all");
+        pushed.push("first", BulbState::color(true, 0.0, 1.0));
+        pushed.push("second", BulbState::color(false, 0.0, 0.0));
+        assert!(!all.get_state().unwrap().on);
+        pushed.push("second", BulbState::color(true, 0.0, 1.0));
+        assert!(all.get_state().unwrap().on);
+    }
+
+    #[test]
+    fn averages_the_hue_the_short_way_around() {
+        let (mut backend, pushed) = aggregate("hue: average\nbrightness: average");
+        pushed.push("first", BulbState::color(true, 0.9, 0.2));
+        pushed.push("second", BulbState::color(true, 0.2, 0.6));
+        let state = backend.get_state().unwrap();
+        assert!(close(state.hue, 0.05));
+        assert!(close(state.brightness, 0.4));
+        // Lights that are off are left out
+        pushed.push("second", BulbState::color(false, 0.5, 0.0));
+        let state = backend.get_state().unwrap();
+        assert!(close(state.hue, 0.9));
+        assert!(close(state.brightness, 0.2));
+    }
+
+    #[test]
+    fn uses_the_brightest_or_dimmest_source() {
+        let (mut max, pushed) = aggregate("brightness: max");
+        pushed.push("first", BulbState::color(true, 0.0, 0.3));
+        pushed.push("second", BulbState::color(true, 0.0, 0.8));
+        assert!(close(max.get_state().unwrap().brightness, 0.8));
+
+        let (mut min, pushed) = aggregate("brightness: min");
+        pushed.push("first", BulbState::color(true, 0.0, 0.3));
+        pushed.push("second", BulbState::color(true, 0.0, 0.8));
+        assert!(close(min.get_state().unwrap().brightness, 0.3));
+    }
+
+    #[test]
+    fn uses_the_color_that_changed_last() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let (mut backend, pushed) = aggregate("hue: most_recent");
+        pushed.push("first", BulbState::color(true, 0.1, 1.0));
+        pushed.push("second", BulbState::color(true, 0.2, 1.0));
+        backend.get_state().unwrap();
+
+        mock.advance(Duration::from_secs(1));
+        pushed.push("first", BulbState::white(true, 2700.0, 1.0));
+        let state = backend.get_state().unwrap();
+        assert_eq!(state.color_temp, Some(2700.0));
+
+        mock.advance(Duration::from_secs(1));
+        pushed.push("second", BulbState::color(true, 0.6, 1.0));
+        let state = backend.get_state().unwrap();
+        assert!(close(state.hue, 0.6));
+        assert_eq!(state.color_temp, None);
+    }
+
+    #[test]
+    fn is_only_a_white_while_all_of_them_are() {
+        let (mut backend, pushed) = aggregate("hue: average");
+        pushed.push("first", BulbState::white(true, 2000.0, 1.0));
+        pushed.push("second", BulbState::white(true, 4000.0, 1.0));
+        assert_eq!(backend.get_state().unwrap().color_temp, Some(3000.0));
+        pushed.push("second", BulbState::color(true, 0.6, 1.0));
+        assert_eq!(backend.get_state().unwrap().color_temp, None);
+    }
+
+    #[test]
+    fn leaves_out_the_sources_that_fail() {
+        let (mut backend, pushed) = aggregate("on: all\nbrightness: min");
+        assert!(backend.get_state().is_err());
+        // Nothing was pushed to the second one yet
+        pushed.push("first", BulbState::color(true, 0.3, 0.7));
+        let state = backend.get_state().unwrap();
+        assert!(state.on);
+        assert!(close(state.brightness, 0.7));
+    }
+}
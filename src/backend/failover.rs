@@ -0,0 +1,232 @@
+use super::push::PushStore;
+use super::{create_backend, BackendError, BulbBackend};
+use crate::clock;
+use crate::config::{validate_seconds, SourceConfig};
+use crate::logging::{self, Category};
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+fn default_fail_after() -> u32 {
+    3
+}
+
+fn default_fail_back_after() -> f32 {
+    30.0
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FailoverConfig {
+    // Equivalent sources for the same light, in order of preference
+    pub sources: Vec<SourceConfig>,
+    // Failures in a row before moving on to the next source
+    #[serde(default = "default_fail_after")]
+    pub fail_after: u32,
+    // Seconds between checks of whether a preferred source is back
+    #[serde(default = "default_fail_back_after")]
+    pub fail_back_after: f32,
+}
+
+impl FailoverConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sources.is_empty() {
+            return Err("The failover bulb service needs at least one source.".into());
+        }
+        validate_seconds("failover.fail_back_after", self.fail_back_after, false)?;
+        self.sources.iter().try_for_each(SourceConfig::validate)
+    }
+}
+
+// Reads a light from the first healthy source out of several equivalent ones,
+// going back to the preferred sources when they recover.
+pub struct FailoverBackend {
+    backends: Vec<Box<dyn BulbBackend>>,
+    fail_after: u32,
+    fail_back_after: Duration,
+    active: usize,
+    failures: u32,
+    last_fail_back_check: Instant,
+    last_state: Option<BulbState>,
+}
+
+impl FailoverBackend {
+    pub fn new(config: &FailoverConfig, pushed: &PushStore) -> FailoverBackend {
+        FailoverBackend {
+            backends: config
+                .sources
+                .iter()
+                .map(|source| create_backend(source, pushed))
+                .collect(),
+            fail_after: config.fail_after.max(1),
+            fail_back_after: Duration::from_secs_f32(config.fail_back_after),
+            active: 0,
+            failures: 0,
+            last_fail_back_check: clock::now(),
+            last_state: None,
+        }
+    }
+
+    fn try_fail_back(&mut self) -> Option<BulbState> {
+        if self.active == 0 || clock::elapsed(self.last_fail_back_check) < self.fail_back_after {
+            return None;
+        }
+        self.last_fail_back_check = clock::now();
+        for i in 0..self.active {
+            if let Ok(state) = self.backends[i].get_state() {
+                println!("Source {} is back, failing back to it", i + 1);
+                self.active = i;
+                self.failures = 0;
+                return Some(state);
+            }
+        }
+        None
+    }
+}
+
+impl BulbBackend for FailoverBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        if let Some(state) = self.try_fail_back() {
+            self.last_state = Some(state);
+            return Ok(state);
+        }
+        loop {
+            match self.backends[self.active].get_state() {
+                Ok(state) => {
+                    self.failures = 0;
+                    self.last_state = Some(state);
+                    return Ok(state);
+                }
+                Err(err) => {
+                    self.failures += 1;
+                    logging::error(
+                        Category::Source,
+                        format!("Source {} failed: {}", self.active + 1, err),
+                    );
+                    if self.failures >= self.fail_after && self.active + 1 < self.backends.len() {
+                        self.active += 1;
+                        self.failures = 0;
+                        self.last_fail_back_check = clock::now();
+                        println!("Failing over to source {}", self.active + 1);
+                        continue;
+                    }
+                    // Ride out failures that haven't reached the limit yet
+                    // with the last state we got, past it there's nothing
+                    // left to fail over to and the light is down
+                    return match self.last_state {
+                        Some(state) if self.failures < self.fail_after => Ok(state),
+                        _ => Err(err),
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    // A source that answers with its hue while it's up
+    struct Source(Arc<AtomicBool>, f32);
+
+    impl BulbBackend for Source {
+        fn get_state(&mut self) -> Result<BulbState, BackendError> {
+            if self.0.load(Ordering::SeqCst) {
+                Ok(BulbState::color(true, self.1, 1.0))
+            } else {
+                Err("unreachable".into())
+            }
+        }
+    }
+
+    // Two sources that start out up, and how to take them down
+    fn failover() -> (FailoverBackend, [Arc<AtomicBool>; 2]) {
+        let up = [
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(AtomicBool::new(true)),
+        ];
+        let backend = FailoverBackend {
+            backends: vec![
+                Box::new(Source(up[0].clone(), 0.1)),
+                Box::new(Source(up[1].clone(), 0.2)),
+            ],
+            fail_after: 3,
+            fail_back_after: Duration::from_secs(30),
+            active: 0,
+            failures: 0,
+            last_fail_back_check: clock::now(),
+            last_state: None,
+        };
+        (backend, up)
+    }
+
+    fn hue(backend: &mut FailoverBackend) -> Option<f32> {
+        backend.get_state().ok().map(|state| state.hue)
+    }
+
+    #[test]
+    fn fails_over_once_the_source_keeps_failing() {
+        clock::set_local(Arc::new(MockClock::new(SystemTime::UNIX_EPOCH)));
+        let (mut backend, up) = failover();
+        assert_eq!(hue(&mut backend), Some(0.1));
+        up[0].store(false, Ordering::SeqCst);
+        // The last state until it has failed fail_after times
+        assert_eq!(hue(&mut backend), Some(0.1));
+        assert_eq!(hue(&mut backend), Some(0.1));
+        assert_eq!(hue(&mut backend), Some(0.2));
+        assert_eq!(backend.active, 1);
+    }
+
+    #[test]
+    fn fails_back_once_the_preferred_source_is_back() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let (mut backend, up) = failover();
+        up[0].store(false, Ordering::SeqCst);
+        for _ in 0..3 {
+            backend.get_state().ok();
+        }
+        assert_eq!(backend.active, 1);
+        up[0].store(true, Ordering::SeqCst);
+        // Only checked every fail_back_after
+        mock.advance(Duration::from_secs(29));
+        assert_eq!(hue(&mut backend), Some(0.2));
+        mock.advance(Duration::from_secs(1));
+        assert_eq!(hue(&mut backend), Some(0.1));
+        assert_eq!(backend.active, 0);
+    }
+
+    #[test]
+    fn reports_the_light_down_when_every_source_is() {
+        clock::set_local(Arc::new(MockClock::new(SystemTime::UNIX_EPOCH)));
+        let (mut backend, up) = failover();
+        assert_eq!(hue(&mut backend), Some(0.1));
+        up[0].store(false, Ordering::SeqCst);
+        up[1].store(false, Ordering::SeqCst);
+        for _ in 0..2 {
+            assert_eq!(hue(&mut backend), Some(0.1));
+        }
+        // Failing over to the last source, which has to fail as often too
+        for _ in 0..2 {
+            assert_eq!(hue(&mut backend), Some(0.1));
+        }
+        assert_eq!(backend.active, 1);
+        assert!(backend.get_state().is_err());
+        assert!(backend.get_state().is_err());
+        // And it's back as soon as a source is
+        up[1].store(true, Ordering::SeqCst);
+        assert_eq!(hue(&mut backend), Some(0.2));
+    }
+
+    #[test]
+    fn has_nothing_to_ride_out_with_before_the_first_state() {
+        clock::set_local(Arc::new(MockClock::new(SystemTime::UNIX_EPOCH)));
+        let (mut backend, up) = failover();
+        up[0].store(false, Ordering::SeqCst);
+        assert!(backend.get_state().is_err());
+    }
+}
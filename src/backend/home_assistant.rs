@@ -0,0 +1,610 @@
+use super::home_assistant_auth::{self, OAuthConfig};
+#[cfg(feature = "home-assistant-ws")]
+use super::home_assistant_ws::{
+    watch_registry, watch_state, RegistryChange, RegistryWatch, StateStream,
+};
+use super::{BackendError, BulbBackend};
+use crate::state::{
+    mireds_to_kelvin, rgb_color, translate, white_color, xy_to_rgb, BulbState, Color,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+#[cfg(feature = "home-assistant-ws")]
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RenameHandling {
+    // Don't watch for renamed entities
+    Off,
+    // Tell the user to update their settings when the entity is renamed
+    Warn,
+    // Switch to the entity's new id
+    #[default]
+    Follow,
+}
+
+// What a light is able to show, from least to most
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSupport {
+    // Work it out from the light's supported_color_modes
+    #[default]
+    Auto,
+    Onoff,
+    Brightness,
+    ColorTemp,
+    Color,
+}
+
+impl ColorSupport {
+    fn from_modes(modes: &[serde_json::Value]) -> ColorSupport {
+        modes
+            .iter()
+            .filter_map(|mode| match mode.as_str()? {
+                "hs" | "xy" | "rgb" | "rgbw" | "rgbww" => Some(ColorSupport::Color),
+                "color_temp" => Some(ColorSupport::ColorTemp),
+                "brightness" | "white" => Some(ColorSupport::Brightness),
+                "onoff" => Some(ColorSupport::Onoff),
+                _ => None,
+            })
+            .fold(ColorSupport::Onoff, |best, support| {
+                if support > best {
+                    support
+                } else {
+                    best
+                }
+            })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HomeAssistantConfig {
+    pub entity_id: String,
+    pub server_ip: String,
+    pub server_port: i32,
+    // A long-lived access token, not needed when using oauth
+    #[serde(default)]
+    pub bearer_token: String,
+    pub oauth: Option<OAuthConfig>,
+    #[serde(default)]
+    pub renames: RenameHandling,
+    #[serde(default)]
+    pub color_support: ColorSupport,
+    // Have Home Assistant push state changes over its websocket instead of
+    // asking for the state every poll
+    #[serde(default = "default_subscribe")]
+    pub subscribe: bool,
+}
+
+impl HomeAssistantConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bearer_token.is_empty() && self.oauth.is_none() {
+            return Err("home_assistant needs a bearer_token or an oauth section.".into());
+        }
+        Ok(())
+    }
+}
+
+fn default_subscribe() -> bool {
+    true
+}
+
+pub fn api_url(config: &HomeAssistantConfig, path: &str) -> String {
+    "http://".to_owned() + &config.server_ip + ":" + &config.server_port.to_string() + path
+}
+
+pub struct HomeAssistantBackend {
+    config: HomeAssistantConfig,
+    #[cfg(feature = "home-assistant-ws")]
+    registry: Option<RegistryWatch>,
+    #[cfg(feature = "home-assistant-ws")]
+    stream: Option<StateStream>,
+    // The newest state pushed by Home Assistant
+    #[cfg(feature = "home-assistant-ws")]
+    pushed: Option<serde_json::Value>,
+    // Worked out on the first successful read
+    support: Option<ColorSupport>,
+    // Kept so the connection stays open between polls
+    client: reqwest::blocking::Client,
+    // Where the entity's state is read from, made again when it's renamed
+    state_url: String,
+}
+
+impl HomeAssistantBackend {
+    pub fn new(config: HomeAssistantConfig) -> HomeAssistantBackend {
+        #[cfg(feature = "home-assistant-ws")]
+        let registry = match config.renames {
+            RenameHandling::Off => None,
+            _ => Some(watch_registry(&config)),
+        };
+        #[cfg(feature = "home-assistant-ws")]
+        let stream = config.subscribe.then(|| watch_state(&config));
+        #[cfg(not(feature = "home-assistant-ws"))]
+        if config.renames != RenameHandling::Off {
+            println!(
+                "WARNING: Following renamed entities needs the home-assistant-ws feature, {} won't be watched",
+                config.entity_id
+            );
+        }
+        #[cfg(not(feature = "home-assistant-ws"))]
+        if config.subscribe {
+            println!(
+                "WARNING: Subscribing to state changes needs the home-assistant-ws feature, {} will be polled",
+                config.entity_id
+            );
+        }
+        HomeAssistantBackend {
+            #[cfg(feature = "home-assistant-ws")]
+            registry,
+            #[cfg(feature = "home-assistant-ws")]
+            stream,
+            #[cfg(feature = "home-assistant-ws")]
+            pushed: None,
+            support: None,
+            client: reqwest::blocking::Client::new(),
+            state_url: state_url(&config),
+            config,
+        }
+    }
+
+    #[cfg(feature = "home-assistant-ws")]
+    fn handle_registry_changes(&mut self) {
+        let changes: Vec<RegistryChange> = match &self.registry {
+            Some(registry) => registry.changes.try_iter().collect(),
+            None => return,
+        };
+        for change in changes {
+            match change {
+                RegistryChange::Renamed { old, new } if old == self.config.entity_id => {
+                    if self.config.renames == RenameHandling::Follow {
+                        println!("{} was renamed to {}, following it", old, new);
+                        self.config.entity_id = new;
+                        self.state_url = state_url(&self.config);
+                        if let Some(stream) = &self.stream {
+                            *stream.entity_id.lock().unwrap() = self.config.entity_id.clone();
+                            stream.live.store(false, Ordering::Relaxed);
+                        }
+                        self.pushed = None;
+                    } else {
+                        println!(
+                            "WARNING: {} was renamed to {} in Home Assistant, change the entity_id in your settings to keep syncing it",
+                            old, new
+                        );
+                    }
+                }
+                RegistryChange::Removed { entity_id } if entity_id == self.config.entity_id => {
+                    println!(
+                        "WARNING: {} was removed from Home Assistant, it can't be synced anymore",
+                        entity_id
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The entity's state from the subscription, None while it's down so the
+    // state is polled instead
+    #[cfg(feature = "home-assistant-ws")]
+    fn pushed_state(&mut self) -> Option<serde_json::Value> {
+        let stream = self.stream.as_ref()?;
+        if let Some(json) = stream.states.try_iter().last() {
+            self.pushed = Some(json);
+        }
+        // The subscription sends the whole state again once it's back up
+        if !stream.live.load(Ordering::Relaxed) {
+            self.pushed = None;
+        }
+        self.pushed.clone()
+    }
+}
+
+impl BulbBackend for HomeAssistantBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        #[cfg(feature = "home-assistant-ws")]
+        self.handle_registry_changes();
+        #[cfg(feature = "home-assistant-ws")]
+        let json = match self.pushed_state() {
+            Some(json) => json,
+            None => fetch_state(&self.config, &self.client, &self.state_url)?,
+        };
+        #[cfg(not(feature = "home-assistant-ws"))]
+        let json = fetch_state(&self.config, &self.client, &self.state_url)?;
+        // Home Assistant can't reach the light itself, so what it has isn't
+        // worth anything
+        let state = json["state"].as_str().unwrap_or_default();
+        if state == "unavailable" || state == "unknown" {
+            return Err(format!("{} is {} in Home Assistant", self.config.entity_id, state).into());
+        }
+        let support = match self.support {
+            Some(support) => support,
+            None => {
+                let support = detect_support(&self.config, &json);
+                self.support = Some(support);
+                support
+            }
+        };
+        parse_state(&json, support)
+    }
+
+    fn set_state(&mut self, state: &BulbState) -> Result<(), BackendError> {
+        // Sent as a color until the light has been read
+        let support = self.support.unwrap_or(ColorSupport::Auto);
+        set_state(&self.config, &self.client, state, support)
+    }
+}
+
+// Decides how to read the light, warning when the settings ask for more than
+// the light can ever show
+fn detect_support(config: &HomeAssistantConfig, json: &serde_json::Value) -> ColorSupport {
+    let detected = match json["attributes"]["supported_color_modes"].as_array() {
+        Some(modes) => ColorSupport::from_modes(modes),
+        // Lights from before color modes existed always report hs_color
+        None => ColorSupport::Color,
+    };
+    let support = match config.color_support {
+        ColorSupport::Auto => detected,
+        configured => {
+            if configured > detected {
+                println!(
+                    "WARNING: color_support for {} is {:?} but the light only supports {:?}",
+                    config.entity_id, configured, detected
+                );
+            }
+            configured
+        }
+    };
+    if support < ColorSupport::Color {
+        println!(
+            "{} can't show colors, reading it as {:?}",
+            config.entity_id, support
+        );
+    }
+    support
+}
+
+// None when the attribute isn't there
+fn number(value: &serde_json::Value, name: &str) -> Result<Option<f32>, BackendError> {
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Number(val) => Ok(val.as_f64().map(|val| val as f32)),
+        _ => Err(format!(
+            "the {} value from Home Assistant isn't a number: {}",
+            name, value
+        )
+        .into()),
+    }
+}
+
+fn numbers<const N: usize>(
+    value: &serde_json::Value,
+    name: &str,
+) -> Result<Option<[f32; N]>, BackendError> {
+    let mut res = [0.0; N];
+    for (i, part) in res.iter_mut().enumerate() {
+        match number(&value[i], name)? {
+            Some(value) => *part = value,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(res))
+}
+
+// HA's warm white channel, roughly 2700K
+const WARM_WHITE: (f32, f32, f32) = (1.0, 0.65, 0.35);
+
+// The color of the light, read from whatever the current color mode fills in,
+// hs_color is there for lights from before color modes existed
+fn color(attributes: &serde_json::Value) -> Result<Option<Color>, BackendError> {
+    let hs = || {
+        Ok::<_, BackendError>(
+            numbers::<2>(&attributes["hs_color"], "Hue and saturation")?
+                .map(|[hue, saturation]| (hue / 360.0, saturation / 100.0, None)),
+        )
+    };
+    let color =
+        match attributes["color_mode"].as_str() {
+            Some("hs") => hs()?,
+            Some("xy") => numbers::<2>(&attributes["xy_color"], "xy")?
+                .map(|[x, y]| rgb_color(xy_to_rgb(x, y))),
+            Some("rgb") => numbers::<3>(&attributes["rgb_color"], "RGB")?
+                .map(|[r, g, b]| rgb_color((r / 255.0, g / 255.0, b / 255.0))),
+            Some("rgbw") => numbers::<4>(&attributes["rgbw_color"], "RGBW")?
+                .map(|[r, g, b, w]| rgb_color(((r + w) / 255.0, (g + w) / 255.0, (b + w) / 255.0))),
+            Some("rgbww") => {
+                numbers::<5>(&attributes["rgbww_color"], "RGBWW")?.map(|[r, g, b, cold, warm]| {
+                    rgb_color((
+                        (r + cold + warm * WARM_WHITE.0) / 255.0,
+                        (g + cold + warm * WARM_WHITE.1) / 255.0,
+                        (b + cold + warm * WARM_WHITE.2) / 255.0,
+                    ))
+                })
+            }
+            Some("color_temp") => {
+                let kelvin = match number(&attributes["color_temp_kelvin"], "Color temperature")? {
+                    Some(kelvin) => Some(kelvin),
+                    // Older versions only report mireds
+                    None => number(&attributes["color_temp"], "Color temperature")?
+                        .map(mireds_to_kelvin),
+                };
+                kelvin.map(white_color)
+            }
+            _ => None,
+        };
+    match color {
+        Some(color) => Ok(Some(color)),
+        None => hs(),
+    }
+}
+
+fn parse_state(json: &serde_json::Value, support: ColorSupport) -> Result<BulbState, BackendError> {
+    let on = json["state"] == "on";
+    let attributes = &json["attributes"];
+    let color = match support {
+        ColorSupport::Color | ColorSupport::ColorTemp => {
+            color(attributes)?.unwrap_or((0.0, 1.0, None))
+        }
+        // Lights that can't show colors are white
+        _ => (0.0, 0.0, None),
+    };
+    let brightness = match support {
+        // Lights that can only be switched are at full brightness when on
+        ColorSupport::Onoff if on => 255.0,
+        _ => number(&attributes["brightness"], "Brightness")?.unwrap_or(0.0),
+    };
+    Ok(BulbState::new(
+        on,
+        color,
+        translate(brightness, 0.0, 255.0, 0.0, 1.0),
+    ))
+}
+
+fn state_url(config: &HomeAssistantConfig) -> String {
+    api_url(config, &("/api/states/".to_owned() + &config.entity_id))
+}
+
+fn fetch_state(
+    config: &HomeAssistantConfig,
+    client: &reqwest::blocking::Client,
+    url: &str,
+) -> Result<serde_json::Value, BackendError> {
+    let res = client
+        .get(url)
+        .bearer_auth(home_assistant_auth::bearer_token(config)?)
+        .send()?;
+    if res.status() == StatusCode::UNAUTHORIZED {
+        home_assistant_auth::invalidate(config);
+    }
+    if res.status() == StatusCode::NOT_FOUND {
+        return Err(format!(
+            "{} doesn't exist in Home Assistant, was it renamed?",
+            config.entity_id
+        )
+        .into());
+    }
+    let res = res.error_for_status()?;
+    Ok(serde_json::from_str(&res.text()?)?)
+}
+
+// What the entity can show, read from its state
+pub fn read_support(
+    config: &HomeAssistantConfig,
+    client: &reqwest::blocking::Client,
+) -> Result<ColorSupport, BackendError> {
+    let json = fetch_state(config, client, &state_url(config))?;
+    Ok(detect_support(config, &json))
+}
+
+// The service to call for a state and what to call it with, leaving out what
+// the light can't show since Home Assistant refuses the whole call otherwise
+fn service_call(
+    entity_id: &str,
+    state: &BulbState,
+    support: ColorSupport,
+) -> (&'static str, serde_json::Value) {
+    if !state.on {
+        return (
+            "/api/services/light/turn_off",
+            serde_json::json!({ "entity_id": entity_id }),
+        );
+    }
+    let mut body = serde_json::json!({ "entity_id": entity_id });
+    if support != ColorSupport::Onoff {
+        body["brightness"] =
+            (translate(state.brightness, 0.0, 1.0, 0.0, 255.0).round() as u8).into();
+    }
+    match (support, state.color_temp) {
+        (ColorSupport::Onoff | ColorSupport::Brightness, _) => {}
+        (_, Some(kelvin)) => body["color_temp_kelvin"] = (kelvin.round() as u32).into(),
+        // The nearest white to the color
+        (ColorSupport::ColorTemp, None) => {
+            body["color_temp_kelvin"] = (state.kelvin().round() as u32).into()
+        }
+        (ColorSupport::Color | ColorSupport::Auto, None) => {
+            body["hs_color"] = serde_json::json!([state.hue * 360.0, state.saturation * 100.0])
+        }
+    }
+    ("/api/services/light/turn_on", body)
+}
+
+// Sets the state of the entity through the light.turn_on/turn_off services
+pub fn set_state(
+    config: &HomeAssistantConfig,
+    client: &reqwest::blocking::Client,
+    state: &BulbState,
+    support: ColorSupport,
+) -> Result<(), BackendError> {
+    let (path, body) = service_call(&config.entity_id, state, support);
+    client
+        .post(api_url(config, path))
+        .header(
+            "Authorization",
+            "Bearer ".to_owned() + &home_assistant_auth::bearer_token(config)?,
+        )
+        .body(body.to_string())
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    fn read(attributes: serde_json::Value, support: ColorSupport) -> BulbState {
+        parse_state(&json!({ "state": "on", "attributes": attributes }), support).unwrap()
+    }
+
+    #[test]
+    fn reads_the_color_of_every_color_mode() {
+        let hs = read(
+            json!({ "color_mode": "hs", "hs_color": [180, 50], "brightness": 255 }),
+            ColorSupport::Color,
+        );
+        assert!(hs.on && close(hs.hue, 0.5) && close(hs.saturation, 0.5));
+        assert!(close(hs.brightness, 1.0) && hs.color_temp.is_none());
+
+        let xy = read(
+            json!({ "color_mode": "xy", "xy_color": [0.64, 0.33], "brightness": 128 }),
+            ColorSupport::Color,
+        );
+        assert!(close(xy.hue, 0.0) && close(xy.saturation, 1.0));
+        assert!(close(xy.brightness, 128.0 / 255.0));
+
+        let rgb = read(
+            json!({ "color_mode": "rgb", "rgb_color": [0, 0, 255], "brightness": 255 }),
+            ColorSupport::Color,
+        );
+        assert!(close(rgb.hue, 2.0 / 3.0) && close(rgb.saturation, 1.0));
+
+        // The white channels wash the color out
+        let rgbw = read(
+            json!({ "color_mode": "rgbw", "rgbw_color": [255, 0, 0, 128], "brightness": 255 }),
+            ColorSupport::Color,
+        );
+        assert!(close(rgbw.hue, 0.0) && close(rgbw.saturation, 2.0 / 3.0));
+        let rgbww = read(
+            json!({ "color_mode": "rgbww", "rgbww_color": [0, 0, 0, 0, 255], "brightness": 255 }),
+            ColorSupport::Color,
+        );
+        assert!(rgbww.hue < 0.1 && rgbww.saturation > 0.5);
+
+        let white = read(
+            json!({ "color_mode": "color_temp", "color_temp_kelvin": 2700, "brightness": 255 }),
+            ColorSupport::ColorTemp,
+        );
+        assert_eq!(white.color_temp, Some(2700.0));
+        assert!(white.hue < 0.1);
+        // Older versions only report mireds
+        let mireds = read(
+            json!({ "color_mode": "color_temp", "color_temp": 250, "brightness": 255 }),
+            ColorSupport::Color,
+        );
+        assert_eq!(mireds.color_temp, Some(4000.0));
+    }
+
+    #[test]
+    fn reads_lights_that_cant_show_colors() {
+        let dimmable = read(
+            json!({ "color_mode": "brightness", "brightness": 51 }),
+            ColorSupport::Brightness,
+        );
+        assert!(close(dimmable.saturation, 0.0) && close(dimmable.brightness, 0.2));
+        let switch = read(json!({ "color_mode": "onoff" }), ColorSupport::Onoff);
+        assert!(switch.on && close(switch.saturation, 0.0) && close(switch.brightness, 1.0));
+        // Lights from before color modes existed only have hs_color
+        let old = read(
+            json!({ "hs_color": [120, 100], "brightness": 255 }),
+            ColorSupport::Color,
+        );
+        assert!(close(old.hue, 1.0 / 3.0));
+    }
+
+    #[test]
+    fn reads_lights_that_are_off() {
+        // Home Assistant leaves the color and brightness out while it's off
+        for support in [
+            ColorSupport::Color,
+            ColorSupport::ColorTemp,
+            ColorSupport::Brightness,
+            ColorSupport::Onoff,
+        ] {
+            let json = json!({ "state": "off", "attributes": {
+                "color_mode": null,
+                "hs_color": null,
+                "xy_color": null,
+                "color_temp_kelvin": null,
+                "brightness": null,
+                "supported_color_modes": ["hs", "color_temp"],
+            } });
+            let state = parse_state(&json, support).unwrap();
+            assert!(!state.on && close(state.brightness, 0.0));
+        }
+        // The color mode can be left over from when it was on
+        let json = json!({ "state": "off", "attributes": { "color_mode": "xy" } });
+        assert!(!parse_state(&json, ColorSupport::Color).unwrap().on);
+    }
+
+    #[test]
+    fn refuses_values_that_arent_numbers() {
+        let json = json!({ "state": "on", "attributes": {
+            "color_mode": "hs",
+            "hs_color": ["red", 100],
+        } });
+        assert!(parse_state(&json, ColorSupport::Color).is_err());
+        let json = json!({ "state": "on", "attributes": { "brightness": "full" } });
+        assert!(parse_state(&json, ColorSupport::Brightness).is_err());
+    }
+
+    #[test]
+    fn works_out_what_lights_can_show() {
+        let support =
+            |modes: serde_json::Value| ColorSupport::from_modes(modes.as_array().unwrap());
+        assert_eq!(support(json!(["color_temp", "xy"])), ColorSupport::Color);
+        assert_eq!(support(json!(["color_temp"])), ColorSupport::ColorTemp);
+        assert_eq!(support(json!(["brightness"])), ColorSupport::Brightness);
+        assert_eq!(support(json!(["onoff"])), ColorSupport::Onoff);
+        assert_eq!(support(json!([])), ColorSupport::Onoff);
+    }
+
+    #[test]
+    fn only_sets_what_the_light_can_show() {
+        let color = BulbState::new(true, (0.5, 0.5, None), 1.0);
+        let white = BulbState::white(true, 2700.0, 0.5);
+
+        let (path, body) = service_call("light.desk", &color, ColorSupport::Color);
+        assert_eq!(path, "/api/services/light/turn_on");
+        assert_eq!(
+            body,
+            json!({ "entity_id": "light.desk", "brightness": 255, "hs_color": [180.0, 50.0] })
+        );
+        let (_, body) = service_call("light.desk", &white, ColorSupport::Color);
+        assert_eq!(
+            body,
+            json!({ "entity_id": "light.desk", "brightness": 128, "color_temp_kelvin": 2700 })
+        );
+
+        // Colors become the nearest white on lights that can only show whites
+        let (_, body) = service_call("light.desk", &color, ColorSupport::ColorTemp);
+        assert!(body["hs_color"].is_null());
+        assert_eq!(body["color_temp_kelvin"], (color.kelvin().round() as u32));
+        let (_, body) = service_call("light.desk", &white, ColorSupport::Brightness);
+        assert_eq!(
+            body,
+            json!({ "entity_id": "light.desk", "brightness": 128 })
+        );
+        let (_, body) = service_call("light.desk", &white, ColorSupport::Onoff);
+        assert_eq!(body, json!({ "entity_id": "light.desk" }));
+
+        let off = BulbState::color(false, 0.5, 1.0);
+        let (path, body) = service_call("light.desk", &off, ColorSupport::Color);
+        assert_eq!(path, "/api/services/light/turn_off");
+        assert_eq!(body, json!({ "entity_id": "light.desk" }));
+    }
+}
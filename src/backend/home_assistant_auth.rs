@@ -0,0 +1,196 @@
+use super::home_assistant::{api_url, HomeAssistantConfig};
+use super::BackendError;
+use crate::clock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+fn default_token_file() -> PathBuf {
+    PathBuf::from("home_assistant_token.json")
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OAuthConfig {
+    // The client ID the tokens were made for, a URL like http://localhost/
+    pub client_id: String,
+    pub refresh_token: Option<String>,
+    // Code from logging in through /auth/authorize, traded for a refresh
+    // token the first time
+    pub authorization_code: Option<String>,
+    // Where the tokens are kept between runs
+    #[serde(default = "default_token_file")]
+    pub token_file: PathBuf,
+}
+
+// Refresh the access token this many seconds before it runs out
+const EXPIRY_MARGIN: u64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Tokens {
+    refresh_token: Option<String>,
+    access_token: Option<String>,
+    // Unix time the access token runs out at
+    expires_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+    refresh_token: Option<String>,
+}
+
+// Tokens are shared by every copy of the same Home Assistant settings, which
+// all live in different places
+static TOKENS: Mutex<Option<HashMap<PathBuf, Tokens>>> = Mutex::new(None);
+
+fn now() -> u64 {
+    clock::system()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+fn load(path: &Path) -> Tokens {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+// Only readable by us, written next to the file first so it's never left half
+// written
+fn write_private(path: &Path, text: &str) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    // A file left over from before could have been made readable to others
+    let _ = fs::remove_file(&temp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&temp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+fn save(path: &Path, tokens: &Tokens) {
+    let res = serde_json::to_string_pretty(tokens)
+        .map_err(|err| err.to_string())
+        .and_then(|text| write_private(path, &text).map_err(|err| err.to_string()));
+    if let Err(err) = res {
+        println!(
+            "Couldn't save the Home Assistant tokens to {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+fn request_token(
+    config: &HomeAssistantConfig,
+    form: &[(&str, &str)],
+) -> Result<TokenResponse, BackendError> {
+    let res = reqwest::blocking::Client::new()
+        .post(api_url(config, "/auth/token"))
+        .form(form)
+        .send()?;
+    if !res.status().is_success() {
+        return Err(format!(
+            "Home Assistant didn't give out a new token ({}), log in again to get a new refresh token",
+            res.status()
+        )
+        .into());
+    }
+    Ok(serde_json::from_str(&res.text()?)?)
+}
+
+// The tokens after getting a new access token with them
+fn refresh(
+    config: &HomeAssistantConfig,
+    oauth: &OAuthConfig,
+    tokens: &Tokens,
+) -> Result<Tokens, BackendError> {
+    let refresh_token = tokens
+        .refresh_token
+        .clone()
+        .or_else(|| oauth.refresh_token.clone());
+    let res = match (&refresh_token, &oauth.authorization_code) {
+        (Some(refresh_token), _) => request_token(
+            config,
+            &[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+                ("client_id", &oauth.client_id),
+            ],
+        )?,
+        (None, Some(code)) => request_token(
+            config,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("client_id", &oauth.client_id),
+            ],
+        )?,
+        (None, None) => {
+            return Err("oauth needs a refresh_token or an authorization_code".into());
+        }
+    };
+    Ok(Tokens {
+        refresh_token: res.refresh_token.or(refresh_token),
+        access_token: Some(res.access_token),
+        expires_at: now() + res.expires_in,
+    })
+}
+
+// The token to send to Home Assistant, refreshed first when it's about to run
+// out
+pub fn bearer_token(config: &HomeAssistantConfig) -> Result<String, BackendError> {
+    let oauth = match &config.oauth {
+        Some(oauth) => oauth,
+        None => return Ok(config.bearer_token.clone()),
+    };
+    let tokens = TOKENS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .entry(oauth.token_file.clone())
+        .or_insert_with(|| load(&oauth.token_file))
+        .clone();
+    if let Some(token) = &tokens.access_token {
+        if tokens.expires_at > now() + EXPIRY_MARGIN {
+            return Ok(token.clone());
+        }
+    }
+    // Not kept locked while asking, so the other lights don't wait on it
+    let tokens = refresh(config, oauth, &tokens)?;
+    let token = tokens.access_token.clone().unwrap();
+    let mut all_tokens = TOKENS.lock().unwrap();
+    save(&oauth.token_file, &tokens);
+    all_tokens
+        .get_or_insert_with(HashMap::new)
+        .insert(oauth.token_file.clone(), tokens);
+    Ok(token)
+}
+
+// Makes the next request get a new access token, for when Home Assistant
+// turned the current one away
+pub fn invalidate(config: &HomeAssistantConfig) {
+    if let Some(oauth) = &config.oauth {
+        if let Some(tokens) = TOKENS
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|all| all.get_mut(&oauth.token_file))
+        {
+            tokens.access_token = None;
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use super::home_assistant::HomeAssistantConfig;
+use super::home_assistant_auth::bearer_token;
+use super::BackendError;
+use crate::clock;
+use crate::logging::{self, Category};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+pub type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+// How often the connection checks which subscriptions are still needed
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// The connection is tried again after this at first, doubling the wait every
+// time it fails up to the max
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+fn read_json(socket: &mut Socket) -> Result<Value, BackendError> {
+    loop {
+        if let Message::Text(text) = socket.read()? {
+            return Ok(serde_json::from_str(&text)?);
+        }
+    }
+}
+
+pub fn send_json(socket: &mut Socket, value: Value) -> Result<(), BackendError> {
+    socket.send(Message::Text(value.to_string()))?;
+    Ok(())
+}
+
+// Connects to Home Assistant's WebSocket API and logs in
+pub fn connect(config: &HomeAssistantConfig) -> Result<Socket, BackendError> {
+    let url = format!(
+        "ws://{}:{}/api/websocket",
+        config.server_ip, config.server_port
+    );
+    let (mut socket, _) = tungstenite::connect(url)?;
+    read_json(&mut socket)?;
+    send_json(
+        &mut socket,
+        json!({ "type": "auth", "access_token": bearer_token(config)? }),
+    )?;
+    let answer = read_json(&mut socket)?;
+    if answer["type"] != "auth_ok" {
+        return Err(format!("Home Assistant didn't accept the bearer token: {}", answer).into());
+    }
+    Ok(socket)
+}
+
+// Tells the connection to stop passing things on once the backend that
+// subscribed is gone
+struct StopOnDrop(Arc<AtomicBool>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// Something that happened to an entity in Home Assistant's entity registry
+#[derive(Clone)]
+pub enum RegistryChange {
+    Renamed { old: String, new: String },
+    Removed { entity_id: String },
+}
+
+fn registry_change(data: &Value) -> Option<RegistryChange> {
+    let entity_id = data["entity_id"].as_str()?.to_owned();
+    match data["action"].as_str()? {
+        "update" => Some(RegistryChange::Renamed {
+            old: data["old_entity_id"].as_str()?.to_owned(),
+            new: entity_id,
+        }),
+        "remove" => Some(RegistryChange::Removed { entity_id }),
+        _ => None,
+    }
+}
+
+// The changes to the entity registry, watched until this is dropped
+pub struct RegistryWatch {
+    pub changes: mpsc::Receiver<RegistryChange>,
+    _stop: StopOnDrop,
+}
+
+// The states of an entity as Home Assistant pushes them, instead of asking
+// for them every poll
+pub struct StateStream {
+    // The new state objects, shaped like the ones from /api/states
+    pub states: mpsc::Receiver<Value>,
+    // Whether the subscription is up and has sent the entity's state since
+    // then, changes can have been missed while it wasn't
+    pub live: Arc<AtomicBool>,
+    // The entity to pass on the states of, which changes when it's renamed.
+    // live should be cleared along with changing it.
+    pub entity_id: Arc<Mutex<String>>,
+    _stop: StopOnDrop,
+}
+
+struct StateSubscriber {
+    entity_id: Arc<Mutex<String>>,
+    sender: mpsc::Sender<Value>,
+    live: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+}
+
+struct RegistrySubscriber {
+    sender: mpsc::Sender<RegistryChange>,
+    stop: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    states: Vec<StateSubscriber>,
+    registry: Vec<RegistrySubscriber>,
+}
+
+impl Subscribers {
+    fn remove_stopped(&mut self) {
+        self.states
+            .retain(|subscriber| !subscriber.stop.load(Ordering::Relaxed));
+        self.registry
+            .retain(|subscriber| !subscriber.stop.load(Ordering::Relaxed));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.states.is_empty() && self.registry.is_empty()
+    }
+
+    // The entities to subscribe to, sorted so they can be compared
+    fn entity_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .states
+            .iter()
+            .map(|subscriber| subscriber.entity_id.lock().unwrap().clone())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    fn set_live(&self, live: bool) {
+        for subscriber in &self.states {
+            subscriber.live.store(live, Ordering::Relaxed);
+        }
+    }
+
+    fn send_state(&self, entity_id: &str, state: Option<&Value>) {
+        for subscriber in &self.states {
+            if *subscriber.entity_id.lock().unwrap() != entity_id {
+                continue;
+            }
+            match state {
+                Some(state) => {
+                    // Set after sending, so a live stream always has a state
+                    let _ = subscriber.sender.send(state.clone());
+                    subscriber.live.store(true, Ordering::Relaxed);
+                }
+                // Removed, so the backend reads it itself and finds out
+                None => subscriber.live.store(false, Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+// One WebSocket connection to a Home Assistant server, shared by every light
+// on it for their state subscriptions and watching the entity registry
+struct Connection {
+    server: String,
+    // The server and how to log in, lights that log in differently get their
+    // own connection
+    key: String,
+    config: HomeAssistantConfig,
+    subscribers: Mutex<Subscribers>,
+}
+
+// The connections to every server that has something subscribed to it
+static CONNECTIONS: Mutex<Vec<Arc<Connection>>> = Mutex::new(Vec::new());
+
+fn server(config: &HomeAssistantConfig) -> String {
+    format!("{}:{}", config.server_ip, config.server_port)
+}
+
+fn key(config: &HomeAssistantConfig) -> String {
+    format!(
+        "{} {} {:?}",
+        server(config),
+        config.bearer_token,
+        config.oauth
+    )
+}
+
+// Adds a subscriber to the connection to the config's server, starting the
+// connection if there isn't one yet
+fn add_subscriber(config: &HomeAssistantConfig, add: impl FnOnce(&mut Subscribers)) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let key = key(config);
+    let connection = match connections.iter().find(|conn| conn.key == key) {
+        Some(connection) => connection.clone(),
+        None => {
+            let connection = Arc::new(Connection {
+                server: server(config),
+                key,
+                config: config.clone(),
+                subscribers: Mutex::new(Subscribers::default()),
+            });
+            connections.push(connection.clone());
+            let thread_connection = connection.clone();
+            clock::spawn(move || thread_connection.run());
+            connection
+        }
+    };
+    add(&mut connection.subscribers.lock().unwrap());
+}
+
+impl Connection {
+    // Forgets the connection once nothing is subscribed anymore, new
+    // subscribers are added while holding the same lock so none get lost
+    fn close_if_unused(&self) -> bool {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.remove_stopped();
+        if !subscribers.is_empty() {
+            return false;
+        }
+        connections.retain(|conn| conn.key != self.key);
+        true
+    }
+
+    // Connects and keeps the subscriptions up, reconnecting when the
+    // connection drops, until nothing is subscribed anymore
+    fn run(&self) {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            if self.close_if_unused() {
+                return;
+            }
+            let res =
+                connect(&self.config).and_then(|mut socket| self.serve(&mut socket, &mut delay));
+            self.subscribers.lock().unwrap().set_live(false);
+            match res {
+                // Nothing is subscribed anymore
+                Ok(()) => return,
+                Err(err) => logging::error(
+                    Category::Network,
+                    format!(
+                        "Lost the connection to Home Assistant at {}, polling until it's back in {:?}: {}",
+                        self.server, delay, err
+                    ),
+                ),
+            }
+            clock::sleep(delay);
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    // Sets up the subscriptions that are needed and passes on what comes
+    // through them
+    fn serve(&self, socket: &mut Socket, delay: &mut Duration) -> Result<(), BackendError> {
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(CHECK_INTERVAL))?;
+        }
+        let mut next_id = 1;
+        // The ids of the subscriptions asked for, with the entities the state
+        // subscription is for
+        let mut states: Option<(u64, Vec<String>)> = None;
+        let mut registry: Option<u64> = None;
+        // The entities' states as they were last sent, changes only come with
+        // what changed
+        let mut cache: HashMap<String, Value> = HashMap::new();
+        loop {
+            if self.close_if_unused() {
+                return Ok(());
+            }
+            let (entity_ids, wants_registry) = {
+                let subscribers = self.subscribers.lock().unwrap();
+                (subscribers.entity_ids(), !subscribers.registry.is_empty())
+            };
+            // Subscribed again whenever a light is added, removed or renamed
+            let subscribed = states.as_ref().map_or(&[][..], |(_, ids)| ids);
+            if subscribed != entity_ids {
+                if let Some((id, _)) = states.take() {
+                    let unsubscribe = json!({
+                        "id": next_id,
+                        "type": "unsubscribe_events",
+                        "subscription": id,
+                    });
+                    send_json(socket, unsubscribe)?;
+                    next_id += 1;
+                }
+                cache.clear();
+                if !entity_ids.is_empty() {
+                    let subscribe = json!({
+                        "id": next_id,
+                        "type": "subscribe_entities",
+                        "entity_ids": entity_ids,
+                    });
+                    send_json(socket, subscribe)?;
+                    states = Some((next_id, entity_ids));
+                    next_id += 1;
+                }
+            }
+            if wants_registry && registry.is_none() {
+                let subscribe = json!({
+                    "id": next_id,
+                    "type": "subscribe_events",
+                    "event_type": "entity_registry_updated",
+                });
+                send_json(socket, subscribe)?;
+                registry = Some(next_id);
+                next_id += 1;
+            }
+
+            let message: Value = match socket.read() {
+                Ok(Message::Text(text)) => serde_json::from_str(&text)?,
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let id = message["id"].as_u64();
+            let is_states = id.is_some() && states.as_ref().map(|(id, _)| *id) == id;
+            let is_registry = id.is_some() && registry == id;
+            match message["type"].as_str() {
+                Some("result") if is_states && message["success"] != true => {
+                    return Err(format!(
+                        "Home Assistant didn't allow the subscription: {}",
+                        message
+                    )
+                    .into());
+                }
+                Some("result") if is_states => {
+                    *delay = MIN_RECONNECT_DELAY;
+                }
+                // Renames just aren't noticed then, the states keep coming
+                Some("result") if is_registry && message["success"] != true => {
+                    logging::error(
+                        Category::Network,
+                        format!(
+                            "Home Assistant at {} didn't allow watching for renamed entities: {}",
+                            self.server, message["error"]
+                        ),
+                    );
+                }
+                Some("event") if is_states => {
+                    let subscribers = self.subscribers.lock().unwrap();
+                    for (entity_id, state) in apply_entity_event(&mut cache, &message["event"]) {
+                        subscribers.send_state(&entity_id, state.as_ref());
+                    }
+                }
+                Some("event") if is_registry => {
+                    if let Some(change) = registry_change(&message["event"]["data"]) {
+                        let subscribers = self.subscribers.lock().unwrap();
+                        for subscriber in &subscribers.registry {
+                            let _ = subscriber.sender.send(change.clone());
+                        }
+                    }
+                }
+                // Answers to unsubscribing and events from before it
+                _ => {}
+            }
+        }
+    }
+}
+
+// An entity's state from subscribe_entities, which leaves out the names to
+// save space, shaped like the ones from /api/states
+fn full_state(entity_id: &str, compressed: &Value) -> Value {
+    let attributes = match &compressed["a"] {
+        Value::Object(attributes) => Value::Object(attributes.clone()),
+        _ => json!({}),
+    };
+    json!({
+        "entity_id": entity_id,
+        "state": compressed["s"],
+        "attributes": attributes,
+    })
+}
+
+// Applies what changed about a state, with "+" holding the new and changed
+// values and "-" the attributes that were removed
+fn apply_diff(state: &mut Value, diff: &Value) {
+    let added = &diff["+"];
+    if !added["s"].is_null() {
+        state["state"] = added["s"].clone();
+    }
+    if let Value::Object(attributes) = &added["a"] {
+        for (name, value) in attributes {
+            state["attributes"][name] = value.clone();
+        }
+    }
+    if let (Value::Array(removed), Value::Object(attributes)) =
+        (&diff["-"]["a"], &mut state["attributes"])
+    {
+        for name in removed.iter().filter_map(Value::as_str) {
+            attributes.remove(name);
+        }
+    }
+}
+
+// Updates the cached states with an event from subscribe_entities, returning
+// the new state of every entity it was about, None for removed ones
+fn apply_entity_event(
+    cache: &mut HashMap<String, Value>,
+    event: &Value,
+) -> Vec<(String, Option<Value>)> {
+    let mut changed = Vec::new();
+    if let Value::Object(added) = &event["a"] {
+        for (entity_id, compressed) in added {
+            let state = full_state(entity_id, compressed);
+            cache.insert(entity_id.clone(), state.clone());
+            changed.push((entity_id.clone(), Some(state)));
+        }
+    }
+    if let Value::Object(diffs) = &event["c"] {
+        for (entity_id, diff) in diffs {
+            // A change to a state that wasn't sent first can't be worked out
+            if let Some(state) = cache.get_mut(entity_id) {
+                apply_diff(state, diff);
+                changed.push((entity_id.clone(), Some(state.clone())));
+            }
+        }
+    }
+    if let Value::Array(removed) = &event["r"] {
+        for entity_id in removed.iter().filter_map(Value::as_str) {
+            cache.remove(entity_id);
+            changed.push((entity_id.to_owned(), None));
+        }
+    }
+    changed
+}
+
+// Watches Home Assistant's entity registry in the background, through the
+// connection shared with the server's other lights
+pub fn watch_registry(config: &HomeAssistantConfig) -> RegistryWatch {
+    let (sender, changes) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let subscriber = RegistrySubscriber {
+        sender,
+        stop: stop.clone(),
+    };
+    add_subscriber(config, |subscribers| subscribers.registry.push(subscriber));
+    RegistryWatch {
+        changes,
+        _stop: StopOnDrop(stop),
+    }
+}
+
+// Subscribes to the entity's state changes in the background, through the
+// connection shared with the server's other lights
+pub fn watch_state(config: &HomeAssistantConfig) -> StateStream {
+    let (sender, states) = mpsc::channel();
+    let live = Arc::new(AtomicBool::new(false));
+    let entity_id = Arc::new(Mutex::new(config.entity_id.clone()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let subscriber = StateSubscriber {
+        entity_id: entity_id.clone(),
+        sender,
+        live: live.clone(),
+        stop: stop.clone(),
+    };
+    add_subscriber(config, |subscribers| subscribers.states.push(subscriber));
+    StateStream {
+        states,
+        live,
+        entity_id,
+        _stop: StopOnDrop(stop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn works_out_states_from_the_changes() {
+        let mut cache = HashMap::new();
+        let added = json!({ "a": { "light.desk": {
+            "s": "on",
+            "a": { "brightness": 255, "hs_color": [30, 100], "color_mode": "hs" },
+            "c": "01H", "lc": 1.0,
+        } } });
+        let changed = apply_entity_event(&mut cache, &added);
+        assert_eq!(
+            changed,
+            [(
+                "light.desk".to_owned(),
+                Some(json!({
+                    "entity_id": "light.desk",
+                    "state": "on",
+                    "attributes": { "brightness": 255, "hs_color": [30, 100], "color_mode": "hs" },
+                }))
+            )]
+        );
+
+        let diff = json!({ "c": { "light.desk": {
+            "+": { "a": { "color_temp_kelvin": 2700, "color_mode": "color_temp" }, "lu": 2.0 },
+            "-": { "a": ["hs_color"] },
+        } } });
+        let (_, state) = apply_entity_event(&mut cache, &diff).remove(0);
+        let state = state.unwrap();
+        assert_eq!(state["state"], "on");
+        assert_eq!(
+            state["attributes"],
+            json!({ "brightness": 255, "color_temp_kelvin": 2700, "color_mode": "color_temp" })
+        );
+
+        let off = json!({ "c": { "light.desk": { "+": { "s": "off" } } } });
+        let (_, state) = apply_entity_event(&mut cache, &off).remove(0);
+        assert_eq!(state.unwrap()["state"], "off");
+    }
+
+    #[test]
+    fn leaves_out_changes_it_cant_work_out() {
+        let mut cache = HashMap::new();
+        // Never sent in full, so only the change is known
+        let diff = json!({ "c": { "light.desk": { "+": { "s": "off" } } } });
+        assert!(apply_entity_event(&mut cache, &diff).is_empty());
+
+        let added = json!({ "a": { "light.desk": { "s": "on" } } });
+        let (_, state) = apply_entity_event(&mut cache, &added).remove(0);
+        assert_eq!(state.unwrap()["attributes"], json!({}));
+        let removed = json!({ "r": ["light.desk"] });
+        assert_eq!(
+            apply_entity_event(&mut cache, &removed),
+            [("light.desk".to_owned(), None)]
+        );
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use super::{BackendError, BulbBackend};
+use crate::state::{
+    kelvin_to_mireds, mireds_to_kelvin, rgb_color, white_color, xy_to_rgb, BulbState,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HueBridgeConfig {
+    pub bridge_ip: String,
+    // The username the bridge hands out when its link button is pressed:
+    // https://developers.meethue.com/develop/get-started-2/
+    pub username: String,
+    // The light's number on the bridge, from /api/<username>/lights
+    pub light_id: String,
+}
+
+// A light on a Philips Hue bridge, read through the bridge's local API
+pub struct HueBridgeBackend {
+    // Kept so the connection stays open between polls
+    client: reqwest::blocking::Client,
+    url: String,
+}
+
+impl HueBridgeBackend {
+    pub fn new(config: &HueBridgeConfig) -> HueBridgeBackend {
+        HueBridgeBackend {
+            client: reqwest::blocking::Client::new(),
+            url: format!(
+                "http://{}/api/{}/lights/{}",
+                config.bridge_ip, config.username, config.light_id
+            ),
+        }
+    }
+}
+
+// The bridge answers with a list of errors instead of failing the request
+fn check_errors(json: &serde_json::Value) -> Result<(), BackendError> {
+    let error = json
+        .as_array()
+        .and_then(|answers| answers.iter().find_map(|answer| answer.get("error")));
+    match error {
+        Some(error) => Err(format!(
+            "The Hue bridge refused: {}",
+            error["description"].as_str().unwrap_or("unknown error")
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+fn parse_state(state: &serde_json::Value) -> BulbState {
+    let number = |value: &serde_json::Value| value.as_f64().map(|value| value as f32);
+    let color = match state["colormode"].as_str() {
+        Some("xy") => number(&state["xy"][0])
+            .zip(number(&state["xy"][1]))
+            .map(|(x, y)| rgb_color(xy_to_rgb(x, y))),
+        Some("ct") => number(&state["ct"]).map(|mireds| white_color(mireds_to_kelvin(mireds))),
+        Some("hs") => number(&state["hue"]).map(|hue| {
+            let saturation = number(&state["sat"]).unwrap_or(254.0) / 254.0;
+            (hue / 65535.0, saturation, None)
+        }),
+        // Lights that can only be dimmed have no color mode and are white
+        _ => None,
+    };
+    BulbState::new(
+        state["on"].as_bool().unwrap_or(false),
+        color.unwrap_or((0.0, 0.0, None)),
+        // Only goes up to 254, 1 is the dimmest the light can be while on
+        number(&state["bri"]).unwrap_or(254.0) / 254.0,
+    )
+}
+
+impl BulbBackend for HueBridgeBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        let res = self.client.get(&self.url).send()?.error_for_status()?;
+        let json: serde_json::Value = serde_json::from_str(&res.text()?)?;
+        check_errors(&json)?;
+        Ok(parse_state(&json["state"]))
+    }
+
+    fn set_state(&mut self, state: &BulbState) -> Result<(), BackendError> {
+        let body = if state.on {
+            let bri = (state.brightness * 254.0).round().max(1.0) as u8;
+            match state.color_temp {
+                // The bridge takes color temperatures in mireds
+                Some(kelvin) => json!({
+                    "on": true,
+                    "bri": bri,
+                    "ct": kelvin_to_mireds(kelvin).round() as u16,
+                }),
+                None => json!({
+                    "on": true,
+                    "bri": bri,
+                    "hue": (state.hue.rem_euclid(1.0) * 65535.0).round() as u16,
+                    "sat": (state.saturation * 254.0).round() as u8,
+                }),
+            }
+        } else {
+            json!({ "on": false })
+        };
+        let res = self
+            .client
+            .put(self.url.clone() + "/state")
+            .body(body.to_string())
+            .send()?
+            .error_for_status()?;
+        check_errors(&serde_json::from_str(&res.text()?)?)
+    }
+}
@@ -0,0 +1,70 @@
+pub mod aggregate;
+pub mod failover;
+#[cfg(feature = "home-assistant")]
+pub mod home_assistant;
+#[cfg(feature = "home-assistant")]
+pub mod home_assistant_auth;
+#[cfg(feature = "home-assistant-ws")]
+pub mod home_assistant_ws;
+#[cfg(feature = "hue-bridge")]
+pub mod hue_bridge;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod priority;
+pub mod push;
+#[cfg(feature = "wled")]
+pub mod wled;
+
+use crate::config::{BulbService, SourceConfig};
+use crate::state::BulbState;
+use aggregate::AggregateBackend;
+use failover::FailoverBackend;
+#[cfg(feature = "home-assistant")]
+use home_assistant::HomeAssistantBackend;
+#[cfg(feature = "hue-bridge")]
+use hue_bridge::HueBridgeBackend;
+#[cfg(feature = "mqtt")]
+use mqtt::MqttBackend;
+use priority::PriorityBackend;
+use push::{PushBackend, PushStore};
+#[cfg(feature = "wled")]
+use wled::WledBackend;
+
+pub type BackendError = Box<dyn std::error::Error + Send + Sync>;
+
+// A service the state of a light can be read from
+// Send so lights can be polled in parallel
+pub trait BulbBackend: Send {
+    fn get_state(&mut self) -> Result<BulbState, BackendError>;
+
+    // Changes the light itself, for controlling it from the avatar
+    fn set_state(&mut self, _state: &BulbState) -> Result<(), BackendError> {
+        Err("this bulb service can't be changed from VRChat".into())
+    }
+
+    // Whether to poll less often while it keeps failing, so a service that's
+    // down isn't flooded with requests
+    fn backs_off(&self) -> bool {
+        true
+    }
+}
+
+// Push sources take their states from pushed
+pub fn create_backend(source: &SourceConfig, pushed: &PushStore) -> Box<dyn BulbBackend> {
+    match source.bulb_service() {
+        #[cfg(feature = "home-assistant")]
+        BulbService::HomeAssistant => {
+            Box::new(HomeAssistantBackend::new(source.home_assistant().clone()))
+        }
+        #[cfg(feature = "wled")]
+        BulbService::Wled => Box::new(WledBackend::new(source.wled())),
+        #[cfg(feature = "hue-bridge")]
+        BulbService::HueBridge => Box::new(HueBridgeBackend::new(source.hue_bridge())),
+        #[cfg(feature = "mqtt")]
+        BulbService::Mqtt => Box::new(MqttBackend::new(source.mqtt())),
+        BulbService::Priority => Box::new(PriorityBackend::new(source.priority(), pushed)),
+        BulbService::Failover => Box::new(FailoverBackend::new(source.failover(), pushed)),
+        BulbService::Aggregate => Box::new(AggregateBackend::new(source.aggregate(), pushed)),
+        BulbService::Push => Box::new(PushBackend::new(source.push(), pushed)),
+    }
+}
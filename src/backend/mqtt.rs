@@ -0,0 +1,598 @@
+use super::{BackendError, BulbBackend};
+use crate::clock;
+use crate::config::validate_rate;
+use crate::logging::{self, Category};
+use crate::state::{
+    kelvin_to_mireds, mireds_to_kelvin, rgb_color, white_color, xy_to_rgb, BulbState, Color,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_brightness_scale() -> f32 {
+    254.0
+}
+
+// How long the broker is told it can go without hearing from us, a ping is
+// sent every half of it
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MqttConfig {
+    // IP or hostname of the MQTT broker
+    pub broker: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // Where the light publishes its state as JSON, like zigbee2mqtt/desk_lamp
+    pub topic: String,
+    // Where changes to the light are published, the topic with /set after it
+    // if left out
+    pub set_topic: Option<String>,
+    // Where the light is asked to publish its state once subscribed, the
+    // topic with /get after it if left out like zigbee2mqtt takes. Empty
+    // doesn't ask, for lights that keep their state retained.
+    pub get_topic: Option<String>,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    // The brightness the light reports when fully on, zigbee2mqtt goes up to
+    // 254 and Home Assistant's JSON schema to 255
+    #[serde(default = "default_brightness_scale")]
+    pub brightness_scale: f32,
+}
+
+impl MqttConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_rate("mqtt.brightness_scale", self.brightness_scale, 65535.0)
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    // Where packets are written while connected
+    writer: Option<TcpStream>,
+    // The newest state published since connecting
+    state: Option<BulbState>,
+    closed: bool,
+}
+
+fn push_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+// Puts the fixed header with the type and length in front of the packet
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        out.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+// Reads the rest of a packet after its first byte
+fn read_packet(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = 0;
+    let mut byte = [0];
+    for shift in [0, 7, 14, 21] {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body)?;
+            return Ok(body);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the broker sent a packet that's too long",
+    ))
+}
+
+fn send(shared: &Mutex<Shared>, bytes: &[u8]) -> Result<(), BackendError> {
+    match &mut shared.lock().unwrap().writer {
+        Some(writer) => Ok(writer.write_all(bytes)?),
+        None => Err("not connected to the MQTT broker".into()),
+    }
+}
+
+fn publish(shared: &Mutex<Shared>, topic: &str, payload: &str) -> Result<(), BackendError> {
+    let mut body = Vec::new();
+    push_string(&mut body, topic);
+    body.extend_from_slice(payload.as_bytes());
+    send(shared, &packet(0x30, &body))
+}
+
+// Connects to the broker and subscribes to the light's topic
+fn connect(config: &MqttConfig) -> Result<TcpStream, BackendError> {
+    let mut stream = TcpStream::connect((config.broker.as_str(), config.port))?;
+    stream.set_read_timeout(Some(KEEP_ALIVE / 2))?;
+    // Always starts a clean session, the state is read again anyway
+    let mut flags = 0x02;
+    if !config.username.is_empty() {
+        flags |= 0x80;
+    }
+    if !config.password.is_empty() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, "MQTT");
+    // Version 3.1.1
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    // An empty client ID has the broker make one up
+    push_string(&mut body, "");
+    if !config.username.is_empty() {
+        push_string(&mut body, &config.username);
+    }
+    if !config.password.is_empty() {
+        push_string(&mut body, &config.password);
+    }
+    stream.write_all(&packet(0x10, &body))?;
+    let mut kind = [0];
+    stream.read_exact(&mut kind)?;
+    let answer = read_packet(&mut stream)?;
+    match (kind[0], answer.get(1)) {
+        (0x20, Some(0)) => {}
+        (0x20, Some(code)) => {
+            return Err(format!("the broker refused the connection with code {}", code).into())
+        }
+        _ => return Err("the broker didn't answer the connection".into()),
+    }
+    // Packet ID 1, and only asking for the messages to be sent once
+    let mut body = vec![0, 1];
+    push_string(&mut body, &config.topic);
+    body.push(0);
+    stream.write_all(&packet(0x82, &body))?;
+    Ok(stream)
+}
+
+// Whether the broker's answer to subscribing let us subscribe
+fn subscribed(body: &[u8], topic: &str) -> Result<(), BackendError> {
+    match body.get(2) {
+        Some(0x80) => Err(format!("the broker refused the subscription to {}", topic).into()),
+        Some(_) => Ok(()),
+        None => Err("the broker sent a broken answer to subscribing".into()),
+    }
+}
+
+// What was published in a packet of the given type, None for packets that
+// aren't published messages or are cut short
+fn published(kind: u8, body: &[u8]) -> Option<&[u8]> {
+    if kind >> 4 != 3 {
+        return None;
+    }
+    let topic_len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    // Messages sent more than once have an ID before the payload
+    let start = 2 + topic_len + if kind & 0x06 != 0 { 2 } else { 0 };
+    body.get(start..)
+}
+
+// The state from a message in the JSON format zigbee2mqtt and Home Assistant
+// use for lights
+fn parse_state(payload: &[u8], brightness_scale: f32) -> Result<BulbState, BackendError> {
+    let json: Value = serde_json::from_slice(payload)?;
+    let on = match json["state"].as_str() {
+        Some(state) => state.eq_ignore_ascii_case("on"),
+        None => return Err("it has no state".into()),
+    };
+    let number = |value: &Value| value.as_f64().map(|value| value as f32);
+    let color = &json["color"];
+    let color_color = || -> Option<Color> {
+        number(&color["hue"])
+            .or_else(|| number(&color["h"]))
+            .map(|hue| {
+                let saturation = number(&color["saturation"])
+                    .or_else(|| number(&color["s"]))
+                    .unwrap_or(100.0);
+                (hue / 360.0, saturation / 100.0, None)
+            })
+            .or_else(|| {
+                number(&color["x"])
+                    .zip(number(&color["y"]))
+                    .map(|(x, y)| rgb_color(xy_to_rgb(x, y)))
+            })
+            .or_else(|| {
+                let channel = |name| number(&color[name]).map(|value| value / 255.0);
+                Some(rgb_color((channel("r")?, channel("g")?, channel("b")?)))
+            })
+    };
+    let temperature_color =
+        || number(&json["color_temp"]).map(|mireds| white_color(mireds_to_kelvin(mireds)));
+    // Lights in white mode still report an approximate color
+    let color = if json["color_mode"] == "color_temp" {
+        temperature_color().or_else(color_color)
+    } else {
+        color_color().or_else(temperature_color)
+    };
+    let brightness = match number(&json["brightness"]) {
+        Some(brightness) => brightness / brightness_scale,
+        // Lights that can only be switched are at full brightness when on
+        None if on => 1.0,
+        None => 0.0,
+    };
+    // Lights that can't show colors are white
+    Ok(BulbState::new(
+        on,
+        color.unwrap_or((0.0, 0.0, None)),
+        brightness,
+    ))
+}
+
+// The message that changes the light to the state, in the same format
+fn state_payload(state: &BulbState, brightness_scale: f32) -> Value {
+    if !state.on {
+        return json!({ "state": "OFF" });
+    }
+    let brightness = (state.brightness * brightness_scale).round();
+    match state.color_temp {
+        Some(kelvin) => json!({
+            "state": "ON",
+            "brightness": brightness,
+            "color_temp": kelvin_to_mireds(kelvin).round(),
+        }),
+        None => json!({
+            "state": "ON",
+            "brightness": brightness,
+            "color": {
+                "h": state.hue.rem_euclid(1.0) * 360.0,
+                "s": state.saturation * 100.0,
+            },
+        }),
+    }
+}
+
+// Passes on the states published on the topic until the connection drops or
+// the backend is gone
+fn listen(
+    config: &MqttConfig,
+    shared: &Mutex<Shared>,
+    mut stream: TcpStream,
+) -> Result<(), BackendError> {
+    {
+        let mut shared = shared.lock().unwrap();
+        // Dropped while connecting, before there was a stream to shut down
+        if shared.closed {
+            return Ok(());
+        }
+        shared.writer = Some(stream.try_clone()?);
+    }
+    let get_topic = match &config.get_topic {
+        Some(topic) if topic.is_empty() => None,
+        Some(topic) => Some(topic.clone()),
+        None => Some(config.topic.clone() + "/get"),
+    };
+    let mut last_ping = clock::now();
+    loop {
+        if shared.lock().unwrap().closed {
+            return Ok(());
+        }
+        if clock::elapsed(last_ping) >= KEEP_ALIVE / 2 {
+            send(shared, &packet(0xc0, &[]))?;
+            last_ping = clock::now();
+        }
+        let mut kind = [0];
+        match stream.read(&mut kind) {
+            Ok(0) => return Err("the broker closed the connection".into()),
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err.into()),
+        }
+        let body = read_packet(&mut stream)?;
+        // Asks for the state once it's sure to hear the answer
+        if kind[0] >> 4 == 9 {
+            subscribed(&body, &config.topic)?;
+            if let Some(topic) = &get_topic {
+                publish(shared, topic, r#"{"state":""}"#)?;
+            }
+            continue;
+        }
+        // Only published messages matter, the rest are acknowledgements
+        let payload = match published(kind[0], &body) {
+            Some(payload) => payload,
+            None => continue,
+        };
+        match parse_state(payload, config.brightness_scale) {
+            Ok(state) => shared.lock().unwrap().state = Some(state),
+            Err(err) => logging::error(
+                Category::Source,
+                format!(
+                    "Couldn't read the state published on {}: {}",
+                    config.topic, err
+                ),
+            ),
+        }
+    }
+}
+
+fn run(config: &MqttConfig, shared: &Mutex<Shared>) {
+    loop {
+        if shared.lock().unwrap().closed {
+            return;
+        }
+        let res = connect(config).and_then(|stream| listen(config, shared, stream));
+        {
+            let mut shared = shared.lock().unwrap();
+            shared.writer = None;
+            shared.state = None;
+            if shared.closed {
+                return;
+            }
+        }
+        if let Err(err) = res {
+            logging::error(
+                Category::Source,
+                format!(
+                    "Lost the connection to the MQTT broker at {}, trying again in {:?}: {}",
+                    config.broker, RECONNECT_DELAY, err
+                ),
+            );
+        }
+        clock::sleep(RECONNECT_DELAY);
+    }
+}
+
+// A light whose state is published to an MQTT broker, like one paired through
+// zigbee2mqtt. The state is kept from the messages instead of being polled.
+pub struct MqttBackend {
+    config: MqttConfig,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl MqttBackend {
+    pub fn new(config: &MqttConfig) -> MqttBackend {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let thread_config = config.clone();
+        let thread_shared = shared.clone();
+        clock::spawn(move || run(&thread_config, &thread_shared));
+        MqttBackend {
+            config: config.clone(),
+            shared,
+        }
+    }
+}
+
+impl BulbBackend for MqttBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        let shared = self.shared.lock().unwrap();
+        if shared.writer.is_none() {
+            return Err(
+                format!("Not connected to the MQTT broker at {}", self.config.broker).into(),
+            );
+        }
+        shared.state.ok_or_else(|| {
+            format!("Nothing has been published on {} yet", self.config.topic).into()
+        })
+    }
+
+    fn set_state(&mut self, state: &BulbState) -> Result<(), BackendError> {
+        let payload = state_payload(state, self.config.brightness_scale);
+        let topic = match &self.config.set_topic {
+            Some(topic) => topic.clone(),
+            None => self.config.topic.clone() + "/set",
+        };
+        publish(&self.shared, &topic, &payload.to_string())
+    }
+}
+
+impl Drop for MqttBackend {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.closed = true;
+        // Wakes the thread up from reading
+        if let Some(writer) = &shared.writer {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(port: u16) -> MqttConfig {
+        MqttConfig {
+            broker: "127.0.0.1".to_owned(),
+            port,
+            topic: "zigbee2mqtt/lamp".to_owned(),
+            set_topic: None,
+            get_topic: None,
+            username: String::new(),
+            password: String::new(),
+            brightness_scale: 254.0,
+        }
+    }
+
+    // Reads a whole packet from the client
+    fn receive(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut kind = [0];
+        stream.read_exact(&mut kind).unwrap();
+        (kind[0], read_packet(stream).unwrap())
+    }
+
+    #[test]
+    fn asks_for_the_state_once_subscribed() {
+        let broker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut backend = MqttBackend::new(&config(broker.local_addr().unwrap().port()));
+        let (mut client, _) = broker.accept().unwrap();
+        assert_eq!(receive(&mut client).0, 0x10);
+        client.write_all(&packet(0x20, &[0, 0])).unwrap();
+        let (kind, subscribe) = receive(&mut client);
+        assert_eq!(kind, 0x82);
+        assert_eq!(&subscribe[4..], b"zigbee2mqtt/lamp\0");
+        // Nothing's asked before the subscription is through
+        client.write_all(&packet(0x90, &[0, 1, 0])).unwrap();
+        let (kind, get) = receive(&mut client);
+        assert_eq!(kind, 0x30);
+        assert_eq!(published(kind, &get), Some(&br#"{"state":""}"#[..]));
+        assert_eq!(&get[2..22], b"zigbee2mqtt/lamp/get");
+        let mut answer = Vec::new();
+        push_string(&mut answer, "zigbee2mqtt/lamp");
+        answer.extend_from_slice(br#"{"state":"ON","brightness":254}"#);
+        client.write_all(&packet(0x30, &answer)).unwrap();
+        let start = std::time::Instant::now();
+        while backend.get_state().is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(backend.get_state().unwrap().on);
+    }
+
+    #[test]
+    fn refused_subscriptions_are_errors() {
+        assert!(subscribed(&[0, 1, 0x80], "lamp").is_err());
+        assert!(subscribed(&[0, 1], "lamp").is_err());
+        assert!(subscribed(&[0, 1, 0], "lamp").is_ok());
+    }
+
+    #[test]
+    fn stops_when_dropped_while_waiting_to_reconnect() {
+        let mock = Arc::new(crate::clock::MockClock::new(
+            std::time::SystemTime::UNIX_EPOCH,
+        ));
+        clock::set_local(mock.clone());
+        let broker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend = MqttBackend::new(&config(broker.local_addr().unwrap().port()));
+        // Hangs up straight away so it waits to connect again
+        drop(broker.accept().unwrap());
+        mock.wait_for_sleepers(1);
+        drop(backend);
+        mock.advance_to_next();
+        broker.set_nonblocking(true).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(broker.accept().is_err());
+    }
+
+    fn close(a: &BulbState, b: &BulbState) -> bool {
+        a.on == b.on
+            && (a.hue - b.hue).abs() < 0.01
+            && (a.saturation - b.saturation).abs() < 0.01
+            && (a.brightness - b.brightness).abs() < 0.01
+            && a.color_temp.map(f32::round) == b.color_temp.map(f32::round)
+    }
+
+    #[test]
+    fn packets_read_back_at_every_length() {
+        for len in [0, 1, 127, 128, 16_383, 16_384, 2_097_152] {
+            let body: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let bytes = packet(0x30, &body);
+            assert_eq!(bytes[0], 0x30);
+            assert_eq!(read_packet(&mut &bytes[1..]).unwrap(), body);
+        }
+        // The length takes as few bytes as it can
+        assert_eq!(packet(0xc0, &[]), [0xc0, 0]);
+        assert_eq!(packet(0x30, &[0; 128])[..3], [0x30, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn broken_packets_are_errors() {
+        // Cut short in the length and in the body
+        let short: [&[u8]; 3] = [&[], &[0x80], &[5, 1, 2]];
+        for bytes in short {
+            let err = read_packet(&mut &bytes[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        // Lengths only go up to four bytes
+        let err = read_packet(&mut &[0xff, 0xff, 0xff, 0xff, 0x01][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn finds_what_was_published() {
+        let mut body = Vec::new();
+        push_string(&mut body, "zigbee2mqtt/lamp");
+        body.extend_from_slice(b"{}");
+        assert_eq!(published(0x30, &body), Some(&b"{}"[..]));
+        // Sent at least once, with a packet ID after the topic
+        let mut with_id = Vec::new();
+        push_string(&mut with_id, "zigbee2mqtt/lamp");
+        with_id.extend_from_slice(&[0, 7]);
+        with_id.extend_from_slice(b"{}");
+        assert_eq!(published(0x32, &with_id), Some(&b"{}"[..]));
+        // Acknowledgements and packets cut short before the payload
+        assert_eq!(published(0x90, &[0, 1, 0]), None);
+        assert_eq!(published(0x30, &[0]), None);
+        assert_eq!(published(0x30, &body[..10]), None);
+        assert_eq!(published(0x32, &with_id[..19]), None);
+    }
+
+    #[test]
+    fn reads_the_light_formats() {
+        let state = |json: &str| parse_state(json.as_bytes(), 254.0).unwrap();
+        let hs = state(r#"{"state":"ON","brightness":127,"color":{"hue":180,"saturation":50}}"#);
+        assert!(close(&hs, &BulbState::new(true, (0.5, 0.5, None), 0.5)));
+        let short = state(r#"{"state":"on","brightness":254,"color":{"h":90}}"#);
+        assert!(close(&short, &BulbState::color(true, 0.25, 1.0)));
+        let rgb = state(r#"{"state":"ON","color":{"r":0,"g":0,"b":255}}"#);
+        assert!(close(&rgb, &BulbState::color(true, 2.0 / 3.0, 1.0)));
+        let xy = state(r#"{"state":"ON","color":{"x":0.3127,"y":0.329}}"#);
+        assert!(xy.saturation < 0.05);
+        // White mode goes by the temperature even when a color is reported
+        let white = state(
+            r#"{"state":"ON","brightness":254,"color_mode":"color_temp","color_temp":400,"color":{"h":0,"s":100}}"#,
+        );
+        assert!(close(&white, &BulbState::white(true, 2500.0, 1.0)));
+        // Switches without a brightness or color
+        assert!(close(
+            &state(r#"{"state":"ON"}"#),
+            &BulbState::new(true, (0.0, 0.0, None), 1.0)
+        ));
+        assert!(!state(r#"{"state":"OFF","brightness":254}"#).on);
+    }
+
+    #[test]
+    fn rejects_broken_messages() {
+        for payload in [
+            &b""[..],
+            b"{",
+            b"not json",
+            b"{}",
+            b"{\"state\":1}",
+            b"[\"ON\"]",
+        ] {
+            assert!(parse_state(payload, 254.0).is_err(), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn published_states_read_back() {
+        let states = [
+            BulbState::color(true, 0.3, 0.6),
+            BulbState::new(true, (0.9, 0.4, None), 1.0),
+            BulbState::white(true, 2500.0, 0.2),
+        ];
+        for scale in [254.0, 255.0] {
+            for state in states {
+                let payload = state_payload(&state, scale).to_string();
+                let read = parse_state(payload.as_bytes(), scale).unwrap();
+                assert!(close(&read, &state), "{:?} read back as {:?}", state, read);
+            }
+        }
+        let off = state_payload(&BulbState::color(false, 0.3, 0.6), 254.0).to_string();
+        assert!(!parse_state(off.as_bytes(), 254.0).unwrap().on);
+    }
+}
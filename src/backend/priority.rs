@@ -0,0 +1,224 @@
+use super::push::PushStore;
+use super::{create_backend, BackendError, BulbBackend};
+use crate::clock;
+use crate::config::{validate_seconds, SourceConfig};
+use crate::logging::{self, Category};
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PrioritySourceConfig {
+    // Higher priorities override lower ones while they are active
+    #[serde(default)]
+    pub priority: i32,
+    // Seconds without changes before the source gives control back, the source
+    // is always active when this isn't set.
+    pub idle_timeout: Option<f32>,
+    #[serde(flatten)]
+    pub source: SourceConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PriorityConfig {
+    pub sources: Vec<PrioritySourceConfig>,
+}
+
+impl PriorityConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sources.is_empty() {
+            return Err("The priority bulb service needs at least one source.".into());
+        }
+        for source in &self.sources {
+            if let Some(idle_timeout) = source.idle_timeout {
+                validate_seconds("priority.idle_timeout", idle_timeout, false)?;
+            }
+            source.source.validate()?;
+        }
+        Ok(())
+    }
+}
+
+struct PrioritySource {
+    priority: i32,
+    idle_timeout: Option<Duration>,
+    backend: Box<dyn BulbBackend>,
+    last_state: Option<BulbState>,
+    last_change: Option<Instant>,
+}
+
+impl PrioritySource {
+    fn is_active(&self, now: Instant) -> bool {
+        match self.idle_timeout {
+            None => true,
+            Some(timeout) => self
+                .last_change
+                .is_some_and(|changed| now.duration_since(changed) < timeout),
+        }
+    }
+}
+
+// Reads several sources for the same light, using the state of the highest
+// priority source that is currently active.
+pub struct PriorityBackend {
+    // Sorted from the highest priority to the lowest
+    sources: Vec<PrioritySource>,
+    active: Option<usize>,
+}
+
+impl PriorityBackend {
+    pub fn new(config: &PriorityConfig, pushed: &PushStore) -> PriorityBackend {
+        let mut sources: Vec<PrioritySource> = config
+            .sources
+            .iter()
+            .map(|source| PrioritySource {
+                priority: source.priority,
+                idle_timeout: source.idle_timeout.map(Duration::from_secs_f32),
+                backend: create_backend(&source.source, pushed),
+                last_state: None,
+                last_change: None,
+            })
+            .collect();
+        sources.sort_by_key(|source| -source.priority);
+        PriorityBackend {
+            sources,
+            active: None,
+        }
+    }
+}
+
+impl BulbBackend for PriorityBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        let now = clock::now();
+        let mut first_error = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.backend.get_state() {
+                Ok(state) => {
+                    // The first state at startup doesn't count as the source
+                    // being used, only changes after that do.
+                    if source.last_state.is_some_and(|last| last != state) {
+                        source.last_change = Some(now);
+                    }
+                    source.last_state = Some(state);
+                }
+                Err(err) => {
+                    logging::error(
+                        Category::Source,
+                        format!("Priority source {} failed: {}", i + 1, err),
+                    );
+                    source.last_state = None;
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+
+        // Use the highest priority active source, falling back to the lowest
+        // priority one that has a state.
+        let chosen = self
+            .sources
+            .iter()
+            .position(|source| source.last_state.is_some() && source.is_active(now))
+            .or_else(|| {
+                self.sources
+                    .iter()
+                    .rposition(|source| source.last_state.is_some())
+            });
+        let chosen = match chosen {
+            Some(chosen) => chosen,
+            None => return Err(first_error.unwrap()),
+        };
+        if self.active != Some(chosen) {
+            println!(
+                "Source with priority {} is now in control",
+                self.sources[chosen].priority
+            );
+            self.active = Some(chosen);
+        }
+        Ok(self.sources[chosen].last_state.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    const CONFIG: &str = "
+sources:
+    - priority: 10
+      idle_timeout: 60
+      bulb_service: push
+      push:
+          name: override
+    - priority: 0
+      bulb_service: push
+      push:
+          name: room
+";
+
+    fn priority() -> (PriorityBackend, PushStore) {
+        let config: PriorityConfig = serde_yaml::from_str(CONFIG).unwrap();
+        config.validate().unwrap();
+        let pushed = PushStore::default();
+        (PriorityBackend::new(&config, &pushed), pushed)
+    }
+
+    fn hue(backend: &mut PriorityBackend) -> Option<f32> {
+        backend.get_state().ok().map(|state| state.hue)
+    }
+
+    #[test]
+    fn the_highest_priority_source_takes_over_while_its_changing() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let (mut backend, pushed) = priority();
+        pushed.push("room", BulbState::color(true, 0.1, 1.0));
+        pushed.push("override", BulbState::color(true, 0.5, 1.0));
+        // Its state from the start doesn't count as being used
+        assert_eq!(hue(&mut backend), Some(0.1));
+        pushed.push("override", BulbState::color(true, 0.6, 1.0));
+        assert_eq!(hue(&mut backend), Some(0.6));
+        // The lower priority changing doesn't take it back
+        pushed.push("room", BulbState::color(true, 0.2, 1.0));
+        assert_eq!(hue(&mut backend), Some(0.6));
+
+        // Handed back once it hasn't changed for idle_timeout
+        mock.advance(Duration::from_secs(59));
+        assert_eq!(hue(&mut backend), Some(0.6));
+        mock.advance(Duration::from_secs(1));
+        assert_eq!(hue(&mut backend), Some(0.2));
+        // and taken over again with the next change
+        pushed.push("override", BulbState::color(true, 0.7, 1.0));
+        assert_eq!(hue(&mut backend), Some(0.7));
+    }
+
+    #[test]
+    fn falls_back_to_the_sources_that_can_be_read() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let (mut backend, pushed) = priority();
+        assert!(backend.get_state().is_err());
+        // Inactive, but the only one with a state
+        pushed.push("override", BulbState::color(true, 0.5, 1.0));
+        assert_eq!(hue(&mut backend), Some(0.5));
+        pushed.push("room", BulbState::color(true, 0.1, 1.0));
+        assert_eq!(hue(&mut backend), Some(0.1));
+    }
+
+    #[test]
+    fn sorts_the_sources_by_priority() {
+        let config = CONFIG
+            .replace("priority: 10", "priority: -5")
+            .replace("idle_timeout: 60", "idle_timeout: 1");
+        let config: PriorityConfig = serde_yaml::from_str(&config).unwrap();
+        let backend = PriorityBackend::new(&config, &PushStore::default());
+        let priorities: Vec<i32> = backend
+            .sources
+            .iter()
+            .map(|source| source.priority)
+            .collect();
+        assert_eq!(priorities, [0, -5]);
+    }
+}
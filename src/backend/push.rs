@@ -0,0 +1,61 @@
+use super::{BackendError, BulbBackend};
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PushConfig {
+    // What the program embedding the sync pushes this light's state as
+    pub name: String,
+}
+
+// The newest state pushed under every name, each embedded sync has its own
+#[derive(Debug, Clone, Default)]
+pub struct PushStore(Arc<Mutex<HashMap<String, BulbState>>>);
+
+impl PushStore {
+    // Makes every push source with this name show the state from now on
+    pub fn push(&self, name: &str, state: BulbState) {
+        self.0.lock().unwrap().insert(name.to_owned(), state);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<BulbState> {
+        self.0.lock().unwrap().get(name).copied()
+    }
+}
+
+// A light whose state is handed over by whatever embeds the sync, instead of
+// being read from a service
+pub struct PushBackend {
+    name: String,
+    pushed: PushStore,
+}
+
+impl PushBackend {
+    pub fn new(config: &PushConfig, pushed: &PushStore) -> PushBackend {
+        PushBackend {
+            name: config.name.clone(),
+            pushed: pushed.clone(),
+        }
+    }
+}
+
+impl BulbBackend for PushBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        self.pushed
+            .get(&self.name)
+            .ok_or_else(|| format!("Nothing has been pushed as {} yet", self.name).into())
+    }
+
+    // Shows up as if the embedding program pushed it, until it pushes again
+    fn set_state(&mut self, state: &BulbState) -> Result<(), BackendError> {
+        self.pushed.push(&self.name, *state);
+        Ok(())
+    }
+
+    // Nothing to wait for, a push can come any moment
+    fn backs_off(&self) -> bool {
+        false
+    }
+}
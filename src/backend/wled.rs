@@ -0,0 +1,84 @@
+use super::{BackendError, BulbBackend};
+use crate::state::{hsv_to_rgb, rgb_color, BulbState};
+use serde::Deserialize;
+use serde_json::json;
+
+fn default_port() -> u16 {
+    80
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WledConfig {
+    // IP or hostname of the WLED controller
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // Which segment's color is synced, the first one by default
+    #[serde(default)]
+    pub segment: usize,
+}
+
+// A WLED LED strip, read through its JSON API
+pub struct WledBackend {
+    config: WledConfig,
+    // Kept so the connection stays open between polls
+    client: reqwest::blocking::Client,
+    url: String,
+}
+
+impl WledBackend {
+    pub fn new(config: &WledConfig) -> WledBackend {
+        WledBackend {
+            config: config.clone(),
+            client: reqwest::blocking::Client::new(),
+            url: format!("http://{}:{}/json/state", config.host, config.port),
+        }
+    }
+}
+
+fn parse_state(json: &serde_json::Value, segment: usize) -> Result<BulbState, BackendError> {
+    let color = &json["seg"][segment]["col"][0];
+    let channel = |i: usize| color[i].as_f64().map(|value| value as f32 / 255.0);
+    let (red, green, blue) = match (channel(0), channel(1), channel(2)) {
+        (Some(red), Some(green), Some(blue)) => (red, green, blue),
+        _ => return Err(format!("WLED has no color for segment {}", segment).into()),
+    };
+    // The white channel of RGBW strips washes the color out
+    let white = color[3].as_f64().map_or(0.0, |value| value as f32 / 255.0);
+    let channel = |value: f32| (value + white).min(1.0);
+    Ok(BulbState::new(
+        json["on"].as_bool().unwrap_or(false),
+        rgb_color((channel(red), channel(green), channel(blue))),
+        json["bri"].as_f64().unwrap_or(0.0) as f32 / 255.0,
+    ))
+}
+
+impl BulbBackend for WledBackend {
+    fn get_state(&mut self) -> Result<BulbState, BackendError> {
+        let res = self.client.get(&self.url).send()?.error_for_status()?;
+        parse_state(&serde_json::from_str(&res.text()?)?, self.config.segment)
+    }
+
+    fn set_state(&mut self, state: &BulbState) -> Result<(), BackendError> {
+        let body = if state.on {
+            let (red, green, blue) = hsv_to_rgb(state.hue, state.saturation, 1.0);
+            let channel = |value: f32| (value * 255.0).round() as u8;
+            json!({
+                "on": true,
+                "bri": channel(state.brightness),
+                "seg": [{
+                    "id": self.config.segment,
+                    "col": [[channel(red), channel(green), channel(blue)]],
+                }],
+            })
+        } else {
+            json!({ "on": false })
+        };
+        self.client
+            .post(&self.url)
+            .body(body.to_string())
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
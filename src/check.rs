@@ -0,0 +1,101 @@
+use crate::clock;
+use crate::config::Config;
+use crate::light::Light;
+use crate::output::remote::{host_info, RemoteTarget};
+use crate::vrchat_addr;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+// How long a light gets to answer, sources that stream their state like MQTT
+// need a moment to connect first
+const POLL_TIMEOUT: Duration = Duration::from_secs(3);
+const POLL_RETRY: Duration = Duration::from_millis(250);
+
+// Starts every light with the settings and reads it, then looks for VRChat
+// where the OSC goes. The settings have to be validated already, which
+// checks the times, rates and LUT files, the world profiles' too. Returns
+// whether every light could be started and read and VRChat could be reached.
+pub fn run(config: &Config) -> bool {
+    println!("OK      settings: valid");
+    let vrc_addr = vrchat_addr(config);
+    let mut ok = true;
+    for light_config in config.lights.iter() {
+        let mut light = match Light::new(config, light_config, &vrc_addr) {
+            Ok(light) => light,
+            Err(err) => {
+                println!("FAILED  {}: {}", light_config.name, err);
+                ok = false;
+                continue;
+            }
+        };
+        let start = clock::now();
+        while !light.status().healthy && clock::elapsed(start) < POLL_TIMEOUT {
+            clock::sleep(POLL_RETRY);
+            light.poll();
+        }
+        let status = light.status();
+        if status.healthy {
+            println!("OK      {}: {:?}", status.name, status.state);
+        } else {
+            println!("FAILED  {}: couldn't be read", status.name);
+            ok = false;
+        }
+    }
+
+    let mut target = match RemoteTarget::new(
+        &vrc_addr,
+        config.vrchat_target.as_ref(),
+        config.vrchat_autodiscover,
+    ) {
+        Ok(target) => target,
+        Err(err) => {
+            println!("FAILED  VRChat: {}", err);
+            return false;
+        }
+    };
+    // Hostnames are looked up in the background
+    let start = clock::now();
+    while target.addr().is_none() && clock::elapsed(start) < POLL_TIMEOUT {
+        clock::sleep(POLL_RETRY);
+        target.refresh();
+    }
+    let addr = match target.addr() {
+        Some(addr) => addr,
+        None => {
+            println!("FAILED  VRChat: couldn't look up {}", vrc_addr);
+            return false;
+        }
+    };
+    if let Some(oscquery_port) = config.vrchat_target.as_ref().and_then(|t| t.oscquery_port) {
+        match host_info(&addr, oscquery_port) {
+            Ok(info) => println!(
+                "OK      VRChat: OSCQuery answered, sending OSC to {}",
+                info.osc_addr(addr)
+            ),
+            Err(err) => {
+                println!(
+                    "FAILED  VRChat: OSCQuery on port {}: {}",
+                    oscquery_port, err
+                );
+                ok = false;
+            }
+        }
+    } else if addr.ip().is_loopback() {
+        // OSC doesn't answer, but VRChat on this computer holds the port
+        if UdpSocket::bind(addr).is_ok() {
+            println!(
+                "FAILED  VRChat: nothing is listening on {}, is it running with OSC enabled?",
+                addr
+            );
+            ok = false;
+        } else {
+            println!("OK      VRChat: listening on {}", addr);
+        }
+    } else {
+        println!(
+            "OK      VRChat: sending to {}, add an oscquery_port to vrchat_target to check it's there",
+            addr
+        );
+    }
+    ok
+}
@@ -0,0 +1,220 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use std::cell::RefCell;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+// Where syncing gets the time from and how it waits, so rate limiting,
+// resyncs, backoff and the rest can be run against a clock that's moved by
+// hand instead of waiting for real
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    // The wall clock, for anything that happens at a time of day or is stored
+    fn system(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// A clock that only moves when it's told to. Sleeping waits until advance has
+// moved it past the end of the sleep, so a test decides when every loop wakes
+// up and they go through their schedule the same way every time.
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    state: Mutex<MockState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct MockState {
+    elapsed: Duration,
+    // When each sleeping thread wakes up, a thread is taken off as soon as the
+    // clock passes its time so it no longer counts as sleeping
+    sleepers: Vec<Duration>,
+}
+
+impl MockClock {
+    // Starts at the given wall clock time
+    pub fn new(system: SystemTime) -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            system_start: system,
+            state: Mutex::new(MockState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    // Moves the clock forward, waking up the threads whose sleep ended
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += duration;
+        let elapsed = state.elapsed;
+        state.sleepers.retain(|until| *until > elapsed);
+        self.changed.notify_all();
+    }
+
+    // Moves the clock to when the next sleeping thread wakes up, returns how
+    // far it went or None when nothing is sleeping
+    pub fn advance_to_next(&self) -> Option<Duration> {
+        let next = {
+            let state = self.state.lock().unwrap();
+            *state.sleepers.iter().min()? - state.elapsed
+        };
+        self.advance(next);
+        Some(next)
+    }
+
+    // Waits until at least count threads are sleeping, so they can be moved
+    // along knowing where they are
+    pub fn wait_for_sleepers(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.sleepers.len() < count {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.state.lock().unwrap().elapsed
+    }
+
+    fn system(&self) -> SystemTime {
+        self.system_start + self.state.lock().unwrap().elapsed
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if duration.is_zero() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let until = state.elapsed + duration;
+        state.sleepers.push(until);
+        self.changed.notify_all();
+        while state.elapsed < until {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+// The system clock until another one is set
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+thread_local! {
+    // Takes over from CLOCK on this thread and the ones it starts with spawn
+    static LOCAL: RefCell<Option<Arc<dyn Clock>>> = const { RefCell::new(None) };
+}
+
+// Switches every part of syncing over to the clock, best done before anything
+// starts so times from different clocks aren't compared
+pub fn set(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = Some(clock);
+}
+
+// Switches only this thread and the threads it starts over to the clock, so
+// syncs running side by side, like tests or embedded engines, each keep their
+// own
+pub fn set_local(clock: Arc<dyn Clock>) {
+    LOCAL.with(|local| *local.borrow_mut() = Some(clock));
+}
+
+// Starts a thread that goes by the same clock as this one
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let local = LOCAL.with(|local| local.borrow().clone());
+    thread::spawn(move || {
+        if let Some(clock) = local {
+            set_local(clock);
+        }
+        f()
+    })
+}
+
+fn with_clock<T>(f: impl FnOnce(&dyn Clock) -> T) -> T {
+    // Not kept locked while sleeping
+    let clock = LOCAL
+        .with(|local| local.borrow().clone())
+        .or_else(|| CLOCK.read().unwrap().clone());
+    match clock {
+        Some(clock) => f(clock.as_ref()),
+        None => f(&SystemClock),
+    }
+}
+
+pub fn now() -> Instant {
+    with_clock(|clock| clock.now())
+}
+
+pub fn system() -> SystemTime {
+    with_clock(|clock| clock.system())
+}
+
+// The wall clock in the local time zone
+pub fn local() -> NaiveDateTime {
+    DateTime::<Local>::from(system()).naive_local()
+}
+
+pub fn sleep(duration: Duration) {
+    with_clock(|clock| clock.sleep(duration))
+}
+
+// Time passed since an earlier now
+pub fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn mock_sleep_waits_for_the_clock() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        set_local(mock.clone());
+        let (sender, woke) = mpsc::channel();
+        let thread = spawn(move || {
+            sleep(Duration::from_secs(5));
+            sender.send(now()).unwrap();
+        });
+        mock.wait_for_sleepers(1);
+        mock.advance(Duration::from_secs(4));
+        assert!(woke.try_recv().is_err());
+        assert_eq!(mock.advance_to_next(), Some(Duration::from_secs(1)));
+        let at = woke.recv().unwrap();
+        thread.join().unwrap();
+        assert_eq!(at, mock.now());
+        assert_eq!(mock.advance_to_next(), None);
+    }
+
+    #[test]
+    fn local_clocks_are_kept_apart() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        set_local(mock.clone());
+        mock.advance(Duration::from_secs(60));
+        assert_eq!(system(), SystemTime::UNIX_EPOCH + Duration::from_secs(60));
+        // Threads started without spawn go by the system clock
+        let other = thread::spawn(system).join().unwrap();
+        assert!(other > SystemTime::UNIX_EPOCH + Duration::from_secs(3600));
+        // and the ones started with it by this thread's
+        assert_eq!(spawn(system).join().unwrap(), system());
+    }
+}
@@ -0,0 +1,612 @@
+use crate::backend::aggregate::AggregateConfig;
+use crate::backend::failover::FailoverConfig;
+#[cfg(feature = "home-assistant")]
+use crate::backend::home_assistant::HomeAssistantConfig;
+#[cfg(feature = "hue-bridge")]
+use crate::backend::hue_bridge::HueBridgeConfig;
+#[cfg(feature = "mqtt")]
+use crate::backend::mqtt::MqttConfig;
+use crate::backend::priority::PriorityConfig;
+use crate::backend::push::{PushConfig, PushStore};
+#[cfg(feature = "wled")]
+use crate::backend::wled::WledConfig;
+use crate::control::ControlConfig;
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcConfig;
+#[cfg(feature = "history")]
+use crate::history::HistoryConfig;
+use crate::influx::InfluxConfig;
+use crate::logging::LoggingConfig;
+use crate::osc_receive::OscReceiveConfig;
+#[cfg(feature = "artnet")]
+use crate::output::artnet::ArtNetConfig;
+use crate::output::derived::DerivedConfig;
+use crate::output::lut::LutConfig;
+#[cfg(feature = "home-assistant")]
+use crate::output::mirror::{self, MirrorConfig};
+use crate::output::multiplex::MultiplexConfig;
+use crate::output::packed::PackedConfig;
+use crate::output::parameters::ParametersConfig;
+use crate::output::rate_limit::RateLimitConfig;
+use crate::output::remote::RemoteTargetConfig;
+use crate::output::smoothing;
+use crate::output::vrchat::{HueOutput, MulticastConfig};
+use crate::resync::ResyncConfig;
+#[cfg(feature = "secrets")]
+use crate::secrets::apply as decrypt_secrets;
+use crate::state::BulbState;
+use crate::vrchat_settings::Autodetect;
+#[cfg(feature = "websocket")]
+use crate::websocket::WebSocketConfig;
+use crate::world_filter::WorldFilterConfig;
+use crate::world_profiles::WorldProfilesConfig;
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Why the settings can't be used
+#[derive(Debug)]
+pub enum ConfigError {
+    // The settings file couldn't be read
+    Read(String, io::Error),
+    // It isn't YAML or doesn't have the right sections
+    Parse(serde_yaml::Error),
+    // The secrets section couldn't be decrypted
+    Secrets(String),
+    // A setting can't be used, like a missing section or a number out of range
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Couldn't open {}: {}", path, err),
+            ConfigError::Parse(err) => write!(f, "Error while parsing the settings: {}", err),
+            ConfigError::Secrets(err) => {
+                write!(f, "Couldn't decrypt the secrets in the settings: {}", err)
+            }
+            ConfigError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BulbService {
+    #[cfg(feature = "home-assistant")]
+    HomeAssistant,
+    #[cfg(feature = "wled")]
+    Wled,
+    #[cfg(feature = "hue-bridge")]
+    HueBridge,
+    #[cfg(feature = "mqtt")]
+    Mqtt,
+    Priority,
+    Failover,
+    Aggregate,
+    Push,
+}
+
+// The longest time the settings and control clients can ask for. A year is
+// far longer than anything needs, and still leaves room to add it to a point
+// in time.
+pub const MAX_SECONDS: f32 = 365.0 * 24.0 * 60.0 * 60.0;
+
+// Checks a time in seconds from the settings, 0 is only allowed for the ones
+// it turns off or makes immediate
+pub fn validate_seconds(name: &str, seconds: f32, zero_allowed: bool) -> Result<(), String> {
+    let min = if zero_allowed { 0.0 } else { f32::MIN_POSITIVE };
+    // NaN isn't in any range
+    if !(min..=MAX_SECONDS).contains(&seconds) {
+        return Err(format!(
+            "{} is {} seconds, it has to be {} and at most a year.",
+            name,
+            seconds,
+            if zero_allowed {
+                "at least 0"
+            } else {
+                "above 0"
+            }
+        ));
+    }
+    Ok(())
+}
+
+// Checks a rate or count from the settings, it has to be above 0 and up to
+// max
+pub fn validate_rate(name: &str, rate: f32, max: f32) -> Result<(), String> {
+    if !(rate > 0.0 && rate <= max) {
+        return Err(format!(
+            "{} is {}, it has to be above 0 and at most {}.",
+            name, rate, max
+        ));
+    }
+    Ok(())
+}
+
+// Where the state of a light comes from
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SourceConfig {
+    pub bulb_service: Option<BulbService>,
+    #[cfg(feature = "home-assistant")]
+    pub home_assistant: Option<HomeAssistantConfig>,
+    #[cfg(feature = "wled")]
+    pub wled: Option<WledConfig>,
+    #[cfg(feature = "hue-bridge")]
+    pub hue_bridge: Option<HueBridgeConfig>,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttConfig>,
+    pub priority: Option<PriorityConfig>,
+    pub failover: Option<FailoverConfig>,
+    pub aggregate: Option<AggregateConfig>,
+    pub push: Option<PushConfig>,
+}
+
+// The section for the bulb_service, once the settings are validated it's there
+fn section<'a, T>(section: &'a Option<T>, service: &str) -> Result<&'a T, String> {
+    section.as_ref().ok_or_else(|| {
+        format!(
+            "bulb_service is {} but there's no {} section.",
+            service, service
+        )
+    })
+}
+
+impl SourceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match self
+            .bulb_service
+            .ok_or("Every light needs a bulb_service in the settings file.")?
+        {
+            #[cfg(feature = "home-assistant")]
+            BulbService::HomeAssistant => {
+                section(&self.home_assistant, "home_assistant")?.validate()
+            }
+            #[cfg(feature = "wled")]
+            BulbService::Wled => section(&self.wled, "wled").map(drop),
+            #[cfg(feature = "hue-bridge")]
+            BulbService::HueBridge => section(&self.hue_bridge, "hue_bridge").map(drop),
+            #[cfg(feature = "mqtt")]
+            BulbService::Mqtt => section(&self.mqtt, "mqtt")?.validate(),
+            BulbService::Priority => section(&self.priority, "priority")?.validate(),
+            BulbService::Failover => section(&self.failover, "failover")?.validate(),
+            BulbService::Aggregate => section(&self.aggregate, "aggregate")?.validate(),
+            BulbService::Push => section(&self.push, "push").map(drop),
+        }
+    }
+
+    pub fn bulb_service(&self) -> BulbService {
+        self.bulb_service
+            .expect("Every light needs a bulb_service in the settings file.")
+    }
+
+    #[cfg(feature = "home-assistant")]
+    pub fn home_assistant(&self) -> &HomeAssistantConfig {
+        section(&self.home_assistant, "home_assistant").unwrap()
+    }
+
+    #[cfg(feature = "wled")]
+    pub fn wled(&self) -> &WledConfig {
+        section(&self.wled, "wled").unwrap()
+    }
+
+    #[cfg(feature = "hue-bridge")]
+    pub fn hue_bridge(&self) -> &HueBridgeConfig {
+        section(&self.hue_bridge, "hue_bridge").unwrap()
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn mqtt(&self) -> &MqttConfig {
+        section(&self.mqtt, "mqtt").unwrap()
+    }
+
+    pub fn priority(&self) -> &PriorityConfig {
+        section(&self.priority, "priority").unwrap()
+    }
+
+    pub fn failover(&self) -> &FailoverConfig {
+        section(&self.failover, "failover").unwrap()
+    }
+
+    pub fn aggregate(&self) -> &AggregateConfig {
+        section(&self.aggregate, "aggregate").unwrap()
+    }
+
+    pub fn push(&self) -> &PushConfig {
+        section(&self.push, "push").unwrap()
+    }
+}
+
+fn default_poll_concurrency() -> usize {
+    4
+}
+
+fn default_parameter_prefix() -> String {
+    "/avatar/parameters/".to_owned()
+}
+
+// What to do while a light's source can't be reached, its last known state is
+// kept until max_staleness seconds have passed, after which the fallback is used
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct OutageConfig {
+    pub max_staleness: Option<f32>,
+    pub fallback: Option<BulbState>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct LightConfig {
+    #[serde(default)]
+    pub name: String,
+    // Prepended to the on, Color and brightness parameter names
+    #[serde(default = "default_parameter_prefix")]
+    pub parameter_prefix: String,
+    #[serde(flatten)]
+    pub source: SourceConfig,
+    pub packed: Option<PackedConfig>,
+    #[serde(default)]
+    pub hue_output: HueOutput,
+    // Also send the hue and brightness from the last time the light was on
+    #[serde(default)]
+    pub last_color: bool,
+    // Also send the saturation and color temperature
+    #[serde(default)]
+    pub full_color: bool,
+    #[cfg(feature = "home-assistant")]
+    pub mirror: Option<MirrorConfig>,
+    #[cfg(feature = "artnet")]
+    pub artnet: Option<ArtNetConfig>,
+    pub lut: Option<LutConfig>,
+    #[serde(default)]
+    pub outage: OutageConfig,
+    // Bool parameter that is true while the light's state is fresh
+    pub health_parameter: Option<String>,
+    // Seconds each float parameter takes to ease towards a new value, by
+    // parameter name
+    #[serde(default)]
+    pub smoothing: HashMap<String, f32>,
+    // Milliseconds the avatar takes to go from one state to the next
+    #[serde(default)]
+    pub transition_ms: u64,
+    // Parameters worked out from the state, like how warm the light is
+    pub derived: Option<DerivedConfig>,
+    // Custom parameters for the light's attributes, instead of on, Color and
+    // brightness
+    pub parameters: Option<ParametersConfig>,
+}
+
+impl LightConfig {
+    // Files like the LUT are read too, ports and addresses are only tried
+    // when the light is started
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        self.source.validate()?;
+        self.validate_mapping(config)?;
+        if let Some(max_staleness) = self.outage.max_staleness {
+            validate_seconds("outage.max_staleness", max_staleness, true)?;
+        }
+        #[cfg(feature = "home-assistant")]
+        if self.mirror.is_some() {
+            mirror::validate(&self.source)?;
+        }
+        #[cfg(feature = "artnet")]
+        if let Some(artnet) = &self.artnet {
+            artnet.validate()?;
+        }
+        Ok(())
+    }
+
+    // How the state is turned into avatar parameters
+    pub fn validate_mapping(&self, config: &Config) -> Result<(), String> {
+        if let Some(packed) = &self.packed {
+            packed.validate()?;
+            if config.multiplex.is_some() {
+                return Err("packed can't be used together with multiplex.".into());
+            }
+            if self.parameters.is_some() {
+                return Err("packed can't be used together with parameters.".into());
+            }
+        }
+        if let Some(parameters) = &self.parameters {
+            if config.multiplex.is_some() {
+                return Err("parameters can't be used together with multiplex.".into());
+            }
+            parameters.validate()?;
+        }
+        if let Some(lut) = &self.lut {
+            lut.validate()?;
+        }
+        smoothing::validate(&self.smoothing)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub vrchat_ip: String,
+    pub vrchat_port: i32,
+    #[serde(default)]
+    pub vrchat_autodetect: Autodetect,
+    // Find VRChat through the OSC and OSCQuery services it advertises with
+    // mDNS, falling back to vrchat_ip and vrchat_port
+    #[serde(default)]
+    pub vrchat_autodiscover: bool,
+    // Local address to send OSC from, picks the network interface it leaves on
+    pub osc_bind_address: Option<String>,
+    pub osc_ttl: Option<u32>,
+    pub osc_multicast: Option<MulticastConfig>,
+    pub vrchat_target: Option<RemoteTargetConfig>,
+    pub osc_receive: Option<OscReceiveConfig>,
+    pub max_updates_per_second: i32,
+    // How many lights can be polled at the same time
+    #[serde(default = "default_poll_concurrency")]
+    pub poll_concurrency: usize,
+    // Up to how many seconds each poll is randomly delayed, to spread out the
+    // requests to a source
+    #[serde(default)]
+    pub poll_jitter: f32,
+    pub osc_rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub quantize_floats: bool,
+    #[serde(default)]
+    pub startup_test_pattern: bool,
+    pub world_filter: Option<WorldFilterConfig>,
+    pub world_profiles: Option<WorldProfilesConfig>,
+    pub resync: Option<ResyncConfig>,
+    pub multiplex: Option<MultiplexConfig>,
+    pub control: Option<ControlConfig>,
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<GrpcConfig>,
+    #[cfg(feature = "websocket")]
+    pub websocket: Option<WebSocketConfig>,
+    pub logging: Option<LoggingConfig>,
+    #[cfg(feature = "history")]
+    pub history: Option<HistoryConfig>,
+    pub influx: Option<InfluxConfig>,
+    #[serde(default)]
+    pub lights: Vec<LightConfig>,
+    // A single light can also be set up directly at the top level
+    #[serde(flatten)]
+    top_level_light: LightConfig,
+    // Where the lights with the push bulb service get their states from
+    #[serde(skip)]
+    pub pushed: PushStore,
+}
+
+pub fn get_config(file: &str) -> Result<Config, ConfigError> {
+    let text = fs::read_to_string(Path::new(file))
+        .map_err(|err| ConfigError::Read(file.to_owned(), err))?;
+    parse_config(&text)
+}
+
+// Reads settings that didn't come from a file, like from a program embedding
+// the sync
+pub fn parse_config(text: &str) -> Result<Config, ConfigError> {
+    let mut value: Value = serde_yaml::from_str(text).map_err(ConfigError::Parse)?;
+    let config = if value.get("secrets").is_none() {
+        // Straight from the text so errors point at the right line
+        serde_yaml::from_str(text)
+    } else {
+        decrypt_secrets(&mut value).map_err(ConfigError::Secrets)?;
+        serde_yaml::from_value(value)
+    };
+    let config = prepare(config.map_err(ConfigError::Parse)?);
+    validate(&config).map_err(ConfigError::Invalid)?;
+    Ok(config)
+}
+
+#[cfg(not(feature = "secrets"))]
+fn decrypt_secrets(_: &mut Value) -> Result<(), String> {
+    Err("the settings have a secrets section, but this build can't decrypt it".into())
+}
+
+// Everything that can be checked before starting, so settings that can't be
+// used don't get halfway started
+fn validate(config: &Config) -> Result<(), String> {
+    if !(1..=65535).contains(&config.vrchat_port) {
+        return Err(format!(
+            "{} isn't a port, vrchat_port has to be 1 to 65535.",
+            config.vrchat_port
+        ));
+    }
+    validate_rate(
+        "max_updates_per_second",
+        config.max_updates_per_second as f32,
+        1000.0,
+    )?;
+    validate_rate("poll_concurrency", config.poll_concurrency as f32, 1000.0)?;
+    validate_seconds("poll_jitter", config.poll_jitter, true)?;
+    if let Some(target) = &config.vrchat_target {
+        target.validate()?;
+    }
+    #[cfg(feature = "history")]
+    if let Some(history) = &config.history {
+        history.validate()?;
+    }
+    for light in config.lights.iter() {
+        light
+            .validate(config)
+            .map_err(|err| format!("{}: {}", light.name, err))?;
+    }
+    if let Some(multicast) = &config.osc_multicast {
+        if !multicast.group.is_multicast() {
+            return Err(format!("{} isn't a multicast address.", multicast.group));
+        }
+    }
+    if let Some(rate_limit) = &config.osc_rate_limit {
+        rate_limit.validate()?;
+    }
+    if let Some(osc_receive) = &config.osc_receive {
+        osc_receive.validate()?;
+    }
+    if let Some(world_profiles) = &config.world_profiles {
+        world_profiles.validate(config)?;
+    }
+    if let Some(resync) = &config.resync {
+        resync.validate()?;
+    }
+    if let Some(multiplex) = &config.multiplex {
+        multiplex.validate()?;
+    }
+    if let Some(logging) = &config.logging {
+        logging.validate()?;
+    }
+    if let Some(influx) = &config.influx {
+        influx.validate()?;
+    }
+    Ok(())
+}
+
+fn prepare(mut config: Config) -> Config {
+    if config.lights.is_empty() || config.top_level_light.source.bulb_service.is_some() {
+        let light = std::mem::take(&mut config.top_level_light);
+        config.lights.insert(0, light);
+    }
+    for (i, light) in config.lights.iter_mut().enumerate() {
+        if light.name.is_empty() {
+            light.name = format!("light {}", i + 1);
+        }
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIGHT: &str = "
+vrchat_ip: 127.0.0.1
+vrchat_port: 9000
+max_updates_per_second: 5
+";
+
+    fn invalid(settings: &str) -> String {
+        match parse_config(&(LIGHT.to_owned() + settings)) {
+            Err(ConfigError::Invalid(err)) => err,
+            other => panic!("expected invalid settings, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "home-assistant")]
+    #[test]
+    fn accepts_the_example_settings() {
+        let config = get_config("settings.example.yaml").unwrap();
+        assert_eq!(config.lights[0].name, "light 1");
+    }
+
+    #[test]
+    fn tells_which_light_is_missing_its_section() {
+        let err = invalid("lights:\n  - name: desk\n    bulb_service: push\n");
+        assert_eq!(
+            err,
+            "desk: bulb_service is push but there's no push section."
+        );
+    }
+
+    #[test]
+    fn checks_the_sources_of_combined_lights() {
+        let err = invalid(
+            "bulb_service: failover\nfailover:\n  sources:\n    - bulb_service: priority\n",
+        );
+        assert_eq!(
+            err,
+            "light 1: bulb_service is priority but there's no priority section."
+        );
+        let err = invalid("bulb_service: aggregate\naggregate:\n  sources: []\n");
+        assert_eq!(
+            err,
+            "light 1: The aggregate bulb service needs at least one source."
+        );
+    }
+
+    #[test]
+    fn checks_sections_outside_the_lights() {
+        let light = "bulb_service: push\npush:\n  name: desk\n";
+        let err =
+            invalid(&(light.to_owned() + "multiplex:\n  index_parameter: index\n  slot_time: 0\n"));
+        assert_eq!(
+            err,
+            "multiplex.slot_time is 0 seconds, it has to be above 0 and at most a year."
+        );
+        let err =
+            invalid(&(light.to_owned() + "packed: {}\nmultiplex:\n  index_parameter: index\n"));
+        assert_eq!(
+            err,
+            "light 1: packed can't be used together with multiplex."
+        );
+    }
+
+    #[test]
+    fn rejects_times_and_rates_that_cant_be_used() {
+        let light = "bulb_service: push\npush:\n  name: desk\n";
+        let settings = [
+            ("max_updates_per_second: 0\n", "max_updates_per_second"),
+            ("poll_concurrency: 0\n", "poll_concurrency"),
+            ("poll_jitter: -1\n", "poll_jitter"),
+            (
+                "vrchat_target:\n  resolve_interval: -1\n",
+                "resolve_interval",
+            ),
+            (
+                "vrchat_target:\n  resolve_interval: 0\n",
+                "resolve_interval",
+            ),
+            ("resync:\n  interval: .inf\n", "resync.interval"),
+            ("resync:\n  interval: .nan\n", "resync.interval"),
+            (
+                "influx:\n  file: out.txt\n  interval: 1e30\n",
+                "influx.interval",
+            ),
+            (
+                "osc_rate_limit:\n  rate: .inf\n  burst: 5\n",
+                "osc_rate_limit.rate",
+            ),
+        ];
+        for (setting, name) in settings {
+            // Without the max_updates_per_second everything else has
+            let text = if setting.starts_with("max_updates") {
+                "vrchat_ip: 127.0.0.1\nvrchat_port: 9000\n".to_owned()
+            } else {
+                LIGHT.to_owned()
+            } + light
+                + setting;
+            match parse_config(&text) {
+                Err(ConfigError::Invalid(err)) => assert!(err.contains(name), "{}", err),
+                other => panic!("{:?} was let through: {:?}", setting, other),
+            }
+        }
+        let err = invalid(
+            "bulb_service: failover\nfailover:\n  fail_back_after: -5\n  sources:\n    - bulb_service: push\n      push:\n        name: desk\n",
+        );
+        assert!(err.contains("failover.fail_back_after"), "{}", err);
+        let err = invalid(
+            "bulb_service: priority\npriority:\n  sources:\n    - priority: 1\n      idle_timeout: 0\n      bulb_service: push\n      push:\n        name: desk\n",
+        );
+        assert!(err.contains("priority.idle_timeout"), "{}", err);
+    }
+
+    #[test]
+    fn reads_the_lut_files_with_the_settings() {
+        let err = invalid("bulb_service: push\npush:\n  name: desk\nlut:\n  brightness: /nonexistent/brightness.lut\n");
+        assert!(
+            err.starts_with("light 1: Couldn't read the LUT /nonexistent/brightness.lut: "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn broken_yaml_is_a_parse_error() {
+        assert!(matches!(
+            parse_config("lights: ["),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            get_config("missing.yaml"),
+            Err(ConfigError::Read(..))
+        ));
+    }
+}
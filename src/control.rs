@@ -0,0 +1,583 @@
+use crate::clock;
+use crate::config::MAX_SECONDS;
+use crate::effects::{Effect, EffectKind, STROBE_MAX_HZ};
+use crate::light::{Light, LightStatus};
+use crate::state::BulbState;
+use nannou_osc::Type;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::time::Duration;
+
+fn default_port() -> u16 {
+    9123
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ControlConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+pub enum ControlCommand {
+    // Runs an effect on a light, or all lights if none is given
+    StartEffect {
+        kind: EffectKind,
+        duration: Duration,
+        light: Option<String>,
+    },
+    StopEffect {
+        light: Option<String>,
+    },
+    // Shows a fixed state on the avatar instead of the live one, until it's
+    // stopped if there's no duration
+    Override {
+        state: BulbState,
+        duration: Option<Duration>,
+        light: Option<String>,
+    },
+    SetPaused(bool),
+    Status,
+    // Sends every event from then on to the given channel
+    Subscribe(mpsc::Sender<Event>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    // A light's state was just sent
+    State(LightStatus),
+    // A parameter was sent to VRChat
+    OscSent {
+        light: String,
+        address: String,
+        value: Type,
+    },
+}
+
+pub struct Status {
+    pub paused: bool,
+    pub syncing: bool,
+    pub lights: Vec<LightStatus>,
+}
+
+pub enum ControlReply {
+    Ok,
+    Status(Status),
+    Error(String),
+}
+
+// A command from a control client along with where to send the answer
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: mpsc::Sender<ControlReply>,
+}
+
+// Sends a command to the sync loop and waits for the answer
+pub fn request(requests: &mpsc::Sender<ControlRequest>, command: ControlCommand) -> ControlReply {
+    let (reply, answer) = mpsc::channel();
+    if requests.send(ControlRequest { command, reply }).is_err() {
+        return ControlReply::Error("the sync loop isn't running".to_owned());
+    }
+    answer
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap_or_else(|_| ControlReply::Error("no answer from the sync loop".to_owned()))
+}
+
+const HELP: &str = "commands: effect <pulse|breathe|strobe|rainbow> <seconds> [light], \
+                    strobe_hz <hz> <seconds> [light], \
+                    override <on|off> <hue> <brightness> <seconds|forever> [light], \
+                    stop [light], pause, resume, status, watch";
+
+fn optional_light(words: &[&str]) -> Option<String> {
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+fn parse_seconds(word: Option<&&str>) -> Result<Duration, String> {
+    word.and_then(|word| word.parse::<f32>().ok())
+        .filter(|seconds| *seconds > 0.0 && *seconds <= MAX_SECONDS)
+        .map(Duration::from_secs_f32)
+        .ok_or_else(|| {
+            "the duration has to be a positive number of seconds, up to a year".to_owned()
+        })
+}
+
+fn parse_command(line: &str) -> Result<ControlCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.first() {
+        Some(&"effect") => {
+            let kind = words
+                .get(1)
+                .and_then(|name| EffectKind::parse(name))
+                .ok_or_else(|| "unknown effect".to_owned())?;
+            Ok(ControlCommand::StartEffect {
+                kind,
+                duration: parse_seconds(words.get(2))?,
+                light: optional_light(words.get(3..).unwrap_or_default()),
+            })
+        }
+        Some(&"strobe_hz") => {
+            let hz: f32 = words
+                .get(1)
+                .and_then(|hz| hz.parse::<f32>().ok())
+                .filter(|hz| !hz.is_nan())
+                .ok_or_else(|| "the strobe frequency has to be a number".to_owned())?;
+            if hz > STROBE_MAX_HZ {
+                println!(
+                    "Strobe frequency {} Hz capped to {} Hz for safety",
+                    hz, STROBE_MAX_HZ
+                );
+            }
+            Ok(ControlCommand::StartEffect {
+                kind: EffectKind::Strobe { hz },
+                duration: parse_seconds(words.get(2))?,
+                light: optional_light(words.get(3..).unwrap_or_default()),
+            })
+        }
+        Some(&"override") => {
+            let on = match words.get(1) {
+                Some(&"on") => true,
+                Some(&"off") => false,
+                _ => return Err("override needs on or off".to_owned()),
+            };
+            let value = |i: usize| {
+                words
+                    .get(i)
+                    .and_then(|word| word.parse::<f32>().ok())
+                    .filter(|value| (0.0..=1.0).contains(value))
+                    .ok_or_else(|| "the hue and brightness have to be from 0 to 1".to_owned())
+            };
+            let state = BulbState::color(on, value(2)?, value(3)?);
+            let duration = match words.get(4) {
+                Some(&"forever") => None,
+                word => Some(parse_seconds(word)?),
+            };
+            Ok(ControlCommand::Override {
+                state,
+                duration,
+                light: optional_light(words.get(5..).unwrap_or_default()),
+            })
+        }
+        Some(&"stop") => Ok(ControlCommand::StopEffect {
+            light: optional_light(&words[1..]),
+        }),
+        Some(&"pause") => Ok(ControlCommand::SetPaused(true)),
+        Some(&"resume") => Ok(ControlCommand::SetPaused(false)),
+        Some(&"status") => Ok(ControlCommand::Status),
+        _ => Err(HELP.to_owned()),
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn reply_json(reply: ControlReply) -> serde_json::Value {
+    match reply {
+        ControlReply::Ok => json!({ "ok": true }),
+        ControlReply::Error(err) => json!({ "ok": false, "error": err }),
+        ControlReply::Status(status) => status_json(&status),
+    }
+}
+
+// The answer as a single line for the line protocol
+fn format_reply(reply: ControlReply) -> String {
+    match reply {
+        ControlReply::Ok => "ok".to_owned(),
+        ControlReply::Error(err) => "error: ".to_owned() + &err,
+        ControlReply::Status(status) => {
+            let mut line = format!(
+                "paused: {}, syncing: {}",
+                yes_no(status.paused),
+                yes_no(status.syncing)
+            );
+            for light in status.lights {
+                line += "; ";
+                line += &format_light(&light);
+            }
+            line
+        }
+    }
+}
+
+fn format_light(light: &LightStatus) -> String {
+    let mut line = format!(
+        "{}: {} hue {:.3} saturation {:.3} brightness {:.3}",
+        light.name,
+        if light.state.on { "on" } else { "off" },
+        light.state.hue,
+        light.state.saturation,
+        light.state.brightness
+    );
+    if let Some(kelvin) = light.state.color_temp {
+        line += &format!(" color_temp {:.0}K", kelvin);
+    }
+    if let Some(effect) = light.effect {
+        line += &format!(" effect {:?}", effect);
+    }
+    if !light.healthy {
+        line += " unreachable";
+    }
+    line
+}
+
+pub fn light_json(light: &LightStatus) -> serde_json::Value {
+    json!({
+        "light": light.name,
+        "on": light.state.on,
+        "hue": light.state.hue,
+        "saturation": light.state.saturation,
+        "brightness": light.state.brightness,
+        "color_temp": light.state.color_temp,
+        "effect": light.effect.map(|effect| format!("{:?}", effect)),
+        "healthy": light.healthy,
+    })
+}
+
+pub fn status_json(status: &Status) -> serde_json::Value {
+    json!({
+        "paused": status.paused,
+        "syncing": status.syncing,
+        "lights": status.lights.iter().map(light_json).collect::<Vec<_>>(),
+    })
+}
+
+pub fn event_json(event: &Event) -> serde_json::Value {
+    match event {
+        Event::State(light) => {
+            let mut value = light_json(light);
+            value["type"] = json!("state");
+            value
+        }
+        Event::OscSent {
+            light,
+            address,
+            value,
+        } => json!({
+            "type": "osc",
+            "light": light,
+            "address": address,
+            "value": value_json(value),
+        }),
+    }
+}
+
+pub fn value_json(value: &Type) -> serde_json::Value {
+    match value {
+        Type::Bool(value) => json!(value),
+        Type::Float(value) => json!(value),
+        Type::Int(value) => json!(value),
+        value => json!(format!("{:?}", value)),
+    }
+}
+
+// Writes a line for every state change until the client goes away
+fn watch(writer: &mut TcpStream, requests: &mpsc::Sender<ControlRequest>) {
+    let (sender, events) = mpsc::channel();
+    if let ControlReply::Error(err) = request(requests, ControlCommand::Subscribe(sender)) {
+        writeln!(writer, "error: {}", err).ok();
+        return;
+    }
+    for event in events {
+        if let Event::State(status) = event {
+            if writeln!(writer, "{}", format_light(&status)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn handle_client(stream: TcpStream, requests: mpsc::Sender<ControlRequest>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.trim() == "watch" {
+            return watch(&mut writer, &requests);
+        }
+        // Commands ending in json get their answer as JSON
+        let (line, json) = match line.trim().strip_suffix(" json") {
+            Some(line) => (line, true),
+            None => (line.as_str(), false),
+        };
+        let reply = match parse_command(line) {
+            Ok(command) => request(&requests, command),
+            Err(err) => ControlReply::Error(err),
+        };
+        let answer = if json {
+            reply_json(reply).to_string()
+        } else {
+            format_reply(reply)
+        };
+        if writeln!(writer, "{}", answer).is_err() {
+            return;
+        }
+    }
+}
+
+// Sends a single command to the running instance and returns its answer
+pub fn query(config: &ControlConfig, line: &str) -> std::io::Result<String> {
+    let stream = TcpStream::connect(("127.0.0.1", config.port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    writeln!(&stream, "{}", line)?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    Ok(answer.trim_end().to_owned())
+}
+
+// Listens for control clients on localhost, their commands are sent to the
+// sync loop through the given channel
+pub fn start(config: &ControlConfig, requests: mpsc::Sender<ControlRequest>) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).map_err(|err| {
+        format!(
+            "Couldn't start the control API on port {}: {}",
+            config.port, err
+        )
+    })?;
+    println!("Control API listening on 127.0.0.1:{}", config.port);
+    clock::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let requests = requests.clone();
+            clock::spawn(move || handle_client(stream, requests));
+        }
+    });
+    Ok(())
+}
+
+// The lights a command is meant for, all of them when no name is given
+fn selected<'a>(
+    lights: &'a mut [Light],
+    name: &Option<String>,
+) -> Result<Vec<&'a mut Light>, String> {
+    let selected: Vec<&mut Light> = lights
+        .iter_mut()
+        .filter(|light| name.as_ref().is_none_or(|name| *name == light.name))
+        .collect();
+    match name {
+        Some(name) if selected.is_empty() => Err(format!("there's no light named {}", name)),
+        _ => Ok(selected),
+    }
+}
+
+// Carries out what control clients ask for in the sync loop, and keeps the
+// state that belongs to them
+pub struct Controller {
+    sender: mpsc::Sender<ControlRequest>,
+    requests: mpsc::Receiver<ControlRequest>,
+    subscribers: Vec<mpsc::Sender<Event>>,
+    pub paused: bool,
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller::new()
+    }
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        let (sender, requests) = mpsc::channel();
+        Controller {
+            sender,
+            requests,
+            subscribers: Vec::new(),
+            paused: false,
+        }
+    }
+
+    // Sends every event from now on to the given channel, for subscribers
+    // that can't miss the first sends
+    pub fn subscribe(&mut self, subscriber: mpsc::Sender<Event>) {
+        self.subscribers.push(subscriber);
+    }
+
+    // Where control APIs send their requests
+    pub fn sender(&self) -> mpsc::Sender<ControlRequest> {
+        self.sender.clone()
+    }
+
+    // Answers every request that came in since the last time
+    pub fn handle_requests(&mut self, lights: &mut [Light], syncing: bool) {
+        for request in self.requests.try_iter().collect::<Vec<ControlRequest>>() {
+            let answer = self.handle(lights, request.command, syncing);
+            request.reply.send(answer).ok();
+        }
+    }
+
+    // Carries out a command on the lights
+    fn handle(
+        &mut self,
+        lights: &mut [Light],
+        command: ControlCommand,
+        syncing: bool,
+    ) -> ControlReply {
+        let res = match command {
+            ControlCommand::StartEffect {
+                kind,
+                duration,
+                light,
+            } => selected(lights, &light).map(|lights| {
+                for light in lights {
+                    let effect = Effect::new(kind, duration);
+                    println!(
+                        "Starting {:?} on {} for {:?}",
+                        effect.kind(),
+                        light.name,
+                        duration
+                    );
+                    light.start_effect(effect);
+                }
+            }),
+            ControlCommand::StopEffect { light } => selected(lights, &light).map(|lights| {
+                for light in lights {
+                    light.stop_effect();
+                }
+            }),
+            ControlCommand::Override {
+                state,
+                duration,
+                light,
+            } => selected(lights, &light).map(|lights| {
+                for light in lights {
+                    let kind = EffectKind::Override(state);
+                    println!("Overriding {} with {:?}", light.name, state);
+                    light.start_effect(match duration {
+                        Some(duration) => Effect::new(kind, duration),
+                        None => Effect::until_stopped(kind),
+                    });
+                }
+            }),
+            ControlCommand::SetPaused(paused) => {
+                if paused != self.paused {
+                    println!(
+                        "Syncing {} by a control client",
+                        if paused { "paused" } else { "resumed" }
+                    );
+                }
+                self.paused = paused;
+                Ok(())
+            }
+            ControlCommand::Status => {
+                return ControlReply::Status(Status {
+                    paused: self.paused,
+                    syncing: syncing && !self.paused,
+                    lights: lights.iter().map(Light::status).collect(),
+                })
+            }
+            ControlCommand::Subscribe(subscriber) => {
+                self.subscribe(subscriber);
+                Ok(())
+            }
+        };
+        match res {
+            Ok(()) => ControlReply::Ok,
+            Err(err) => ControlReply::Error(err),
+        }
+    }
+
+    // Tells subscribers about something that happened, forgetting the ones
+    // that went away
+    fn publish(&mut self, event: Event) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    // Tells subscribers that a light's state was just sent
+    pub fn publish_state(&mut self, light: &Light) {
+        if !self.subscribers.is_empty() {
+            self.publish(Event::State(light.status()));
+        }
+    }
+
+    // Tells subscribers about the parameters a light sent to VRChat since the
+    // last time
+    pub fn publish_sent(&mut self, light: &mut Light) {
+        if self.subscribers.is_empty() {
+            light.drain_sent();
+            return;
+        }
+        let name = light.name.clone();
+        for (address, value) in light.drain_sent() {
+            self.publish(Event::OscSent {
+                light: name.clone(),
+                address: address.to_string(),
+                value,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(line: &str) -> String {
+        match parse_command(line) {
+            Err(err) => err,
+            Ok(_) => panic!("{} was let through", line),
+        }
+    }
+
+    #[test]
+    fn parses_effects_and_overrides() {
+        match parse_command("effect pulse 1.5 desk lamp") {
+            Ok(ControlCommand::StartEffect {
+                kind: EffectKind::Pulse,
+                duration,
+                light,
+            }) => {
+                assert_eq!(duration, Duration::from_millis(1500));
+                assert_eq!(light.as_deref(), Some("desk lamp"));
+            }
+            _ => panic!("not a pulse"),
+        }
+        match parse_command("strobe_hz 2 10") {
+            Ok(ControlCommand::StartEffect {
+                kind: EffectKind::Strobe { hz },
+                light: None,
+                ..
+            }) => assert_eq!(hz, 2.0),
+            _ => panic!("not a strobe"),
+        }
+        match parse_command("override on 0.5 1 forever") {
+            Ok(ControlCommand::Override {
+                state,
+                duration: None,
+                light: None,
+            }) => assert_eq!(state, BulbState::color(true, 0.5, 1.0)),
+            _ => panic!("not an override"),
+        }
+    }
+
+    #[test]
+    fn rejects_durations_that_cant_be_used() {
+        let duration = "the duration has to be a positive number of seconds, up to a year";
+        for seconds in ["0", "-1", "inf", "NaN", "1e30", "soon"] {
+            assert_eq!(error(&format!("effect pulse {}", seconds)), duration);
+            assert_eq!(error(&format!("override on 0 1 {}", seconds)), duration);
+        }
+        assert_eq!(error("effect pulse"), duration);
+        assert_eq!(
+            error("strobe_hz NaN 5"),
+            "the strobe frequency has to be a number"
+        );
+        assert_eq!(error("effect disco 5"), "unknown effect");
+    }
+}
@@ -0,0 +1,426 @@
+use crate::clock;
+use crate::logging::{self, Category};
+use crate::output::remote::host_info;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Condvar, Mutex, Once};
+use std::time::Duration;
+
+const MDNS: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+// What VRChat calls itself on the network, other OSCQuery apps like face
+// trackers advertise the same services
+const VRCHAT_PREFIX: &str = "VRChat-Client-";
+const OSC_SERVICE: &str = "_osc._udp.local";
+const OSCQUERY_SERVICE: &str = "_oscjson._tcp.local";
+// How long a search waits for answers, asking again every QUERY_INTERVAL
+const SEARCH_TIME: Duration = Duration::from_millis(1500);
+const QUERY_INTERVAL: Duration = Duration::from_millis(500);
+// How often to search again, to follow VRChat when it's restarted on another
+// port
+const SEARCH_INTERVAL: Duration = Duration::from_secs(10);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+
+// Reads a possibly compressed name starting at pos, returns it and where the
+// record continues after it
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Pointers can only go back, more jumps than this means a loop
+    for _ in 0..64 {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Some((name, end.unwrap_or(pos + 1)));
+            }
+            _ if len & 0xc0 == 0xc0 => {
+                let target = ((len & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            _ => {
+                let label = packet.get(pos + 1..pos + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn query(questions: &[(&str, u16)]) -> Vec<u8> {
+    let mut out = vec![0, 0, 0, 0, 0, questions.len() as u8, 0, 0, 0, 0, 0, 0];
+    for (name, kind) in questions {
+        push_name(&mut out, name);
+        out.extend_from_slice(&kind.to_be_bytes());
+        // IN, asking for the answer to come straight back
+        out.extend_from_slice(&0x8001u16.to_be_bytes());
+    }
+    out
+}
+
+fn u16_at(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(pos)?,
+        *packet.get(pos + 1)?,
+    ]))
+}
+
+// What the answers so far say about the services on the network
+#[derive(Default)]
+struct Records {
+    // The instances of each service
+    instances: HashMap<String, Vec<String>>,
+    // The port and host of each instance
+    services: HashMap<String, (u16, String)>,
+    addresses: HashMap<String, Ipv4Addr>,
+    // Where each instance's answer came from, for when there's no A record
+    senders: HashMap<String, IpAddr>,
+}
+
+impl Records {
+    fn read(&mut self, packet: &[u8], sender: IpAddr) -> Option<()> {
+        let questions = u16_at(packet, 4)?;
+        let records = u16_at(packet, 6)? as usize
+            + u16_at(packet, 8)? as usize
+            + u16_at(packet, 10)? as usize;
+        let mut pos = 12;
+        for _ in 0..questions {
+            pos = read_name(packet, pos)?.1 + 4;
+        }
+        for _ in 0..records {
+            let (name, after) = read_name(packet, pos)?;
+            let kind = u16_at(packet, after)?;
+            let len = u16_at(packet, after + 8)? as usize;
+            let data = after + 10;
+            let name = name.to_ascii_lowercase();
+            match kind {
+                TYPE_PTR => {
+                    let instance = read_name(packet, data)?.0;
+                    self.senders.insert(instance.to_ascii_lowercase(), sender);
+                    let instances = self.instances.entry(name).or_default();
+                    if !instances.contains(&instance) {
+                        instances.push(instance);
+                    }
+                }
+                TYPE_SRV => {
+                    let port = u16_at(packet, data + 4)?;
+                    let host = read_name(packet, data + 6)?.0.to_ascii_lowercase();
+                    self.services.insert(name, (port, host));
+                }
+                TYPE_A if len == 4 => {
+                    let ip = packet.get(data..data + 4)?;
+                    self.addresses
+                        .insert(name, Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]));
+                }
+                _ => {}
+            }
+            pos = data + len;
+        }
+        Some(())
+    }
+
+    // The VRChat instances of a service that are known so far
+    fn vrchat(&self, service: &str) -> impl Iterator<Item = &String> {
+        self.instances
+            .get(service)
+            .into_iter()
+            .flatten()
+            .filter(|instance| instance.starts_with(VRCHAT_PREFIX))
+    }
+
+    // Where the instance is, None until enough has been heard about it
+    fn locate(&self, instance: &str) -> Option<SocketAddr> {
+        let instance = instance.to_ascii_lowercase();
+        let (port, host) = self.services.get(&instance)?;
+        let ip = match self.addresses.get(host) {
+            Some(ip) => IpAddr::V4(*ip),
+            None => *self.senders.get(&instance)?,
+        };
+        Some(SocketAddr::new(local_if_own(ip), *port))
+    }
+
+    // What still needs asking to find the VRChat instances
+    fn missing(&self) -> Vec<(String, u16)> {
+        let mut missing = Vec::new();
+        for instance in self
+            .vrchat(OSC_SERVICE)
+            .chain(self.vrchat(OSCQUERY_SERVICE))
+        {
+            match self.services.get(&instance.to_ascii_lowercase()) {
+                None => missing.push((instance.clone(), TYPE_SRV)),
+                Some((_, host)) if !self.addresses.contains_key(host) => {
+                    missing.push((host.clone(), TYPE_A))
+                }
+                _ => {}
+            }
+        }
+        missing
+    }
+}
+
+// VRChat on this computer is sent to on localhost, it might not listen on the
+// network
+fn local_if_own(ip: IpAddr) -> IpAddr {
+    if UdpSocket::bind((ip, 0)).is_ok() {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        ip
+    }
+}
+
+// Asks the network where VRChat's OSC server is, through the _osc._udp service
+// it advertises or its OSCQuery server if that's all that answered
+pub fn find() -> Option<SocketAddr> {
+    // Asked from a port other than 5353, so the answers are sent back to it
+    // instead of to the whole network
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.set_read_timeout(Some(QUERY_INTERVAL)).ok()?;
+    let mut records = Records::default();
+    let start = clock::now();
+    let mut buffer = [0; 9000];
+    while clock::elapsed(start) < SEARCH_TIME {
+        let mut questions = vec![
+            (OSC_SERVICE.to_owned(), TYPE_PTR),
+            (OSCQUERY_SERVICE.to_owned(), TYPE_PTR),
+        ];
+        questions.extend(records.missing());
+        let questions: Vec<(&str, u16)> = questions
+            .iter()
+            .map(|(name, kind)| (name.as_str(), *kind))
+            .collect();
+        socket.send_to(&query(&questions), MDNS).ok()?;
+        let asked = clock::now();
+        while clock::elapsed(asked) < QUERY_INTERVAL {
+            let (len, sender) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(_) => break,
+            };
+            records.read(&buffer[..len], sender.ip());
+        }
+        if let Some(addr) = records
+            .vrchat(OSC_SERVICE)
+            .find_map(|instance| records.locate(instance))
+        {
+            return Some(addr);
+        }
+    }
+    // Without the OSC service VRChat's OSCQuery server can still say where it
+    // listens
+    let found = records
+        .vrchat(OSCQUERY_SERVICE)
+        .filter_map(|instance| records.locate(instance))
+        .find_map(|oscquery| {
+            let info = host_info(&oscquery, oscquery.port()).ok()?;
+            let ip = info
+                .osc_ip
+                .and_then(|ip| ip.parse().ok())
+                .unwrap_or(oscquery.ip());
+            Some(SocketAddr::new(local_if_own(ip), info.osc_port))
+        });
+    found
+}
+
+// The newest place VRChat was found, and whether the first search is done
+static FOUND: Mutex<(Option<SocketAddr>, bool)> = Mutex::new((None, false));
+static SEARCHED: Condvar = Condvar::new();
+static START: Once = Once::new();
+
+// Keeps looking for VRChat in the background, waiting for the first search to
+// finish. Returns where it was found, if it was.
+pub fn start() -> Option<SocketAddr> {
+    START.call_once(|| {
+        clock::spawn(|| loop {
+            let found = find();
+            {
+                let mut state = FOUND.lock().unwrap();
+                match (state.0, found) {
+                    (Some(old), Some(new)) if old != new => {
+                        println!("VRChat moved from {} to {}", old, new)
+                    }
+                    (None, Some(new)) => println!("Found VRChat at {}", new),
+                    (_, None) if !state.1 => logging::error(
+                        Category::Network,
+                        "Couldn't find VRChat on the network, sending to vrchat_ip and vrchat_port until it shows up".to_owned(),
+                    ),
+                    _ => {}
+                }
+                // VRChat not answering, like while it's restarting, doesn't
+                // mean it went somewhere else
+                if found.is_some() {
+                    state.0 = found;
+                }
+                state.1 = true;
+            }
+            SEARCHED.notify_all();
+            clock::sleep(SEARCH_INTERVAL);
+        });
+    });
+    let mut state = FOUND.lock().unwrap();
+    while !state.1 {
+        state = SEARCHED.wait(state).unwrap();
+    }
+    state.0
+}
+
+// Where VRChat was last found, None when it hasn't been or discovery isn't on
+pub fn current() -> Option<SocketAddr> {
+    FOUND.lock().unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record in an answer, its name and data already encoded
+    fn push_record(out: &mut Vec<u8>, name: &[u8], kind: u16, data: &[u8]) {
+        out.extend_from_slice(name);
+        out.extend_from_slice(&kind.to_be_bytes());
+        // IN, and a TTL of two minutes
+        out.extend_from_slice(&[0, 1, 0, 0, 0, 120]);
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+    }
+
+    fn encoded(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        push_name(&mut out, name);
+        out
+    }
+
+    // What VRChat answers with, with the names compressed the way it does
+    fn answer() -> Vec<u8> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 3, 0, 0, 0, 0];
+        // The service's name is at 12, and its local at 22
+        push_record(&mut packet, &encoded(OSC_SERVICE), TYPE_PTR, &{
+            let mut data = vec![20];
+            data.extend_from_slice(b"VRChat-Client-ABC123");
+            data.extend_from_slice(&[0xc0, 12]);
+            data
+        });
+        let instance = 12 + encoded(OSC_SERVICE).len() + 10;
+        let mut srv = vec![0, 0, 0, 0, 0x23, 0x29, 4];
+        srv.extend_from_slice(b"host");
+        srv.extend_from_slice(&[0xc0, 22]);
+        push_record(&mut packet, &[0xc0, instance as u8], TYPE_SRV, &srv);
+        let host = packet.len() - srv.len() + 6;
+        push_record(&mut packet, &[0xc0, host as u8], TYPE_A, &[192, 0, 2, 10]);
+        packet
+    }
+
+    #[test]
+    fn names_read_back() {
+        for name in [
+            "_osc._udp.local",
+            "VRChat-Client-ABC123._oscjson._tcp.local",
+        ] {
+            let bytes = encoded(name);
+            assert_eq!(read_name(&bytes, 0), Some((name.to_owned(), bytes.len())));
+        }
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        let mut packet = encoded("_osc._udp.local");
+        let start = packet.len();
+        packet.push(4);
+        packet.extend_from_slice(b"lamp");
+        // Points at _udp.local
+        packet.extend_from_slice(&[0xc0, 5, 0xff]);
+        // Continues right after the pointer
+        assert_eq!(
+            read_name(&packet, start),
+            Some(("lamp._udp.local".to_owned(), start + 7))
+        );
+        // Pointers to pointers
+        packet.extend_from_slice(&[0xc0, start as u8]);
+        assert_eq!(
+            read_name(&packet, start + 8),
+            Some(("lamp._udp.local".to_owned(), start + 10))
+        );
+    }
+
+    #[test]
+    fn compression_loops_end() {
+        // Pointing at itself, and two pointing at each other
+        assert_eq!(read_name(&[0xc0, 0], 0), None);
+        assert_eq!(read_name(&[0xc0, 2, 0xc0, 0], 0), None);
+        // A label before the loop
+        assert_eq!(read_name(&[1, b'a', 0xc0, 0], 0), None);
+    }
+
+    #[test]
+    fn short_names_are_none() {
+        let bytes = encoded("_osc._udp.local");
+        for len in 0..bytes.len() {
+            assert_eq!(read_name(&bytes[..len], 0), None, "cut at {}", len);
+        }
+        // A pointer without its second byte and one past the end
+        assert_eq!(read_name(&[0xc0], 0), None);
+        assert_eq!(read_name(&[0xc0, 9], 0), None);
+    }
+
+    #[test]
+    fn queries_read_back() {
+        let packet = query(&[(OSC_SERVICE, TYPE_PTR), (OSCQUERY_SERVICE, TYPE_PTR)]);
+        assert_eq!(u16_at(&packet, 4), Some(2));
+        assert_eq!(read_name(&packet, 12).unwrap().0, OSC_SERVICE);
+        let mut records = Records::default();
+        assert_eq!(
+            records.read(&packet, IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            Some(())
+        );
+        assert!(records.instances.is_empty());
+    }
+
+    #[test]
+    fn locates_vrchat_from_its_answer() {
+        let sender = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20));
+        let mut records = Records::default();
+        assert_eq!(records.read(&answer(), sender), Some(()));
+        let instances: Vec<_> = records.vrchat(OSC_SERVICE).collect();
+        assert_eq!(instances, ["VRChat-Client-ABC123._osc._udp.local"]);
+        assert!(records.missing().is_empty());
+        assert_eq!(
+            records.locate(instances[0]),
+            Some("192.0.2.10:9001".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn without_an_address_the_sender_is_used() {
+        let mut packet = answer();
+        // Only the PTR and SRV records
+        packet[7] = 2;
+        let sender = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20));
+        let mut records = Records::default();
+        assert_eq!(records.read(&packet, sender), Some(()));
+        let instance = "VRChat-Client-ABC123._osc._udp.local";
+        assert_eq!(records.missing(), [("host.local".to_owned(), TYPE_A)]);
+        assert_eq!(
+            records.locate(instance),
+            Some("192.0.2.20:9001".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn short_answers_are_none() {
+        let packet = answer();
+        let sender = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20));
+        for len in 0..packet.len() {
+            let mut records = Records::default();
+            assert_eq!(records.read(&packet[..len], sender), None, "cut at {}", len);
+        }
+    }
+}
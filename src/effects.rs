@@ -0,0 +1,186 @@
+use crate::clock;
+use crate::state::BulbState;
+use std::f32::consts::TAU;
+use std::time::{Duration, Instant};
+
+// Flashing faster than this can trigger photosensitive seizures
+pub const STROBE_MAX_HZ: f32 = 3.0;
+
+const PULSE_PERIOD: f32 = 1.0;
+const BREATHE_PERIOD: f32 = 4.0;
+const RAINBOW_PERIOD: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectKind {
+    Pulse,
+    Breathe,
+    Strobe { hz: f32 },
+    Rainbow,
+    // Shows a fixed state instead of the live one
+    Override(BulbState),
+}
+
+impl EffectKind {
+    pub fn parse(name: &str) -> Option<EffectKind> {
+        match name {
+            "pulse" => Some(EffectKind::Pulse),
+            "breathe" => Some(EffectKind::Breathe),
+            "strobe" => Some(EffectKind::Strobe { hz: STROBE_MAX_HZ }),
+            "rainbow" => Some(EffectKind::Rainbow),
+            _ => None,
+        }
+    }
+}
+
+// A locally generated effect that overrides a light's live state for a while
+pub struct Effect {
+    kind: EffectKind,
+    started: Instant,
+    // None when it runs until it's stopped
+    until: Option<Instant>,
+}
+
+impl Effect {
+    pub fn new(kind: EffectKind, duration: Duration) -> Effect {
+        let kind = match kind {
+            EffectKind::Strobe { hz } if hz.is_nan() => EffectKind::Strobe { hz: 0.1 },
+            EffectKind::Strobe { hz } => EffectKind::Strobe {
+                hz: hz.clamp(0.1, STROBE_MAX_HZ),
+            },
+            kind => kind,
+        };
+        let started = clock::now();
+        Effect {
+            kind,
+            started,
+            // Too far away to ever come
+            until: started.checked_add(duration),
+        }
+    }
+
+    pub fn until_stopped(kind: EffectKind) -> Effect {
+        Effect {
+            kind,
+            started: clock::now(),
+            until: None,
+        }
+    }
+
+    pub fn kind(&self) -> EffectKind {
+        self.kind
+    }
+
+    pub fn is_finished(&self, now: Instant) -> bool {
+        self.until.is_some_and(|until| now >= until)
+    }
+
+    // The effect's state at a point in time, based on the light's live state
+    pub fn state_at(&self, now: Instant, live: &BulbState) -> BulbState {
+        let t = now.duration_since(self.started).as_secs_f32();
+        // Effects on a light that's off use full brightness
+        let brightness = if live.on { live.brightness } else { 1.0 };
+        match self.kind {
+            EffectKind::Pulse => BulbState {
+                on: true,
+                brightness: brightness * (-5.0 * (t % PULSE_PERIOD)).exp(),
+                ..*live
+            },
+            EffectKind::Breathe => BulbState {
+                on: true,
+                brightness: brightness * (0.55 - 0.45 * (t / BREATHE_PERIOD * TAU).cos()),
+                ..*live
+            },
+            EffectKind::Strobe { hz } => BulbState {
+                on: (t * hz).fract() < 0.5,
+                brightness,
+                ..*live
+            },
+            // Goes through the fully saturated colors even from a white
+            EffectKind::Rainbow => {
+                BulbState::color(true, (live.hue + t / RAINBOW_PERIOD).fract(), brightness)
+            }
+            EffectKind::Override(state) => state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    fn mock_clock() -> Arc<MockClock> {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        mock
+    }
+
+    #[test]
+    fn runs_for_its_duration() {
+        let mock = mock_clock();
+        let effect = Effect::new(EffectKind::Pulse, Duration::from_secs(2));
+        mock.advance(Duration::from_millis(1999));
+        assert!(!effect.is_finished(clock::now()));
+        mock.advance(Duration::from_millis(1));
+        assert!(effect.is_finished(clock::now()));
+
+        let forever = Effect::until_stopped(EffectKind::Pulse);
+        mock.advance(Duration::from_secs(1_000_000));
+        assert!(!forever.is_finished(clock::now()));
+        // Doesn't overflow the clock
+        let far = Effect::new(EffectKind::Pulse, Duration::MAX);
+        assert!(!far.is_finished(clock::now()));
+    }
+
+    #[test]
+    fn strobes_no_faster_than_is_safe() {
+        mock_clock();
+        let hz = |hz| match Effect::new(EffectKind::Strobe { hz }, Duration::from_secs(1)).kind() {
+            EffectKind::Strobe { hz } => hz,
+            kind => panic!("became {:?}", kind),
+        };
+        assert_eq!(hz(2.0), 2.0);
+        assert_eq!(hz(50.0), STROBE_MAX_HZ);
+        assert_eq!(hz(f32::INFINITY), STROBE_MAX_HZ);
+        assert_eq!(hz(0.0), 0.1);
+        assert_eq!(hz(f32::NAN), 0.1);
+    }
+
+    #[test]
+    fn changes_the_live_state_over_time() {
+        let mock = mock_clock();
+        let live = BulbState::color(true, 0.9, 0.5);
+        let at = |effect: &Effect, millis| {
+            effect.state_at(effect.started + Duration::from_millis(millis), &live)
+        };
+
+        let strobe = Effect::new(EffectKind::Strobe { hz: 2.0 }, Duration::from_secs(5));
+        assert!(at(&strobe, 0).on);
+        assert!(!at(&strobe, 250).on);
+        assert!(at(&strobe, 500).on);
+        assert_eq!(at(&strobe, 250).brightness, 0.5);
+
+        let pulse = Effect::new(EffectKind::Pulse, Duration::from_secs(5));
+        assert_eq!(at(&pulse, 0).brightness, 0.5);
+        assert!(at(&pulse, 500).brightness < 0.05);
+        assert_eq!(at(&pulse, 1000).brightness, 0.5);
+
+        let breathe = Effect::new(EffectKind::Breathe, Duration::from_secs(5));
+        assert!((at(&breathe, 0).brightness - 0.05).abs() < 1e-6);
+        assert!((at(&breathe, 2000).brightness - 0.5).abs() < 1e-6);
+
+        let rainbow = Effect::new(EffectKind::Rainbow, Duration::from_secs(5));
+        assert!((at(&rainbow, 1000).hue - 0.1).abs() < 1e-6);
+        assert_eq!(at(&rainbow, 1000).saturation, 1.0);
+
+        // Effects on a light that's off light it up fully
+        mock.advance(Duration::from_secs(1));
+        let off = BulbState::color(false, 0.0, 0.0);
+        assert_eq!(pulse.state_at(pulse.started, &off).brightness, 1.0);
+        let red = BulbState::color(true, 0.0, 1.0);
+        let fixed = Effect::until_stopped(EffectKind::Override(red));
+        assert_eq!(fixed.state_at(clock::now(), &live), red);
+    }
+}
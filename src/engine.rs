@@ -0,0 +1,227 @@
+use crate::clock;
+use crate::config::{parse_config, Config, ConfigError};
+use crate::control::{ControlCommand, ControlRequest, Controller, Event};
+use crate::state::BulbState;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+// The sync running in a background thread, for programs embedding it
+pub struct Engine {
+    config: Arc<Config>,
+    sender: mpsc::Sender<ControlRequest>,
+    // Lives in the sync thread while it's running
+    controller: Option<Controller>,
+    stop: Arc<AtomicBool>,
+    // The control, websocket, grpc and history servers are only started the
+    // first time, they keep running and talking to the controller between starts
+    servers_started: Arc<AtomicBool>,
+    thread: Option<JoinHandle<(Controller, Option<String>)>>,
+    // Why syncing couldn't start the last time it stopped, like a port that's
+    // already taken
+    error: Option<String>,
+}
+
+impl Engine {
+    // Takes the YAML text of a settings file
+    pub fn new(settings: &str) -> Result<Engine, ConfigError> {
+        parse_config(settings).map(Engine::from_config)
+    }
+
+    pub fn from_config(config: Config) -> Engine {
+        let controller = Controller::new();
+        Engine {
+            config: Arc::new(config),
+            sender: controller.sender(),
+            controller: Some(controller),
+            stop: Arc::new(AtomicBool::new(false)),
+            servers_started: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            error: None,
+        }
+    }
+
+    // Returns false when it's already running
+    pub fn start(&mut self) -> bool {
+        let mut controller = match self.controller.take() {
+            Some(controller) => controller,
+            None => return false,
+        };
+        self.stop.store(false, Ordering::Relaxed);
+        self.error = None;
+        let config = self.config.clone();
+        let stop = self.stop.clone();
+        let servers_started = self.servers_started.clone();
+        self.thread = Some(clock::spawn(move || {
+            // The controller is handed back even when syncing failed, the
+            // servers already send to it
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                if !servers_started.load(Ordering::Relaxed) {
+                    crate::start_servers(&config, &mut controller)?;
+                    servers_started.store(true, Ordering::Relaxed);
+                }
+                crate::sync(&config, &mut controller, &stop, None)
+            }));
+            (controller, res.ok().and_then(Result::err))
+        }));
+        true
+    }
+
+    // Whether the sync thread is still going, it can be started again once
+    // it stopped or failed
+    pub fn is_running(&mut self) -> bool {
+        match &self.thread {
+            Some(thread) if !thread.is_finished() => true,
+            Some(_) => {
+                self.stop();
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Stops syncing and waits for the sync thread to end
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::Relaxed);
+            // Only a panic outside of syncing loses the controller
+            let (controller, error) = thread.join().unwrap_or_else(|_| (Controller::new(), None));
+            self.error = error;
+            self.sender = controller.sender();
+            self.controller = Some(controller);
+        }
+    }
+
+    // Why syncing couldn't be started, once it stopped
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    // The same, but only returned once
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    // Sets the state of every light using the push bulb service with this
+    // name, works before starting as well
+    pub fn push(&self, name: &str, state: BulbState) {
+        self.config.pushed.push(name, state);
+    }
+
+    // Calls on_event from a background thread with every event from now on,
+    // works before starting as well
+    pub fn subscribe(&self, mut on_event: impl FnMut(Event) + Send + 'static) -> bool {
+        let (sender, events) = mpsc::channel();
+        // Not waiting for the answer, the sync loop might not be running yet
+        let (reply, _) = mpsc::channel();
+        let request = ControlRequest {
+            command: ControlCommand::Subscribe(sender),
+            reply,
+        };
+        if self.sender.send(request).is_err() {
+            return false;
+        }
+        clock::spawn(move || {
+            for event in events {
+                on_event(event);
+            }
+        });
+        true
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SETTINGS: &str = "
+vrchat_ip: 127.0.0.1
+vrchat_port: 47120
+max_updates_per_second: 20
+control:
+    port: 47121
+lights:
+    - name: overlay
+      bulb_service: push
+      push:
+          name: overlay
+";
+
+    // Panics when no event like this comes soon
+    fn wait_for(events: &mpsc::Receiver<Event>, matches: impl Fn(&Event) -> bool) {
+        while !matches(&events.recv_timeout(Duration::from_secs(5)).unwrap()) {}
+    }
+
+    // The light was sent for the first time, so it's syncing
+    fn wait_for_sync(events: &mpsc::Receiver<Event>) {
+        wait_for(events, |event| matches!(event, Event::OscSent { .. }));
+    }
+
+    fn wait_for_state(events: &mpsc::Receiver<Event>, state: BulbState) {
+        wait_for(
+            events,
+            |event| matches!(event, Event::State(status) if status.state == state),
+        );
+    }
+
+    #[test]
+    fn starts_again_after_stopping() {
+        let mut engine = Engine::new(SETTINGS).unwrap();
+        let (sender, events) = mpsc::channel();
+        assert!(engine.subscribe(move |event| drop(sender.send(event))));
+
+        engine.push("overlay", BulbState::color(false, 0.0, 0.0));
+        assert!(engine.start());
+        assert!(!engine.start());
+        wait_for_sync(&events);
+        let red = BulbState::color(true, 0.0, 1.0);
+        engine.push("overlay", red);
+        wait_for_state(&events, red);
+        engine.stop();
+        events.try_iter().for_each(drop);
+
+        // The control API isn't started a second time, and the events keep
+        // coming to the same subscriber
+        assert!(engine.start());
+        wait_for_sync(&events);
+        let blue = BulbState::color(true, 0.6, 0.5);
+        engine.push("overlay", blue);
+        wait_for_state(&events, blue);
+        assert!(engine.is_running());
+    }
+
+    #[test]
+    fn says_why_it_couldnt_start() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let settings = SETTINGS.replace("port: 47121", &format!("port: {}", port));
+        let mut engine = Engine::new(&settings).unwrap();
+        assert!(engine.start());
+        let start = clock::now();
+        while engine.is_running() {
+            assert!(clock::elapsed(start) < Duration::from_secs(5));
+            clock::sleep(Duration::from_millis(10));
+        }
+        assert!(engine
+            .error()
+            .is_some_and(|err| err.starts_with("Couldn't start the control API")));
+        assert!(engine.take_error().is_some());
+        assert!(engine.error().is_none());
+    }
+
+    #[test]
+    fn every_engine_has_its_own_pushed_states() {
+        let first = Engine::new(SETTINGS).unwrap();
+        let second = Engine::new(SETTINGS).unwrap();
+        first.push("overlay", BulbState::color(true, 0.0, 1.0));
+        assert!(second.config.pushed.get("overlay").is_none());
+    }
+}
@@ -0,0 +1,178 @@
+// The C interface for embedding the sync in other programs, described in
+// include/vrchat_light_sync.h. Every function is safe to call with a null
+// handle and returns a negative number when something went wrong.
+use crate::control::event_json;
+use crate::engine::Engine;
+use crate::state::BulbState;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+
+const OK: c_int = 0;
+const ERROR: c_int = -1;
+
+pub type EventCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+pub struct LightSync {
+    engine: Engine,
+}
+
+// The pointer handed to a callback, which the embedding program promised can
+// be used from any thread
+struct UserData(*mut c_void);
+unsafe impl Send for UserData {}
+
+// Turns a panic into an error code, unwinding into C isn't allowed
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(ERROR)
+}
+
+fn status(ok: bool) -> c_int {
+    if ok {
+        OK
+    } else {
+        ERROR
+    }
+}
+
+unsafe fn text<'a>(text: *const c_char) -> Option<&'a str> {
+    if text.is_null() {
+        return None;
+    }
+    CStr::from_ptr(text).to_str().ok()
+}
+
+/// Creates a sync from the YAML text of a settings file, returns null when the
+/// settings can't be used.
+///
+/// # Safety
+/// `settings` has to be null or a null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_new(settings: *const c_char) -> *mut LightSync {
+    let settings = match text(settings) {
+        Some(settings) => settings,
+        None => return std::ptr::null_mut(),
+    };
+    match Engine::new(settings) {
+        Ok(engine) => Box::into_raw(Box::new(LightSync { engine })),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Starts syncing in a background thread.
+///
+/// # Safety
+/// `sync` has to be null or come from `lightsync_new`.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_start(sync: *mut LightSync) -> c_int {
+    match sync.as_mut() {
+        Some(sync) => guard(|| status(sync.engine.start())),
+        None => ERROR,
+    }
+}
+
+/// Returns 1 while the sync thread is running and 0 once it stopped or
+/// failed, after which `lightsync_start` can start it again. Why it couldn't
+/// start is printed to stderr.
+///
+/// # Safety
+/// `sync` has to be null or come from `lightsync_new`.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_is_running(sync: *mut LightSync) -> c_int {
+    match sync.as_mut() {
+        Some(sync) => guard(|| {
+            let running = sync.engine.is_running();
+            // Shown once, like the settings errors of lightsync_new
+            if let Some(err) = sync.engine.take_error() {
+                eprintln!("{}", err);
+            }
+            running as c_int
+        }),
+        None => ERROR,
+    }
+}
+
+/// Stops syncing and waits for the sync thread to end.
+///
+/// # Safety
+/// `sync` has to be null or come from `lightsync_new`.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_stop(sync: *mut LightSync) -> c_int {
+    match sync.as_mut() {
+        Some(sync) => guard(|| {
+            sync.engine.stop();
+            OK
+        }),
+        None => ERROR,
+    }
+}
+
+/// Sets the state of every light using the push bulb service with this name,
+/// hue and brightness go from 0 to 1.
+///
+/// # Safety
+/// `sync` has to be null or come from `lightsync_new` and `name` has to be
+/// null or a null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_push_state(
+    sync: *mut LightSync,
+    name: *const c_char,
+    on: bool,
+    hue: f32,
+    brightness: f32,
+) -> c_int {
+    let (sync, name) = match (sync.as_ref(), text(name)) {
+        (Some(sync), Some(name)) => (sync, name),
+        _ => return ERROR,
+    };
+    guard(|| {
+        sync.engine.push(
+            name,
+            BulbState::color(on, hue.rem_euclid(1.0), brightness.clamp(0.0, 1.0)),
+        );
+        OK
+    })
+}
+
+/// Calls `callback` from a background thread with every event as JSON, in the
+/// same format as the websocket stream. The string is only valid during the
+/// call. Subscribing before starting works as well.
+///
+/// # Safety
+/// `sync` has to be null or come from `lightsync_new` and `user_data` has to
+/// be usable from another thread.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_subscribe(
+    sync: *mut LightSync,
+    callback: EventCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let sync = match sync.as_ref() {
+        Some(sync) => sync,
+        None => return ERROR,
+    };
+    let user_data = UserData(user_data);
+    guard(|| {
+        status(sync.engine.subscribe(move |event| {
+            let user_data = &user_data;
+            if let Ok(json) = CString::new(event_json(&event).to_string()) {
+                callback(json.as_ptr(), user_data.0);
+            }
+        }))
+    })
+}
+
+/// Stops syncing and frees the sync.
+///
+/// # Safety
+/// `sync` has to be null or come from `lightsync_new`, and can't be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lightsync_free(sync: *mut LightSync) {
+    if !sync.is_null() {
+        let sync = Box::from_raw(sync);
+        panic::catch_unwind(AssertUnwindSafe(|| drop(sync))).ok();
+    }
+}
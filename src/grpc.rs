@@ -0,0 +1,186 @@
+use crate::clock;
+use crate::config::MAX_SECONDS;
+use crate::control::{self, ControlCommand, ControlReply, ControlRequest, Event};
+use crate::light::LightStatus;
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("lightsync");
+}
+
+use proto::light_sync_server::{LightSync, LightSyncServer};
+
+fn default_port() -> u16 {
+    9124
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrpcConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn to_proto(status: LightStatus) -> proto::LightStatus {
+    proto::LightStatus {
+        name: status.name,
+        state: Some(proto::BulbState {
+            on: status.state.on,
+            hue: status.state.hue,
+            brightness: status.state.brightness,
+            saturation: Some(status.state.saturation),
+            color_temp: status.state.color_temp,
+        }),
+        effect: status
+            .effect
+            .map_or(String::new(), |effect| format!("{:?}", effect)),
+        healthy: status.healthy,
+    }
+}
+
+struct Service {
+    requests: mpsc::Sender<ControlRequest>,
+}
+
+impl Service {
+    // Waits for the sync loop on a blocking thread so the server keeps going
+    async fn request(&self, command: ControlCommand) -> Result<ControlReply, Status> {
+        let requests = self.requests.clone();
+        let reply = tokio::task::spawn_blocking(move || control::request(&requests, command))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        match reply {
+            ControlReply::Error(err) => Err(Status::invalid_argument(err)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl LightSync for Service {
+    async fn get_status(
+        &self,
+        _: Request<proto::StatusRequest>,
+    ) -> Result<Response<proto::Status>, Status> {
+        match self.request(ControlCommand::Status).await? {
+            ControlReply::Status(status) => Ok(Response::new(proto::Status {
+                paused: status.paused,
+                syncing: status.syncing,
+                lights: status.lights.into_iter().map(to_proto).collect(),
+            })),
+            _ => Err(Status::internal(
+                "the sync loop didn't answer with a status",
+            )),
+        }
+    }
+
+    async fn set_override(
+        &self,
+        request: Request<proto::OverrideRequest>,
+    ) -> Result<Response<proto::Reply>, Status> {
+        let request = request.into_inner();
+        let state = request
+            .state
+            .ok_or_else(|| Status::invalid_argument("the override needs a state"))?;
+        let duration = if request.seconds > MAX_SECONDS || request.seconds.is_nan() {
+            return Err(Status::invalid_argument(
+                "the override can last up to a year, 0 keeps it until it's cleared",
+            ));
+        } else if request.seconds > 0.0 {
+            Some(Duration::from_secs_f32(request.seconds))
+        } else {
+            None
+        };
+        self.request(ControlCommand::Override {
+            state: match state.color_temp {
+                Some(kelvin) => {
+                    BulbState::white(state.on, kelvin, state.brightness.clamp(0.0, 1.0))
+                }
+                None => BulbState {
+                    on: state.on,
+                    hue: state.hue.clamp(0.0, 1.0),
+                    saturation: state.saturation.unwrap_or(1.0).clamp(0.0, 1.0),
+                    brightness: state.brightness.clamp(0.0, 1.0),
+                    color_temp: None,
+                },
+            },
+            duration,
+            light: request.light,
+        })
+        .await?;
+        Ok(Response::new(proto::Reply {}))
+    }
+
+    async fn clear_override(
+        &self,
+        request: Request<proto::LightSelector>,
+    ) -> Result<Response<proto::Reply>, Status> {
+        self.request(ControlCommand::StopEffect {
+            light: request.into_inner().light,
+        })
+        .await?;
+        Ok(Response::new(proto::Reply {}))
+    }
+
+    async fn set_paused(
+        &self,
+        request: Request<proto::PauseRequest>,
+    ) -> Result<Response<proto::Reply>, Status> {
+        self.request(ControlCommand::SetPaused(request.into_inner().paused))
+            .await?;
+        Ok(Response::new(proto::Reply {}))
+    }
+
+    type WatchStatesStream = ReceiverStream<Result<proto::LightStatus, Status>>;
+
+    async fn watch_states(
+        &self,
+        request: Request<proto::LightSelector>,
+    ) -> Result<Response<Self::WatchStatesStream>, Status> {
+        let light = request.into_inner().light;
+        let (sender, events) = mpsc::channel();
+        self.request(ControlCommand::Subscribe(sender)).await?;
+        let (stream_sender, stream) = tokio::sync::mpsc::channel(16);
+        // Carry the statuses over from the sync loop until the client leaves
+        tokio::task::spawn_blocking(move || {
+            for event in events {
+                let status = match event {
+                    Event::State(status) => status,
+                    _ => continue,
+                };
+                if light.as_ref().is_some_and(|light| *light != status.name) {
+                    continue;
+                }
+                if stream_sender.blocking_send(Ok(to_proto(status))).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+// Serves the gRPC API on localhost in the background
+pub fn start(config: &GrpcConfig, requests: mpsc::Sender<ControlRequest>) -> Result<(), String> {
+    let addr = ([127, 0, 0, 1], config.port).into();
+    println!("gRPC API listening on {}", addr);
+    clock::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let res = runtime.block_on(
+            tonic::transport::Server::builder()
+                .add_service(LightSyncServer::new(Service { requests }))
+                .serve(addr),
+        );
+        if let Err(err) = res {
+            println!("The gRPC API stopped: {}", err);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,491 @@
+use crate::clock;
+use crate::config::validate_rate;
+use crate::control::{self, value_json, ControlCommand, ControlReply, Controller, Event};
+use crate::logging::{self, Category};
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::sync::mpsc;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn default_path() -> String {
+    "history.sqlite".to_owned()
+}
+
+// How often rows older than keep_days are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    // Days to keep the history for, forever if not set
+    pub keep_days: Option<f32>,
+}
+
+impl HistoryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.keep_days {
+            Some(keep_days) => validate_rate("history.keep_days", keep_days, 36_500.0),
+            None => Ok(()),
+        }
+    }
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS states (
+        time INTEGER NOT NULL,
+        light TEXT NOT NULL,
+        is_on INTEGER NOT NULL,
+        hue REAL NOT NULL,
+        brightness REAL NOT NULL,
+        effect TEXT,
+        healthy INTEGER NOT NULL,
+        saturation REAL NOT NULL DEFAULT 1,
+        color_temp REAL
+    );
+    CREATE INDEX IF NOT EXISTS states_time ON states (light, time);
+    CREATE TABLE IF NOT EXISTS sent (
+        time INTEGER NOT NULL,
+        light TEXT NOT NULL,
+        address TEXT NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS sent_time ON sent (light, address, time);
+";
+
+// Columns added after the first version, added to older histories when
+// they're opened
+const ADDED_COLUMNS: [(&str, &str); 2] = [
+    ("saturation", "REAL NOT NULL DEFAULT 1"),
+    ("color_temp", "REAL"),
+];
+
+const STATE_COLUMNS: &str =
+    "time, light, is_on, hue, brightness, effect, healthy, saturation, color_temp";
+
+fn migrate(db: &Connection) -> rusqlite::Result<()> {
+    let mut statement = db.prepare("SELECT name FROM pragma_table_info('states')")?;
+    let columns = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    for (name, definition) in ADDED_COLUMNS {
+        if !columns.iter().any(|column| column == name) {
+            db.execute_batch(&format!(
+                "ALTER TABLE states ADD COLUMN {} {}",
+                name, definition
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+// Milliseconds since the Unix epoch, which is how times are stored
+fn now_millis() -> i64 {
+    clock::system()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as i64)
+}
+
+fn record(db: &mut Connection, events: &[Event]) -> rusqlite::Result<()> {
+    let time = now_millis();
+    let transaction = db.transaction()?;
+    for event in events {
+        match event {
+            Event::State(status) => {
+                transaction.execute(
+                    &format!(
+                        "INSERT INTO states ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                        STATE_COLUMNS
+                    ),
+                    params![
+                        time,
+                        status.name,
+                        status.state.on,
+                        status.state.hue,
+                        status.state.brightness,
+                        status.effect.map(|effect| format!("{:?}", effect)),
+                        status.healthy,
+                        status.state.saturation,
+                        status.state.color_temp,
+                    ],
+                )?;
+            }
+            Event::OscSent {
+                light,
+                address,
+                value,
+            } => {
+                transaction.execute(
+                    "INSERT INTO sent VALUES (?1, ?2, ?3, ?4)",
+                    params![time, light, address, value_json(value).to_string()],
+                )?;
+            }
+        }
+    }
+    transaction.commit()
+}
+
+fn prune(db: &Connection, keep_days: f32) -> rusqlite::Result<()> {
+    let oldest = now_millis() - (keep_days * 24.0 * 60.0 * 60.0 * 1000.0) as i64;
+    db.execute("DELETE FROM states WHERE time < ?1", [oldest])?;
+    db.execute("DELETE FROM sent WHERE time < ?1", [oldest])?;
+    Ok(())
+}
+
+// Records every state change and sent parameter into the SQLite database in
+// the background, subscribed right away so the first sends are recorded too
+pub fn start(config: &HistoryConfig, controller: &mut Controller) -> Result<(), String> {
+    let mut db = Connection::open(&config.path)
+        .and_then(|db| db.execute_batch(SCHEMA).map(|()| db))
+        .and_then(|db| migrate(&db).map(|()| db))
+        .map_err(|err| format!("Couldn't open the history in {}: {}", config.path, err))?;
+    let keep_days = config.keep_days;
+    println!("Recording the history in {}", config.path);
+    let (sender, events) = mpsc::channel();
+    controller.subscribe(sender);
+    let requests = controller.sender();
+    clock::spawn(move || {
+        // The lights as they are now, later states only come when they change
+        if let ControlReply::Status(status) = control::request(&requests, ControlCommand::Status) {
+            let states: Vec<Event> = status.lights.into_iter().map(Event::State).collect();
+            if let Err(err) = record(&mut db, &states) {
+                logging::error(
+                    Category::Output,
+                    format!("Couldn't record the history: {}", err),
+                );
+            }
+        }
+        let mut last_prune = None;
+        while let Ok(event) = events.recv() {
+            // Everything that piled up goes in one transaction
+            let batch: Vec<Event> = std::iter::once(event).chain(events.try_iter()).collect();
+            if let Err(err) = record(&mut db, &batch) {
+                logging::error(
+                    Category::Output,
+                    format!("Couldn't record the history: {}", err),
+                );
+            }
+            if let Some(keep_days) = keep_days {
+                if last_prune.is_none_or(|last| clock::elapsed(last) >= PRUNE_INTERVAL) {
+                    last_prune = Some(clock::now());
+                    if let Err(err) = prune(&db, keep_days) {
+                        logging::error(
+                            Category::Output,
+                            format!("Couldn't remove old history: {}", err),
+                        );
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Reads a local time like "2024-05-17 21:30", or "21:30" for today
+pub fn parse_time(text: &str) -> Result<i64, Box<dyn Error>> {
+    let text = text.trim();
+    let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M"))
+        .or_else(|_| NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|date| date.into()))
+        .or_else(|_| {
+            NaiveTime::parse_from_str(text, "%H:%M:%S")
+                .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M"))
+                .map(|time| clock::local().date().and_time(time))
+        })
+        .map_err(|_| format!("{} isn't a time like 2024-05-17 21:30 or 21:30", text))?;
+    let local = Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{} doesn't exist in the local time zone", text))?;
+    Ok(local.timestamp_millis())
+}
+
+fn format_time(millis: i64) -> String {
+    Local.timestamp_millis_opt(millis).single().map_or_else(
+        || millis.to_string(),
+        |time| time.format("%Y-%m-%d %H:%M:%S").to_string(),
+    )
+}
+
+// Floats are shortened like the states are
+fn format_value(value: &str) -> String {
+    match serde_json::from_str(value) {
+        Ok(serde_json::Value::Number(number)) if number.is_f64() => {
+            format!("{:.3}", number.as_f64().unwrap_or_default())
+        }
+        _ => value.to_owned(),
+    }
+}
+
+struct StateRow {
+    time: i64,
+    light: String,
+    on: bool,
+    hue: f32,
+    brightness: f32,
+    effect: Option<String>,
+    healthy: bool,
+    saturation: f32,
+    // In Kelvin while it was a white
+    color_temp: Option<f32>,
+}
+
+impl StateRow {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<StateRow> {
+        Ok(StateRow {
+            time: row.get(0)?,
+            light: row.get(1)?,
+            on: row.get(2)?,
+            hue: row.get(3)?,
+            brightness: row.get(4)?,
+            effect: row.get(5)?,
+            healthy: row.get(6)?,
+            saturation: row.get(7)?,
+            color_temp: row.get(8)?,
+        })
+    }
+
+    fn format(&self) -> String {
+        let color = match self.color_temp {
+            Some(kelvin) => format!("white {:.0}K", kelvin),
+            None => format!("hue {:.3} saturation {:.3}", self.hue, self.saturation),
+        };
+        let mut line = format!(
+            "{} {}: {} {} brightness {:.3}",
+            format_time(self.time),
+            self.light,
+            if self.on { "on" } else { "off" },
+            color,
+            self.brightness
+        );
+        if let Some(effect) = &self.effect {
+            line += &format!(" effect {}", effect);
+        }
+        if !self.healthy {
+            line += " unreachable";
+        }
+        line
+    }
+
+    fn json(&self) -> serde_json::Value {
+        json!({
+            "time": format_time(self.time),
+            "light": self.light,
+            "on": self.on,
+            "hue": self.hue,
+            "saturation": self.saturation,
+            "color_temp": self.color_temp,
+            "brightness": self.brightness,
+            "effect": self.effect,
+            "healthy": self.healthy,
+        })
+    }
+}
+
+// An address and the JSON of the value sent to it
+type SentRow = (String, String);
+
+// What to look up with the history command
+pub enum Query {
+    // The state of every light at a time, along with the parameters it had
+    // been sent by then
+    At(i64),
+    // Every state change between two times
+    Between(i64, i64),
+}
+
+fn open(config: &HistoryConfig) -> Result<Connection, Box<dyn Error>> {
+    Connection::open_with_flags(&config.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|err| format!("Couldn't open the history in {}: {}", config.path, err).into())
+}
+
+fn lights(db: &Connection, light: Option<&str>) -> rusqlite::Result<Vec<String>> {
+    let mut statement = db.prepare("SELECT DISTINCT light FROM states ORDER BY light")?;
+    let names = statement.query_map([], |row| row.get(0))?;
+    Ok(names
+        .collect::<rusqlite::Result<Vec<String>>>()?
+        .into_iter()
+        .filter(|name| light.is_none_or(|light| light == name))
+        .collect())
+}
+
+// State of a light at a time and the newest value of each parameter sent to
+// it by then
+fn state_at(
+    db: &Connection,
+    light: &str,
+    time: i64,
+) -> rusqlite::Result<Option<(StateRow, Vec<SentRow>)>> {
+    let state = db
+        .query_row(
+            &format!(
+                "SELECT {} FROM states WHERE light = ?1 AND time <= ?2
+                    ORDER BY time DESC LIMIT 1",
+                STATE_COLUMNS
+            ),
+            params![light, time],
+            StateRow::from_row,
+        )
+        .map(Some)
+        .or_else(|err| match err {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            err => Err(err),
+        })?;
+    let state = match state {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+    let mut statement = db.prepare(
+        // SQLite takes the value from the row with the newest time
+        "SELECT address, value, MAX(time) FROM sent WHERE light = ?1 AND time <= ?2
+            GROUP BY address ORDER BY address",
+    )?;
+    let sent = statement
+        .query_map(params![light, time], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<SentRow>>>()?;
+    Ok(Some((state, sent)))
+}
+
+// Answers a history query, as text or JSON
+pub fn query(
+    config: &HistoryConfig,
+    query: Query,
+    light: Option<&str>,
+    as_json: bool,
+) -> Result<String, Box<dyn Error>> {
+    let db = open(config)?;
+    let mut lines = Vec::new();
+    let mut values = Vec::new();
+    match query {
+        Query::At(time) => {
+            for name in lights(&db, light)? {
+                let (state, sent) = match state_at(&db, &name, time)? {
+                    Some(found) => found,
+                    None => continue,
+                };
+                if as_json {
+                    let mut value = state.json();
+                    value["parameters"] = sent
+                        .iter()
+                        .map(|(address, value)| {
+                            let value = serde_json::from_str(value).unwrap_or(json!(value));
+                            (address.clone(), value)
+                        })
+                        .collect::<serde_json::Map<_, _>>()
+                        .into();
+                    values.push(value);
+                } else {
+                    lines.push(state.format());
+                    for (address, value) in sent {
+                        lines.push(format!("    {} {}", address, format_value(&value)));
+                    }
+                }
+            }
+        }
+        Query::Between(from, to) => {
+            let mut statement = db.prepare(&format!(
+                "SELECT {} FROM states WHERE time >= ?1 AND time <= ?2
+                    AND (?3 IS NULL OR light = ?3) ORDER BY time",
+                STATE_COLUMNS
+            ))?;
+            for state in statement.query_map(params![from, to, light], StateRow::from_row)? {
+                let state = state?;
+                if as_json {
+                    values.push(state.json());
+                } else {
+                    lines.push(state.format());
+                }
+            }
+        }
+    }
+    if as_json {
+        return Ok(serde_json::Value::from(values).to_string());
+    }
+    if lines.is_empty() {
+        return Ok("Nothing was recorded for then.".to_owned());
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+
+    fn local_millis(date: &str) -> i64 {
+        let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").unwrap();
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    #[test]
+    fn parses_times() {
+        let time = local_millis("2024-05-17 21:30:00");
+        assert_eq!(parse_time("2024-05-17 21:30").unwrap(), time);
+        assert_eq!(parse_time(" 2024-05-17 21:30:00 ").unwrap(), time);
+        assert_eq!(parse_time("2024-05-17 21:30:15").unwrap(), time + 15_000);
+        assert_eq!(
+            parse_time("2024-05-17").unwrap(),
+            local_millis("2024-05-17 00:00:00")
+        );
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("2024-13-01").is_err());
+        assert!(parse_time("25:00").is_err());
+    }
+
+    #[test]
+    fn parses_times_of_today() {
+        let today = std::time::SystemTime::UNIX_EPOCH
+            + Duration::from_millis(local_millis("2024-05-17 12:00:00") as u64);
+        clock::set_local(Arc::new(MockClock::new(today)));
+        assert_eq!(
+            parse_time("21:30").unwrap(),
+            local_millis("2024-05-17 21:30:00")
+        );
+        assert_eq!(
+            parse_time("08:05:30").unwrap(),
+            local_millis("2024-05-17 08:05:30")
+        );
+    }
+
+    #[test]
+    fn adds_the_new_columns_to_old_histories() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE states (
+                time INTEGER NOT NULL,
+                light TEXT NOT NULL,
+                is_on INTEGER NOT NULL,
+                hue REAL NOT NULL,
+                brightness REAL NOT NULL,
+                effect TEXT,
+                healthy INTEGER NOT NULL
+            );
+            INSERT INTO states VALUES (1, 'desk', 1, 0.5, 1.0, NULL, 1);",
+        )
+        .unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+        migrate(&db).unwrap();
+        // Running it again leaves it alone
+        migrate(&db).unwrap();
+        let row = db
+            .query_row(
+                &format!("SELECT {} FROM states", STATE_COLUMNS),
+                [],
+                StateRow::from_row,
+            )
+            .unwrap();
+        assert_eq!(row.saturation, 1.0);
+        assert_eq!(row.color_temp, None);
+        assert!(row
+            .format()
+            .ends_with("desk: on hue 0.500 saturation 1.000 brightness 1.000"));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::clock;
+use crate::config::validate_seconds;
+use crate::light::Light;
+use crate::logging::{self, Category};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+fn default_interval() -> f32 {
+    10.0
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct InfluxConfig {
+    // Write API to post to, like
+    // http://localhost:8086/api/v2/write?org=home&bucket=lights
+    pub url: Option<String>,
+    // Sent as "Authorization: Token <token>"
+    pub token: Option<String>,
+    // File to append the lines to instead, for Telegraf or importing later
+    pub file: Option<String>,
+    // Seconds between writes
+    #[serde(default = "default_interval")]
+    pub interval: f32,
+}
+
+impl InfluxConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.url.is_none() && self.file.is_none() {
+            return Err("influx needs a url or a file to write to.".into());
+        }
+        validate_seconds("influx.interval", self.interval, false)
+    }
+}
+
+// Tag values can't have unescaped spaces, commas or equal signs
+fn escape_tag(value: &str) -> String {
+    value
+        .replace(' ', "\\ ")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+}
+
+fn now_nanos() -> u128 {
+    clock::system()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos())
+}
+
+fn write(config: &InfluxConfig, lines: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(url) = &config.url {
+        let mut request = reqwest::blocking::Client::new()
+            .post(url)
+            .timeout(Duration::from_secs(10))
+            .body(lines.to_owned());
+        if let Some(token) = &config.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+        request.send()?.error_for_status()?;
+    }
+    if let Some(file) = &config.file {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?
+            .write_all(lines.as_bytes())?;
+    }
+    Ok(())
+}
+
+// Collects the lights' states and how long the sync loop takes in InfluxDB
+// line protocol, which a background thread writes out every interval so a
+// slow database can't hold up syncing
+pub struct InfluxExporter {
+    interval: Duration,
+    last_write: Instant,
+    lines: String,
+    cycles: u32,
+    cycle_total: Duration,
+    cycle_max: Duration,
+    poll_total: Duration,
+    writer: mpsc::Sender<String>,
+}
+
+impl InfluxExporter {
+    pub fn new(config: &InfluxConfig) -> InfluxExporter {
+        let interval = Duration::from_secs_f32(config.interval);
+        let (writer, batches) = mpsc::channel::<String>();
+        let config = config.clone();
+        clock::spawn(move || {
+            for batch in batches {
+                if let Err(err) = write(&config, &batch) {
+                    logging::error(
+                        Category::Output,
+                        format!("Couldn't write the InfluxDB metrics: {}", err),
+                    );
+                }
+            }
+        });
+        InfluxExporter {
+            interval,
+            last_write: clock::now(),
+            lines: String::new(),
+            cycles: 0,
+            cycle_total: Duration::ZERO,
+            cycle_max: Duration::ZERO,
+            poll_total: Duration::ZERO,
+            writer,
+        }
+    }
+
+    fn add_state(&mut self, light: &Light, time: u128) {
+        let status = light.status();
+        // Only whites have a color temperature
+        let color_temp = status
+            .state
+            .color_temp
+            .map_or_else(String::new, |kelvin| format!(",color_temp={}", kelvin));
+        self.lines += &format!(
+            "light_sync_state,light={} on={},hue={},saturation={}{},brightness={},healthy={} {}\n",
+            escape_tag(&status.name),
+            status.state.on,
+            status.state.hue,
+            status.state.saturation,
+            color_temp,
+            status.state.brightness,
+            status.healthy,
+            time
+        );
+    }
+
+    // Records the state of a light that just changed
+    pub fn state_changed(&mut self, light: &Light) {
+        self.add_state(light, now_nanos());
+    }
+
+    // Records how long a loop spent working, leaving out the wait until the
+    // next one, and how long the slowest poll it took in took
+    pub fn cycle(&mut self, busy: Duration, poll: Duration) {
+        self.cycles += 1;
+        self.cycle_total += busy;
+        self.cycle_max = self.cycle_max.max(busy);
+        self.poll_total += poll;
+    }
+
+    // Hands everything collected to the writer thread once the interval has
+    // passed, with every light's state so graphs don't have gaps while the
+    // lights stay the same
+    pub fn flush(&mut self, lights: &[Light]) {
+        if clock::elapsed(self.last_write) < self.interval {
+            return;
+        }
+        self.last_write = clock::now();
+        let time = now_nanos();
+        for light in lights {
+            self.add_state(light, time);
+        }
+        if self.cycles > 0 {
+            let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+            self.lines += &format!(
+                "light_sync_timing cycles={}i,busy_ms={},busy_max_ms={},poll_ms={} {}\n",
+                self.cycles,
+                millis(self.cycle_total) / self.cycles as f64,
+                millis(self.cycle_max),
+                millis(self.poll_total) / self.cycles as f64,
+                time
+            );
+        }
+        self.cycles = 0;
+        self.cycle_total = Duration::ZERO;
+        self.cycle_max = Duration::ZERO;
+        self.poll_total = Duration::ZERO;
+        let _ = self.writer.send(std::mem::take(&mut self.lines));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::state::{white_color, BulbState};
+
+    #[test]
+    fn escapes_tag_values() {
+        assert_eq!(escape_tag("desk"), "desk");
+        assert_eq!(escape_tag("desk lamp,left=1"), "desk\\ lamp\\,left\\=1");
+    }
+
+    #[test]
+    fn writes_the_states_as_line_protocol() {
+        let config = parse_config(
+            "
+vrchat_ip: 127.0.0.1
+vrchat_port: 9
+max_updates_per_second: 5
+lights:
+    - name: desk lamp, left
+      bulb_service: push
+      push:
+          name: desk
+    - name: ceiling
+      bulb_service: push
+      push:
+          name: ceiling
+",
+        )
+        .unwrap();
+        config
+            .pushed
+            .push("desk", BulbState::color(true, 0.5, 0.25));
+        config
+            .pushed
+            .push("ceiling", BulbState::white(false, 2700.0, 1.0));
+        let lights: Vec<Light> = config
+            .lights
+            .iter()
+            .map(|light| Light::new(&config, light, "127.0.0.1:9").unwrap())
+            .collect();
+        let mut exporter = InfluxExporter::new(&InfluxConfig {
+            url: None,
+            token: None,
+            file: None,
+            interval: 10.0,
+        });
+        exporter.add_state(&lights[0], 1);
+        exporter.add_state(&lights[1], 2);
+        // Whites get the hue they look like, and their color temperature
+        let (hue, saturation, _) = white_color(2700.0);
+        assert_eq!(
+            exporter.lines,
+            format!(
+                "light_sync_state,light=desk\\ lamp\\,\\ left \
+                 on=true,hue=0.5,saturation=1,brightness=0.25,healthy=true 1\n\
+                 light_sync_state,light=ceiling \
+                 on=false,hue={},saturation={},color_temp=2700,brightness=1,healthy=true 2\n",
+                hue, saturation
+            )
+        );
+    }
+}
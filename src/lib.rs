@@ -0,0 +1,308 @@
+pub mod autostart;
+pub mod backend;
+pub mod check;
+pub mod clock;
+pub mod config;
+pub mod control;
+mod discovery;
+mod effects;
+pub mod engine;
+pub mod ffi;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "history")]
+pub mod history;
+mod influx;
+mod light;
+mod logging;
+mod osc_receive;
+pub mod output;
+#[cfg(feature = "preview")]
+pub mod preview;
+#[cfg(feature = "python")]
+mod python;
+mod reload;
+mod resync;
+#[cfg(feature = "secrets")]
+pub mod secrets;
+pub mod selftest;
+pub mod state;
+mod test_pattern;
+mod transition;
+#[cfg(feature = "update")]
+pub mod update;
+mod vrchat_log;
+mod vrchat_settings;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod world_filter;
+mod world_profiles;
+
+use config::{get_config, Config};
+use control::Controller;
+use influx::InfluxExporter;
+use light::Light;
+use osc_receive::OscReceiver;
+use output::multiplex::Multiplexer;
+use output::vrchat::VrchatOutput;
+use output::Output;
+use reload::SettingsWatcher;
+use resync::Resync;
+use state::BulbState;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time;
+use world_filter::WorldFilter;
+use world_profiles::WorldProfiles;
+
+fn vrchat_addr(config: &Config) -> String {
+    // The outputs go where discovery finds VRChat by themselves, this is only
+    // where they send until it's found. It's only started the first time, a
+    // reload gets where it was found last.
+    if config.vrchat_autodiscover {
+        discovery::start();
+    }
+    let vrc_port =
+        vrchat_settings::resolve_port(config.vrchat_port as u16, &config.vrchat_autodetect);
+    format!("{}:{}", config.vrchat_ip, vrc_port)
+}
+
+// Starts the outputs of every light and reads its state for the first time
+fn create_lights(config: &Config, vrc_addr: &str) -> Result<Vec<Light>, String> {
+    // Only changes the limits, what was counted so far is kept through reloads
+    logging::init(&config.logging.clone().unwrap_or_default());
+    config
+        .lights
+        .iter()
+        .map(|light| Light::new(config, light, vrc_addr))
+        .collect()
+}
+
+// Reads every light once and sends it, returns whether all of them could be
+// read. Lights that couldn't be aren't sent at all.
+pub fn oneshot(config: &Config) -> Result<bool, String> {
+    let vrc_addr = vrchat_addr(config);
+    let mut lights = create_lights(config, &vrc_addr)?;
+    let read = |light: &Light| light.status().healthy;
+    for light in lights.iter_mut().filter(|light| read(light)) {
+        light.send();
+    }
+    if let Some(multiplex) = &config.multiplex {
+        // One slot for every light that was read
+        let mut multiplexer = Multiplexer::new(&vrc_addr, config, multiplex)?;
+        for (index, light) in lights.iter().enumerate().filter(|(_, light)| read(light)) {
+            multiplexer.send_slot(index, light.multiplexed_frame());
+            clock::sleep(time::Duration::from_secs_f32(multiplex.slot_time));
+        }
+    }
+    Ok(lights.iter().all(read))
+}
+
+// Sends a fixed state to the avatar instead of the lights', to try out how it
+// looks. Goes to every light or only the one with the name, returns whether
+// there was one to send to.
+pub fn test_send(config: &Config, state: &BulbState, light: Option<&str>) -> Result<bool, String> {
+    let vrc_addr = vrchat_addr(config);
+    let mut sent = false;
+    for light_config in config.lights.iter() {
+        if light.is_some_and(|name| name != light_config.name) {
+            continue;
+        }
+        let mut output = VrchatOutput::new(&vrc_addr, config, light_config)?;
+        println!("Sending {:?} as {}", state, light_config.name);
+        output.send(state);
+        sent = true;
+    }
+    Ok(sent)
+}
+
+// The servers control clients connect to, they keep running through reloads
+pub(crate) fn start_servers(config: &Config, controller: &mut Controller) -> Result<(), String> {
+    if let Some(control) = &config.control {
+        control::start(control, controller.sender())?;
+    }
+    #[cfg(feature = "websocket")]
+    if let Some(websocket) = &config.websocket {
+        websocket::start(websocket, controller.sender())?;
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = &config.grpc {
+        grpc::start(grpc, controller.sender())?;
+    }
+    #[cfg(feature = "history")]
+    if let Some(history) = &config.history {
+        history::start(history, controller)?;
+    }
+    Ok(())
+}
+
+// Syncs the lights until stop is set, returns why syncing couldn't be started
+pub fn run(config: &Config, controller: &mut Controller, stop: &AtomicBool) -> Result<(), String> {
+    start_servers(config, controller)?;
+    sync(config, controller, stop, None).map(drop)
+}
+
+// Like run, but starts syncing over with the settings file at path whenever
+// it's saved. Settings that can't be read or started with are skipped and the
+// old ones kept. The control, websocket, grpc and history sections only change
+// on a restart.
+pub fn run_reloading(
+    path: &str,
+    config: Config,
+    controller: &mut Controller,
+    stop: &AtomicBool,
+) -> Result<(), String> {
+    start_servers(&config, controller)?;
+    let mut watcher = SettingsWatcher::new(path);
+    let mut config = config;
+    // The settings to go back to while the new ones haven't started syncing
+    let mut previous: Option<Config> = None;
+    loop {
+        match sync(&config, controller, stop, Some(&mut watcher)) {
+            Ok(false) => return Ok(()),
+            Ok(true) => {}
+            Err(err) => match previous.take() {
+                Some(old) => {
+                    eprintln!(
+                        "{}\nCouldn't sync with the changed settings, keeping the old ones until {} is saved again",
+                        err, path
+                    );
+                    config = old;
+                    continue;
+                }
+                None => return Err(err),
+            },
+        }
+        println!("{} changed, reloading it", path);
+        match get_config(path) {
+            Ok(new) => previous = Some(mem::replace(&mut config, new)),
+            Err(err) => eprintln!("{}\nKeeping the old settings until {} is fixed", err, path),
+        }
+    }
+}
+
+// Syncs the lights until stop is set or the watched settings file changes,
+// returns whether it was the change, or why syncing couldn't be started
+pub(crate) fn sync(
+    config: &Config,
+    controller: &mut Controller,
+    stop: &AtomicBool,
+    mut watcher: Option<&mut SettingsWatcher>,
+) -> Result<bool, String> {
+    let vrc_addr = vrchat_addr(config);
+    let mut lights = create_lights(config, &vrc_addr)?;
+    let mut multiplexer = config
+        .multiplex
+        .as_ref()
+        .map(|multiplex| Multiplexer::new(&vrc_addr, config, multiplex))
+        .transpose()?;
+
+    let mut world_filter = config
+        .world_filter
+        .as_ref()
+        .map(WorldFilter::new)
+        .transpose()?;
+    let mut world_profiles = config
+        .world_profiles
+        .as_ref()
+        .map(|profiles| WorldProfiles::new(config, profiles, &vrc_addr))
+        .transpose()?;
+    let mut resync = config.resync.as_ref().map(Resync::new);
+    let mut influx = config.influx.as_ref().map(InfluxExporter::new);
+    let mut receiver = config
+        .osc_receive
+        .as_ref()
+        .map(OscReceiver::new)
+        .transpose()?;
+    let mut syncing = world_filter.as_mut().is_none_or(|filter| filter.poll());
+
+    // Run loop
+    let max_loop_speed = time::Duration::from_secs_f32(1.0 / config.max_updates_per_second as f32);
+
+    if config.startup_test_pattern {
+        test_pattern::play(
+            &mut lights,
+            multiplexer.as_mut(),
+            config.max_updates_per_second,
+        );
+    }
+    if syncing {
+        for light in lights.iter_mut() {
+            light.send();
+        }
+    }
+    light::start_polling(
+        &mut lights,
+        config.poll_concurrency,
+        config.poll_jitter,
+        max_loop_speed,
+    );
+    while !stop.load(Ordering::Relaxed) {
+        if watcher.as_mut().is_some_and(|watcher| watcher.changed()) {
+            return Ok(true);
+        }
+        // Save the start
+        let start = clock::now();
+        // Check if we just entered or left a world where syncing is disabled
+        let was_syncing = syncing;
+        syncing = world_filter.as_mut().is_none_or(|filter| filter.poll());
+        // Carry out what control clients asked for
+        controller.handle_requests(&mut lights, syncing);
+        syncing = syncing && !controller.paused;
+        // Change the lights to what was changed on the avatar
+        if let Some(receiver) = &mut receiver {
+            receiver.apply(&mut lights, syncing);
+        }
+        // Switch the lights to the parameters of the world's profile
+        if let Some(world_profiles) = &mut world_profiles {
+            world_profiles.poll(&mut lights, syncing);
+        }
+        let resync_due = resync.as_mut().is_some_and(|resync| resync.due());
+        // Send the update to the outputs of every light whose status has
+        // changed, or everything when syncing was just turned back on or a
+        // resync is due
+        for light in lights.iter_mut() {
+            if !syncing {
+                continue;
+            }
+            light.update_effect();
+            light.update_transition();
+            if !was_syncing || resync_due {
+                light.resend();
+            } else if light.changed() {
+                light.send();
+            }
+            if light.changed() {
+                controller.publish_state(light);
+                if let Some(influx) = &mut influx {
+                    influx.state_changed(light);
+                }
+            }
+            light.flush();
+            controller.publish_sent(light);
+        }
+        if let (true, Some(multiplexer)) = (syncing, &mut multiplexer) {
+            if !was_syncing || resync_due {
+                multiplexer.forget_sent();
+            }
+            multiplexer.update(&lights, start);
+        }
+        // Wait if the max update time hasn't passed
+        let elapsed = clock::elapsed(start);
+        if elapsed < max_loop_speed {
+            clock::sleep(max_loop_speed - elapsed);
+        }
+        // Take in the new states the lights were polled as
+        let poll_time = lights
+            .iter_mut()
+            .map(|light| light.collect_polls())
+            .max()
+            .unwrap_or_default();
+        if let Some(influx) = &mut influx {
+            influx.cycle(elapsed, poll_time);
+            influx.flush(&lights);
+        }
+        logging::flush();
+    }
+    Ok(false)
+}
@@ -0,0 +1,712 @@
+use crate::backend::{create_backend, BackendError, BulbBackend};
+use crate::clock;
+use crate::config::{Config, LightConfig, OutageConfig};
+use crate::effects::{Effect, EffectKind};
+use crate::logging::{self, Category};
+use crate::output::address::Address;
+#[cfg(feature = "artnet")]
+use crate::output::artnet::ArtNetOutput;
+#[cfg(feature = "home-assistant")]
+use crate::output::mirror::MirrorOutput;
+use crate::output::vrchat::VrchatOutput;
+use crate::output::Output;
+use crate::state::BulbState;
+use crate::transition::Transition;
+use nannou_osc::Type;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::{mpsc, Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+// A light's state as reported to control clients
+#[derive(Debug, Clone)]
+pub struct LightStatus {
+    pub name: String,
+    pub state: BulbState,
+    pub effect: Option<EffectKind>,
+    pub healthy: bool,
+}
+
+// A synced light, with the backend its state comes from and everything that
+// state gets sent to
+pub struct Light {
+    pub name: String,
+    // Shared with the thread polling it in the background
+    backend: Arc<Mutex<Box<dyn BulbBackend>>>,
+    // What the background polls found and how long each took, None until
+    // polling in the background starts
+    polls: Option<mpsc::Receiver<Poll>>,
+    // Where the states asked for from the avatar go to be set, started the
+    // first time one is
+    changes: Option<mpsc::Sender<BulbState>>,
+    vrchat: VrchatOutput,
+    // Everything besides the avatar
+    outputs: Vec<Box<dyn Output>>,
+    pub state: BulbState,
+    old_state: BulbState,
+    // Whether the state is the light's yet, it's only a placeholder until the
+    // source is first read or the outage fallback is applied
+    known: bool,
+    effect: Option<Effect>,
+    outage: OutageConfig,
+    health_parameter: Option<Address>,
+    // When the backend started failing, None while it works
+    stale_since: Option<Instant>,
+    health_changed: bool,
+    // How long a state asked for from the avatar is kept while the source
+    // still reports another one, None when not listening to the avatar
+    hold: Option<Duration>,
+    // The state asked for from the avatar and when
+    requested: Option<(BulbState, Instant)>,
+    // The avatar already shows the state, because it came from there
+    avatar_current: bool,
+    // How long the avatar takes to go to a new state, zero to jump to it
+    transition_time: Duration,
+    transition: Option<Transition>,
+    // The live state last sent to the avatar, None before the first time
+    shown: Option<BulbState>,
+}
+
+// Whether the source reports about the state that was asked for, it rounds
+// the values to its own steps
+fn matches_request(state: &BulbState, requested: &BulbState) -> bool {
+    const CLOSE: f32 = 0.02;
+    let hue_diff = (state.hue - requested.hue + 0.5).rem_euclid(1.0) - 0.5;
+    state.on == requested.on
+        && (!requested.on
+            || (hue_diff.abs() < CLOSE
+                && (state.saturation - requested.saturation).abs() < CLOSE
+                && (state.brightness - requested.brightness).abs() < CLOSE))
+}
+
+impl Light {
+    pub fn new(global: &Config, config: &LightConfig, vrc_addr: &str) -> Result<Light, String> {
+        let vrchat = VrchatOutput::new(vrc_addr, global, config)?;
+        // Only filled in by the outputs that are built
+        #[cfg_attr(
+            not(any(feature = "home-assistant", feature = "artnet")),
+            allow(unused_mut)
+        )]
+        let mut outputs: Vec<Box<dyn Output>> = Vec::new();
+        #[cfg(feature = "home-assistant")]
+        if let Some(mirror) = &config.mirror {
+            outputs.push(Box::new(MirrorOutput::new(&config.source, mirror)));
+        }
+        #[cfg(feature = "artnet")]
+        if let Some(artnet) = &config.artnet {
+            outputs.push(Box::new(ArtNetOutput::new(artnet)?));
+        }
+
+        let mut light = Light {
+            name: config.name.clone(),
+            backend: Arc::new(Mutex::new(create_backend(&config.source, &global.pushed))),
+            polls: None,
+            changes: None,
+            vrchat,
+            outputs,
+            state: BulbState::color(false, 0.0, 0.0),
+            old_state: BulbState::color(false, 0.0, 0.0),
+            known: false,
+            effect: None,
+            outage: config.outage.clone(),
+            health_parameter: config.health_parameter.as_deref().map(Address::new),
+            stale_since: None,
+            health_changed: false,
+            hold: global
+                .osc_receive
+                .as_ref()
+                .map(|receive| Duration::from_secs_f32(receive.hold)),
+            requested: None,
+            avatar_current: false,
+            transition_time: Duration::from_millis(config.transition_ms),
+            transition: None,
+            shown: None,
+        };
+        light.poll();
+        light.old_state = light.state;
+        Ok(light)
+    }
+
+    // Gets the new state from the backend, keeping the last known state if
+    // the backend can't be reached
+    pub fn poll(&mut self) {
+        self.old_state = self.state;
+        let was_healthy = self.stale_since.is_none();
+        let result = self.backend.lock().unwrap().get_state();
+        self.handle_poll(result);
+        self.health_changed = was_healthy != self.stale_since.is_none();
+    }
+
+    // Takes in what the background polls found since the last time, returns
+    // how long the slowest of them took
+    pub fn collect_polls(&mut self) -> Duration {
+        self.old_state = self.state;
+        let was_healthy = self.stale_since.is_none();
+        let mut slowest = Duration::ZERO;
+        let results: Vec<_> = match &self.polls {
+            Some(polls) => polls.try_iter().collect(),
+            None => Vec::new(),
+        };
+        for (result, took) in results {
+            self.handle_poll(result);
+            slowest = slowest.max(took);
+        }
+        self.health_changed = was_healthy != self.stale_since.is_none();
+        slowest
+    }
+
+    fn handle_poll(&mut self, result: Result<BulbState, BackendError>) {
+        match result {
+            Ok(state) => {
+                if self.stale_since.take().is_some() {
+                    println!("{} is reachable again", self.name);
+                }
+                self.state = state;
+                self.known = true;
+                if let (Some((requested, at)), Some(hold)) = (self.requested, self.hold) {
+                    if matches_request(&state, &requested) || clock::elapsed(at) >= hold {
+                        self.requested = None;
+                    } else {
+                        // The change from the avatar hasn't gone through yet
+                        self.state = requested;
+                    }
+                }
+            }
+            Err(err) => {
+                let now = clock::now();
+                let stale_since = *self.stale_since.get_or_insert(now);
+                logging::error(
+                    Category::Source,
+                    format!(
+                        "Failed to get status of {}, keeping its last state: {}",
+                        self.name, err
+                    ),
+                );
+                if let (Some(max_staleness), Some(fallback)) =
+                    (self.outage.max_staleness, self.outage.fallback)
+                {
+                    let stale_for = now.duration_since(stale_since).as_secs_f32();
+                    if stale_for >= max_staleness && self.state != fallback {
+                        println!(
+                            "{} has been unreachable for over {}s, applying the fallback",
+                            self.name, max_staleness
+                        );
+                        self.state = fallback;
+                        self.known = true;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn status(&self) -> LightStatus {
+        LightStatus {
+            name: self.name.clone(),
+            state: self.state,
+            effect: self.effect.as_ref().map(|effect| effect.kind()),
+            healthy: self.stale_since.is_none(),
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        self.state != self.old_state || self.health_changed
+    }
+
+    fn send_health(&mut self) {
+        if let Some(addr) = &self.health_parameter {
+            let healthy = self.stale_since.is_none();
+            self.vrchat
+                .send_parameter(addr.clone(), Type::Bool(healthy));
+        }
+    }
+
+    // Sends a state to the avatar only, leaving the other outputs alone
+    pub fn send_to_avatar(&mut self, state: &BulbState) {
+        self.vrchat.send(state);
+    }
+
+    // The state an avatar parameter VRChat sent asks for, if it's one of this
+    // light's
+    pub fn avatar_change(
+        &mut self,
+        addr: &str,
+        arg: &Type,
+        current: &BulbState,
+        echo_window: Duration,
+    ) -> Option<BulbState> {
+        self.vrchat.avatar_change(addr, arg, current, echo_window)
+    }
+
+    // Changes the light itself to a state asked for from the avatar. The
+    // state is kept as asked for until the source reports it or the hold runs
+    // out, so polls from before the change went through can't move the avatar
+    // back while it's being changed. When the change fails the hold running
+    // out moves the avatar back to what the source reports.
+    pub fn request_state(&mut self, state: BulbState) {
+        let changes = self
+            .changes
+            .get_or_insert_with(|| change_in_background(&self.name, Arc::downgrade(&self.backend)));
+        if changes.send(state).is_err() {
+            return;
+        }
+        self.requested = Some((state, clock::now()));
+        self.state = state;
+        self.known = true;
+        self.avatar_current = true;
+        self.transition = None;
+        self.shown = Some(state);
+    }
+
+    // Sends the live state to the avatar straight away
+    fn show(&mut self) {
+        if !self.known {
+            return;
+        }
+        self.transition = None;
+        self.shown = Some(self.state);
+        self.vrchat.send(&self.state);
+    }
+
+    // Starts moving the avatar to the live state, from wherever it's at now
+    fn show_transition(&mut self) {
+        let now = clock::now();
+        let from = match (&self.transition, self.shown) {
+            (Some(transition), _) => transition.state_at(now),
+            (None, Some(shown)) => shown,
+            // Nothing to move from yet
+            (None, None) => return self.show(),
+        };
+        if self.transition_time.is_zero() || from == self.state {
+            return self.show();
+        }
+        self.transition = Some(Transition::new(from, self.state, now, self.transition_time));
+        self.shown = Some(self.state);
+        self.vrchat.send(&from);
+    }
+
+    // Sends the next step of the transition the avatar is in the middle of
+    pub fn update_transition(&mut self) {
+        if self.effect.is_some() {
+            return;
+        }
+        let now = clock::now();
+        if let Some(transition) = &self.transition {
+            let state = transition.state_at(now);
+            if transition.is_finished(now) {
+                self.transition = None;
+            }
+            self.vrchat.send(&state);
+        }
+    }
+
+    // Switches to other avatar parameters, sending them the state right away
+    // while syncing
+    pub fn set_avatar_output(&mut self, mut vrchat: VrchatOutput, syncing: bool) {
+        vrchat.take_over(&mut self.vrchat);
+        self.vrchat = vrchat;
+        if !syncing {
+            return;
+        }
+        if self.effect.is_none() {
+            self.show();
+        }
+        self.send_health();
+    }
+
+    // Sends the state to every output, the avatar is left alone while an
+    // effect is running on it. Only the health parameter is sent until the
+    // state is known.
+    pub fn send(&mut self) {
+        if !self.known {
+            self.send_health();
+            return;
+        }
+        if std::mem::take(&mut self.avatar_current) {
+            // Without this the avatar couldn't be moved back if the change
+            // doesn't go through
+            self.vrchat.forget_sent();
+        } else if self.effect.is_none() {
+            self.show_transition();
+        }
+        self.send_health();
+        for output in self.outputs.iter_mut() {
+            output.send(&self.state);
+        }
+    }
+
+    // The newest avatar parameters when they're multiplexed
+    pub fn multiplexed_frame(&self) -> &[(String, Type)] {
+        self.vrchat.frame()
+    }
+
+    // The parameters sent to VRChat since the last time, taken out as
+    // they're read
+    pub fn drain_sent(&mut self) -> std::vec::Drain<'_, (Address, Type)> {
+        self.vrchat.drain_sent()
+    }
+
+    // Sends held back avatar parameters once the rate limit allows it, and
+    // everything again if VRChat moved to another address
+    pub fn flush(&mut self) {
+        if self.vrchat.flush() {
+            // A transition in the middle of going sends its next step by itself
+            if self.effect.is_none() && self.transition.is_none() {
+                self.show();
+            }
+            self.send_health();
+        }
+    }
+
+    // Sends the state to every output, including avatar parameters that
+    // haven't changed since they were last sent
+    pub fn resend(&mut self) {
+        self.vrchat.forget_sent();
+        self.send();
+    }
+
+    pub fn start_effect(&mut self, effect: Effect) {
+        self.effect = Some(effect);
+    }
+
+    pub fn stop_effect(&mut self) {
+        if self.effect.take().is_some() {
+            self.show();
+        }
+    }
+
+    // Sends the current frame of a running effect to the avatar, handing the
+    // avatar back to the live state once the effect is over
+    pub fn update_effect(&mut self) {
+        let now = clock::now();
+        match &self.effect {
+            Some(effect) if effect.is_finished(now) => {
+                println!("Effect on {} finished", self.name);
+                self.stop_effect();
+            }
+            Some(effect) => {
+                let state = effect.state_at(now, &self.state);
+                self.vrchat.send(&state);
+            }
+            None => {}
+        }
+    }
+}
+
+// A random delay of up to jitter seconds
+fn jitter_delay(jitter: f32) -> Duration {
+    // Every RandomState is seeded differently, which is random enough here
+    let random = RandomState::new().hash_one(0u8) as f64 / u64::MAX as f64;
+    Duration::from_secs_f64(random * jitter as f64)
+}
+
+// The longest a light that keeps failing to poll waits between tries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// How many polls can run at the same time
+struct Permits {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Permits {
+    fn take(&self) {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.freed.wait(free).unwrap();
+        }
+        *free -= 1;
+    }
+
+    fn give_back(&self) {
+        *self.free.lock().unwrap() += 1;
+        self.freed.notify_one();
+    }
+}
+
+// Sets the backend to the states sent to it, on a thread of its own since a
+// poll holds the backend for as long as the source takes to answer. Only the
+// newest of the states that piled up in the meantime is set.
+fn change_in_background(
+    name: &str,
+    backend: Weak<Mutex<Box<dyn BulbBackend>>>,
+) -> mpsc::Sender<BulbState> {
+    let (changes, states) = mpsc::channel();
+    let name = name.to_owned();
+    clock::spawn(move || {
+        while let Ok(state) = states.recv() {
+            let state = states.try_iter().last().unwrap_or(state);
+            let backend = match backend.upgrade() {
+                Some(backend) => backend,
+                None => return,
+            };
+            let result = backend.lock().unwrap().set_state(&state);
+            drop(backend);
+            match result {
+                Ok(()) => println!("Changed {} from the avatar", name),
+                Err(err) => logging::error(
+                    Category::Source,
+                    format!("Couldn't change {} from the avatar: {}", name, err),
+                ),
+            }
+        }
+    });
+    changes
+}
+
+// Polls every light on a thread of its own once every period, so a light
+// that's slow to answer doesn't hold up the others or the sync loop. A light
+// that keeps failing waits twice as long after every failure in a row. Up to
+// concurrency lights are polled at the same time, what they found is taken in
+// with collect_polls.
+pub fn start_polling(lights: &mut [Light], concurrency: usize, jitter: f32, period: Duration) {
+    let permits = Arc::new(Permits {
+        free: Mutex::new(concurrency.max(1)),
+        freed: Condvar::new(),
+    });
+    for light in lights.iter_mut() {
+        light.polls = Some(poll_in_background(
+            Arc::downgrade(&light.backend),
+            permits.clone(),
+            jitter,
+            period,
+        ));
+    }
+}
+
+type Poll = (Result<BulbState, BackendError>, Duration);
+
+// Polls the backend on a thread of its own until the light is dropped. Only
+// the light keeps the backend, so its connections are closed right away
+// instead of once the thread wakes up.
+fn poll_in_background(
+    backend: Weak<Mutex<Box<dyn BulbBackend>>>,
+    permits: Arc<Permits>,
+    jitter: f32,
+    period: Duration,
+) -> mpsc::Receiver<Poll> {
+    let (sender, polls) = mpsc::channel();
+    let mut delay = period;
+    let backs_off = backend
+        .upgrade()
+        .is_some_and(|backend| backend.lock().unwrap().backs_off());
+    clock::spawn(move || loop {
+        let start = clock::now();
+        if jitter > 0.0 {
+            clock::sleep(jitter_delay(jitter));
+        }
+        let backend = match backend.upgrade() {
+            Some(backend) => backend,
+            None => return,
+        };
+        permits.take();
+        let poll_start = clock::now();
+        let result = backend.lock().unwrap().get_state();
+        drop(backend);
+        let took = clock::elapsed(poll_start);
+        permits.give_back();
+        // Sources that keep failing are asked less and less often, so
+        // one that's down isn't flooded with requests
+        delay = match result {
+            Err(_) if backs_off => (delay * 2).clamp(period, MAX_RETRY_DELAY.max(period)),
+            _ => period,
+        };
+        // The light is gone
+        if sender.send((result, took)).is_err() {
+            return;
+        }
+        let elapsed = clock::elapsed(start);
+        if elapsed < delay {
+            clock::sleep(delay - elapsed);
+        }
+    });
+    polls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::push::{PushBackend, PushConfig, PushStore};
+    use crate::clock::MockClock;
+    use std::collections::VecDeque;
+    use std::time::SystemTime;
+
+    // Answers with the results it was given in order
+    struct Scripted(VecDeque<bool>);
+
+    impl BulbBackend for Scripted {
+        fn get_state(&mut self) -> Result<BulbState, BackendError> {
+            match self.0.pop_front() {
+                Some(true) => Ok(BulbState::color(true, 0.5, 1.0)),
+                _ => Err("unreachable".into()),
+            }
+        }
+    }
+
+    #[test]
+    fn polls_less_often_while_failing() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let start = clock::now();
+        let results = [false, false, false, false, false, false, true, true];
+        let backend: Box<dyn BulbBackend> = Box::new(Scripted(results.into()));
+        let backend = Arc::new(Mutex::new(backend));
+        let permits = Arc::new(Permits {
+            free: Mutex::new(1),
+            freed: Condvar::new(),
+        });
+        let polls = poll_in_background(
+            Arc::downgrade(&backend),
+            permits.clone(),
+            0.0,
+            Duration::from_secs(1),
+        );
+        let mut times = Vec::new();
+        for (i, expected) in results.into_iter().enumerate() {
+            if i > 0 {
+                // Jumps straight to the next poll, nothing is polled before it
+                mock.wait_for_sleepers(1);
+                mock.advance_to_next();
+            }
+            let (result, _) = polls.recv().unwrap();
+            assert_eq!(result.is_ok(), expected);
+            times.push(clock::elapsed(start).as_secs());
+        }
+        // Doubles up to MAX_RETRY_DELAY, and goes back once it answers
+        assert_eq!(times, [0, 2, 6, 14, 30, 60, 90, 91]);
+    }
+
+    #[test]
+    fn keeps_polling_push_lights_while_nothing_was_pushed() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let start = clock::now();
+        let pushed = PushStore::default();
+        let config = PushConfig {
+            name: "overlay".to_owned(),
+        };
+        let backend: Box<dyn BulbBackend> = Box::new(PushBackend::new(&config, &pushed));
+        let backend = Arc::new(Mutex::new(backend));
+        let permits = Arc::new(Permits {
+            free: Mutex::new(1),
+            freed: Condvar::new(),
+        });
+        let polls = poll_in_background(
+            Arc::downgrade(&backend),
+            permits,
+            0.0,
+            Duration::from_secs(1),
+        );
+        let mut times = Vec::new();
+        for i in 0..4 {
+            if i > 0 {
+                mock.wait_for_sleepers(1);
+                if i == 3 {
+                    pushed.push("overlay", BulbState::color(true, 0.5, 1.0));
+                }
+                mock.advance_to_next();
+            }
+            let (result, _) = polls.recv().unwrap();
+            assert_eq!(result.is_ok(), i == 3);
+            times.push(clock::elapsed(start).as_secs());
+        }
+        assert_eq!(times, [0, 1, 2, 3]);
+    }
+
+    // Hands the states it's set to back to the test
+    struct Recording(mpsc::Sender<BulbState>);
+
+    impl BulbBackend for Recording {
+        fn get_state(&mut self) -> Result<BulbState, BackendError> {
+            Err("unreachable".into())
+        }
+
+        fn set_state(&mut self, state: &BulbState) -> Result<(), BackendError> {
+            self.0.send(*state).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn changes_dont_wait_for_polls() {
+        let (set, states) = mpsc::channel();
+        let backend: Box<dyn BulbBackend> = Box::new(Recording(set));
+        let backend = Arc::new(Mutex::new(backend));
+        let changes = change_in_background("lamp", Arc::downgrade(&backend));
+        // Held like a poll waiting on the source
+        let poll = backend.lock().unwrap();
+        changes.send(BulbState::color(true, 0.1, 1.0)).unwrap();
+        changes.send(BulbState::color(true, 0.9, 1.0)).unwrap();
+        assert!(states.try_recv().is_err());
+        drop(poll);
+        // The newest is set once the poll is done, the older one may be too
+        let newest = BulbState::color(true, 0.9, 1.0);
+        while states.recv_timeout(Duration::from_secs(5)).unwrap() != newest {}
+    }
+
+    #[test]
+    fn stops_polling_once_the_light_is_gone() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let backend: Box<dyn BulbBackend> = Box::new(Scripted([true, true].into()));
+        let backend = Arc::new(Mutex::new(backend));
+        let permits = Arc::new(Permits {
+            free: Mutex::new(1),
+            freed: Condvar::new(),
+        });
+        let polls = poll_in_background(
+            Arc::downgrade(&backend),
+            permits,
+            0.0,
+            Duration::from_secs(1),
+        );
+        assert!(polls.recv().unwrap().0.is_ok());
+        mock.wait_for_sleepers(1);
+        // The thread doesn't hold on to the backend while it waits
+        assert_eq!(Arc::strong_count(&backend), 1);
+        drop(backend);
+        mock.advance_to_next();
+        // Ends without polling, which drops its sender
+        assert!(polls.recv().is_err());
+    }
+
+    #[test]
+    fn sends_only_the_health_until_the_light_is_read() {
+        let config = crate::config::parse_config(
+            "
+vrchat_ip: 127.0.0.1
+vrchat_port: 9
+max_updates_per_second: 5
+health_parameter: /avatar/parameters/LightHealthy
+bulb_service: push
+push:
+    name: desk
+",
+        )
+        .unwrap();
+        let mut light = Light::new(&config, &config.lights[0], "127.0.0.1:9").unwrap();
+        light.send();
+        let sent: Vec<_> = light.drain_sent().collect();
+        assert_eq!(
+            sent,
+            [(
+                Address::new("/avatar/parameters/LightHealthy"),
+                Type::Bool(false)
+            )]
+        );
+
+        config.pushed.push("desk", BulbState::color(true, 0.5, 1.0));
+        light.poll();
+        assert!(light.changed());
+        light.send();
+        let sent: Vec<_> = light
+            .drain_sent()
+            .map(|(addr, _)| addr.to_string())
+            .collect();
+        assert!(
+            sent.contains(&"/avatar/parameters/Color".to_owned()),
+            "{:?}",
+            sent
+        );
+        assert!(sent.contains(&"/avatar/parameters/LightHealthy".to_owned()));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::clock;
+use crate::config::validate_seconds;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// What an error is about, every category is rate limited on its own so one
+// flapping part can't drown out the others
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    // Reading the lights
+    Source,
+    // Sending to VRChat and the other outputs
+    Output,
+    // Finding VRChat and Home Assistant on the network
+    Network,
+}
+
+impl Category {
+    fn name(self) -> &'static str {
+        match self {
+            Category::Source => "source",
+            Category::Output => "output",
+            Category::Network => "network",
+        }
+    }
+}
+
+fn default_summary_interval() -> f32 {
+    60.0
+}
+
+fn default_per_minute() -> f32 {
+    10.0
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoggingConfig {
+    // Seconds an error is held back for after being printed, after which how
+    // often it repeated is printed
+    #[serde(default = "default_summary_interval")]
+    pub summary_interval: f32,
+    // Different errors each category can print per minute, categories that
+    // aren't listed get the default
+    #[serde(default)]
+    pub per_minute: HashMap<Category, f32>,
+    #[serde(default = "default_per_minute")]
+    pub default_per_minute: f32,
+    // Also prints every state sent to the outputs
+    #[serde(default)]
+    pub debug: bool,
+}
+
+impl LoggingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_seconds("logging.summary_interval", self.summary_interval, true)?;
+        let limits_ok = [self.default_per_minute]
+            .iter()
+            .chain(self.per_minute.values())
+            .all(|limit| (1.0..=f32::MAX).contains(limit));
+        if !limits_ok {
+            return Err("logging needs per_minute limits of at least 1.".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            summary_interval: default_summary_interval(),
+            per_minute: HashMap::new(),
+            default_per_minute: default_per_minute(),
+            debug: false,
+        }
+    }
+}
+
+struct Repeat {
+    printed_at: Instant,
+    count: u32,
+}
+
+struct Budget {
+    tokens: f32,
+    last_refill: Instant,
+    // Errors that didn't fit since the last summary
+    dropped: u32,
+}
+
+struct Logger {
+    config: LoggingConfig,
+    repeats: HashMap<(Category, String), Repeat>,
+    budgets: HashMap<Category, Budget>,
+}
+
+impl Logger {
+    fn new(config: LoggingConfig) -> Logger {
+        Logger {
+            config,
+            repeats: HashMap::new(),
+            budgets: HashMap::new(),
+        }
+    }
+
+    fn per_minute(&self, category: Category) -> f32 {
+        *self
+            .config
+            .per_minute
+            .get(&category)
+            .unwrap_or(&self.config.default_per_minute)
+    }
+
+    fn try_take(&mut self, category: Category, now: Instant) -> bool {
+        let per_minute = self.per_minute(category);
+        let budget = self.budgets.entry(category).or_insert(Budget {
+            tokens: per_minute,
+            last_refill: now,
+            dropped: 0,
+        });
+        let elapsed = now.duration_since(budget.last_refill).as_secs_f32();
+        budget.tokens = (budget.tokens + elapsed * per_minute / 60.0).min(per_minute);
+        budget.last_refill = now;
+        if budget.tokens >= 1.0 {
+            budget.tokens -= 1.0;
+            true
+        } else {
+            budget.dropped += 1;
+            false
+        }
+    }
+
+    // The message to print, None when it's held back
+    fn error(&mut self, category: Category, message: String, now: Instant) -> Option<String> {
+        if let Some(repeat) = self.repeats.get_mut(&(category, message.clone())) {
+            repeat.count += 1;
+            return None;
+        }
+        if !self.try_take(category, now) {
+            return None;
+        }
+        self.repeats.insert(
+            (category, message.clone()),
+            Repeat {
+                printed_at: now,
+                count: 0,
+            },
+        );
+        Some(message)
+    }
+
+    // The summaries to print
+    fn flush(&mut self, now: Instant) -> Vec<String> {
+        let interval = Duration::from_secs_f32(self.config.summary_interval);
+        let mut lines = Vec::new();
+        self.repeats.retain(|(_, message), repeat| {
+            if now.duration_since(repeat.printed_at) < interval {
+                return true;
+            }
+            if repeat.count > 0 {
+                lines.push(format!("{} (repeated {}×)", message, repeat.count));
+            }
+            false
+        });
+        for (category, budget) in self.budgets.iter_mut() {
+            if budget.dropped > 0 && budget.tokens >= 1.0 {
+                lines.push(format!(
+                    "Left out {} more {} errors, there were too many",
+                    budget.dropped,
+                    category.name()
+                ));
+                budget.dropped = 0;
+            }
+        }
+        lines
+    }
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+fn with_logger(f: impl FnOnce(&mut Logger)) {
+    let mut logger = LOGGER.lock().unwrap();
+    f(logger.get_or_insert_with(|| Logger::new(LoggingConfig::default())))
+}
+
+pub fn init(config: &LoggingConfig) {
+    with_logger(|logger| logger.config = config.clone());
+}
+
+// Prints an error unless the same one was printed recently or its category
+// has printed too many, those are counted and summarized later
+pub fn error(category: Category, message: String) {
+    with_logger(|logger| {
+        if let Some(message) = logger.error(category, message, clock::now()) {
+            println!("{}", message);
+        }
+    });
+}
+
+// Prints a message only when debug logging is turned on
+pub fn debug(message: String) {
+    with_logger(|logger| {
+        if logger.config.debug {
+            println!("{}", message);
+        }
+    });
+}
+
+// Prints the summaries of errors that are done being held back, should be
+// called regularly
+pub fn flush() {
+    with_logger(|logger| {
+        for line in logger.flush(clock::now()) {
+            println!("{}", line);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger(settings: &str) -> Logger {
+        let config: LoggingConfig = serde_yaml::from_str(settings).unwrap();
+        config.validate().unwrap();
+        Logger::new(config)
+    }
+
+    #[test]
+    fn summarizes_repeated_errors() {
+        let mut logger = logger("summary_interval: 10");
+        let start = Instant::now();
+        let error = |logger: &mut Logger, secs| {
+            logger.error(
+                Category::Source,
+                "Light 1 is unreachable".to_owned(),
+                start + Duration::from_secs(secs),
+            )
+        };
+        assert_eq!(
+            error(&mut logger, 0).as_deref(),
+            Some("Light 1 is unreachable")
+        );
+        assert_eq!(error(&mut logger, 1), None);
+        assert_eq!(error(&mut logger, 2), None);
+        assert!(logger.flush(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            logger.flush(start + Duration::from_secs(10)),
+            ["Light 1 is unreachable (repeated 2×)"]
+        );
+        // Printed again once it's been summarized
+        assert!(error(&mut logger, 11).is_some());
+        // and nothing is summarized when it didn't repeat
+        assert!(logger.flush(start + Duration::from_secs(30)).is_empty());
+    }
+
+    #[test]
+    fn limits_how_many_errors_each_category_prints() {
+        let mut logger = logger("per_minute:\n    source: 2\ndefault_per_minute: 5\n");
+        let start = Instant::now();
+        let printed: Vec<bool> = (0..4)
+            .map(|i| {
+                logger
+                    .error(Category::Source, format!("Light {} failed", i), start)
+                    .is_some()
+            })
+            .collect();
+        assert_eq!(printed, [true, true, false, false]);
+        // The other categories have their own limit
+        assert!(logger
+            .error(Category::Output, "Couldn't send".to_owned(), start)
+            .is_some());
+
+        // The left out ones are mentioned once it can print again, it gets 2
+        // back a minute
+        assert!(logger.flush(start + Duration::from_secs(10)).is_empty());
+        assert_eq!(
+            logger.error(
+                Category::Source,
+                "Light 9 failed".to_owned(),
+                start + Duration::from_secs(20)
+            ),
+            None
+        );
+        let later = start + Duration::from_secs(61);
+        assert!(logger
+            .error(Category::Source, "Light 5 failed".to_owned(), later)
+            .is_some());
+        assert!(logger
+            .flush(later)
+            .contains(&"Left out 3 more source errors, there were too many".to_owned()));
+    }
+
+    #[test]
+    fn needs_limits_of_at_least_one() {
+        let config: LoggingConfig =
+            serde_yaml::from_str("per_minute:\n    network: 0.5\n").unwrap();
+        assert_eq!(
+            config.validate(),
+            Err("logging needs per_minute limits of at least 1.".to_owned())
+        );
+    }
+}
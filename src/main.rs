@@ -1,146 +1,403 @@
-use nannou_osc::Type;
-use serde::Deserialize;
-use std::fs::File;
-use std::path::Path;
-use std::{thread, time};
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum BulbService {
-    HomeAssistant,
+use clap::{Parser, Subcommand};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use vrchat_light_sync::config::{get_config, Config, ConfigError};
+use vrchat_light_sync::control::{self, Controller};
+#[cfg(feature = "history")]
+use vrchat_light_sync::history;
+#[cfg(feature = "preview")]
+use vrchat_light_sync::preview;
+#[cfg(feature = "secrets")]
+use vrchat_light_sync::secrets;
+use vrchat_light_sync::state::{BulbState, COOLEST_KELVIN, WARMEST_KELVIN};
+#[cfg(feature = "update")]
+use vrchat_light_sync::update;
+use vrchat_light_sync::{autostart, check, oneshot, run_reloading, selftest, test_send};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The settings file to use
+    #[arg(long, global = true, default_value = "settings.yaml")]
+    config: PathBuf,
+    /// Read every light once, send it to VRChat and exit. Lights that couldn't
+    /// be read aren't sent and the exit code is 1.
+    #[arg(long)]
+    oneshot: bool,
+    /// Show the color every light is sending in a small window while syncing,
+    /// closing it stops the program
+    #[cfg(feature = "preview")]
+    #[arg(long)]
+    preview: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Deserialize)]
-struct HomeAssistantConfig {
-    entity_id: String,
-    server_ip: String,
-    server_port: i32,
-    bearer_token: String,
+#[derive(Subcommand)]
+enum Command {
+    /// Sync the lights, the same as leaving out the command. The settings are
+    /// reloaded whenever the settings file is saved.
+    Run,
+    /// Check that the settings are valid, every light can be read and VRChat
+    /// can be reached
+    Check,
+    /// Send a fixed state to the avatar instead of the lights', to try out how
+    /// it looks
+    TestSend {
+        /// Send the light as on, the default
+        #[arg(long, conflicts_with = "off")]
+        on: bool,
+        /// Send the light as off
+        #[arg(long)]
+        off: bool,
+        /// From 0 to 1, going around the color wheel from red
+        #[arg(long, default_value_t = 0.0, value_parser = unit)]
+        hue: f32,
+        /// From 0 for white to 1 for the full color
+        #[arg(long, default_value_t = 1.0, value_parser = unit)]
+        saturation: f32,
+        /// From 0 to 1
+        #[arg(long, default_value_t = 1.0, value_parser = unit)]
+        brightness: f32,
+        /// Send a white of this color temperature in Kelvin instead of a color
+        #[arg(long, conflicts_with_all = ["hue", "saturation"], value_parser = kelvin)]
+        kelvin: Option<f32>,
+        /// Only send as this light
+        #[arg(long)]
+        light: Option<String>,
+    },
+    /// Run one full poll and send cycle against a local fake VRChat and
+    /// report which parameters arrived
+    Selftest,
+    /// Print how to decode the packed parameter, including every possible value
+    DescribePacking,
+    /// Ask the running instance for the state of every light, through the
+    /// control API
+    Status {
+        /// Print the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Look up what the lights were synced as in the past, from the history
+    /// recorded with the history section of the settings
+    #[cfg(feature = "history")]
+    History {
+        /// Show the lights at this local time, like "2024-05-17 21:30" or
+        /// "21:30" for today, instead of now
+        #[arg(long)]
+        at: Option<String>,
+        /// List every state change since this time instead
+        #[arg(long, conflicts_with = "at")]
+        since: Option<String>,
+        /// Only list the changes until this time
+        #[arg(long, requires = "since")]
+        until: Option<String>,
+        /// Only show this light
+        #[arg(long)]
+        light: Option<String>,
+        /// Print the history as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Start syncing automatically when you log in, with the current settings
+    /// file
+    Autostart {
+        #[command(subcommand)]
+        action: AutostartAction,
+    },
+    /// Encrypt settings to keep in the secrets section of the settings file,
+    /// or show what it decrypts to
+    #[cfg(feature = "secrets")]
+    Secrets {
+        #[command(subcommand)]
+        action: SecretsAction,
+    },
+    /// Download the newest release and replace this program with it
+    #[cfg(feature = "update")]
+    Update {
+        /// Only check whether there's a newer release
+        #[arg(long)]
+        check: bool,
+    },
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    vrchat_ip: String,
-    vrchat_port: i32,
-    max_updates_per_second: i32,
-    bulb_service: BulbService,
-    home_assistant: HomeAssistantConfig,
+#[derive(Subcommand)]
+enum AutostartAction {
+    Enable,
+    Disable,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-struct BulbState {
-    on: bool,
-    hue: f32,
-    brightness: f32,
+#[cfg(feature = "secrets")]
+#[derive(Subcommand)]
+enum SecretsAction {
+    /// Encrypt a YAML file with the settings to keep secret and print it as a
+    /// secrets section
+    Encrypt {
+        file: PathBuf,
+        /// Encrypt to the recipients of this age identity file instead of a
+        /// passphrase
+        #[arg(long)]
+        identity: Option<PathBuf>,
+    },
+    /// Print what the secrets section of the settings file decrypts to
+    Decrypt,
 }
 
-fn translate(value: f32, prev_start: f32, prev_end: f32, new_start: f32, new_end: f32) -> f32 {
-    let prev_span = prev_end - prev_start;
-    let new_span = new_end - new_start;
-    let scaled_value = (value - prev_start) / prev_span;
-    return new_start + (scaled_value * new_span);
+fn unit(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err("needs to be a number from 0 to 1".to_owned()),
+    }
 }
 
-fn get_config(file: &str) -> Config {
-    let config_file_path = Path::new(file);
-    let display = config_file_path.display();
-    let file = match File::open(&config_file_path) {
-        Err(why) => panic!("Couldn't open {}: {}", display, why),
-        Ok(file) => file,
-    };
+fn kelvin(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(value) if (WARMEST_KELVIN..=COOLEST_KELVIN).contains(&value) => Ok(value),
+        _ => Err(format!(
+            "needs to be a color temperature from {} to {}",
+            WARMEST_KELVIN, COOLEST_KELVIN
+        )),
+    }
+}
+
+// Exit codes scripts and service managers can rely on
+// A check like selftest failed or the running instance couldn't be reached
+const EXIT_FAILED: i32 = 1;
+// The settings couldn't be loaded or syncing couldn't be started with them
+const EXIT_CONFIG: i32 = 2;
+// Something went wrong while syncing
+const EXIT_RUNTIME: i32 = 3;
 
-    return serde_yaml::from_reader(file).expect("Error while parsing settings file.");
+fn config_exit_code(err: &ConfigError) -> i32 {
+    match err {
+        ConfigError::Read(..)
+        | ConfigError::Parse(_)
+        | ConfigError::Secrets(_)
+        | ConfigError::Invalid(_) => EXIT_CONFIG,
+    }
 }
 
-fn get_home_assistant_state(config: &HomeAssistantConfig) -> Result<BulbState, reqwest::Error> {
-    let url: String = "http://".to_owned()
-        + &config.server_ip
-        + ":"
-        + &config.server_port.to_string()
-        + "/api/states/"
-        + &config.entity_id;
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .get(url)
-        .header("Authorization", "Bearer ".to_owned() + &config.bearer_token)
-        .send()?;
-    let json: serde_json::Value = serde_json::from_str(&res.text()?)
-        .expect("JSON from Home Assistant endpoint contained errors.");
-
-    let on = json["state"] == "on";
-    let hue: f32 = match &json["attributes"]["hs_color"][0] {
-        serde_json::Value::Number(val) => val
-            .as_f64()
-            .expect("Hue value in home_assistant was there but wasn't a number.")
-            as f32,
-        _ => 0.0,
-    };
-    let brightness: f32 = match &json["attributes"]["brightness"] {
-        serde_json::Value::Number(val) => val
-            .as_f64()
-            .expect("Brightness value in home_assistant was there but wasn't a number.")
-            as f32,
-        _ => 0.0,
+// The settings were validated when they were loaded, what can still stop a
+// command from starting is something they point at, like a port that's
+// already taken
+fn or_exit<T>(res: Result<T, String>) -> T {
+    res.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(EXIT_CONFIG)
+    })
+}
+
+fn status(config: &Config, json: bool) -> i32 {
+    let control = match &config.control {
+        Some(control) => control,
+        None => {
+            eprintln!("The control API isn't enabled, add a control section to settings.yaml.");
+            return EXIT_CONFIG;
+        }
     };
-    return Ok(BulbState {
-        on: on,
-        hue: translate(hue, 0.0, 360.0, 0.0, 1.0),
-        brightness: translate(brightness, 0.0, 255.0, 0.0, 1.0),
-    });
+    let command = if json { "status json" } else { "status" };
+    match control::query(control, command) {
+        Ok(answer) => {
+            println!("{}", answer);
+            0
+        }
+        Err(err) => {
+            eprintln!("Couldn't reach the running instance: {}", err);
+            EXIT_FAILED
+        }
+    }
 }
 
-fn get_bulb_state(config: &Config) -> BulbState {
-    return match config.bulb_service {
-        BulbService::HomeAssistant => match get_home_assistant_state(&config.home_assistant) {
-            Ok(res) => res,
-            Err(err) => panic!("Failed to get status from home assistant: {}", err),
-        },
+#[cfg(feature = "history")]
+fn history(
+    config: &Config,
+    at: Option<&str>,
+    since: Option<&str>,
+    until: Option<&str>,
+    light: Option<&str>,
+    json: bool,
+) -> i32 {
+    let history = match &config.history {
+        Some(history) => history,
+        None => {
+            eprintln!("The history isn't enabled, add a history section to settings.yaml.");
+            return EXIT_CONFIG;
+        }
     };
+    let time = |time: Option<&str>| time.map(history::parse_time).transpose();
+    let query = match (time(at), time(since), time(until)) {
+        (_, Ok(Some(since)), Ok(until)) => {
+            history::Query::Between(since, until.unwrap_or(i64::MAX))
+        }
+        (Ok(at), Ok(None), _) => history::Query::At(at.unwrap_or(i64::MAX)),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            eprintln!("{}", err);
+            return EXIT_FAILED;
+        }
+    };
+    match history::query(history, query, light, json) {
+        Ok(answer) => {
+            println!("{}", answer);
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            EXIT_FAILED
+        }
+    }
 }
 
-fn update_vrchat(sender: &nannou_osc::Sender<nannou_osc::Connected>, state: &BulbState) -> () {
-    sender
-        .send(("/avatar/parameters/on", vec![Type::Bool(state.on)]))
-        .ok();
-    sender
-        .send(("/avatar/parameters/Color", vec![Type::Float(state.hue)]))
-        .ok();
-    sender
-        .send((
-            "/avatar/parameters/brightness",
-            vec![Type::Float(state.brightness)],
-        ))
-        .ok();
-    println!("Sent updated state to VRChat");
+// The flags to start with when the user logs in, besides the settings file
+fn autostart_flags(cli: &Cli) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    if cli.oneshot {
+        flags.push("--oneshot");
+    }
+    #[cfg(feature = "preview")]
+    if cli.preview {
+        flags.push("--preview");
+    }
+    flags
 }
 
 fn main() {
-    let config: Config = get_config("settings.yaml");
-
-    // Start the OSC sender
-    let vrc_addr = format!("{}:{}", config.vrchat_ip, config.vrchat_port);
-    let sender = nannou_osc::sender().unwrap().connect(vrc_addr).unwrap();
-
-    // Run loop
-    let max_loop_speed = time::Duration::from_secs_f32(1.0 / config.max_updates_per_second as f32);
-    let mut state = get_bulb_state(&config);
-    let mut old_state = state.clone();
-    update_vrchat(&sender, &state);
-    loop {
-        // Save the start
-        let start = time::Instant::now();
-        // Send the update to VRChat if the light status has changed
-        if state != old_state {
-            update_vrchat(&sender, &state);
-        }
-        // Wait if the max update time hasn't passed
-        let elapsed = start.elapsed();
-        if elapsed < max_loop_speed {
-            thread::sleep(max_loop_speed - elapsed);
-        }
-        println!("{:?}", start.elapsed());
-        // Get the new state from the light
-        old_state = state;
-        state = get_bulb_state(&config);
+    let cli = Cli::parse();
+    // Doesn't need working settings, so it can be turned off when they're broken
+    if let Some(Command::Autostart { action }) = &cli.command {
+        let res = match action {
+            AutostartAction::Enable => autostart::enable(&cli.config, &autostart_flags(&cli)),
+            AutostartAction::Disable => autostart::disable(),
+        };
+        if let Err(err) = res {
+            eprintln!("{}", err);
+            process::exit(EXIT_FAILED);
+        }
+        println!("Done");
+        return;
+    }
+    // Has to work before the secrets can be decrypted
+    #[cfg(feature = "secrets")]
+    if let Some(Command::Secrets { action }) = &cli.command {
+        let res = match action {
+            SecretsAction::Encrypt { file, identity } => {
+                secrets::encrypt_file(file, identity.as_deref())
+            }
+            SecretsAction::Decrypt => secrets::decrypt_file(&cli.config),
+        };
+        match res {
+            Ok(output) => println!("{}", output.trim_end()),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(EXIT_FAILED);
+            }
+        }
+        return;
+    }
+    #[cfg(feature = "update")]
+    if let Some(Command::Update { check }) = &cli.command {
+        if let Err(err) = update::run(*check) {
+            eprintln!("Couldn't update: {}", err);
+            process::exit(EXIT_FAILED);
+        }
+        return;
+    }
+    let config_path = cli.config.display().to_string();
+    let config = get_config(&config_path).unwrap_or_else(|err| {
+        if let Some(Command::Check) = &cli.command {
+            println!("FAILED  settings: {}", err);
+        } else {
+            eprintln!("{}", err);
+        }
+        process::exit(config_exit_code(&err))
+    });
+
+    match cli.command {
+        Some(Command::Selftest) => {
+            let passed = or_exit(selftest::run(&config));
+            process::exit(if passed { 0 } else { EXIT_FAILED });
+        }
+        Some(Command::DescribePacking) => {
+            let mut any_packed = false;
+            for light in config.lights.iter() {
+                if let Some(packed) = &light.packed {
+                    println!("{}:", light.name);
+                    print!("{}", packed.describe());
+                    any_packed = true;
+                }
+            }
+            if !any_packed {
+                println!("Packed mode isn't enabled, add a packed section to settings.yaml.");
+            }
+            return;
+        }
+        Some(Command::Check) => {
+            let passed = check::run(&config);
+            process::exit(if passed { 0 } else { EXIT_FAILED });
+        }
+        Some(Command::TestSend {
+            off,
+            hue,
+            saturation,
+            brightness,
+            kelvin,
+            light,
+            ..
+        }) => {
+            let state = match kelvin {
+                Some(kelvin) => BulbState::white(!off, kelvin, brightness),
+                None => BulbState::new(!off, (hue, saturation, None), brightness),
+            };
+            let sent = or_exit(test_send(&config, &state, light.as_deref()));
+            if !sent {
+                eprintln!("There's no light called {}.", light.unwrap_or_default());
+                process::exit(EXIT_FAILED);
+            }
+            return;
+        }
+        Some(Command::Status { json }) => process::exit(status(&config, json)),
+        #[cfg(feature = "history")]
+        Some(Command::History {
+            at,
+            since,
+            until,
+            light,
+            json,
+        }) => process::exit(history(
+            &config,
+            at.as_deref(),
+            since.as_deref(),
+            until.as_deref(),
+            light.as_deref(),
+            json,
+        )),
+        Some(Command::Run | Command::Autostart { .. }) | None => {}
+        #[cfg(feature = "secrets")]
+        Some(Command::Secrets { .. }) => {}
+        #[cfg(feature = "update")]
+        Some(Command::Update { .. }) => {}
+    }
+
+    if cli.oneshot {
+        let read = or_exit(oneshot(&config));
+        process::exit(if read { 0 } else { EXIT_FAILED });
+    }
+
+    #[cfg(feature = "preview")]
+    if cli.preview {
+        let closed = or_exit(preview::run(config));
+        process::exit(if closed { 0 } else { EXIT_RUNTIME });
+    }
+
+    // Syncing couldn't start when it returns an error, a panic is from while
+    // it was syncing
+    let stop = AtomicBool::new(false);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_reloading(&config_path, config, &mut Controller::new(), &stop)
+    }));
+    match res {
+        Ok(res) => or_exit(res),
+        Err(_) => process::exit(EXIT_RUNTIME),
     }
 }
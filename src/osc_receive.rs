@@ -0,0 +1,255 @@
+use crate::clock;
+use crate::config::validate_seconds;
+use crate::effects::{Effect, EffectKind};
+use crate::light::Light;
+use crate::logging::{self, Category};
+use crate::state::BulbState;
+use nannou_osc::Type;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_owned()
+}
+
+fn default_port() -> u16 {
+    9001
+}
+
+fn default_hold() -> f32 {
+    2.0
+}
+
+fn default_echo_window() -> f32 {
+    0.5
+}
+
+// How often the receiving thread checks whether it's still needed
+const STOP_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize)]
+pub struct OscReceiveConfig {
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    // Where VRChat sends its OSC, 9001 unless it was started with --osc
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // Seconds the state asked for from the avatar is kept while the light
+    // still reports the old one, so a poll from before the change went through
+    // doesn't move the avatar back
+    #[serde(default = "default_hold")]
+    pub hold: f32,
+    // Seconds after sending a parameter during which VRChat sending it back is
+    // taken as only an echo of what was sent
+    #[serde(default = "default_echo_window")]
+    pub echo_window: f32,
+    // Effects started on every light while an avatar parameter is on, by the
+    // parameter's address. VRChat doesn't send what's typed into the chatbox
+    // over OSC, so a toggle in the action menu is how they're started in game.
+    #[serde(default)]
+    pub effects: HashMap<String, String>,
+    // Seconds those effects run for at most, until the parameter turns off if
+    // left out
+    pub effect_duration: Option<f32>,
+}
+
+impl OscReceiveConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_seconds("osc_receive.hold", self.hold, true)?;
+        validate_seconds("osc_receive.echo_window", self.echo_window, true)?;
+        if let Some(name) = self
+            .effects
+            .values()
+            .find(|name| EffectKind::parse(name).is_none())
+        {
+            return Err(format!(
+                "{} isn't an effect, it can be pulse, breathe, strobe or rainbow.",
+                name
+            ));
+        }
+        match self.effect_duration {
+            Some(duration) => validate_seconds("osc_receive.effect_duration", duration, false),
+            None => Ok(()),
+        }
+    }
+}
+
+// Whether a parameter turning an effect on and off is on
+fn is_on(value: &Type) -> bool {
+    match value {
+        Type::Bool(value) => *value,
+        Type::Int(value) => *value != 0,
+        Type::Float(value) => *value >= 0.5,
+        _ => false,
+    }
+}
+
+// Listens for avatar parameters VRChat sends, like the on toggle or the Color
+// and brightness sliders in the radial menu, and changes the lights to match
+pub struct OscReceiver {
+    messages: mpsc::Receiver<(String, Type)>,
+    echo_window: Duration,
+    effects: HashMap<String, EffectKind>,
+    effect_duration: Option<Duration>,
+    // The effect parameters that are on, only those stop the effects again so
+    // ones started from the control API are left alone
+    effects_on: HashSet<String>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl OscReceiver {
+    pub fn new(config: &OscReceiveConfig) -> Result<OscReceiver, String> {
+        let socket = UdpSocket::bind((config.bind_address.as_str(), config.port))
+            .and_then(|socket| socket.set_read_timeout(Some(STOP_CHECK)).map(|()| socket))
+            .map_err(|err| {
+                format!(
+                    "Couldn't listen for OSC from VRChat on {}:{}: {}",
+                    config.bind_address, config.port, err
+                )
+            })?;
+        println!(
+            "Listening for avatar parameters on {}:{}",
+            config.bind_address, config.port
+        );
+        let (sender, messages) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = clock::spawn(move || {
+            let mut buffer = [0; 1536];
+            while !thread_stop.load(Ordering::Relaxed) {
+                let len = match socket.recv(&mut buffer) {
+                    Ok(len) => len,
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(err) => {
+                        logging::error(
+                            Category::Network,
+                            format!("Couldn't receive OSC from VRChat: {}", err),
+                        );
+                        clock::sleep(STOP_CHECK);
+                        continue;
+                    }
+                };
+                let packet = match nannou_osc::decode(&buffer[..len]) {
+                    Ok(packet) => packet,
+                    Err(_) => continue,
+                };
+                for message in packet.into_msgs() {
+                    if let Some(value) = message.args.and_then(|args| args.into_iter().next()) {
+                        let _ = sender.send((message.addr, value));
+                    }
+                }
+            }
+        });
+        Ok(OscReceiver {
+            messages,
+            echo_window: Duration::from_secs_f32(config.echo_window),
+            effects: config
+                .effects
+                .iter()
+                .filter_map(|(address, name)| Some((address.clone(), EffectKind::parse(name)?)))
+                .collect(),
+            effect_duration: config.effect_duration.map(Duration::from_secs_f32),
+            effects_on: HashSet::new(),
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    // Changes the lights to what the avatar parameters VRChat sent since the
+    // last call ask for, only the newest state of each light is written. What
+    // was sent while not syncing is thrown away.
+    pub fn apply(&mut self, lights: &mut [Light], syncing: bool) {
+        let mut requested: Vec<Option<BulbState>> = Vec::new();
+        for (address, value) in self.messages.try_iter() {
+            if !syncing {
+                continue;
+            }
+            if let Some(kind) = self.effects.get(&address) {
+                if is_on(&value) {
+                    println!("Starting {:?} on every light from {}", kind, address);
+                    for light in lights.iter_mut() {
+                        light.start_effect(match self.effect_duration {
+                            Some(duration) => Effect::new(*kind, duration),
+                            None => Effect::until_stopped(*kind),
+                        });
+                    }
+                    self.effects_on.insert(address);
+                } else if self.effects_on.remove(&address) {
+                    for light in lights.iter_mut() {
+                        light.stop_effect();
+                    }
+                }
+                continue;
+            }
+            requested.resize(lights.len(), None);
+            for (light, requested) in lights.iter_mut().zip(requested.iter_mut()) {
+                let current = requested.unwrap_or(light.state);
+                if let Some(state) =
+                    light.avatar_change(&address, &value, &current, self.echo_window)
+                {
+                    *requested = Some(state);
+                }
+            }
+        }
+        for (light, state) in lights.iter_mut().zip(requested) {
+            if let Some(state) = state {
+                light.request_state(state);
+            }
+        }
+    }
+}
+
+impl Drop for OscReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Waits for the socket to be closed, so it can be bound again right
+        // away when the settings are reloaded
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn effect_toggles_turn_on_like_avatar_parameters() {
+        assert!(is_on(&Type::Bool(true)));
+        assert!(!is_on(&Type::Bool(false)));
+        assert!(is_on(&Type::Int(2)));
+        assert!(!is_on(&Type::Int(0)));
+        assert!(is_on(&Type::Float(0.5)));
+        assert!(!is_on(&Type::Float(0.2)));
+    }
+
+    #[test]
+    fn only_known_effects_can_be_started() {
+        let config = |effect: &str| -> OscReceiveConfig {
+            serde_yaml::from_str(&format!(
+                "effects:\n    /avatar/parameters/LightFx: {}\n",
+                effect
+            ))
+            .unwrap()
+        };
+        assert!(config("rainbow").validate().is_ok());
+        assert_eq!(
+            config("sparkle").validate().unwrap_err(),
+            "sparkle isn't an effect, it can be pulse, breathe, strobe or rainbow."
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use nannou_osc::Type;
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+// OSC strings end with a null and are padded with more to a multiple of 4
+fn push_padded(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(bytes);
+    out.push(0);
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+#[derive(Debug)]
+struct Encoded {
+    name: Box<str>,
+    bytes: Box<[u8]>,
+}
+
+// An OSC address along with how it's encoded, worked out once so sending the
+// same parameters over and over doesn't build new strings. Cloning it only
+// bumps a count.
+#[derive(Debug, Clone)]
+pub struct Address(Arc<Encoded>);
+
+impl Address {
+    pub fn new(name: &str) -> Address {
+        let mut bytes = Vec::new();
+        push_padded(&mut bytes, name.as_bytes());
+        Address(Arc::new(Encoded {
+            name: name.into(),
+            bytes: bytes.into(),
+        }))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0.name
+    }
+
+    // Writes the OSC message setting this address to the value into the buffer,
+    // replacing what was in it. Returns whether the value could be encoded.
+    pub fn encode(&self, arg: &Type, out: &mut Vec<u8>) -> bool {
+        out.clear();
+        out.extend_from_slice(&self.0.bytes);
+        match arg {
+            Type::Float(value) => {
+                out.extend_from_slice(b",f\0\0");
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Type::Int(value) => {
+                out.extend_from_slice(b",i\0\0");
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            Type::Bool(true) => out.extend_from_slice(b",T\0\0"),
+            Type::Bool(false) => out.extend_from_slice(b",F\0\0"),
+            // Nothing sends the other types, so they can take the slow way
+            arg => match nannou_osc::encode((self.as_str().to_owned(), vec![arg.clone()]).into()) {
+                Ok(bytes) => {
+                    out.clear();
+                    out.extend_from_slice(&bytes);
+                }
+                Err(_) => return false,
+            },
+        }
+        true
+    }
+}
+
+impl From<&str> for Address {
+    fn from(name: &str) -> Address {
+        Address::new(name)
+    }
+}
+
+impl From<String> for Address {
+    fn from(name: String) -> Address {
+        Address::new(&name)
+    }
+}
+
+impl Deref for Address {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for Address {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for Address {
+    fn eq(&self, other: &Address) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Address {}
+
+// Hashed like the str so maps can be looked up by str
+impl Hash for Address {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_like_the_osc_library() {
+        // Every length the padding can end up at
+        let names = [
+            "",
+            "/a",
+            "/ab",
+            "/abc",
+            "/abcd",
+            "/avatar/parameters/LightHue",
+        ];
+        let args = [
+            Type::Float(0.25),
+            Type::Float(-1.0),
+            Type::Int(0),
+            Type::Int(-70_000),
+            Type::Bool(true),
+            Type::Bool(false),
+            Type::String("warm".to_owned()),
+            Type::Double(0.5),
+        ];
+        let mut out = vec![1, 2, 3];
+        for name in names {
+            let address = Address::new(name);
+            for arg in &args {
+                assert!(address.encode(arg, &mut out));
+                let expected = nannou_osc::encode((name.to_owned(), vec![arg.clone()]).into());
+                assert_eq!(out, expected.unwrap(), "{:?} {:?}", name, arg);
+                assert_eq!(out.len() % 4, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn messages_read_back() {
+        let address = Address::new("/avatar/parameters/LightOn");
+        let mut out = Vec::new();
+        for arg in [
+            Type::Float(0.75),
+            Type::Int(3),
+            Type::Bool(true),
+            Type::Bool(false),
+        ] {
+            assert!(address.encode(&arg, &mut out));
+            let msgs = nannou_osc::decode(&out).unwrap().into_msgs();
+            assert_eq!(msgs.len(), 1);
+            assert_eq!(msgs[0].addr, address.as_str());
+            assert_eq!(msgs[0].args, Some(vec![arg]));
+        }
+    }
+
+    #[test]
+    fn looks_up_like_the_str() {
+        let mut map = std::collections::HashMap::new();
+        map.insert(Address::from("/a"), 1);
+        assert_eq!(map.get("/a"), Some(&1));
+        assert_eq!(Address::from("/a".to_owned()), Address::new("/a"));
+        assert_ne!(Address::new("/a"), Address::new("/b"));
+    }
+}
@@ -0,0 +1,112 @@
+use super::Output;
+use crate::logging::{self, Category};
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::net::UdpSocket;
+
+const DMX_CHANNELS: usize = 512;
+
+fn default_port() -> u16 {
+    6454
+}
+
+fn default_channel() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArtNetConfig {
+    pub ip: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub universe: u16,
+    // First of the three RGB channels, counting from 1 like DMX consoles do.
+    #[serde(default = "default_channel")]
+    pub channel: usize,
+}
+
+impl ArtNetConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.channel < 1 || self.channel + 2 > DMX_CHANNELS {
+            return Err(format!(
+                "The Art-Net channel has to be between 1 and {}.",
+                DMX_CHANNELS - 2
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Emits the synced color as RGB to a fixture on an Art-Net DMX universe
+pub struct ArtNetOutput {
+    socket: UdpSocket,
+    target: String,
+    universe: u16,
+    channel: usize,
+    sequence: u8,
+}
+
+impl ArtNetOutput {
+    pub fn new(config: &ArtNetConfig) -> Result<ArtNetOutput, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .map_err(|err| format!("Couldn't open the Art-Net socket: {}", err))?;
+        // Art-Net is commonly broadcast to every node on the network
+        socket.set_broadcast(true).ok();
+        Ok(ArtNetOutput {
+            socket,
+            target: format!("{}:{}", config.ip, config.port),
+            universe: config.universe,
+            channel: config.channel,
+            sequence: 0,
+        })
+    }
+
+    fn art_dmx_packet(&self, data: &[u8; DMX_CHANNELS]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(18 + DMX_CHANNELS);
+        packet.extend_from_slice(b"Art-Net\0");
+        // OpDmx, little endian
+        packet.extend_from_slice(&[0x00, 0x50]);
+        // Protocol version 14, big endian
+        packet.extend_from_slice(&[0, 14]);
+        packet.push(self.sequence);
+        // Physical input port, informational only
+        packet.push(0);
+        // The 15 bit port address, SubUni followed by Net
+        packet.push((self.universe & 0xff) as u8);
+        packet.push(((self.universe >> 8) & 0x7f) as u8);
+        packet.extend_from_slice(&(DMX_CHANNELS as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+}
+
+impl Output for ArtNetOutput {
+    fn send(&mut self, state: &BulbState) {
+        let mut data = [0u8; DMX_CHANNELS];
+        if state.on {
+            let (r, g, b) = state.rgb();
+            let start = self.channel - 1;
+            data[start] = (r * 255.0).round() as u8;
+            data[start + 1] = (g * 255.0).round() as u8;
+            data[start + 2] = (b * 255.0).round() as u8;
+        }
+        // Sequence 0 means sequencing is disabled so wrap around from 255 to 1
+        self.sequence = if self.sequence == 255 {
+            1
+        } else {
+            self.sequence + 1
+        };
+        let packet = self.art_dmx_packet(&data);
+        match self.socket.send_to(&packet, &self.target) {
+            Ok(_) => logging::debug(format!(
+                "Sent updated state to Art-Net universe {}",
+                self.universe
+            )),
+            Err(err) => logging::error(
+                Category::Output,
+                format!("Failed to send Art-Net packet: {}", err),
+            ),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use super::address::Address;
+use crate::state::BulbState;
+use serde::Deserialize;
+
+pub type Metric = fn(&BulbState) -> f32;
+
+// Extra float parameters worked out from the light's state, each one is the
+// parameter name to send it as without the prefix
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DerivedConfig {
+    // 1 for orange light, 0 for light blue, the other hues in between
+    pub warmth: Option<String>,
+    // How colorful the light looks, saturation times brightness
+    pub vividness: Option<String>,
+    // How bright the light looks to the eye, green counts for more than red
+    // and red for more than blue
+    pub luma: Option<String>,
+}
+
+pub fn warmth(state: &BulbState) -> f32 {
+    state.warmth()
+}
+
+pub fn vividness(state: &BulbState) -> f32 {
+    if state.on {
+        state.saturation * state.brightness
+    } else {
+        0.0
+    }
+}
+
+// Rec. 709 luma of the light's color
+pub fn luma(state: &BulbState) -> f32 {
+    let (red, green, blue) = state.rgb();
+    0.2126 * red + 0.7152 * green + 0.0722 * blue
+}
+
+impl DerivedConfig {
+    // The address of every parameter that's turned on, with the prefix in
+    // front, and how to work it out
+    pub fn parameters(&self, prefix: &str) -> Vec<(Address, Metric)> {
+        let metrics: [(&Option<String>, Metric); 3] = [
+            (&self.warmth, warmth),
+            (&self.vividness, vividness),
+            (&self.luma, luma),
+        ];
+        metrics
+            .iter()
+            .filter_map(|(name, metric)| {
+                name.as_ref()
+                    .map(|name| (Address::new(&(prefix.to_owned() + name)), *metric))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    #[test]
+    fn works_out_the_metrics() {
+        let red = BulbState::color(true, 0.0, 0.5);
+        assert!(close(vividness(&red), 0.5));
+        assert!(close(luma(&red), 0.2126 * 0.5));
+        let pale = BulbState::new(true, (0.33, 0.25, None), 1.0);
+        assert!(close(vividness(&pale), 0.25));
+        let white = BulbState::new(true, (0.0, 0.0, None), 1.0);
+        assert!(close(luma(&white), 1.0));
+        assert!(close(warmth(&BulbState::white(true, 2000.0, 1.0)), 1.0));
+
+        // Nothing shows while it's off
+        let off = BulbState::color(false, 0.0, 1.0);
+        assert!(close(vividness(&off), 0.0));
+        assert!(close(luma(&off), 0.0));
+    }
+
+    #[test]
+    fn sends_only_the_metrics_that_are_turned_on() {
+        let config = DerivedConfig {
+            warmth: Some("Warmth".to_owned()),
+            luma: Some("Luma".to_owned()),
+            ..DerivedConfig::default()
+        };
+        let parameters = config.parameters("/avatar/parameters/Light");
+        let addresses: Vec<_> = parameters
+            .iter()
+            .map(|(addr, _)| addr.to_string())
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                "/avatar/parameters/LightWarmth",
+                "/avatar/parameters/LightLuma"
+            ]
+        );
+    }
+}
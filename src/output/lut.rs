@@ -0,0 +1,267 @@
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LutConfig {
+    pub hue: Option<PathBuf>,
+    pub brightness: Option<PathBuf>,
+}
+
+impl LutConfig {
+    // Reads the files, so ones that are missing or broken are found with the
+    // rest of the settings
+    pub fn validate(&self) -> Result<(), String> {
+        ColorGrading::new(self).map(drop)
+    }
+}
+
+// A curve from input to output values, linearly interpolated between its
+// points and flat past the ends
+pub struct Lut {
+    points: Vec<(f32, f32)>,
+}
+
+impl Lut {
+    // Reads a curve file with an "input output" pair on every line, empty
+    // lines and lines starting with # are skipped. 1D .cube files exported by
+    // color grading tools work too, their first channel is used.
+    pub fn load(path: &Path) -> Result<Lut, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read the LUT {}: {}", path.display(), err))?;
+        Lut::parse(&text).map_err(|err| format!("The LUT {} {}", path.display(), err))
+    }
+
+    fn parse(text: &str) -> Result<Lut, String> {
+        let lines = text.lines().enumerate().filter_map(|(i, line)| {
+            let line = line.trim();
+            (!line.is_empty() && !line.starts_with('#')).then_some((i + 1, line))
+        });
+        let is_cube = lines
+            .clone()
+            .any(|(_, line)| line.starts_with("LUT_1D_SIZE") || line.starts_with("LUT_3D_SIZE"));
+        let mut points = if is_cube {
+            Lut::parse_cube(lines)?
+        } else {
+            lines
+                .map(|(number, line)| match numbers(line).as_deref() {
+                    Some(&[input, output]) => Ok((input, output)),
+                    _ => Err(format!(
+                        "should have an input and an output number on line {}.",
+                        number
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        if points.is_empty() {
+            return Err("doesn't have any points.".to_owned());
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Lut { points })
+    }
+
+    // The outputs are spread evenly over the domain, from 0 to 1 unless the
+    // file says otherwise
+    fn parse_cube<'a>(
+        lines: impl Iterator<Item = (usize, &'a str)>,
+    ) -> Result<Vec<(f32, f32)>, String> {
+        let mut size = None;
+        let (mut min, mut max) = (0.0, 1.0);
+        let mut outputs = Vec::new();
+        for (number, line) in lines {
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let first = || {
+                numbers(rest)
+                    .and_then(|values| values.first().copied())
+                    .ok_or_else(|| {
+                        format!("should have a number after {} on line {}.", keyword, number)
+                    })
+            };
+            match keyword {
+                "TITLE" => {}
+                "LUT_3D_SIZE" => {
+                    return Err(
+                        "is a 3D LUT, only 1D ones can be used on hue and brightness.".to_owned(),
+                    )
+                }
+                "LUT_1D_SIZE" => size = Some(first()? as usize),
+                "DOMAIN_MIN" => min = first()?,
+                "DOMAIN_MAX" => max = first()?,
+                _ => match numbers(line).as_deref() {
+                    Some(&[output, _, _]) => outputs.push(output),
+                    _ => {
+                        return Err(format!(
+                            "should have three output numbers on line {}.",
+                            number
+                        ))
+                    }
+                },
+            }
+        }
+        if size != Some(outputs.len()) {
+            return Err(format!(
+                "says it has {} points but has {}.",
+                size.unwrap_or_default(),
+                outputs.len()
+            ));
+        }
+        let step = (max - min) / (outputs.len().max(2) - 1) as f32;
+        Ok(outputs
+            .into_iter()
+            .enumerate()
+            .map(|(i, output)| (min + i as f32 * step, output))
+            .collect())
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if value <= first.0 {
+            return first.1;
+        }
+        if value >= last.0 {
+            return last.1;
+        }
+        let next = self.points.partition_point(|point| point.0 <= value);
+        let (x0, y0) = self.points[next - 1];
+        let (x1, y1) = self.points[next];
+        if x1 == x0 {
+            return y1;
+        }
+        y0 + (value - x0) / (x1 - x0) * (y1 - y0)
+    }
+}
+
+// The hue and brightness curves of a light
+pub struct ColorGrading {
+    hue: Option<Lut>,
+    brightness: Option<Lut>,
+}
+
+impl ColorGrading {
+    pub fn new(config: &LutConfig) -> Result<ColorGrading, String> {
+        Ok(ColorGrading {
+            hue: config.hue.as_deref().map(Lut::load).transpose()?,
+            brightness: config.brightness.as_deref().map(Lut::load).transpose()?,
+        })
+    }
+
+    pub fn apply(&self, state: &BulbState) -> BulbState {
+        BulbState {
+            hue: self
+                .hue
+                .as_ref()
+                .map_or(state.hue, |lut| lut.apply(state.hue).rem_euclid(1.0)),
+            brightness: self.brightness.as_ref().map_or(state.brightness, |lut| {
+                lut.apply(state.brightness).clamp(0.0, 1.0)
+            }),
+            ..*state
+        }
+    }
+}
+
+// The numbers on a line, None when one of them isn't a finite number
+fn numbers(line: &str) -> Option<Vec<f32>> {
+    line.split_whitespace()
+        .map(|value| value.parse().ok().filter(|value: &f32| value.is_finite()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn interpolates_between_the_points_and_is_flat_past_the_ends() {
+        let lut = Lut::parse("# gamma\n\n0.5 0.25\n0 0\n  1 1  \n").unwrap();
+        assert_close(lut.apply(0.0), 0.0);
+        assert_close(lut.apply(0.25), 0.125);
+        assert_close(lut.apply(0.5), 0.25);
+        assert_close(lut.apply(0.75), 0.625);
+        assert_close(lut.apply(-1.0), 0.0);
+        assert_close(lut.apply(2.0), 1.0);
+
+        let single = Lut::parse("0.3 0.7").unwrap();
+        assert_close(single.apply(0.0), 0.7);
+        assert_close(single.apply(1.0), 0.7);
+    }
+
+    #[test]
+    fn reads_1d_cube_files() {
+        let cube = "TITLE \"warm\"\nLUT_1D_SIZE 3\n0 0 0\n0.25 0.3 0.2\n1 1 1\n";
+        let lut = Lut::parse(cube).unwrap();
+        assert_close(lut.apply(0.5), 0.25);
+        assert_close(lut.apply(0.75), 0.625);
+
+        let domain = "LUT_1D_SIZE 2\nDOMAIN_MIN 0.5 0.5 0.5\nDOMAIN_MAX 1 1 1\n0 0 0\n1 1 1\n";
+        let lut = Lut::parse(domain).unwrap();
+        assert_close(lut.apply(0.25), 0.0);
+        assert_close(lut.apply(0.75), 0.5);
+    }
+
+    #[test]
+    fn says_what_is_wrong_with_broken_files() {
+        let err = |text| Lut::parse(text).err().unwrap();
+        assert_eq!(
+            err("0 0\n0.5\n"),
+            "should have an input and an output number on line 2."
+        );
+        assert_eq!(
+            err("0 0\n0.5 half\n"),
+            "should have an input and an output number on line 2."
+        );
+        assert_eq!(
+            err("0 0\n0.5 NaN\n"),
+            "should have an input and an output number on line 2."
+        );
+        assert_eq!(err("# nothing yet\n"), "doesn't have any points.");
+        assert_eq!(
+            err("LUT_3D_SIZE 2\n"),
+            "is a 3D LUT, only 1D ones can be used on hue and brightness."
+        );
+        assert_eq!(
+            err("LUT_1D_SIZE 3\n0 0 0\n1 1 1\n"),
+            "says it has 3 points but has 2."
+        );
+        assert_eq!(
+            err("LUT_1D_SIZE 2\n0 0\n1 1\n"),
+            "should have three output numbers on line 2."
+        );
+        assert_eq!(
+            err("LUT_1D_SIZE\n"),
+            "should have a number after LUT_1D_SIZE on line 1."
+        );
+    }
+
+    #[test]
+    fn loads_the_files_of_the_settings() {
+        let path = env::temp_dir().join(format!("vrchat-light-sync-{}.lut", std::process::id()));
+        fs::write(&path, "0 1\n1 0\n").unwrap();
+        let grading = ColorGrading::new(&LutConfig {
+            hue: None,
+            brightness: Some(path.clone()),
+        });
+        fs::remove_file(&path).unwrap();
+        let state = grading.unwrap().apply(&BulbState::color(true, 0.4, 0.25));
+        assert_close(state.hue, 0.4);
+        assert_close(state.brightness, 0.75);
+
+        let missing = Lut::load(&path).err().unwrap();
+        assert!(
+            missing.starts_with(&format!("Couldn't read the LUT {}: ", path.display())),
+            "{}",
+            missing
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use super::Output;
+use crate::backend::home_assistant::{self, ColorSupport, HomeAssistantConfig};
+use crate::config::{BulbService, SourceConfig};
+use crate::logging::{self, Category};
+use crate::state::BulbState;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct MirrorConfig {
+    // The light to keep matched, on the same service as the synced light.
+    pub entity_id: String,
+}
+
+// Only lights read from Home Assistant directly can be mirrored
+pub fn validate(source: &SourceConfig) -> Result<(), String> {
+    match source.bulb_service() {
+        BulbService::HomeAssistant => Ok(()),
+        BulbService::Priority | BulbService::Failover | BulbService::Aggregate => {
+            Err("mirror only works for lights using a single bulb service directly.".into())
+        }
+        BulbService::Push => Err("mirror can't set lights through the push bulb service.".into()),
+        #[allow(unreachable_patterns)]
+        _ => Err("mirror only works for lights on home_assistant.".into()),
+    }
+}
+
+// Sets a second physical light to the synced state through the bulb service
+pub struct MirrorOutput {
+    home_assistant: HomeAssistantConfig,
+    // Kept so the connection stays open between sends
+    client: reqwest::blocking::Client,
+    // What the mirrored light can show, read the first time it's set
+    support: Option<ColorSupport>,
+}
+
+impl MirrorOutput {
+    pub fn new(source: &SourceConfig, mirror: &MirrorConfig) -> MirrorOutput {
+        let mut home_assistant = source.home_assistant().clone();
+        home_assistant.entity_id = mirror.entity_id.clone();
+        MirrorOutput {
+            home_assistant,
+            client: reqwest::blocking::Client::new(),
+            support: None,
+        }
+    }
+}
+
+impl Output for MirrorOutput {
+    fn send(&mut self, state: &BulbState) {
+        let entity_id = &self.home_assistant.entity_id;
+        if self.support.is_none() {
+            self.support = home_assistant::read_support(&self.home_assistant, &self.client).ok();
+        }
+        let support = self.support.unwrap_or(ColorSupport::Auto);
+        match home_assistant::set_state(&self.home_assistant, &self.client, state, support) {
+            Ok(()) => logging::debug(format!("Sent updated state to {}", entity_id)),
+            Err(err) => logging::error(
+                Category::Output,
+                format!("Failed to update mirror light {}: {}", entity_id, err),
+            ),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+pub mod address;
+#[cfg(feature = "artnet")]
+pub mod artnet;
+pub mod derived;
+pub mod lut;
+#[cfg(feature = "home-assistant")]
+pub mod mirror;
+pub mod multiplex;
+pub mod osc_socket;
+pub mod packed;
+pub mod parameters;
+pub mod rate_limit;
+pub mod remote;
+pub mod send_queue;
+pub mod smoothing;
+pub mod vrchat;
+
+use crate::state::BulbState;
+
+// Something the synced light state gets sent to whenever it changes
+pub trait Output: Send {
+    fn send(&mut self, state: &BulbState);
+}
@@ -0,0 +1,213 @@
+use super::address::Address;
+use super::vrchat::VrchatOutput;
+use crate::config::validate_seconds;
+use crate::config::Config;
+use crate::light::Light;
+use nannou_osc::Type;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+fn default_parameter_prefix() -> String {
+    "/avatar/parameters/Mux_".to_owned()
+}
+
+fn default_slot_time() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MultiplexConfig {
+    // Int parameter saying which light the shared parameters belong to
+    pub index_parameter: String,
+    // Prepended to the shared on, Color and brightness parameter names
+    #[serde(default = "default_parameter_prefix")]
+    pub parameter_prefix: String,
+    // Seconds each light gets the shared parameters for
+    #[serde(default = "default_slot_time")]
+    pub slot_time: f32,
+}
+
+impl MultiplexConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_seconds("multiplex.slot_time", self.slot_time, false)
+    }
+}
+
+// Takes turns sending every light through the same few parameters, along with
+// the index of the light they belong to right now
+pub struct Multiplexer {
+    output: VrchatOutput,
+    index_parameter: Address,
+    // The shared parameters' addresses by name, made the first time each is
+    // sent
+    addresses: HashMap<String, Address>,
+    messages: Vec<(Address, Type)>,
+    slot_time: Duration,
+    next: usize,
+    last_slot: Option<Instant>,
+}
+
+impl Multiplexer {
+    pub fn new(
+        addr: &str,
+        config: &Config,
+        multiplex: &MultiplexConfig,
+    ) -> Result<Multiplexer, String> {
+        Ok(Multiplexer {
+            output: VrchatOutput::new_multiplexer(addr, config, &multiplex.parameter_prefix)?,
+            index_parameter: Address::new(&multiplex.index_parameter),
+            addresses: HashMap::new(),
+            messages: Vec::new(),
+            slot_time: Duration::from_secs_f32(multiplex.slot_time),
+            next: 0,
+            last_slot: None,
+        })
+    }
+
+    // Moves on to the next light once its slot time is up
+    pub fn update(&mut self, lights: &[Light], now: Instant) {
+        self.output.flush();
+        if lights.is_empty()
+            || self
+                .last_slot
+                .is_some_and(|last| now.duration_since(last) < self.slot_time)
+        {
+            return;
+        }
+        self.last_slot = Some(now);
+        let index = self.next % lights.len();
+        self.next = index + 1;
+        self.send_slot(index, lights[index].multiplexed_frame());
+    }
+
+    // Sends the frame of the light at index through the shared parameters
+    pub fn send_slot(&mut self, index: usize, frame: &[(String, Type)]) {
+        let prefix = self.output.prefix();
+        self.messages
+            .push((self.index_parameter.clone(), Type::Int(index as i32)));
+        for (name, arg) in frame {
+            let address = match self.addresses.get(name) {
+                Some(address) => address.clone(),
+                None => {
+                    let address = Address::new(&(prefix.to_owned() + name));
+                    self.addresses.insert(name.clone(), address.clone());
+                    address
+                }
+            };
+            self.messages.push((address, arg.clone()));
+        }
+        self.output.send_batch(&mut self.messages);
+    }
+
+    // Everything sent since the last time, taken out as it's read
+    pub fn drain_sent(&mut self) -> std::vec::Drain<'_, (Address, Type)> {
+        self.output.drain_sent()
+    }
+
+    // Makes the next slots send every parameter again
+    pub fn forget_sent(&mut self) {
+        self.output.forget_sent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, MockClock};
+    use crate::config::parse_config;
+    use crate::state::BulbState;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    const SETTINGS: &str = "
+vrchat_ip: 127.0.0.1
+vrchat_port: 9
+max_updates_per_second: 5
+multiplex:
+    index_parameter: /avatar/parameters/LightIndex
+lights:
+    - name: desk
+      bulb_service: push
+      push:
+          name: desk
+    - name: ceiling
+      bulb_service: push
+      push:
+          name: ceiling
+";
+
+    fn sent(multiplexer: &mut Multiplexer) -> Vec<(String, Type)> {
+        multiplexer
+            .drain_sent()
+            .map(|(addr, arg)| (addr.to_string(), arg))
+            .collect()
+    }
+
+    // The index each slot that was sent belongs to
+    fn indices(multiplexer: &mut Multiplexer) -> Vec<i32> {
+        sent(multiplexer)
+            .into_iter()
+            .filter(|(addr, _)| addr == "/avatar/parameters/LightIndex")
+            .filter_map(|(_, arg)| arg.int())
+            .collect()
+    }
+
+    #[test]
+    fn sends_the_frame_with_the_shared_parameters() {
+        let config = parse_config(SETTINGS).unwrap();
+        let mut multiplexer =
+            Multiplexer::new("127.0.0.1:9", &config, config.multiplex.as_ref().unwrap()).unwrap();
+        let frame = [
+            ("Color".to_owned(), Type::Float(0.25)),
+            ("on".to_owned(), Type::Bool(true)),
+        ];
+        multiplexer.send_slot(3, &frame);
+        assert_eq!(
+            sent(&mut multiplexer),
+            [
+                ("/avatar/parameters/LightIndex".to_owned(), Type::Int(3)),
+                ("/avatar/parameters/Mux_Color".to_owned(), Type::Float(0.25)),
+                ("/avatar/parameters/Mux_on".to_owned(), Type::Bool(true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn takes_turns_once_the_slot_time_is_up() {
+        let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        clock::set_local(mock.clone());
+        let config = parse_config(SETTINGS).unwrap();
+        config.pushed.push("desk", BulbState::color(true, 0.1, 1.0));
+        config
+            .pushed
+            .push("ceiling", BulbState::color(true, 0.6, 1.0));
+        let mut lights: Vec<Light> = config
+            .lights
+            .iter()
+            .map(|light| Light::new(&config, light, "127.0.0.1:9").unwrap())
+            .collect();
+        lights.iter_mut().for_each(Light::send);
+        assert!(!lights[0].multiplexed_frame().is_empty());
+        let mut multiplexer =
+            Multiplexer::new("127.0.0.1:9", &config, config.multiplex.as_ref().unwrap()).unwrap();
+
+        let start = clock::now();
+        multiplexer.update(&lights, start);
+        assert_eq!(indices(&mut multiplexer), [0]);
+        // Still the first light's slot
+        multiplexer.update(&lights, start + Duration::from_millis(400));
+        assert!(indices(&mut multiplexer).is_empty());
+        multiplexer.update(&lights, start + Duration::from_millis(500));
+        assert_eq!(indices(&mut multiplexer), [1]);
+        // Goes back around to the first light
+        multiplexer.update(&lights, start + Duration::from_millis(1000));
+        assert_eq!(indices(&mut multiplexer), [0]);
+
+        // A light that was taken out doesn't leave the turn out of range
+        multiplexer.update(&lights[..1], start + Duration::from_millis(1500));
+        assert_eq!(indices(&mut multiplexer), [0]);
+        multiplexer.update(&[], start + Duration::from_millis(2000));
+        assert!(indices(&mut multiplexer).is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::clock;
+use crate::config::Config;
+use crate::logging::{self, Category};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+// How long to wait before setting the socket up again after it failed, doubling
+// every time it fails again
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// How often to check which network interface OSC leaves through
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// The socket OSC is sent from, set up again when sending fails or the network
+// changes, like when Wi-Fi roams or a VPN goes up or down
+pub struct OscSocket {
+    // Set in the settings, otherwise every address of the kind VRChat is at
+    bind_address: Option<String>,
+    ipv6: bool,
+    ttl: Option<u32>,
+    multicast_loopback: Option<bool>,
+    // None while waiting to set it up again
+    socket: Option<UdpSocket>,
+    retry_delay: Duration,
+    retry_at: Instant,
+    // The local address OSC to VRChat left from last time it was checked
+    route: Option<IpAddr>,
+    last_route_check: Instant,
+}
+
+impl OscSocket {
+    pub fn new(config: &Config) -> OscSocket {
+        let mut socket = OscSocket {
+            bind_address: config.osc_bind_address.clone(),
+            ipv6: false,
+            ttl: config.osc_ttl,
+            multicast_loopback: config.osc_multicast.as_ref().map(|m| m.loopback),
+            socket: None,
+            retry_delay: MIN_RETRY_DELAY,
+            retry_at: clock::now(),
+            route: None,
+            last_route_check: clock::now(),
+        };
+        match socket.bind() {
+            Ok(bound) => socket.socket = Some(bound),
+            // Like when the network the bind address is on isn't up yet
+            Err(err) => {
+                logging::error(
+                    Category::Network,
+                    format!(
+                        "Couldn't send OSC from {}, trying again in {}s: {}",
+                        socket.address(),
+                        socket.retry_delay.as_secs(),
+                        err
+                    ),
+                );
+                socket.back_off(clock::now());
+            }
+        }
+        socket
+    }
+
+    fn address(&self) -> &str {
+        match &self.bind_address {
+            Some(address) => address,
+            None if self.ipv6 => "::",
+            None => "0.0.0.0",
+        }
+    }
+
+    fn bind(&self) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((self.address(), 0))?;
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        if let Some(loopback) = self.multicast_loopback {
+            socket.set_multicast_loop_v4(loopback)?;
+            if let Some(ttl) = self.ttl {
+                socket.set_multicast_ttl_v4(ttl)?;
+            }
+        }
+        Ok(socket)
+    }
+
+    // Sends nothing while the socket is being set up again, a failed send
+    // drops the socket so it gets set up again
+    pub fn send_to(&mut self, bytes: &[u8], target: SocketAddr) -> io::Result<()> {
+        let socket = match &self.socket {
+            Some(socket) => socket,
+            None => return Ok(()),
+        };
+        if let Err(err) = socket.send_to(bytes, target) {
+            self.back_off(clock::now());
+            return Err(err);
+        }
+        self.retry_delay = MIN_RETRY_DELAY;
+        Ok(())
+    }
+
+    fn back_off(&mut self, now: Instant) {
+        self.socket = None;
+        self.retry_at = now + self.retry_delay;
+        self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+
+    // The local address the system picks for sending to the target
+    fn current_route(&self, target: SocketAddr) -> Option<IpAddr> {
+        let probe = UdpSocket::bind((self.address(), 0)).ok()?;
+        probe.connect(target).ok()?;
+        Some(probe.local_addr().ok()?.ip())
+    }
+
+    // Sets the socket up again once the backoff has passed if it failed, or
+    // right away if the network interface towards the target changed. Returns
+    // whether there's a new socket, in which case everything needs to be sent
+    // again.
+    pub fn refresh(&mut self, target: SocketAddr, now: Instant) -> bool {
+        // Sockets on every IPv4 address can't send to IPv6 ones, or the other
+        // way around
+        if self.bind_address.is_none() && target.is_ipv6() != self.ipv6 {
+            self.ipv6 = target.is_ipv6();
+            self.socket = self.bind().ok();
+            self.retry_at = now;
+            if self.socket.is_some() {
+                self.route = self.current_route(target);
+                return true;
+            }
+        }
+        if self.socket.is_none() {
+            if now < self.retry_at {
+                return false;
+            }
+            return match self.bind() {
+                Ok(socket) => {
+                    println!("Sending OSC again");
+                    self.socket = Some(socket);
+                    self.route = self.current_route(target);
+                    true
+                }
+                Err(err) => {
+                    logging::error(
+                        Category::Network,
+                        format!(
+                            "Couldn't send OSC from {}, trying again in {}s: {}",
+                            self.address(),
+                            self.retry_delay.as_secs(),
+                            err
+                        ),
+                    );
+                    self.back_off(now);
+                    false
+                }
+            };
+        }
+        if now.duration_since(self.last_route_check) < ROUTE_CHECK_INTERVAL {
+            return false;
+        }
+        self.last_route_check = now;
+        let route = self.current_route(target);
+        let changed = self.route.is_some() && route.is_some() && route != self.route;
+        if route.is_some() {
+            self.route = route;
+        }
+        if !changed {
+            return false;
+        }
+        println!("The network changed, setting up OSC again");
+        match self.bind() {
+            Ok(socket) => self.socket = Some(socket),
+            Err(_) => {
+                self.socket = None;
+                self.retry_at = now;
+            }
+        }
+        self.socket.is_some()
+    }
+}
@@ -0,0 +1,225 @@
+use crate::state::BulbState;
+use serde::Deserialize;
+use std::fmt::Write;
+
+fn default_parameter() -> String {
+    "/avatar/parameters/LightPacked".to_owned()
+}
+
+fn default_on_bits() -> u32 {
+    1
+}
+
+fn default_hue_bits() -> u32 {
+    4
+}
+
+fn default_brightness_bits() -> u32 {
+    3
+}
+
+// Packs on, hue and brightness into a single 8 bit VRChat int parameter, most
+// significant bits first in that order.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PackedConfig {
+    #[serde(default = "default_parameter")]
+    pub parameter: String,
+    #[serde(default = "default_on_bits")]
+    pub on_bits: u32,
+    #[serde(default = "default_hue_bits")]
+    pub hue_bits: u32,
+    #[serde(default = "default_brightness_bits")]
+    pub brightness_bits: u32,
+}
+
+impl PackedConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.on_bits > 1 {
+            return Err("on_bits in packed can only be 0 or 1.".into());
+        }
+        if self.on_bits + self.hue_bits + self.brightness_bits > 8 {
+            return Err(
+                "The packed bits add up to more than 8, which doesn't fit in a VRChat int.".into(),
+            );
+        }
+        Ok(())
+    }
+
+    fn hue_shift(&self) -> u32 {
+        self.brightness_bits
+    }
+
+    fn on_shift(&self) -> u32 {
+        self.brightness_bits + self.hue_bits
+    }
+
+    pub fn pack(&self, state: &BulbState) -> u8 {
+        let hue_steps = 1 << self.hue_bits;
+        // Hue wraps around, so rounding up to the last step means the first one
+        let hue = (state.hue * hue_steps as f32).round() as u32 % hue_steps;
+        let brightness_max = (1 << self.brightness_bits) - 1;
+        let brightness = (state.brightness * brightness_max as f32).round() as u32;
+        let on = if self.on_bits == 1 && state.on { 1 } else { 0 };
+        ((on << self.on_shift()) | (hue << self.hue_shift()) | brightness) as u8
+    }
+
+    pub fn unpack(&self, value: u8) -> BulbState {
+        let value = value as u32;
+        let brightness_max = (1 << self.brightness_bits) - 1;
+        let hue_steps = 1 << self.hue_bits;
+        let brightness = value & brightness_max;
+        let hue = (value >> self.hue_shift()) & (hue_steps - 1);
+        BulbState::color(
+            // Without an on bit the light counts as on whenever it has any brightness
+            if self.on_bits == 1 {
+                (value >> self.on_shift()) & 1 == 1
+            } else {
+                brightness > 0
+            },
+            hue as f32 / hue_steps as f32,
+            if brightness_max == 0 {
+                1.0
+            } else {
+                brightness as f32 / brightness_max as f32
+            },
+        )
+    }
+
+    // A human readable description of how to decode the packed parameter in an
+    // avatar animator, including a table of every possible value.
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        let used_bits = self.on_bits + self.hue_bits + self.brightness_bits;
+        writeln!(out, "Packed parameter {} (Int)", self.parameter).unwrap();
+        writeln!(
+            out,
+            "Bit layout, most significant first: on ({} bit), hue ({} bits), brightness ({} bits)",
+            self.on_bits, self.hue_bits, self.brightness_bits
+        )
+        .unwrap();
+        if self.on_bits == 1 {
+            writeln!(out, "  on         = (value >> {}) & 1", self.on_shift()).unwrap();
+        } else {
+            writeln!(out, "  on         = brightness > 0").unwrap();
+        }
+        writeln!(
+            out,
+            "  hue        = ((value >> {}) & {}) / {}",
+            self.hue_shift(),
+            (1 << self.hue_bits) - 1,
+            1 << self.hue_bits
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "  brightness = (value & {}) / {}",
+            (1 << self.brightness_bits) - 1,
+            (1 << self.brightness_bits) - 1
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "value  on     hue    brightness").unwrap();
+        for value in 0..(1u32 << used_bits) {
+            let state = self.unpack(value as u8);
+            writeln!(
+                out,
+                "{:<6} {:<6} {:<6.3} {:.3}",
+                value, state.on, state.hue, state.brightness
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(on_bits: u32, hue_bits: u32, brightness_bits: u32) -> PackedConfig {
+        PackedConfig {
+            parameter: default_parameter(),
+            on_bits,
+            hue_bits,
+            brightness_bits,
+        }
+    }
+
+    const LAYOUTS: [(u32, u32, u32); 7] = [
+        (1, 4, 3),
+        (0, 4, 3),
+        (0, 5, 3),
+        (1, 7, 0),
+        (1, 0, 7),
+        (0, 8, 0),
+        (0, 0, 0),
+    ];
+
+    #[test]
+    fn every_value_reads_back() {
+        for (on, hue, brightness) in LAYOUTS {
+            let packed = layout(on, hue, brightness);
+            assert_eq!(packed.validate(), Ok(()));
+            for value in 0..1u32 << (on + hue + brightness) {
+                let value = value as u8;
+                assert_eq!(
+                    packed.pack(&packed.unpack(value)),
+                    value,
+                    "{:?}",
+                    (on, hue, brightness)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn states_come_back_to_the_nearest_step() {
+        let packed = layout(1, 4, 3);
+        let state = BulbState::color(true, 0.3, 0.6);
+        let read = packed.unpack(packed.pack(&state));
+        assert!(read.on);
+        assert!((read.hue - state.hue).abs() <= 1.0 / 32.0);
+        assert!((read.brightness - state.brightness).abs() <= 1.0 / 14.0);
+        // The layout goes on, hue, brightness from the top
+        assert_eq!(packed.pack(&BulbState::color(true, 0.5, 1.0)), 0b11000111);
+        assert_eq!(packed.pack(&BulbState::color(false, 0.0, 0.0)), 0);
+    }
+
+    #[test]
+    fn hue_wraps_around() {
+        let packed = layout(1, 4, 3);
+        let last = packed.pack(&BulbState::color(true, 0.99, 1.0));
+        let first = packed.pack(&BulbState::color(true, 0.0, 1.0));
+        assert_eq!(last, first);
+    }
+
+    #[test]
+    fn without_an_on_bit_brightness_says_if_its_on() {
+        let packed = layout(0, 4, 3);
+        assert!(!packed.unpack(0b0101000).on);
+        assert!(packed.unpack(0b0101001).on);
+        // An off light packs like any other
+        assert_eq!(packed.pack(&BulbState::color(false, 0.0, 1.0)), 0b111);
+    }
+
+    #[test]
+    fn bits_past_the_layout_are_ignored() {
+        let packed = layout(0, 4, 3);
+        assert_eq!(packed.unpack(0x80 | 0b0101011), packed.unpack(0b0101011));
+    }
+
+    #[test]
+    fn rejects_layouts_that_dont_fit() {
+        assert!(layout(2, 3, 3).validate().is_err());
+        assert!(layout(1, 5, 3).validate().is_err());
+        assert!(layout(0, 9, 0).validate().is_err());
+    }
+
+    #[test]
+    fn describes_every_value() {
+        let description = layout(1, 4, 3).describe();
+        // The header takes the first 7 lines
+        assert_eq!(description.lines().count(), 7 + 256);
+        assert!(description.contains("on         = (value >> 7) & 1"));
+    }
+}
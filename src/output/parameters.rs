@@ -0,0 +1,412 @@
+use super::address::Address;
+use crate::state::{translate, BulbState, COOLEST_KELVIN, WARMEST_KELVIN};
+use nannou_osc::Type;
+use serde::Deserialize;
+
+fn default_kind() -> ParameterType {
+    ParameterType::Float
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterType {
+    Bool,
+    Float,
+    Int,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ParameterConfig {
+    // Added to the parameter_prefix, unless it starts with a / and is already
+    // a full address
+    pub address: String,
+    #[serde(rename = "type", default = "default_kind")]
+    pub kind: ParameterType,
+    // The values of the attribute to map from, its whole range if left out
+    pub input: Option<[f32; 2]>,
+    // What the start and end of the input are sent as, 0 to 1 for floats and
+    // 0 to 255 for ints if left out. Going from high to low turns it around.
+    pub range: Option<[f32; 2]>,
+    // Bends the values in between, above 1 keeps them low for longer and
+    // below 1 raises them sooner
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+}
+
+// Which parameters each attribute of the light is sent as, instead of the on,
+// Color and brightness parameters
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ParametersConfig {
+    #[serde(default)]
+    pub on: Vec<ParameterConfig>,
+    #[serde(default)]
+    pub hue: Vec<ParameterConfig>,
+    #[serde(default)]
+    pub saturation: Vec<ParameterConfig>,
+    #[serde(default)]
+    pub brightness: Vec<ParameterConfig>,
+    #[serde(default)]
+    pub color_temp: Vec<ParameterConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attribute {
+    On,
+    Hue,
+    Saturation,
+    Brightness,
+    ColorTemp,
+}
+
+impl Attribute {
+    // The values the attribute goes between
+    fn range(self) -> [f32; 2] {
+        match self {
+            Attribute::ColorTemp => [WARMEST_KELVIN, COOLEST_KELVIN],
+            _ => [0.0, 1.0],
+        }
+    }
+
+    fn value(self, state: &BulbState) -> f32 {
+        match self {
+            Attribute::On => {
+                if state.on {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Attribute::Hue => state.hue,
+            Attribute::Saturation => state.saturation,
+            Attribute::Brightness => state.brightness,
+            // In Kelvin, estimated from how warm the hue is for colors
+            Attribute::ColorTemp => state.kelvin(),
+        }
+    }
+
+    // The state with the attribute changed to the value, changing the hue or
+    // saturation switches a white light to a color
+    fn apply(self, state: &BulbState, value: f32) -> Option<BulbState> {
+        match self {
+            Attribute::On => Some(BulbState {
+                on: value >= 0.5,
+                ..*state
+            }),
+            Attribute::Hue => Some(BulbState {
+                hue: value.rem_euclid(1.0),
+                color_temp: None,
+                ..*state
+            }),
+            Attribute::Saturation => Some(BulbState {
+                saturation: value.clamp(0.0, 1.0),
+                color_temp: None,
+                ..*state
+            }),
+            Attribute::Brightness => Some(BulbState {
+                brightness: value.clamp(0.0, 1.0),
+                ..*state
+            }),
+            Attribute::ColorTemp => Some(BulbState::white(
+                state.on,
+                value.clamp(WARMEST_KELVIN, COOLEST_KELVIN),
+                state.brightness,
+            )),
+        }
+    }
+}
+
+// An attribute of the light sent as a parameter of the avatar's choosing
+#[derive(Debug)]
+pub struct Parameter {
+    pub address: Address,
+    attribute: Attribute,
+    kind: ParameterType,
+    input: [f32; 2],
+    range: [f32; 2],
+    gamma: f32,
+}
+
+impl Parameter {
+    fn new(prefix: &str, attribute: Attribute, config: &ParameterConfig) -> Parameter {
+        let input = config.input.unwrap_or(attribute.range());
+        let range = config.range.unwrap_or(match config.kind {
+            ParameterType::Int => [0.0, 255.0],
+            _ => [0.0, 1.0],
+        });
+        let address = if config.address.starts_with('/') {
+            Address::new(&config.address)
+        } else {
+            Address::new(&(prefix.to_owned() + &config.address))
+        };
+        Parameter {
+            address,
+            attribute,
+            kind: config.kind,
+            input,
+            range,
+            gamma: config.gamma,
+        }
+    }
+
+    pub fn value(&self, state: &BulbState) -> Type {
+        let [start, end] = self.input;
+        let position = ((self.attribute.value(state) - start) / (end - start))
+            .clamp(0.0, 1.0)
+            .powf(self.gamma);
+        let value = translate(position, 0.0, 1.0, self.range[0], self.range[1]);
+        match self.kind {
+            ParameterType::Bool => Type::Bool(position >= 0.5),
+            ParameterType::Float => Type::Float(value),
+            ParameterType::Int => Type::Int(value.round() as i32),
+        }
+    }
+
+    // The state the parameter having the value stands for, None if it's not
+    // something the parameter could have been sent as
+    pub fn read(&self, arg: &Type, current: &BulbState) -> Option<BulbState> {
+        let position = match (self.kind, arg) {
+            (ParameterType::Bool, Type::Bool(value)) => {
+                if *value {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            (ParameterType::Float | ParameterType::Int, Type::Float(value)) => *value,
+            (ParameterType::Float | ParameterType::Int, Type::Int(value)) => *value as f32,
+            _ => return None,
+        };
+        let position = match self.kind {
+            ParameterType::Bool => position,
+            _ => translate(position, self.range[0], self.range[1], 0.0, 1.0)
+                .clamp(0.0, 1.0)
+                .powf(1.0 / self.gamma),
+        };
+        let value = translate(position, 0.0, 1.0, self.input[0], self.input[1]);
+        self.attribute.apply(current, value)
+    }
+}
+
+impl ParametersConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        // Without a prefix the addresses are named like in the settings
+        for parameter in self.parameters("") {
+            let [start, end] = parameter.input;
+            let [low, high] = parameter.range;
+            if start == end || low == high {
+                return Err(format!(
+                    "The input and range of the {} parameter need different start and end values.",
+                    parameter.address.as_str()
+                ));
+            }
+            if parameter.gamma <= 0.0 {
+                return Err(format!(
+                    "The gamma of the {} parameter has to be above 0.",
+                    parameter.address.as_str()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Every parameter, in the order of the attributes
+    pub fn parameters(&self, prefix: &str) -> Vec<Parameter> {
+        [
+            (Attribute::On, &self.on),
+            (Attribute::Hue, &self.hue),
+            (Attribute::Saturation, &self.saturation),
+            (Attribute::Brightness, &self.brightness),
+            (Attribute::ColorTemp, &self.color_temp),
+        ]
+        .into_iter()
+        .flat_map(|(attribute, configs)| {
+            configs
+                .iter()
+                .map(move |config| Parameter::new(prefix, attribute, config))
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter(attribute: Attribute, kind: ParameterType) -> ParameterConfig {
+        ParameterConfig {
+            address: format!("{:?}", attribute),
+            kind,
+            input: None,
+            range: None,
+            gamma: 1.0,
+        }
+    }
+
+    #[test]
+    fn values_read_back() {
+        let current = BulbState::color(false, 0.0, 0.0);
+        let cases = [
+            (Attribute::On, BulbState::color(true, 0.0, 0.0)),
+            (Attribute::Hue, BulbState::color(false, 0.7, 0.0)),
+            (
+                Attribute::Saturation,
+                BulbState::new(false, (0.0, 0.3, None), 0.0),
+            ),
+            (Attribute::Brightness, BulbState::color(false, 0.0, 0.6)),
+            (Attribute::ColorTemp, BulbState::white(false, 3000.0, 0.0)),
+        ];
+        let float_ranges = [None, Some([1.0, 0.0]), Some([-1.0, 1.0])];
+        let int_ranges = [None, Some([255.0, 0.0]), Some([-100.0, 100.0])];
+        for (kind, ranges, within) in [
+            (ParameterType::Float, float_ranges, 0.001),
+            // Ints only have so many steps
+            (ParameterType::Int, int_ranges, 0.02),
+        ] {
+            for range in ranges {
+                for gamma in [1.0, 2.2, 0.5] {
+                    for (attribute, state) in cases {
+                        let config = ParameterConfig {
+                            range,
+                            gamma,
+                            ..parameter(attribute, kind)
+                        };
+                        let parameter = Parameter::new("/avatar/parameters/", attribute, &config);
+                        let value = parameter.value(&state);
+                        let read = parameter.read(&value, &current).unwrap();
+                        let [start, end] = attribute.range();
+                        let off =
+                            (attribute.value(&read) - attribute.value(&state)) / (end - start);
+                        assert!(off.abs() < within, "{:?} read {:?}", config, read);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bools_read_back() {
+        let current = BulbState::color(true, 0.2, 0.5);
+        let config = parameter(Attribute::On, ParameterType::Bool);
+        let on = Parameter::new("/", Attribute::On, &config);
+        assert_eq!(on.value(&current), Type::Bool(true));
+        let off = on.read(&Type::Bool(false), &current).unwrap();
+        assert_eq!(
+            off,
+            BulbState {
+                on: false,
+                ..current
+            }
+        );
+        let config = parameter(Attribute::Brightness, ParameterType::Bool);
+        let bright = Parameter::new("/", Attribute::Brightness, &config);
+        assert_eq!(
+            bright.value(&BulbState::color(true, 0.2, 0.4)),
+            Type::Bool(false)
+        );
+        assert_eq!(
+            bright.read(&Type::Bool(true), &current).unwrap().brightness,
+            1.0
+        );
+    }
+
+    #[test]
+    fn uses_the_range_and_input() {
+        let config = ParameterConfig {
+            input: Some([0.0, 0.5]),
+            range: Some([10.0, 20.0]),
+            ..parameter(Attribute::Brightness, ParameterType::Int)
+        };
+        let parameter = Parameter::new("/", Attribute::Brightness, &config);
+        assert_eq!(
+            parameter.value(&BulbState::color(true, 0.0, 0.25)),
+            Type::Int(15)
+        );
+        // Past the end of the input it stays at the end of the range
+        assert_eq!(
+            parameter.value(&BulbState::color(true, 0.0, 1.0)),
+            Type::Int(20)
+        );
+        let current = BulbState::color(true, 0.0, 0.0);
+        assert_eq!(
+            parameter.read(&Type::Int(20), &current).unwrap().brightness,
+            0.5
+        );
+        assert_eq!(
+            parameter.read(&Type::Int(99), &current).unwrap().brightness,
+            0.5
+        );
+        assert_eq!(
+            parameter
+                .read(&Type::Float(-3.0), &current)
+                .unwrap()
+                .brightness,
+            0.0
+        );
+    }
+
+    #[test]
+    fn other_types_are_none() {
+        let current = BulbState::color(true, 0.0, 0.0);
+        let float = Parameter::new(
+            "/",
+            Attribute::Hue,
+            &parameter(Attribute::Hue, ParameterType::Float),
+        );
+        let bool = Parameter::new(
+            "/",
+            Attribute::On,
+            &parameter(Attribute::On, ParameterType::Bool),
+        );
+        assert_eq!(float.read(&Type::Bool(true), &current), None);
+        assert_eq!(float.read(&Type::String("0.5".to_owned()), &current), None);
+        assert_eq!(bool.read(&Type::Float(1.0), &current), None);
+        assert_eq!(bool.read(&Type::Int(1), &current), None);
+    }
+
+    #[test]
+    fn colors_turn_whites_back_into_colors() {
+        let white = BulbState::white(true, 2700.0, 1.0);
+        let hue = Parameter::new(
+            "/",
+            Attribute::Hue,
+            &parameter(Attribute::Hue, ParameterType::Float),
+        );
+        let read = hue.read(&Type::Float(0.5), &white).unwrap();
+        assert_eq!(read.color_temp, None);
+        assert_eq!(read.hue, 0.5);
+        // Hue wraps around
+        assert_eq!(hue.read(&Type::Float(1.0), &white).unwrap().hue, 0.0);
+    }
+
+    #[test]
+    fn addresses_go_after_the_prefix() {
+        let mut config = parameter(Attribute::Hue, ParameterType::Float);
+        config.address = "LightHue".to_owned();
+        let parameter = Parameter::new("/avatar/parameters/", Attribute::Hue, &config);
+        assert_eq!(parameter.address.as_str(), "/avatar/parameters/LightHue");
+        config.address = "/custom/hue".to_owned();
+        let parameter = Parameter::new("/avatar/parameters/", Attribute::Hue, &config);
+        assert_eq!(parameter.address.as_str(), "/custom/hue");
+    }
+
+    #[test]
+    fn rejects_ranges_that_go_nowhere() {
+        let mut config = ParametersConfig::default();
+        assert_eq!(config.validate(), Ok(()));
+        config.hue.push(ParameterConfig {
+            range: Some([1.0, 1.0]),
+            ..parameter(Attribute::Hue, ParameterType::Float)
+        });
+        assert!(config.validate().is_err());
+        config.hue[0].range = None;
+        config.hue[0].gamma = 0.0;
+        assert!(config.validate().is_err());
+        config.hue[0].gamma = 1.0;
+        config.hue[0].input = Some([0.5, 0.5]);
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use super::address::Address;
+use crate::config::validate_rate;
+use nannou_osc::Type;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Instant;
+
+fn default_burst() -> f32 {
+    2.0
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    // Messages per second each address may send on average
+    pub rate: f32,
+    // How many messages an address can send in a row after being quiet
+    #[serde(default = "default_burst")]
+    pub burst: f32,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_rate("osc_rate_limit.rate", self.rate, 10_000.0)?;
+        if !(1.0..=10_000.0).contains(&self.burst) {
+            return Err("osc_rate_limit needs a burst of at least 1 and at most 10000.".into());
+        }
+        Ok(())
+    }
+}
+
+struct TokenBucket {
+    tokens: f32,
+    last_refill: Instant,
+}
+
+fn try_take(
+    buckets: &mut HashMap<Address, TokenBucket>,
+    rate: f32,
+    burst: f32,
+    addr: &Address,
+    now: Instant,
+) -> bool {
+    let bucket = match buckets.get_mut(addr) {
+        Some(bucket) => bucket,
+        None => buckets.entry(addr.clone()).or_insert(TokenBucket {
+            tokens: burst,
+            last_refill: now,
+        }),
+    };
+    let elapsed = now.duration_since(bucket.last_refill).as_secs_f32();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.last_refill = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        true
+    } else {
+        false
+    }
+}
+
+// Limits how often each OSC address is sent separately. Values that don't fit
+// are held back and only the newest one per address is sent once there's room.
+pub struct RateLimiter {
+    rate: f32,
+    burst: f32,
+    buckets: HashMap<Address, TokenBucket>,
+    pending: Vec<(Address, Type)>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            rate: config.rate,
+            burst: config.burst,
+            buckets: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Returns the messages that can go out now in the same list, holding back
+    // the rest
+    pub fn submit(&mut self, messages: &mut Vec<(Address, Type)>, now: Instant) {
+        for (addr, arg) in messages.drain(..) {
+            match self
+                .pending
+                .iter_mut()
+                .find(|(pending, _)| *pending == addr)
+            {
+                Some(pending) => pending.1 = arg,
+                None => self.pending.push((addr, arg)),
+            }
+        }
+        self.flush(messages, now);
+    }
+
+    // Whether nothing is held back
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Adds the held back messages that can go out now to ready
+    pub fn flush(&mut self, ready: &mut Vec<(Address, Type)>, now: Instant) {
+        let RateLimiter {
+            rate,
+            burst,
+            buckets,
+            pending,
+        } = self;
+        ready.extend(
+            pending.extract_if(.., |(addr, _)| try_take(buckets, *rate, *burst, addr, now)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use std::time::{Duration, SystemTime};
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            rate: 2.0,
+            burst: 2.0,
+        })
+    }
+
+    fn message(addr: &Address, value: f32) -> Vec<(Address, Type)> {
+        vec![(addr.clone(), Type::Float(value))]
+    }
+
+    #[test]
+    fn holds_back_after_the_burst() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let mut limiter = limiter();
+        let addr = Address::new("/avatar/parameters/Color");
+        for value in [0.1, 0.2] {
+            let mut messages = message(&addr, value);
+            limiter.submit(&mut messages, clock.now());
+            assert_eq!(messages, message(&addr, value));
+        }
+        let mut messages = message(&addr, 0.3);
+        limiter.submit(&mut messages, clock.now());
+        assert!(messages.is_empty());
+        // Only the newest held back value goes out once there's room
+        limiter.submit(&mut message(&addr, 0.4), clock.now());
+        clock.advance(Duration::from_millis(400));
+        let mut ready = Vec::new();
+        limiter.flush(&mut ready, clock.now());
+        assert!(ready.is_empty());
+        clock.advance(Duration::from_millis(100));
+        limiter.flush(&mut ready, clock.now());
+        assert_eq!(ready, message(&addr, 0.4));
+        limiter.flush(&mut ready, clock.now());
+        assert_eq!(ready.len(), 1);
+    }
+
+    #[test]
+    fn addresses_have_their_own_buckets() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let mut limiter = limiter();
+        let color = Address::new("/avatar/parameters/Color");
+        let brightness = Address::new("/avatar/parameters/brightness");
+        for _ in 0..2 {
+            limiter.submit(&mut message(&color, 0.5), clock.now());
+        }
+        let mut messages = message(&brightness, 0.5);
+        limiter.submit(&mut messages, clock.now());
+        assert_eq!(messages, message(&brightness, 0.5));
+    }
+
+    #[test]
+    fn refills_no_further_than_the_burst() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let mut limiter = limiter();
+        let addr = Address::new("/avatar/parameters/on");
+        limiter.submit(&mut message(&addr, 1.0), clock.now());
+        clock.advance(Duration::from_secs(60));
+        let mut sent = 0;
+        for _ in 0..5 {
+            let mut messages = message(&addr, 1.0);
+            limiter.submit(&mut messages, clock.now());
+            sent += messages.len();
+        }
+        assert_eq!(sent, 2);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::clock;
+use crate::config::validate_seconds;
+use crate::discovery;
+use crate::logging::{self, Category};
+use serde::Deserialize;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+fn default_resolve_interval() -> f32 {
+    30.0
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RemoteTargetConfig {
+    // Seconds between looking vrchat_ip up again, in case its address changed
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: f32,
+    // Port of VRChat's OSCQuery server, used to check that VRChat is reachable
+    // and to find the port it's listening for OSC on
+    pub oscquery_port: Option<u16>,
+}
+
+impl RemoteTargetConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_seconds(
+            "vrchat_target.resolve_interval",
+            self.resolve_interval,
+            false,
+        )
+    }
+}
+
+// The part of OSCQuery's HOST_INFO we care about
+#[derive(Debug, Deserialize)]
+pub struct HostInfo {
+    #[serde(rename = "OSC_PORT")]
+    pub osc_port: u16,
+    // Left out when OSC is on the same address as the OSCQuery server
+    #[serde(rename = "OSC_IP")]
+    pub osc_ip: Option<String>,
+}
+
+impl HostInfo {
+    // Where to send OSC, for the OSCQuery server at oscquery. A loopback or
+    // unspecified OSC_IP is only right from VRChat's own computer, from here
+    // that's the OSCQuery server's address.
+    pub fn osc_addr(&self, oscquery: SocketAddr) -> SocketAddr {
+        let ip = self
+            .osc_ip
+            .as_deref()
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .filter(|ip| !ip.is_loopback() && !ip.is_unspecified())
+            .unwrap_or(oscquery.ip());
+        SocketAddr::new(ip, self.osc_port)
+    }
+}
+
+// Prefers an IPv4 address when the host has both, VRChat usually listens on
+// those
+fn resolve(host: &str, port: u16) -> Option<SocketAddr> {
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs().ok()?.collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+}
+
+pub fn host_info(addr: &SocketAddr, oscquery_port: u16) -> Result<HostInfo, Box<dyn Error>> {
+    let url = format!(
+        "http://{}/?HOST_INFO",
+        SocketAddr::new(addr.ip(), oscquery_port)
+    );
+    let body = reqwest::blocking::Client::new()
+        .get(url)
+        .timeout(Duration::from_secs(2))
+        .send()?
+        .error_for_status()?
+        .text()?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+// Looks the target up on a thread of its own, since DNS and OSCQuery can take
+// seconds to answer or time out
+struct Lookup {
+    host: String,
+    port: u16,
+    oscquery_port: Option<u16>,
+    // The newest address, None until the host could be looked up
+    addr: Option<SocketAddr>,
+    lookup_failed: bool,
+    reachable: bool,
+}
+
+impl Lookup {
+    fn check(&mut self) {
+        let mut addr = match resolve(&self.host, self.port) {
+            Some(addr) => addr,
+            None => {
+                self.lookup_failed = true;
+                let keeping = match self.addr {
+                    Some(addr) => format!("keeping {}", addr),
+                    None => "not sending OSC until it can be".to_owned(),
+                };
+                logging::error(
+                    Category::Network,
+                    format!("Couldn't look up {}, {}", self.host, keeping),
+                );
+                return;
+            }
+        };
+        if let Some(oscquery_port) = self.oscquery_port {
+            match host_info(&addr, oscquery_port) {
+                Ok(info) => {
+                    if !self.reachable {
+                        println!("VRChat at {} is reachable again", addr.ip());
+                    }
+                    self.reachable = true;
+                    addr = info.osc_addr(addr);
+                }
+                Err(err) => {
+                    if self.reachable {
+                        println!(
+                            "VRChat's OSCQuery server at {} isn't reachable: {}",
+                            addr.ip(),
+                            err
+                        );
+                    }
+                    self.reachable = false;
+                    // Stick with where we already sent to
+                    addr.set_port(self.addr.map_or(self.port, |addr| addr.port()));
+                }
+            }
+        }
+        match self.addr {
+            Some(old) if old != addr => println!("VRChat moved from {} to {}", old, addr),
+            None if self.lookup_failed => {
+                println!("Found {} at {}, sending OSC to it", self.host, addr)
+            }
+            _ => {}
+        }
+        self.addr = Some(addr);
+    }
+}
+
+// Where the lookups last found VRChat, shared by every target looking up the
+// same thing
+type Found = Mutex<Option<SocketAddr>>;
+
+// The lookups running in the background, each thread stops once no target
+// uses it anymore
+static LOOKUPS: Mutex<Vec<(String, Weak<Found>)>> = Mutex::new(Vec::new());
+
+// Where VRChat is, kept up to date for when it's on another computer or a Quest
+// whose address or port can change while syncing
+pub struct RemoteTarget {
+    // Where the lookups last found it, shared with their thread which stops
+    // once the target is dropped
+    found: Arc<Found>,
+    addr: Option<SocketAddr>,
+    // Whether to go where discovery last found VRChat, over host and port
+    discover: bool,
+}
+
+impl RemoteTarget {
+    pub fn new(
+        addr: &str,
+        config: Option<&RemoteTargetConfig>,
+        discover: bool,
+    ) -> Result<RemoteTarget, String> {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.to_owned(), port.parse().ok()?)))
+            .ok_or_else(|| format!("{} isn't a valid address to send OSC to.", addr))?;
+        // IPv6 addresses can be written in brackets like in URLs
+        let host = match host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+        {
+            Some(inside) => inside.to_owned(),
+            None => host,
+        };
+        // Hostnames are looked up again even without a vrchat_target section,
+        // their address can change when they're a DDNS name or get it from DHCP.
+        // One that can't be looked up yet, like before the network is up, is
+        // tried again the same way.
+        let resolve_interval = match config {
+            Some(config) => Some(config.resolve_interval),
+            None if host.parse::<IpAddr>().is_err() => Some(default_resolve_interval()),
+            None => None,
+        };
+        let mut lookup = Lookup {
+            host,
+            port,
+            oscquery_port: config.and_then(|config| config.oscquery_port),
+            addr: None,
+            lookup_failed: false,
+            reachable: true,
+        };
+        let mut target = RemoteTarget {
+            found: Arc::new(Mutex::new(None)),
+            addr: None,
+            discover,
+        };
+        match resolve_interval {
+            // Only an IP address, nothing to wait for
+            None => {
+                lookup.check();
+                target.addr = lookup.addr;
+            }
+            Some(interval) => {
+                let key = format!(
+                    "{} {} {:?} {}",
+                    addr, interval, lookup.oscquery_port, discover
+                );
+                let mut lookups = LOOKUPS.lock().unwrap();
+                lookups.retain(|(_, found)| found.strong_count() > 0);
+                // Every light's output and the multiplexer look up the same
+                // thing, they share the thread and what it found
+                if let Some(found) = lookups
+                    .iter()
+                    .find(|(running, _)| *running == key)
+                    .and_then(|(_, found)| found.upgrade())
+                {
+                    target.addr = *found.lock().unwrap();
+                    target.found = found;
+                    target.discovered();
+                    return Ok(target);
+                }
+                lookups.push((key, Arc::downgrade(&target.found)));
+                let found = Arc::downgrade(&target.found);
+                let interval = Duration::from_secs_f32(interval);
+                clock::spawn(move || loop {
+                    // Discovery already keeps track of where it is
+                    if !(discover && discovery::current().is_some()) {
+                        lookup.check();
+                    }
+                    match found.upgrade() {
+                        Some(found) => *found.lock().unwrap() = lookup.addr,
+                        None => return,
+                    }
+                    clock::sleep(interval);
+                });
+            }
+        }
+        target.discovered();
+        Ok(target)
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    // Takes in where VRChat was last found, returns whether its address changed
+    pub fn refresh(&mut self) -> bool {
+        let old = self.addr;
+        if !self.discovered() {
+            if let Some(addr) = *self.found.lock().unwrap() {
+                self.addr = Some(addr);
+            }
+        }
+        old != self.addr
+    }
+
+    // Switches to where VRChat was discovered, returns false when it hasn't
+    // been and the host is used instead. Discovery already says when it moves.
+    fn discovered(&mut self) -> bool {
+        if !self.discover {
+            return false;
+        }
+        match discovery::current() {
+            Some(addr) => {
+                self.addr = Some(addr);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn host_info(osc_ip: Option<&str>) -> HostInfo {
+        HostInfo {
+            osc_port: 9123,
+            osc_ip: osc_ip.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn sends_osc_where_oscquery_says() {
+        let oscquery: SocketAddr = "192.168.1.20:54321".parse().unwrap();
+        let addr = |osc_ip| host_info(osc_ip).osc_addr(oscquery).to_string();
+        assert_eq!(addr(None), "192.168.1.20:9123");
+        assert_eq!(addr(Some("192.168.1.30")), "192.168.1.30:9123");
+        // Only true from VRChat's computer
+        assert_eq!(addr(Some("127.0.0.1")), "192.168.1.20:9123");
+        assert_eq!(addr(Some("0.0.0.0")), "192.168.1.20:9123");
+        assert_eq!(addr(Some("not an ip")), "192.168.1.20:9123");
+    }
+
+    #[test]
+    fn sends_to_ipv6_addresses() {
+        for addr in ["::1:9000", "[::1]:9000"] {
+            let target = RemoteTarget::new(addr, None, false).unwrap();
+            assert_eq!(target.addr(), Some("[::1]:9000".parse().unwrap()));
+        }
+        let config = RemoteTargetConfig {
+            resolve_interval: 30.0,
+            oscquery_port: None,
+        };
+        // Looked up again in the background, but only once for every target
+        let mut first = RemoteTarget::new("localhost:9000", Some(&config), false).unwrap();
+        let start = clock::now();
+        while first.addr().is_none() {
+            assert!(clock::elapsed(start) < Duration::from_secs(5));
+            clock::sleep(Duration::from_millis(10));
+            first.refresh();
+        }
+        let second = RemoteTarget::new("localhost:9000", Some(&config), false).unwrap();
+        assert!(Arc::ptr_eq(&first.found, &second.found));
+        assert_eq!(second.addr(), first.addr());
+    }
+
+    #[test]
+    fn follows_vrchat_to_the_port_oscquery_reports() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let oscquery_port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // Up to the empty line after the headers
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    line.clear();
+                }
+                let body = r#"{"OSC_PORT": 9123, "OSC_IP": "127.0.0.1"}"#;
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .ok();
+            }
+        });
+        let config = RemoteTargetConfig {
+            resolve_interval: 30.0,
+            oscquery_port: Some(oscquery_port),
+        };
+        let mut target = RemoteTarget::new("127.0.0.1:9000", Some(&config), false).unwrap();
+        // Looked up in the background, so it doesn't hold up starting
+        let start = clock::now();
+        while !target.refresh() {
+            assert!(clock::elapsed(start) < Duration::from_secs(5));
+            clock::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(target.addr(), Some("127.0.0.1:9123".parse().unwrap()));
+    }
+}
@@ -0,0 +1,175 @@
+use super::address::Address;
+use super::osc_socket::OscSocket;
+use crate::clock;
+use crate::config::Config;
+use crate::logging::{self, Category};
+use nannou_osc::Type;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// How often the sending thread wakes up to check on the socket when nothing is
+// being sent
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Pending {
+    // The newest value for every address that hasn't been sent yet, in the
+    // order they were first queued
+    messages: Vec<(Address, Type)>,
+    // Where VRChat is, which can change while running
+    target: Option<SocketAddr>,
+    // Values that were replaced before they could be sent
+    skipped: usize,
+    // Whether the thread is sending what it last took out
+    sending: bool,
+    closed: bool,
+}
+
+struct Shared {
+    pending: Mutex<Pending>,
+    wake: Condvar,
+    // Notified when everything queued has been sent
+    idle: Condvar,
+    // Set when the socket was set up again and everything should be resent
+    reconnected: AtomicBool,
+}
+
+// Sends OSC from a background thread, so a stalled network can't hold up
+// syncing. If sending falls behind only the newest value of each parameter is
+// kept, so the avatar always ends up with the freshest state instead of
+// working through old ones.
+pub struct SendQueue {
+    shared: Arc<Shared>,
+}
+
+// Sends each message to VRChat and the multicast group, encoding it only once
+fn send_batch(
+    socket: &mut OscSocket,
+    messages: &[(Address, Type)],
+    targets: [Option<SocketAddr>; 2],
+    bytes: &mut Vec<u8>,
+) {
+    for (addr, arg) in messages {
+        if !addr.encode(arg, bytes) {
+            continue;
+        }
+        for target in targets.into_iter().flatten() {
+            if let Err(err) = socket.send_to(bytes, target) {
+                logging::error(
+                    Category::Output,
+                    format!(
+                        "Failed to send OSC to {}, setting it up again: {}",
+                        target, err
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn run(shared: &Shared, mut socket: OscSocket, multicast: Option<SocketAddr>) {
+    let mut target = None;
+    // Swapped with the pending messages so neither needs allocating again
+    let mut batch = Vec::new();
+    let mut bytes = Vec::new();
+    loop {
+        let skipped = {
+            let mut pending = shared.pending.lock().unwrap();
+            if pending.messages.is_empty() && !pending.closed {
+                pending = shared.wake.wait_timeout(pending, IDLE_CHECK).unwrap().0;
+            }
+            if pending.closed {
+                return;
+            }
+            target = pending.target.or(target);
+            pending.sending = !pending.messages.is_empty();
+            batch.clear();
+            std::mem::swap(&mut batch, &mut pending.messages);
+            std::mem::take(&mut pending.skipped)
+        };
+        if skipped > 0 {
+            logging::error(
+                Category::Output,
+                "Sending OSC fell behind, skipped outdated parameter values".to_owned(),
+            );
+        }
+        if let Some(target) = target {
+            if socket.refresh(target, clock::now()) {
+                shared.reconnected.store(true, Ordering::Relaxed);
+            }
+        }
+        send_batch(&mut socket, &batch, [target, multicast], &mut bytes);
+        shared.pending.lock().unwrap().sending = false;
+        shared.idle.notify_all();
+    }
+}
+
+impl SendQueue {
+    pub fn new(config: &Config, multicast: Option<SocketAddr>) -> SendQueue {
+        let socket = OscSocket::new(config);
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(Pending::default()),
+            wake: Condvar::new(),
+            idle: Condvar::new(),
+            reconnected: AtomicBool::new(false),
+        });
+        let thread_shared = shared.clone();
+        clock::spawn(move || run(&thread_shared, socket, multicast));
+        SendQueue { shared }
+    }
+
+    // Queues messages for VRChat at the target, replacing values that haven't
+    // been sent yet. The messages are left empty so the list can be used
+    // again. Without a target they only go to the multicast group.
+    pub fn push(&self, messages: &mut Vec<(Address, Type)>, target: Option<SocketAddr>) {
+        let mut pending = self.shared.pending.lock().unwrap();
+        for (addr, arg) in messages.drain(..) {
+            match pending
+                .messages
+                .iter()
+                .position(|(known, _)| *known == addr)
+            {
+                Some(i) => {
+                    pending.messages[i].1 = arg;
+                    pending.skipped += 1;
+                }
+                None => pending.messages.push((addr, arg)),
+            }
+        }
+        if target.is_some() {
+            pending.target = target;
+        }
+        self.shared.wake.notify_one();
+    }
+
+    // Whether the socket was set up again since the last time this was
+    // checked, in which case everything needs to be sent again
+    pub fn take_reconnected(&self) -> bool {
+        self.shared.reconnected.swap(false, Ordering::Relaxed)
+    }
+}
+
+// How long dropping the queue waits for what's still queued to be sent, like
+// when exiting right after sending with --oneshot
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        let start = clock::now();
+        let mut pending = self.shared.pending.lock().unwrap();
+        while (!pending.messages.is_empty() || pending.sending)
+            && clock::elapsed(start) < DRAIN_TIMEOUT
+        {
+            pending = self
+                .shared
+                .idle
+                .wait_timeout(pending, DRAIN_TIMEOUT)
+                .unwrap()
+                .0;
+        }
+        pending.closed = true;
+        self.shared.wake.notify_one();
+    }
+}
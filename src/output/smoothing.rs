@@ -0,0 +1,244 @@
+use super::address::Address;
+use crate::clock;
+use crate::config::validate_seconds;
+use nannou_osc::Type;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Parameters closer than this to where they're going are snapped to it
+const SETTLED: f32 = 0.001;
+
+struct Smoothed {
+    time_constant: f32,
+    // Hues wrap around from 1 to 0 so they take the short way around
+    wraps: bool,
+    value: Option<f32>,
+    target: f32,
+    last_step: Instant,
+}
+
+impl Smoothed {
+    fn step(&mut self, now: Instant) -> f32 {
+        let dt = now.duration_since(self.last_step).as_secs_f32();
+        self.last_step = now;
+        let value = match self.value {
+            // The first value is sent as it is
+            None => self.target,
+            Some(value) => {
+                let mut diff = self.target - value;
+                if self.wraps {
+                    diff = (diff + 0.5).rem_euclid(1.0) - 0.5;
+                }
+                if diff.abs() < SETTLED || self.time_constant == 0.0 {
+                    self.target
+                } else {
+                    let moved = value + diff * (1.0 - (-dt / self.time_constant).exp());
+                    if self.wraps {
+                        moved.rem_euclid(1.0)
+                    } else {
+                        moved
+                    }
+                }
+            }
+        };
+        self.value = Some(value);
+        value
+    }
+
+    fn settled(&self) -> bool {
+        self.value == Some(self.target)
+    }
+}
+
+// Checks the time constants by parameter name
+pub fn validate(time_constants: &HashMap<String, f32>) -> Result<(), String> {
+    for (name, time_constant) in time_constants {
+        if name == "on" {
+            return Err("on can't be smoothed, it's a Bool.".into());
+        }
+        validate_seconds(
+            &format!("The smoothing time of {}", name),
+            *time_constant,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+// Eases float parameters towards their newest value, each with its own time
+// constant, the seconds it takes to get about two thirds of the way there
+pub struct Smoother {
+    parameters: HashMap<Address, Smoothed>,
+}
+
+impl Smoother {
+    // Takes time constants by parameter name, the prefix is put in front
+    pub fn new(prefix: &str, time_constants: &HashMap<String, f32>) -> Smoother {
+        let now = clock::now();
+        let parameters = time_constants
+            .iter()
+            .map(|(name, time_constant)| {
+                let smoothed = Smoothed {
+                    time_constant: *time_constant,
+                    wraps: name == "Color" || name == "LastColor",
+                    value: None,
+                    target: 0.0,
+                    last_step: now,
+                };
+                (Address::new(&(prefix.to_owned() + name)), smoothed)
+            })
+            .collect();
+        Smoother { parameters }
+    }
+
+    // Sets where smoothed parameters are going, the ones that have to move
+    // are taken out of the messages and left for step to send
+    pub fn apply(&mut self, messages: &mut Vec<(Address, Type)>, now: Instant) {
+        messages.retain_mut(|(addr, arg)| {
+            let (smoothed, target) = match (self.parameters.get_mut(addr), &*arg) {
+                (Some(smoothed), Type::Float(target)) => (smoothed, *target),
+                _ => return true,
+            };
+            if smoothed.settled() {
+                // Start moving from now on rather than from when it last moved
+                smoothed.last_step = now;
+            }
+            smoothed.target = target;
+            match smoothed.value {
+                Some(value) if value != target => false,
+                _ => {
+                    *arg = Type::Float(smoothed.step(now));
+                    true
+                }
+            }
+        });
+    }
+
+    // Adds the next step of every parameter that hasn't got where it's going
+    // yet to the messages
+    pub fn step(&mut self, now: Instant, messages: &mut Vec<(Address, Type)>) {
+        messages.extend(
+            self.parameters
+                .iter_mut()
+                .filter(|(_, smoothed)| smoothed.value.is_some() && !smoothed.settled())
+                .map(|(addr, smoothed)| (addr.clone(), Type::Float(smoothed.step(now)))),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn smoother(time_constants: &[(&str, f32)]) -> Smoother {
+        let time_constants = time_constants
+            .iter()
+            .map(|(name, time_constant)| (name.to_string(), *time_constant))
+            .collect();
+        Smoother::new("/avatar/parameters/", &time_constants)
+    }
+
+    fn message(name: &str, value: f32) -> Vec<(Address, Type)> {
+        vec![(
+            Address::new(&("/avatar/parameters/".to_owned() + name)),
+            Type::Float(value),
+        )]
+    }
+
+    fn float(messages: &[(Address, Type)]) -> f32 {
+        assert_eq!(messages.len(), 1);
+        match messages[0].1 {
+            Type::Float(value) => value,
+            ref arg => panic!("sent as {:?}", arg),
+        }
+    }
+
+    #[test]
+    fn eases_towards_the_newest_value() {
+        let start = Instant::now();
+        let mut smoother = smoother(&[("LightBrightness", 1.0)]);
+        // The first value goes out as it is
+        let mut messages = message("LightBrightness", 0.0);
+        smoother.apply(&mut messages, start);
+        assert_eq!(float(&messages), 0.0);
+        // Later ones are left for step
+        let mut messages = message("LightBrightness", 1.0);
+        smoother.apply(&mut messages, start);
+        assert!(messages.is_empty());
+        smoother.step(start + SECOND, &mut messages);
+        assert!((float(&messages) - (1.0 - (-1.0f32).exp())).abs() < 1e-4);
+        // Snaps to the end once close, and stops sending
+        let mut now = start + SECOND;
+        let mut last = float(&messages);
+        for _ in 0..20 {
+            messages.clear();
+            now += SECOND;
+            smoother.step(now, &mut messages);
+            if messages.is_empty() {
+                break;
+            }
+            last = float(&messages);
+        }
+        assert!(messages.is_empty());
+        assert_eq!(last, 1.0);
+    }
+
+    #[test]
+    fn starts_moving_when_the_value_changes() {
+        let start = Instant::now();
+        let mut smoother = smoother(&[("LightBrightness", 1.0)]);
+        smoother.apply(&mut message("LightBrightness", 0.0), start);
+        // Settled for a long time before the new value comes in
+        let later = start + SECOND * 60;
+        smoother.apply(&mut message("LightBrightness", 1.0), later);
+        let mut messages = Vec::new();
+        smoother.step(later + SECOND, &mut messages);
+        assert!(float(&messages) < 0.7);
+    }
+
+    #[test]
+    fn hues_go_the_short_way_around() {
+        let start = Instant::now();
+        let mut smoother = smoother(&[("Color", 1.0)]);
+        smoother.apply(&mut message("Color", 0.9), start);
+        smoother.apply(&mut message("Color", 0.1), start);
+        let mut messages = Vec::new();
+        smoother.step(start + SECOND / 2, &mut messages);
+        let value = float(&messages);
+        assert!(!(0.1..=0.9).contains(&value), "went through {}", value);
+    }
+
+    #[test]
+    fn leaves_the_rest_alone() {
+        let start = Instant::now();
+        let mut smoother = smoother(&[("LightBrightness", 1.0), ("LightHue", 0.0)]);
+        smoother.apply(&mut message("LightBrightness", 0.0), start);
+        // Not smoothed and not a float
+        let mut messages = message("LightSaturation", 0.5);
+        messages.push((
+            Address::new("/avatar/parameters/LightBrightness"),
+            Type::Int(3),
+        ));
+        smoother.apply(&mut messages, start);
+        let args: Vec<_> = messages.into_iter().map(|(_, arg)| arg).collect();
+        assert_eq!(args, [Type::Float(0.5), Type::Int(3)]);
+        // A time constant of 0 jumps straight there on the next step
+        smoother.apply(&mut message("LightHue", 0.2), start);
+        smoother.apply(&mut message("LightHue", 0.8), start);
+        let mut messages = Vec::new();
+        smoother.step(start, &mut messages);
+        assert_eq!(float(&messages), 0.8);
+    }
+
+    #[test]
+    fn rejects_what_cant_be_smoothed() {
+        let time_constants =
+            |name: &str, time_constant| HashMap::from([(name.to_owned(), time_constant)]);
+        assert_eq!(validate(&time_constants("LightBrightness", 0.5)), Ok(()));
+        assert!(validate(&time_constants("on", 0.5)).is_err());
+        assert!(validate(&time_constants("LightBrightness", -1.0)).is_err());
+    }
+}
@@ -0,0 +1,511 @@
+use super::address::Address;
+use super::derived::Metric;
+use super::lut::ColorGrading;
+use super::packed::PackedConfig;
+use super::parameters::Parameter;
+use super::rate_limit::RateLimiter;
+use super::remote::RemoteTarget;
+use super::send_queue::SendQueue;
+use super::smoothing::Smoother;
+use super::Output;
+use crate::clock;
+use crate::config::{Config, LightConfig};
+use crate::logging;
+use crate::state::{translate, BulbState, COOLEST_KELVIN, WARMEST_KELVIN};
+use nannou_osc::Type;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Synced float parameters only have this many steps between 0 and 1
+const SYNCED_FLOAT_STEPS: f32 = 127.0;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HueOutput {
+    // The hue from 0 to 1 in the Color parameter
+    #[default]
+    Color,
+    // The sine and cosine of the hue angle in ColorSin and ColorCos, which
+    // blend trees can interpolate without a seam where the hue wraps around
+    SinCos,
+    Both,
+}
+
+fn default_loopback() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MulticastConfig {
+    pub group: Ipv4Addr,
+    pub port: u16,
+    // Also deliver to listeners on this computer
+    #[serde(default = "default_loopback")]
+    pub loopback: bool,
+}
+
+pub struct VrchatOutput {
+    queue: SendQueue,
+    remote: RemoteTarget,
+    prefix: String,
+    parameters: Parameters,
+    packed: Option<(PackedConfig, Address)>,
+    // Sent instead of the on, Color and brightness parameters when set
+    mapped: Option<Vec<Parameter>>,
+    hue_output: HueOutput,
+    last_color: bool,
+    full_color: bool,
+    // Hue and brightness from the last time the light was on
+    last_lit: Option<(f32, f32)>,
+    grading: Option<ColorGrading>,
+    derived: Vec<(Address, Metric)>,
+    smoother: Option<Smoother>,
+    limiter: Option<RateLimiter>,
+    quantize: bool,
+    // What each address was last sent, to skip sends that change nothing
+    last_sent: HashMap<Address, Type>,
+    // What each address last had and when it was sent, to tell VRChat
+    // echoing it back apart from changes made on the avatar. Only kept while
+    // receiving, values that came from the avatar have no time.
+    echoes: Option<HashMap<Address, (Type, Option<Instant>)>>,
+    // Everything sent since the last drain_sent, for control clients
+    sent: Vec<(Address, Type)>,
+    // Whether the light's parameters go through the multiplexer instead of
+    // straight to VRChat
+    multiplexed: bool,
+    // The newest value of each parameter for the multiplexer, without the
+    // prefix
+    frame: Vec<(String, Type)>,
+    // Kept between sends so they don't need allocating every time
+    buffer: Vec<(Address, Type)>,
+}
+
+// The addresses of the light's own parameters, with the prefix in front
+struct Parameters {
+    on: Address,
+    color: Address,
+    color_sin: Address,
+    color_cos: Address,
+    brightness: Address,
+    last_color: Address,
+    last_brightness: Address,
+    saturation: Address,
+    color_temp: Address,
+}
+
+impl Parameters {
+    fn new(prefix: &str) -> Parameters {
+        let address = |name: &str| Address::new(&(prefix.to_owned() + name));
+        Parameters {
+            on: address("on"),
+            color: address("Color"),
+            color_sin: address("ColorSin"),
+            color_cos: address("ColorCos"),
+            brightness: address("brightness"),
+            last_color: address("LastColor"),
+            last_brightness: address("LastBrightness"),
+            saturation: address("Saturation"),
+            color_temp: address("ColorTemp"),
+        }
+    }
+}
+
+// Where the color temperature is from 0 at the warmest to 1 at the coolest
+fn color_temp_position(state: &BulbState) -> f32 {
+    translate(state.kelvin(), WARMEST_KELVIN, COOLEST_KELVIN, 0.0, 1.0).clamp(0.0, 1.0)
+}
+
+impl VrchatOutput {
+    // The light's settings have to be validated already
+    pub fn new(addr: &str, config: &Config, light: &LightConfig) -> Result<VrchatOutput, String> {
+        let multicast_addr = config
+            .osc_multicast
+            .as_ref()
+            .map(|multicast| SocketAddr::from((multicast.group, multicast.port)));
+        Ok(VrchatOutput {
+            queue: SendQueue::new(config, multicast_addr),
+            remote: RemoteTarget::new(
+                addr,
+                config.vrchat_target.as_ref(),
+                config.vrchat_autodiscover,
+            )?,
+            prefix: light.parameter_prefix.clone(),
+            parameters: Parameters::new(&light.parameter_prefix),
+            packed: light
+                .packed
+                .clone()
+                .map(|packed| (packed.clone(), Address::new(&packed.parameter))),
+            mapped: light
+                .parameters
+                .as_ref()
+                .map(|parameters| parameters.parameters(&light.parameter_prefix)),
+            hue_output: light.hue_output,
+            last_color: light.last_color,
+            full_color: light.full_color,
+            last_lit: None,
+            grading: light.lut.as_ref().map(ColorGrading::new).transpose()?,
+            derived: light.derived.as_ref().map_or_else(Vec::new, |derived| {
+                derived.parameters(&light.parameter_prefix)
+            }),
+            smoother: if light.smoothing.is_empty() {
+                None
+            } else {
+                Some(Smoother::new(&light.parameter_prefix, &light.smoothing))
+            },
+            limiter: config.osc_rate_limit.as_ref().map(RateLimiter::new),
+            quantize: config.quantize_floats,
+            last_sent: HashMap::new(),
+            echoes: config.osc_receive.as_ref().map(|_| HashMap::new()),
+            sent: Vec::new(),
+            multiplexed: config.multiplex.is_some(),
+            frame: Vec::new(),
+            buffer: Vec::new(),
+        })
+    }
+
+    // The output the multiplexer sends the shared parameters through, the
+    // multiplexer does its own timing so there's no rate limit
+    pub fn new_multiplexer(
+        addr: &str,
+        config: &Config,
+        prefix: &str,
+    ) -> Result<VrchatOutput, String> {
+        let light = LightConfig {
+            parameter_prefix: prefix.to_owned(),
+            ..Default::default()
+        };
+        let mut output = VrchatOutput::new(addr, config, &light)?;
+        output.multiplexed = false;
+        output.limiter = None;
+        Ok(output)
+    }
+
+    // Keeps what the output this one replaces knew about the avatar, so the
+    // last lit color isn't lost and VRChat repeating what it sent isn't taken
+    // as a change made on the avatar
+    pub fn take_over(&mut self, old: &mut VrchatOutput) {
+        self.last_lit = old.last_lit;
+        if let (Some(echoes), Some(old)) = (&mut self.echoes, old.echoes.take()) {
+            *echoes = old;
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // The newest parameters of a multiplexed light, without the prefix
+    pub fn frame(&self) -> &[(String, Type)] {
+        &self.frame
+    }
+
+    // The OSC messages that make up a full update of the avatar parameters
+    pub fn messages(&self, state: &BulbState) -> Vec<(Address, Type)> {
+        let mut messages = Vec::new();
+        self.fill_messages(state, &mut messages);
+        messages
+    }
+
+    fn fill_messages(&self, state: &BulbState, messages: &mut Vec<(Address, Type)>) {
+        let graded;
+        let state = match &self.grading {
+            Some(grading) => {
+                graded = grading.apply(state);
+                &graded
+            }
+            None => state,
+        };
+        let parameters = &self.parameters;
+        match (&self.packed, &self.mapped) {
+            (Some((packed, address)), _) => {
+                messages.push((address.clone(), Type::Int(packed.pack(state) as i32)))
+            }
+            (None, Some(mapped)) => {
+                for parameter in mapped {
+                    messages.push((parameter.address.clone(), parameter.value(state)));
+                }
+            }
+            (None, None) => {
+                messages.push((parameters.on.clone(), Type::Bool(state.on)));
+                if self.hue_output != HueOutput::SinCos {
+                    messages.push((parameters.color.clone(), Type::Float(state.hue)));
+                }
+                if self.hue_output != HueOutput::Color {
+                    let angle = state.hue * TAU;
+                    messages.push((parameters.color_sin.clone(), Type::Float(angle.sin())));
+                    messages.push((parameters.color_cos.clone(), Type::Float(angle.cos())));
+                }
+                messages.push((parameters.brightness.clone(), Type::Float(state.brightness)));
+                if self.full_color {
+                    messages.push((parameters.saturation.clone(), Type::Float(state.saturation)));
+                    messages.push((
+                        parameters.color_temp.clone(),
+                        Type::Float(color_temp_position(state)),
+                    ));
+                }
+            }
+        }
+        if self.last_color && self.packed.is_none() {
+            let (hue, brightness) = match self.last_lit {
+                Some(last_lit) if !state.on => last_lit,
+                _ => (state.hue, state.brightness),
+            };
+            messages.push((parameters.last_color.clone(), Type::Float(hue)));
+            messages.push((parameters.last_brightness.clone(), Type::Float(brightness)));
+        }
+        for (address, metric) in &self.derived {
+            messages.push((address.clone(), Type::Float(metric(state))));
+        }
+    }
+
+    // Snaps floats to what VRChat can sync and drops anything that wouldn't
+    // change what the avatar already has
+    fn quantize(&self, messages: &mut Vec<(Address, Type)>) {
+        messages.retain_mut(|(addr, arg)| {
+            if let Type::Float(value) = arg {
+                *value = (*value * SYNCED_FLOAT_STEPS).round() / SYNCED_FLOAT_STEPS;
+            }
+            self.last_sent.get(addr) != Some(arg)
+        });
+    }
+
+    // Everything sent since the last time, taken out as it's read
+    pub fn drain_sent(&mut self) -> std::vec::Drain<'_, (Address, Type)> {
+        self.sent.drain(..)
+    }
+
+    // Whether the rate limit is still holding back parameters for later
+    pub fn holding_back(&self) -> bool {
+        self.limiter
+            .as_ref()
+            .is_some_and(|limiter| !limiter.is_empty())
+    }
+
+    // Makes the next send include every parameter, even unchanged ones
+    pub fn forget_sent(&mut self) {
+        self.last_sent.clear();
+    }
+
+    // Leaves the messages empty so the list can be used again
+    fn send_messages(&mut self, messages: &mut Vec<(Address, Type)>) {
+        if messages.is_empty() {
+            return;
+        }
+        let now = clock::now();
+        for (addr, arg) in messages.iter() {
+            if self.quantize {
+                self.last_sent.insert(addr.clone(), arg.clone());
+            }
+            if let Some(echoes) = &mut self.echoes {
+                echoes.insert(addr.clone(), (arg.clone(), Some(now)));
+            }
+            self.sent.push((addr.clone(), arg.clone()));
+        }
+        self.queue.push(messages, self.remote.addr());
+    }
+
+    // Whether VRChat sending this value is only it repeating what was just
+    // sent, or the same value sent any time before
+    fn is_echo(&self, addr: &str, arg: &Type, echo_window: Duration) -> bool {
+        let (sent, at) = match self.echoes.as_ref().and_then(|echoes| echoes.get(addr)) {
+            Some(sent) => sent,
+            None => return false,
+        };
+        let same = match (sent, arg) {
+            // VRChat syncs floats with less precision than they're sent with
+            (Type::Float(sent), Type::Float(arg)) => (sent - arg).abs() <= 1.0 / SYNCED_FLOAT_STEPS,
+            (sent, arg) => sent == arg,
+        };
+        same || at.is_some_and(|at| clock::elapsed(at) < echo_window)
+    }
+
+    // The state an avatar parameter VRChat sent asks for, None if it isn't one
+    // of the light's or it's only an echo of what was sent. Parameters worked
+    // out from more than one value, like ColorSin, are left alone.
+    pub fn avatar_change(
+        &mut self,
+        addr: &str,
+        arg: &Type,
+        current: &BulbState,
+        echo_window: Duration,
+    ) -> Option<BulbState> {
+        if self.multiplexed || self.is_echo(addr, arg, echo_window) {
+            return None;
+        }
+        let parameters = &self.parameters;
+        let state = match (&self.packed, &self.mapped, arg) {
+            (Some((packed, address)), _, Type::Int(value)) if address.as_str() == addr => {
+                packed.unpack(*value as u8)
+            }
+            (Some(_), _, _) => return None,
+            (None, Some(mapped), arg) => mapped
+                .iter()
+                .find(|parameter| parameter.address.as_str() == addr)?
+                .read(arg, current)?,
+            (None, None, Type::Bool(on)) if parameters.on.as_str() == addr => BulbState {
+                on: *on,
+                ..*current
+            },
+            (None, None, Type::Float(hue)) if parameters.color.as_str() == addr => BulbState {
+                hue: hue.rem_euclid(1.0),
+                ..*current
+            },
+            (None, None, Type::Float(brightness)) if parameters.brightness.as_str() == addr => {
+                BulbState {
+                    brightness: brightness.clamp(0.0, 1.0),
+                    ..*current
+                }
+            }
+            (None, None, Type::Float(saturation))
+                if self.full_color && parameters.saturation.as_str() == addr =>
+            {
+                BulbState {
+                    saturation: saturation.clamp(0.0, 1.0),
+                    color_temp: None,
+                    ..*current
+                }
+            }
+            (None, None, Type::Float(position))
+                if self.full_color && parameters.color_temp.as_str() == addr =>
+            {
+                let kelvin = translate(
+                    position.clamp(0.0, 1.0),
+                    0.0,
+                    1.0,
+                    WARMEST_KELVIN,
+                    COOLEST_KELVIN,
+                );
+                BulbState::white(current.on, kelvin, current.brightness)
+            }
+            _ => return None,
+        };
+        if state == *current {
+            return None;
+        }
+        // Changing it back to what was sent before is a change too
+        if let Some(echoes) = &mut self.echoes {
+            if let Some(echo) = echoes.get_mut(addr) {
+                *echo = (arg.clone(), None);
+            }
+        }
+        Some(state)
+    }
+
+    // Sends parameters through quantization and rate limiting, leaving the
+    // messages empty
+    pub fn send_batch(&mut self, messages: &mut Vec<(Address, Type)>) {
+        if self.quantize {
+            self.quantize(messages);
+        }
+        if let Some(limiter) = &mut self.limiter {
+            limiter.submit(messages, clock::now());
+        }
+        self.send_messages(messages);
+    }
+
+    // Sends a single extra parameter alongside the light's own
+    pub fn send_parameter(&mut self, addr: Address, arg: Type) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.push((addr, arg));
+        self.send_batch(&mut buffer);
+        self.buffer = buffer;
+    }
+
+    // Sends whatever the rate limiter held back and now has room for, and
+    // keeps track of where VRChat is. Returns whether VRChat moved or the OSC
+    // socket was set up again, in which case it needs everything sent again.
+    pub fn flush(&mut self) -> bool {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        if let Some(smoother) = &mut self.smoother {
+            smoother.step(clock::now(), &mut buffer);
+            if !buffer.is_empty() {
+                self.deliver(&mut buffer);
+            }
+        }
+        if let Some(limiter) = &mut self.limiter {
+            limiter.flush(&mut buffer, clock::now());
+            self.send_messages(&mut buffer);
+        }
+        self.buffer = buffer;
+        let moved = self.remote.refresh();
+        let reconnected = self.queue.take_reconnected();
+        if moved || reconnected {
+            self.forget_sent();
+        }
+        moved || reconnected
+    }
+
+    // Hands the light's own parameters to VRChat or the multiplexer, leaving
+    // the messages empty
+    fn deliver(&mut self, messages: &mut Vec<(Address, Type)>) {
+        if self.multiplexed {
+            // Extra parameters like the health parameter are still sent
+            // directly, only these are shared
+            for (addr, arg) in messages.drain(..) {
+                let name = &addr[self.prefix.len()..];
+                match self.frame.iter_mut().find(|(known, _)| known == name) {
+                    Some(known) => known.1 = arg,
+                    None => self.frame.push((name.to_owned(), arg)),
+                }
+            }
+            return;
+        }
+        self.send_batch(messages);
+    }
+}
+
+impl Output for VrchatOutput {
+    fn send(&mut self, state: &BulbState) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        self.fill_messages(state, &mut buffer);
+        if state.on {
+            self.last_lit = Some((state.hue, state.brightness));
+        }
+        if let Some(smoother) = &mut self.smoother {
+            smoother.apply(&mut buffer, clock::now());
+        }
+        self.deliver(&mut buffer);
+        self.buffer = buffer;
+        if !self.multiplexed {
+            logging::debug("Sent updated state to VRChat".to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    #[test]
+    fn switching_outputs_keeps_the_last_lit_color() {
+        let config = parse_config(
+            "
+vrchat_ip: 127.0.0.1
+vrchat_port: 9
+max_updates_per_second: 5
+last_color: true
+bulb_service: push
+push:
+    name: desk
+",
+        )
+        .unwrap();
+        let last_color = |output: &VrchatOutput| {
+            output
+                .messages(&BulbState::color(false, 0.0, 0.0))
+                .into_iter()
+                .find(|(addr, _)| addr.as_str().ends_with("LastColor"))
+                .map(|(_, value)| value)
+        };
+        let mut old = VrchatOutput::new("127.0.0.1:9", &config, &config.lights[0]).unwrap();
+        old.send(&BulbState::color(true, 0.25, 1.0));
+        let mut new = VrchatOutput::new("127.0.0.1:9", &config, &config.lights[0]).unwrap();
+        assert_eq!(last_color(&new), Some(Type::Float(0.0)));
+        new.take_over(&mut old);
+        assert_eq!(last_color(&new), Some(Type::Float(0.25)));
+    }
+}
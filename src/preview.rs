@@ -0,0 +1,148 @@
+use crate::config::Config;
+use crate::control::Event;
+use crate::engine::Engine;
+use crate::state::hsv_to_rgb;
+use minifb::{Window, WindowOptions};
+use nannou_osc::Type;
+use std::f32::consts::TAU;
+use std::sync::mpsc;
+
+const SWATCH_SIZE: usize = 96;
+// Height of the brightness bar under each swatch
+const BAR_HEIGHT: usize = 8;
+
+// What the preview shows for a light
+struct Swatch {
+    name: String,
+    prefix: String,
+    on: bool,
+    hue: f32,
+    saturation: f32,
+    brightness: f32,
+    // The last ColorSin and ColorCos, for lights that don't send Color
+    sin_cos: (f32, f32),
+    // Whether the light's own parameters have been sent, until then the state
+    // is shown instead, like for packed and multiplexed lights
+    sent: bool,
+}
+
+impl Swatch {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::State(status) if !self.sent => {
+                self.on = status.state.on;
+                self.hue = status.state.hue;
+                self.saturation = status.state.saturation;
+                self.brightness = status.state.brightness;
+            }
+            Event::OscSent { address, value, .. } => {
+                let name = match address.strip_prefix(&self.prefix) {
+                    Some(name) => name,
+                    None => return,
+                };
+                match (name, value) {
+                    ("on", Type::Bool(on)) => self.on = on,
+                    ("Color", Type::Float(hue)) => self.hue = hue,
+                    ("ColorSin", Type::Float(sin)) => {
+                        self.sin_cos.0 = sin;
+                        self.hue = (sin.atan2(self.sin_cos.1) / TAU).rem_euclid(1.0);
+                    }
+                    ("ColorCos", Type::Float(cos)) => {
+                        self.sin_cos.1 = cos;
+                        self.hue = (self.sin_cos.0.atan2(cos) / TAU).rem_euclid(1.0);
+                    }
+                    ("brightness", Type::Float(brightness)) => self.brightness = brightness,
+                    ("Saturation", Type::Float(saturation)) => self.saturation = saturation,
+                    _ => return,
+                }
+                self.sent = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&self, buffer: &mut [u32], x: usize, width: usize) {
+        let (red, green, blue) = if self.on {
+            hsv_to_rgb(self.hue, self.saturation, self.brightness)
+        } else {
+            (0.0, 0.0, 0.0)
+        };
+        let color = pixel(red, green, blue);
+        let bar_end = (self.brightness.clamp(0.0, 1.0) * SWATCH_SIZE as f32) as usize;
+        for y in 0..SWATCH_SIZE + BAR_HEIGHT {
+            for column in 0..SWATCH_SIZE {
+                buffer[y * width + x + column] = if y < SWATCH_SIZE {
+                    color
+                } else if column < bar_end {
+                    pixel(0.9, 0.9, 0.9)
+                } else {
+                    pixel(0.2, 0.2, 0.2)
+                };
+            }
+        }
+    }
+}
+
+fn pixel(red: f32, green: f32, blue: f32) -> u32 {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+    (channel(red) << 16) | (channel(green) << 8) | channel(blue)
+}
+
+// Syncs in the background while showing the color every light is sending to
+// VRChat, a square for each of them in the order of the settings with its
+// brightness as a bar underneath. Runs until the window is closed, returns
+// false if syncing stopped on its own before that, and the error when it
+// couldn't be started.
+pub fn run(config: Config) -> Result<bool, String> {
+    let mut swatches: Vec<Swatch> = config
+        .lights
+        .iter()
+        .map(|light| Swatch {
+            name: light.name.clone(),
+            prefix: light.parameter_prefix.clone(),
+            on: false,
+            hue: 0.0,
+            saturation: 1.0,
+            brightness: 0.0,
+            sin_cos: (0.0, 1.0),
+            sent: false,
+        })
+        .collect();
+    let names: Vec<&str> = swatches.iter().map(|swatch| swatch.name.as_str()).collect();
+    let title = format!("VRChat Light Sync preview: {}", names.join(", "));
+    let width = SWATCH_SIZE * swatches.len().max(1);
+    let height = SWATCH_SIZE + BAR_HEIGHT;
+    let mut window = Window::new(&title, width, height, WindowOptions::default())
+        .map_err(|err| format!("Couldn't open the preview window: {}", err))?;
+    window.set_target_fps(30);
+
+    let mut engine = Engine::from_config(config);
+    let (sender, events) = mpsc::channel();
+    engine.subscribe(move |event| {
+        let _ = sender.send(event);
+    });
+    engine.start();
+
+    let mut buffer = vec![0; width * height];
+    while window.is_open() {
+        if !engine.is_running() {
+            return engine.error().map_or(Ok(false), |err| Err(err.to_owned()));
+        }
+        for event in events.try_iter() {
+            let light = match &event {
+                Event::State(status) => &status.name,
+                Event::OscSent { light, .. } => light,
+            };
+            if let Some(swatch) = swatches.iter_mut().find(|swatch| swatch.name == *light) {
+                swatch.apply(event);
+            }
+        }
+        for (i, swatch) in swatches.iter().enumerate() {
+            swatch.draw(&mut buffer, i * SWATCH_SIZE, width);
+        }
+        if let Err(err) = window.update_with_buffer(&buffer, width, height) {
+            eprintln!("Couldn't draw the preview: {}", err);
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,290 @@
+// Python bindings, built as the vrchat_light_sync module with the
+// python-extension feature
+// pyo3's generated wrappers convert errors that already have the right type
+#![allow(clippy::useless_conversion)]
+use crate::backend::push::PushStore;
+use crate::backend::{create_backend, BulbBackend};
+use crate::config::{parse_config, SourceConfig};
+use crate::control::event_json;
+use crate::engine::Engine;
+use crate::output::vrchat::VrchatOutput;
+use crate::output::Output;
+use crate::state::{self, BulbState};
+use nannou_osc::Type;
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+#[pyclass(name = "BulbState")]
+#[derive(Clone, Copy)]
+struct PyBulbState {
+    #[pyo3(get, set)]
+    on: bool,
+    #[pyo3(get, set)]
+    hue: f32,
+    #[pyo3(get, set)]
+    brightness: f32,
+    #[pyo3(get, set)]
+    saturation: f32,
+    // In Kelvin while it's a white, None for colors
+    #[pyo3(get, set)]
+    color_temp: Option<f32>,
+}
+
+#[pymethods]
+impl PyBulbState {
+    #[new]
+    #[pyo3(signature = (on = false, hue = 0.0, brightness = 0.0, saturation = 1.0, color_temp = None))]
+    fn new(
+        on: bool,
+        hue: f32,
+        brightness: f32,
+        saturation: f32,
+        color_temp: Option<f32>,
+    ) -> PyBulbState {
+        PyBulbState {
+            on,
+            hue,
+            brightness,
+            saturation,
+            color_temp,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        let color_temp = match self.color_temp {
+            Some(kelvin) => kelvin.to_string(),
+            None => "None".to_owned(),
+        };
+        format!(
+            "BulbState(on={}, hue={}, brightness={}, saturation={}, color_temp={})",
+            if self.on { "True" } else { "False" },
+            self.hue,
+            self.brightness,
+            self.saturation,
+            color_temp
+        )
+    }
+}
+
+impl From<PyBulbState> for BulbState {
+    fn from(state: PyBulbState) -> BulbState {
+        let brightness = state.brightness.clamp(0.0, 1.0);
+        match state.color_temp {
+            Some(kelvin) => BulbState::white(state.on, kelvin, brightness),
+            None => BulbState {
+                on: state.on,
+                hue: state.hue.rem_euclid(1.0),
+                saturation: state.saturation.clamp(0.0, 1.0),
+                brightness,
+                color_temp: None,
+            },
+        }
+    }
+}
+
+impl From<BulbState> for PyBulbState {
+    fn from(state: BulbState) -> PyBulbState {
+        PyBulbState {
+            on: state.on,
+            hue: state.hue,
+            brightness: state.brightness,
+            saturation: state.saturation,
+            color_temp: state.color_temp,
+        }
+    }
+}
+
+fn osc_value(py: Python<'_>, value: &Type) -> PyObject {
+    match value {
+        Type::Bool(value) => value.into_py(py),
+        Type::Int(value) => value.into_py(py),
+        Type::Float(value) => value.into_py(py),
+        other => format!("{:?}", other).into_py(py),
+    }
+}
+
+// A bulb service from a source section of the settings, like a light's
+// bulb_service and home_assistant
+#[pyclass(name = "Source", unsendable)]
+struct PySource {
+    backend: Box<dyn BulbBackend>,
+}
+
+#[pymethods]
+impl PySource {
+    #[new]
+    fn new(settings_yaml: &str) -> PyResult<PySource> {
+        let config: SourceConfig = serde_yaml::from_str(settings_yaml)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        config.validate().map_err(PyValueError::new_err)?;
+        Ok(PySource {
+            // Nothing pushes to it, push sources only work in a LightSync
+            backend: create_backend(&config, &PushStore::default()),
+        })
+    }
+
+    fn get_state(&mut self) -> PyResult<PyBulbState> {
+        self.backend
+            .get_state()
+            .map(PyBulbState::from)
+            .map_err(|err| PyOSError::new_err(err.to_string()))
+    }
+}
+
+// Sends states to VRChat the same way a light does, with the parameter names
+// and mapping of one of the lights in the settings
+#[pyclass(name = "OscSender", unsendable)]
+struct PyOscSender {
+    output: VrchatOutput,
+}
+
+#[pymethods]
+impl PyOscSender {
+    #[new]
+    #[pyo3(signature = (settings_yaml, light = None))]
+    fn new(settings_yaml: &str, light: Option<&str>) -> PyResult<PyOscSender> {
+        let config =
+            parse_config(settings_yaml).map_err(|err| PyValueError::new_err(err.to_string()))?;
+        let light_config = match light {
+            Some(name) => config.lights.iter().find(|light| light.name == name),
+            None => config.lights.first(),
+        }
+        .ok_or_else(|| PyValueError::new_err("there's no such light in the settings"))?;
+        let addr = format!("{}:{}", config.vrchat_ip, config.vrchat_port);
+        Ok(PyOscSender {
+            output: VrchatOutput::new(&addr, &config, light_config)
+                .map_err(PyValueError::new_err)?,
+        })
+    }
+
+    // The parameters a state is sent as, without sending them
+    fn messages(&self, py: Python<'_>, state: PyBulbState) -> Vec<(String, PyObject)> {
+        self.output
+            .messages(&state.into())
+            .iter()
+            .map(|(addr, value)| (addr.to_string(), osc_value(py, value)))
+            .collect()
+    }
+
+    fn send(&mut self, state: PyBulbState) {
+        self.output.send(&state.into());
+    }
+
+    // Sends a single parameter, the type follows the Python value
+    fn send_parameter(&mut self, address: String, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let value = if let Ok(value) = value.extract::<bool>() {
+            Type::Bool(value)
+        } else if let Ok(value) = value.extract::<i32>() {
+            Type::Int(value)
+        } else {
+            Type::Float(value.extract::<f32>()?)
+        };
+        self.output.send_parameter(address.into(), value);
+        Ok(())
+    }
+
+    // Sends what the rate limit held back, should be called regularly when
+    // osc_rate_limit is set
+    fn flush(&mut self) {
+        self.output.flush();
+    }
+}
+
+// The whole sync running in a background thread, see Engine
+#[pyclass(name = "LightSync")]
+struct PyLightSync {
+    engine: Engine,
+}
+
+#[pymethods]
+impl PyLightSync {
+    #[new]
+    fn new(settings_yaml: &str) -> PyResult<PyLightSync> {
+        Engine::new(settings_yaml)
+            .map(|engine| PyLightSync { engine })
+            .map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    fn start(&mut self) -> PyResult<()> {
+        if self.engine.start() {
+            Ok(())
+        } else {
+            Err(PyRuntimeError::new_err("it's already running"))
+        }
+    }
+
+    fn is_running(&mut self) -> bool {
+        self.engine.is_running()
+    }
+
+    // Why syncing couldn't be started, once it stopped
+    #[getter]
+    fn error(&self) -> Option<String> {
+        self.engine.error().map(str::to_owned)
+    }
+
+    fn stop(&mut self, py: Python<'_>) {
+        // The sync loop might be waiting on a callback that needs the GIL
+        py.allow_threads(|| self.engine.stop());
+    }
+
+    // Calls the callback from a background thread with every event as a dict,
+    // like the websocket stream sends them
+    fn subscribe(&self, callback: PyObject) -> PyResult<()> {
+        let subscribed = self.engine.subscribe(move |event| {
+            let json = event_json(&event).to_string();
+            Python::with_gil(|py| {
+                let res = py
+                    .import_bound("json")
+                    .and_then(|json_module| json_module.call_method1("loads", (json,)))
+                    .and_then(|event| callback.call1(py, (event,)));
+                if let Err(err) = res {
+                    err.print(py);
+                }
+            });
+        });
+        if subscribed {
+            Ok(())
+        } else {
+            Err(PyRuntimeError::new_err("the sync loop is gone"))
+        }
+    }
+
+    // Sets the state of every light using the push bulb service with this name
+    fn push_state(&self, name: &str, state: PyBulbState) {
+        self.engine.push(name, state.into());
+    }
+}
+
+#[pyfunction]
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> (f32, f32, f32) {
+    state::hsv_to_rgb(hue, saturation, value)
+}
+
+#[pyfunction]
+fn rgb_to_hsv(red: f32, green: f32, blue: f32) -> (f32, f32, f32) {
+    state::rgb_to_hsv(red, green, blue)
+}
+
+#[pyfunction]
+fn kelvin_to_rgb(kelvin: f32) -> (f32, f32, f32) {
+    state::kelvin_to_rgb(kelvin)
+}
+
+#[pyfunction]
+fn xy_to_rgb(x: f32, y: f32) -> (f32, f32, f32) {
+    state::xy_to_rgb(x, y)
+}
+
+#[pymodule]
+fn vrchat_light_sync(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyBulbState>()?;
+    module.add_class::<PySource>()?;
+    module.add_class::<PyOscSender>()?;
+    module.add_class::<PyLightSync>()?;
+    module.add_function(wrap_pyfunction!(hsv_to_rgb, module)?)?;
+    module.add_function(wrap_pyfunction!(rgb_to_hsv, module)?)?;
+    module.add_function(wrap_pyfunction!(kelvin_to_rgb, module)?)?;
+    module.add_function(wrap_pyfunction!(xy_to_rgb, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use crate::clock;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+// How often the settings file is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Notices when the settings file is saved, so syncing can start over with it
+pub struct SettingsWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    // Changed at the last check, it's only reported once it stays the same for
+    // a check so a file that's still being written isn't read
+    pending: bool,
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|file| file.modified()).ok()
+}
+
+impl SettingsWatcher {
+    pub fn new(path: &str) -> SettingsWatcher {
+        let path = PathBuf::from(path);
+        SettingsWatcher {
+            modified: modified(&path),
+            path,
+            last_check: clock::now(),
+            pending: false,
+        }
+    }
+
+    // Whether the file was changed since the last time this returned true
+    pub fn changed(&mut self) -> bool {
+        if clock::elapsed(self.last_check) < CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = clock::now();
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return std::mem::take(&mut self.pending);
+        }
+        self.modified = modified;
+        self.pending = true;
+        false
+    }
+}
@@ -0,0 +1,149 @@
+use crate::clock;
+use crate::config::validate_seconds;
+use chrono::{NaiveDateTime, NaiveTime};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize)]
+pub struct ResyncConfig {
+    // Seconds between full resends
+    pub interval: Option<f32>,
+    // Times of day to resend everything at, like "18:30"
+    #[serde(default)]
+    pub times: Vec<String>,
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+impl ResyncConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(interval) = self.interval {
+            validate_seconds("resync.interval", interval, false)?;
+        }
+        match self.times.iter().find(|time| parse_time(time).is_none()) {
+            Some(time) => Err(format!("The resync time {} should look like 18:30.", time)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Decides when every parameter gets sent again, even if nothing changed, in
+// case VRChat missed a packet or the avatar was reloaded
+pub struct Resync {
+    interval: Option<Duration>,
+    times: Vec<NaiveTime>,
+    last_resync: Instant,
+    last_check: NaiveDateTime,
+}
+
+impl Resync {
+    pub fn new(config: &ResyncConfig) -> Resync {
+        let interval = config.interval.map(Duration::from_secs_f32);
+        let times = config
+            .times
+            .iter()
+            .filter_map(|time| parse_time(time))
+            .collect();
+        Resync {
+            interval,
+            times,
+            last_resync: clock::now(),
+            last_check: clock::local(),
+        }
+    }
+
+    // Whether it's time to resend everything, should be checked every loop
+    pub fn due(&mut self) -> bool {
+        let now = clock::local();
+        let today = now.date();
+        // Times passed since the last check, including the ones just before
+        // midnight when the day changed in between
+        let passed_time = self.times.iter().any(|time| {
+            [today.pred_opt(), Some(today)]
+                .into_iter()
+                .flatten()
+                .map(|day| day.and_time(*time))
+                .any(|at| self.last_check < at && at <= now)
+        });
+        self.last_check = now;
+        let passed_interval = self
+            .interval
+            .is_some_and(|interval| clock::elapsed(self.last_resync) >= interval);
+        if passed_time || passed_interval {
+            self.last_resync = clock::now();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use chrono::{Local, TimeZone};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    // A clock of its own for the test's thread, starting at a local time
+    fn mock_at(time: &str) -> Arc<MockClock> {
+        let start = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap();
+        let start = Local.from_local_datetime(&start).unwrap();
+        let mock = Arc::new(MockClock::new(SystemTime::from(start)));
+        clock::set_local(mock.clone());
+        mock
+    }
+
+    #[test]
+    fn resyncs_every_interval() {
+        let mock = mock_at("2024-05-17 12:00:00");
+        let mut resync = Resync::new(&ResyncConfig {
+            interval: Some(10.0),
+            times: Vec::new(),
+        });
+        assert!(!resync.due());
+        mock.advance(Duration::from_secs(9));
+        assert!(!resync.due());
+        mock.advance(Duration::from_secs(1));
+        assert!(resync.due());
+        assert!(!resync.due());
+        mock.advance(Duration::from_secs(25));
+        assert!(resync.due());
+        // Counts from the last resync, not from when it was due
+        mock.advance(Duration::from_secs(5));
+        assert!(!resync.due());
+    }
+
+    #[test]
+    fn resyncs_at_the_times_once() {
+        let mock = mock_at("2024-05-17 18:29:00");
+        let mut resync = Resync::new(&ResyncConfig {
+            interval: None,
+            times: vec!["18:30".to_owned()],
+        });
+        mock.advance(Duration::from_secs(59));
+        assert!(!resync.due());
+        mock.advance(Duration::from_secs(1));
+        assert!(resync.due());
+        mock.advance(Duration::from_secs(1));
+        assert!(!resync.due());
+        mock.advance(Duration::from_secs(24 * 60 * 60));
+        assert!(resync.due());
+    }
+
+    #[test]
+    fn resyncs_at_a_time_passed_over_midnight() {
+        let mock = mock_at("2024-05-17 23:58:30");
+        let mut resync = Resync::new(&ResyncConfig {
+            interval: None,
+            times: vec!["23:59".to_owned(), "00:00".to_owned()],
+        });
+        // Both went by between two checks
+        mock.advance(Duration::from_secs(120));
+        assert!(resync.due());
+        assert!(!resync.due());
+    }
+}
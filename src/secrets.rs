@@ -0,0 +1,248 @@
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::SecretString;
+use age::{Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+use serde_yaml::Value;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::{env, fs, iter};
+
+// Where the passphrase is taken from before asking for it
+pub const PASSPHRASE_VAR: &str = "VRCHAT_LIGHT_SYNC_PASSPHRASE";
+
+// The passphrase that was typed in, so it's only asked for once when the
+// settings are reloaded
+static TYPED: Mutex<Option<SecretString>> = Mutex::new(None);
+
+fn passphrase() -> Result<SecretString, Box<dyn Error>> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase.into());
+    }
+    let mut typed = TYPED.lock().unwrap();
+    if let Some(passphrase) = &*typed {
+        return Ok(passphrase.clone());
+    }
+    let passphrase: SecretString = rpassword::prompt_password("Passphrase for the secrets: ")
+        .map_err(|err| {
+            format!(
+                "couldn't ask for the passphrase, set {} instead: {}",
+                PASSPHRASE_VAR, err
+            )
+        })?
+        .into();
+    *typed = Some(passphrase.clone());
+    Ok(passphrase)
+}
+
+// Encrypts to the age identity file when one is given, otherwise to a
+// passphrase. The result is ASCII armored so it fits in the settings file.
+pub fn encrypt(plaintext: &str, identity: Option<&Path>) -> Result<String, Box<dyn Error>> {
+    let encryptor = match identity {
+        Some(path) => {
+            let recipients =
+                IdentityFile::from_file(path.display().to_string())?.to_recipients()?;
+            Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as &dyn Recipient))?
+        }
+        None => Encryptor::with_user_passphrase(passphrase()?),
+    };
+    let mut armored = Vec::new();
+    let mut writer = encryptor.wrap_output(ArmoredWriter::wrap_output(
+        &mut armored,
+        Format::AsciiArmor,
+    )?)?;
+    writer.write_all(plaintext.as_bytes())?;
+    writer.finish()?.finish()?;
+    Ok(String::from_utf8(armored)?)
+}
+
+pub fn decrypt(armored: &str, identity: Option<&Path>) -> Result<String, Box<dyn Error>> {
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(armored.as_bytes()))?;
+    let identities: Vec<Box<dyn Identity + Send + Sync>> = match identity {
+        Some(path) => IdentityFile::from_file(path.display().to_string())?.into_identities()?,
+        None => vec![Box::new(age::scrypt::Identity::new(passphrase()?))],
+    };
+    let mut plaintext = String::new();
+    let res = decryptor
+        .decrypt(
+            identities
+                .iter()
+                .map(|identity| identity.as_ref() as &dyn Identity),
+        )
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|mut reader| Ok(reader.read_to_string(&mut plaintext)?));
+    if res.is_err() {
+        // Asked for again in case it was mistyped
+        *TYPED.lock().unwrap() = None;
+    }
+    res.map(|_| plaintext)
+}
+
+// The decrypted secrets section of the settings, None when there isn't one
+pub fn decrypt_settings(settings: &Value) -> Result<Option<String>, Box<dyn Error>> {
+    let armored = match settings.get("secrets") {
+        Some(Value::String(armored)) => armored,
+        Some(_) => return Err("secrets has to be the text printed by secrets encrypt".into()),
+        None => return Ok(None),
+    };
+    let identity = settings.get("secrets_identity").and_then(Value::as_str);
+    decrypt(armored, identity.map(Path::new)).map(Some)
+}
+
+// The secrets section for a YAML file with the settings to keep secret
+pub fn encrypt_file(file: &Path, identity: Option<&Path>) -> Result<String, Box<dyn Error>> {
+    let plaintext = fs::read_to_string(file)?;
+    // Catch mistakes now instead of when the settings are loaded
+    serde_yaml::from_str::<Value>(&plaintext)?;
+    Ok(section(&encrypt(&plaintext, identity)?))
+}
+
+// What the secrets section of a settings file decrypts to
+pub fn decrypt_file(settings: &Path) -> Result<String, Box<dyn Error>> {
+    let settings = serde_yaml::from_str(&fs::read_to_string(settings)?)?;
+    decrypt_settings(&settings)?.ok_or_else(|| "The settings file has no secrets section.".into())
+}
+
+// Lays the secrets over the rest of the settings, maps are merged key by key
+// and lists item by item
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(over)) => {
+            for (i, value) in over.into_iter().enumerate() {
+                match base.get_mut(i) {
+                    Some(existing) => merge(existing, value),
+                    None => base.push(value),
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+// Replaces the secrets section of the settings with what it decrypts to
+pub fn apply(settings: &mut Value) -> Result<(), String> {
+    let secrets = match decrypt_settings(settings) {
+        Ok(Some(secrets)) => secrets,
+        Ok(None) => return Ok(()),
+        Err(err) => return Err(err.to_string()),
+    };
+    let secrets: Value =
+        serde_yaml::from_str(&secrets).map_err(|err| format!("they aren't valid YAML: {}", err))?;
+    merge(settings, secrets);
+    if let Value::Mapping(settings) = settings {
+        for key in ["secrets", "secrets_identity"] {
+            settings.remove(&Value::from(key));
+        }
+    }
+    Ok(())
+}
+
+// The encrypted text as a secrets section, ready to be pasted into the
+// settings file
+fn section(armored: &str) -> String {
+    iter::once("secrets: |".to_owned())
+        .chain(armored.lines().map(|line| "    ".to_owned() + line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn merges_the_secrets_into_the_settings() {
+        let mut settings = yaml(
+            "
+vrchat_port: 9000
+home_assistant:
+    url: http://homeassistant.local:8123
+lights:
+    - name: desk
+      home_assistant:
+          entity_id: light.desk
+    - name: ceiling
+",
+        );
+        merge(
+            &mut settings,
+            yaml(
+                "
+home_assistant:
+    auth_key: abc
+lights:
+    - home_assistant:
+          auth_key: def
+    - {}
+    - name: extra
+vrchat_port: 9001
+",
+            ),
+        );
+        assert_eq!(
+            settings,
+            yaml(
+                "
+vrchat_port: 9001
+home_assistant:
+    url: http://homeassistant.local:8123
+    auth_key: abc
+lights:
+    - name: desk
+      home_assistant:
+          entity_id: light.desk
+          auth_key: def
+    - name: ceiling
+    - name: extra
+"
+            )
+        );
+    }
+
+    #[test]
+    fn decrypts_the_secrets_section() {
+        let identity = age::x25519::Identity::generate();
+        let path = env::temp_dir().join(format!("vrchat-light-sync-{}.age", std::process::id()));
+        fs::write(&path, identity.to_string().expose_secret()).unwrap();
+        let secrets = encrypt("mqtt:\n    password: hunter2\n", Some(&path)).unwrap();
+        let mut settings = yaml(&format!(
+            "{}\nsecrets_identity: {}\nmqtt:\n    host: broker\n",
+            section(&secrets),
+            path.display()
+        ));
+        let res = apply(&mut settings);
+        fs::remove_file(&path).unwrap();
+        res.unwrap();
+        assert_eq!(
+            settings,
+            yaml("mqtt:\n    host: broker\n    password: hunter2\n")
+        );
+    }
+
+    #[test]
+    fn leaves_settings_without_secrets_alone() {
+        let mut settings = yaml("vrchat_port: 9000\n");
+        apply(&mut settings).unwrap();
+        assert_eq!(settings, yaml("vrchat_port: 9000\n"));
+        let mut settings = yaml("secrets: [1]\n");
+        assert_eq!(
+            apply(&mut settings),
+            Err("secrets has to be the text printed by secrets encrypt".to_owned())
+        );
+    }
+}
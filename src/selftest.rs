@@ -0,0 +1,153 @@
+use crate::backend::create_backend;
+use crate::clock;
+use crate::config::Config;
+use crate::output::multiplex::Multiplexer;
+use crate::output::vrchat::VrchatOutput;
+use crate::output::Output;
+use nannou_osc::{Receiver, Type};
+use std::time::Duration;
+
+// How long to wait for the sent parameters to arrive at the fake VRChat
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+const RECEIVE_RETRY: Duration = Duration::from_millis(10);
+
+// Collects everything that arrives until it's all that was sent or it times
+// out, the rate limit can hold some of it back for a while
+fn receive(
+    receiver: &Receiver,
+    outputs: &mut [(usize, VrchatOutput)],
+    mut multiplexer: Option<&mut Multiplexer>,
+    sent: &mut Vec<(String, Type)>,
+    received: &mut Vec<(String, Vec<Type>)>,
+) {
+    let start = clock::now();
+    loop {
+        for (_, output) in outputs.iter_mut() {
+            output.flush();
+            sent.extend(
+                output
+                    .drain_sent()
+                    .map(|(addr, arg)| (addr.to_string(), arg)),
+            );
+        }
+        if let Some(multiplexer) = &mut multiplexer {
+            sent.extend(
+                multiplexer
+                    .drain_sent()
+                    .map(|(addr, arg)| (addr.to_string(), arg)),
+            );
+        }
+        let holding_back = outputs.iter().any(|(_, output)| output.holding_back());
+        if (!holding_back && received.len() >= sent.len())
+            || clock::elapsed(start) >= RECEIVE_TIMEOUT
+        {
+            return;
+        }
+        match receiver.try_recv() {
+            Ok(Some((packet, _))) => {
+                for msg in packet.into_msgs() {
+                    received.push((msg.addr, msg.args.unwrap_or_default()));
+                }
+            }
+            Ok(None) => clock::sleep(RECEIVE_RETRY),
+            Err(err) => println!("Fake VRChat received an invalid packet: {}", err),
+        }
+    }
+}
+
+// Runs a single poll → map → send cycle against a local OSC receiver that
+// pretends to be VRChat, returns true if every parameter arrived intact. What
+// arrives is compared to what was sent after quantizing, rate limiting and
+// multiplexing, so those are checked as well.
+pub fn run(config: &Config) -> Result<bool, String> {
+    // Start the fake VRChat on a random local port
+    let receiver = Receiver::bind_to("127.0.0.1:0")
+        .map_err(|err| format!("Couldn't start the fake VRChat OSC receiver: {}", err))?;
+    let fake_addr = receiver.local_addr().map_err(|err| {
+        format!(
+            "Couldn't get the address of the fake VRChat OSC receiver: {}",
+            err
+        )
+    })?;
+    println!("Fake VRChat listening on {}", fake_addr);
+
+    let mut ok = true;
+    // With the index of the light, which the multiplexer sends
+    let mut outputs = Vec::new();
+    for (index, light) in config.lights.iter().enumerate() {
+        let mut output = VrchatOutput::new(&fake_addr.to_string(), config, light)?;
+        println!(
+            "Polling {} from {:?}",
+            light.name,
+            light.source.bulb_service()
+        );
+        let state = match create_backend(&light.source, &config.pushed).get_state() {
+            Ok(state) => state,
+            Err(err) => {
+                println!("FAILED  {}: couldn't be read: {}", light.name, err);
+                ok = false;
+                continue;
+            }
+        };
+        println!("Got {:?}", state);
+        output.send(&state);
+        outputs.push((index, output));
+    }
+    let mut multiplexer = config
+        .multiplex
+        .as_ref()
+        .map(|multiplex| Multiplexer::new(&fake_addr.to_string(), config, multiplex))
+        .transpose()?;
+
+    let mut sent = Vec::new();
+    let mut received = Vec::new();
+    receive(&receiver, &mut outputs, None, &mut sent, &mut received);
+    if let Some(multiplexer) = &mut multiplexer {
+        // One slot for every light, each sent once the one before arrived.
+        // Newer values of the same parameters would replace them otherwise.
+        for (index, output) in outputs.iter() {
+            multiplexer.send_slot(*index, output.frame());
+            receive(
+                &receiver,
+                &mut [],
+                Some(multiplexer),
+                &mut sent,
+                &mut received,
+            );
+        }
+    }
+
+    println!("Received {} parameter(s):", received.len());
+    for (addr, args) in &received {
+        println!("  {} = {:?}", addr, args);
+    }
+
+    // The multiplexer sends the same parameters once for every light, so each
+    // one that arrived only counts once
+    for (addr, arg) in &sent {
+        let same_address = |(received_addr, _): &(String, Vec<Type>)| received_addr == addr;
+        if let Some(i) = received
+            .iter()
+            .position(|received| same_address(received) && received.1 == [arg.clone()])
+        {
+            received.remove(i);
+        } else if let Some(i) = received.iter().position(same_address) {
+            println!(
+                "MISMATCH {}: expected {:?}, got {:?}",
+                addr, arg, received[i].1
+            );
+            received.remove(i);
+            ok = false;
+        } else {
+            println!("MISSING  {}: expected {:?}", addr, arg);
+            ok = false;
+        }
+    }
+    for (addr, _) in &received {
+        println!("UNEXPECTED {}", addr);
+        ok = false;
+    }
+
+    println!("Selftest {}", if ok { "passed" } else { "failed" });
+    Ok(ok)
+}
@@ -0,0 +1,287 @@
+use serde::Deserialize;
+use std::f32::consts::TAU;
+
+// Hue the light looks warmest at, orange
+const WARMEST_HUE: f32 = 30.0 / 360.0;
+// The color temperatures colors are estimated to be between, from orange
+// light to light blue
+pub const WARMEST_KELVIN: f32 = 2000.0;
+pub const COOLEST_KELVIN: f32 = 6500.0;
+
+fn default_saturation() -> f32 {
+    1.0
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub struct BulbState {
+    pub on: bool,
+    pub hue: f32,
+    #[serde(default = "default_saturation")]
+    pub saturation: f32,
+    pub brightness: f32,
+    // In Kelvin while the light is set to a white instead of a color, the hue
+    // and saturation are then what that white looks like
+    #[serde(default)]
+    pub color_temp: Option<f32>,
+}
+
+// A hue and saturation from 0-1, and the color temperature in Kelvin when it's
+// a white
+pub type Color = (f32, f32, Option<f32>);
+
+// The color of RGB in the range 0-1, however bright it is
+pub fn rgb_color((red, green, blue): (f32, f32, f32)) -> Color {
+    let (hue, saturation, _) = rgb_to_hsv(red, green, blue);
+    (hue, saturation, None)
+}
+
+// The color white light at a color temperature in Kelvin looks like
+pub fn white_color(kelvin: f32) -> Color {
+    let (hue, saturation, _) = rgb_color(kelvin_to_rgb(kelvin));
+    (hue, saturation, Some(kelvin))
+}
+
+impl BulbState {
+    pub fn new(on: bool, (hue, saturation, color_temp): Color, brightness: f32) -> BulbState {
+        BulbState {
+            on,
+            hue,
+            saturation,
+            brightness,
+            color_temp,
+        }
+    }
+
+    // A fully saturated color, the only kind of state there used to be
+    pub fn color(on: bool, hue: f32, brightness: f32) -> BulbState {
+        BulbState::new(on, (hue, 1.0, None), brightness)
+    }
+
+    pub fn white(on: bool, kelvin: f32, brightness: f32) -> BulbState {
+        BulbState::new(on, white_color(kelvin), brightness)
+    }
+
+    // 1 for orange light, 0 for light blue, the other hues in between, whites
+    // go by their color temperature instead
+    pub fn warmth(&self) -> f32 {
+        match self.color_temp {
+            Some(kelvin) => translate(
+                kelvin.clamp(WARMEST_KELVIN, COOLEST_KELVIN),
+                WARMEST_KELVIN,
+                COOLEST_KELVIN,
+                1.0,
+                0.0,
+            ),
+            None => ((TAU * (self.hue - WARMEST_HUE)).cos() + 1.0) / 2.0,
+        }
+    }
+
+    // The color temperature, estimated from how warm the hue is for colors
+    pub fn kelvin(&self) -> f32 {
+        self.color_temp
+            .unwrap_or_else(|| translate(self.warmth(), 1.0, 0.0, WARMEST_KELVIN, COOLEST_KELVIN))
+    }
+
+    // The light's color as RGB in the range 0-1, black while it's off
+    pub fn rgb(&self) -> (f32, f32, f32) {
+        if !self.on {
+            return (0.0, 0.0, 0.0);
+        }
+        hsv_to_rgb(self.hue, self.saturation, self.brightness)
+    }
+}
+
+pub fn mireds_to_kelvin(mireds: f32) -> f32 {
+    1_000_000.0 / mireds.max(1.0)
+}
+
+pub fn kelvin_to_mireds(kelvin: f32) -> f32 {
+    1_000_000.0 / kelvin.max(1.0)
+}
+
+pub fn translate(value: f32, prev_start: f32, prev_end: f32, new_start: f32, new_end: f32) -> f32 {
+    let prev_span = prev_end - prev_start;
+    let new_span = new_end - new_start;
+    let scaled_value = (value - prev_start) / prev_span;
+    new_start + (scaled_value * new_span)
+}
+
+// Converts a hue, saturation and value in the range 0-1 into RGB in the range 0-1
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> (f32, f32, f32) {
+    let sector = (hue.rem_euclid(1.0) * 6.0).floor();
+    let fraction = hue.rem_euclid(1.0) * 6.0 - sector;
+    let p = value * (1.0 - saturation);
+    let q = value * (1.0 - saturation * fraction);
+    let t = value * (1.0 - saturation * (1.0 - fraction));
+    match sector as i32 {
+        0 => (value, t, p),
+        1 => (q, value, p),
+        2 => (p, value, t),
+        3 => (p, q, value),
+        4 => (t, p, value),
+        _ => (value, p, q),
+    }
+}
+
+// Converts RGB in the range 0-1 into a hue, saturation and value in the range 0-1
+pub fn rgb_to_hsv(red: f32, green: f32, blue: f32) -> (f32, f32, f32) {
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == red {
+        ((green - blue) / delta).rem_euclid(6.0) / 6.0
+    } else if max == green {
+        ((blue - red) / delta + 2.0) / 6.0
+    } else {
+        ((red - green) / delta + 4.0) / 6.0
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+// Converts a CIE 1931 xy chromaticity into RGB in the range 0-1
+pub fn xy_to_rgb(x: f32, y: f32) -> (f32, f32, f32) {
+    if y <= 0.0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let (big_x, big_y, big_z) = (x / y, 1.0, (1.0 - x - y) / y);
+    let rgb = (
+        3.2406 * big_x - 1.5372 * big_y - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * big_y + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * big_y + 1.0570 * big_z,
+    );
+    let max = rgb.0.max(rgb.1).max(rgb.2).max(f32::EPSILON);
+    (
+        (rgb.0 / max).max(0.0),
+        (rgb.1 / max).max(0.0),
+        (rgb.2 / max).max(0.0),
+    )
+}
+
+// Approximates the color of white light at a color temperature as RGB in the
+// range 0-1
+pub fn kelvin_to_rgb(kelvin: f32) -> (f32, f32, f32) {
+    let temp = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let red = if temp <= 66.0 {
+        255.0
+    } else {
+        329.699 * (temp - 60.0).powf(-0.133_204_76)
+    };
+    let green = if temp <= 66.0 {
+        99.470_8 * temp.ln() - 161.119_57
+    } else {
+        288.122_16 * (temp - 60.0).powf(-0.075_514_846)
+    };
+    let blue = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (temp - 10.0).ln() - 305.044_8
+    };
+    (
+        red.clamp(0.0, 255.0) / 255.0,
+        green.clamp(0.0, 255.0) / 255.0,
+        blue.clamp(0.0, 255.0) / 255.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    fn close_rgb(a: (f32, f32, f32), b: (f32, f32, f32)) -> bool {
+        close(a.0, b.0) && close(a.1, b.1) && close(a.2, b.2)
+    }
+
+    #[test]
+    fn converts_rgb_to_hsv_and_back() {
+        assert!(close_rgb(rgb_to_hsv(1.0, 0.0, 0.0), (0.0, 1.0, 1.0)));
+        assert!(close_rgb(rgb_to_hsv(0.0, 0.5, 0.0), (1.0 / 3.0, 1.0, 0.5)));
+        assert!(close_rgb(rgb_to_hsv(0.0, 0.0, 1.0), (2.0 / 3.0, 1.0, 1.0)));
+        assert!(close_rgb(rgb_to_hsv(1.0, 0.0, 1.0), (5.0 / 6.0, 1.0, 1.0)));
+        // Grays and black don't have a hue
+        assert!(close_rgb(rgb_to_hsv(0.5, 0.5, 0.5), (0.0, 0.0, 0.5)));
+        assert!(close_rgb(rgb_to_hsv(0.0, 0.0, 0.0), (0.0, 0.0, 0.0)));
+        for rgb in [(0.2, 0.4, 0.9), (0.9, 0.6, 0.1), (0.3, 1.0, 0.7)] {
+            let (hue, saturation, value) = rgb_to_hsv(rgb.0, rgb.1, rgb.2);
+            assert!(close_rgb(hsv_to_rgb(hue, saturation, value), rgb));
+        }
+    }
+
+    #[test]
+    fn converts_xy_to_rgb() {
+        // The sRGB primaries and the D65 white point
+        assert!(close_rgb(xy_to_rgb(0.64, 0.33), (1.0, 0.0, 0.0)));
+        assert!(close_rgb(xy_to_rgb(0.3, 0.6), (0.0, 1.0, 0.0)));
+        let (red, green, blue) = xy_to_rgb(0.15, 0.06);
+        assert!(close(blue, 1.0) && red < 0.01 && green < 0.01);
+        let (red, green, blue) = xy_to_rgb(0.3127, 0.329);
+        assert!(red > 0.98 && green > 0.98 && blue > 0.98);
+        // Outside of what RGB can show it's brought back in range
+        let (red, green, blue) = xy_to_rgb(0.1, 0.8);
+        assert!(red >= 0.0 && green <= 1.0 && blue >= 0.0);
+        assert_eq!(xy_to_rgb(0.3, 0.0), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn converts_color_temperatures_to_rgb() {
+        // Warm whites are orange, 6600K is about white and hotter is blue
+        let (red, green, blue) = kelvin_to_rgb(2000.0);
+        assert!(close(red, 1.0) && green < 0.6 && blue < 0.2);
+        assert!(close_rgb(kelvin_to_rgb(6600.0), (1.0, 1.0, 1.0)));
+        let (red, green, blue) = kelvin_to_rgb(10000.0);
+        assert!(red < 0.85 && green < 0.9 && close(blue, 1.0));
+        // Warmer is always less blue
+        assert!(kelvin_to_rgb(3000.0).2 < kelvin_to_rgb(4000.0).2);
+        // Temperatures out of range are clamped
+        assert_eq!(kelvin_to_rgb(500.0), kelvin_to_rgb(1000.0));
+        assert_eq!(kelvin_to_rgb(90000.0), kelvin_to_rgb(40000.0));
+        let (hue, _, kelvin) = white_color(2700.0);
+        assert!(hue < 0.1 && kelvin == Some(2700.0));
+    }
+
+    #[test]
+    fn colors_are_as_warm_as_their_hue() {
+        assert!(close(
+            BulbState::color(true, WARMEST_HUE, 1.0).warmth(),
+            1.0
+        ));
+        assert!(close(
+            BulbState::color(true, WARMEST_HUE + 0.5, 1.0).warmth(),
+            0.0
+        ));
+        assert!(close(
+            BulbState::color(true, WARMEST_HUE + 0.25, 1.0).warmth(),
+            0.5
+        ));
+        let orange = BulbState::color(true, WARMEST_HUE, 1.0);
+        assert!(close(orange.kelvin(), WARMEST_KELVIN));
+    }
+
+    #[test]
+    fn whites_are_as_warm_as_their_color_temperature() {
+        assert!(close(
+            BulbState::white(true, WARMEST_KELVIN, 1.0).warmth(),
+            1.0
+        ));
+        assert!(close(
+            BulbState::white(true, COOLEST_KELVIN, 1.0).warmth(),
+            0.0
+        ));
+        assert!(close(BulbState::white(true, 4250.0, 1.0).warmth(), 0.5));
+        // Past the ends they're as warm or cool as it gets
+        assert!(close(BulbState::white(true, 1500.0, 1.0).warmth(), 1.0));
+        assert!(close(BulbState::white(true, 9000.0, 1.0).warmth(), 0.0));
+        // Going from warmth back to Kelvin gives the same temperature
+        let white = BulbState::white(true, 3000.0, 1.0);
+        let kelvin = translate(white.warmth(), 1.0, 0.0, WARMEST_KELVIN, COOLEST_KELVIN);
+        assert!(close(kelvin, white.kelvin()));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::clock;
+use crate::light::Light;
+use crate::output::multiplex::Multiplexer;
+use crate::state::BulbState;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time;
+
+// Set once the pattern has played, it's only played when the program starts
+// and not again when the settings are reloaded
+static PLAYED: AtomicBool = AtomicBool::new(false);
+
+// How long each step of the pattern takes in seconds
+const STEP_DURATIONS: [f32; 4] = [0.5, 2.0, 1.0, 0.5];
+
+// The state at a point 0-1 through a step of the pattern
+fn state_at(step: usize, t: f32) -> BulbState {
+    match step {
+        // Off
+        0 => BulbState::color(false, 0.0, 0.0),
+        // Sweep through every hue
+        1 => BulbState::color(true, t, 1.0),
+        // Ramp up the brightness
+        2 => BulbState::color(true, 0.0, t),
+        // Toggle off and on again
+        _ => BulbState::color(t >= 0.5, 0.0, 1.0),
+    }
+}
+
+// Plays a short pattern to the avatars of all lights so it's easy to see that
+// the avatar is set up correctly, the caller sends the live state after.
+// With multiplexing the lights take turns getting it like they do while
+// syncing.
+pub fn play(
+    lights: &mut [Light],
+    mut multiplexer: Option<&mut Multiplexer>,
+    frames_per_second: i32,
+) {
+    if PLAYED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    println!("Playing the startup test pattern");
+    let frame_time = time::Duration::from_secs_f32(1.0 / frames_per_second as f32);
+    for (step, duration) in STEP_DURATIONS.iter().enumerate() {
+        let frames = ((duration * frames_per_second as f32).round() as i32).max(1);
+        for frame in 0..=frames {
+            let state = state_at(step, frame as f32 / frames as f32);
+            for light in lights.iter_mut() {
+                light.send_to_avatar(&state);
+            }
+            if let Some(multiplexer) = &mut multiplexer {
+                multiplexer.update(lights, clock::now());
+            }
+            clock::sleep(frame_time);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::state::BulbState;
+use std::time::{Duration, Instant};
+
+// A light going from the state the avatar shows to its new one over a while,
+// instead of jumping straight to it
+pub struct Transition {
+    from: BulbState,
+    to: BulbState,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Transition {
+    pub fn new(from: BulbState, to: BulbState, started: Instant, duration: Duration) -> Transition {
+        Transition {
+            from,
+            to,
+            started,
+            duration,
+        }
+    }
+
+    pub fn is_finished(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= self.duration
+    }
+
+    // How far along the transition is at a point in time
+    pub fn state_at(&self, now: Instant) -> BulbState {
+        let t = (now.duration_since(self.started).as_secs_f32() / self.duration.as_secs_f32())
+            .clamp(0.0, 1.0);
+        if t >= 1.0 {
+            return self.to;
+        }
+        // A light that's off is dark whatever brightness it had, so turning on
+        // fades up from nothing in the new color and turning off fades down
+        // in the old one
+        let (from, to) = (self.from, self.to);
+        let brightness = |state: BulbState| if state.on { state.brightness } else { 0.0 };
+        let lerp = |from: f32, to: f32| from + (to - from) * t;
+        let color = match (from.on, to.on) {
+            (true, true) => {
+                // The short way around, going from 0.9 to 0.1 passes 0
+                let diff = (to.hue - from.hue + 0.5).rem_euclid(1.0) - 0.5;
+                (
+                    (from.hue + diff * t).rem_euclid(1.0),
+                    lerp(from.saturation, to.saturation),
+                    // Still a white only when going between two whites
+                    from.color_temp
+                        .zip(to.color_temp)
+                        .map(|(from, to)| lerp(from, to)),
+                )
+            }
+            (false, _) => (to.hue, to.saturation, to.color_temp),
+            (true, false) => (from.hue, from.saturation, from.color_temp),
+        };
+        // Only goes off at the end, once it's faded out
+        BulbState::new(
+            from.on || to.on,
+            color,
+            lerp(brightness(from), brightness(to)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn halfway(from: BulbState, to: BulbState) -> BulbState {
+        let start = Instant::now();
+        Transition::new(from, to, start, SECOND).state_at(start + SECOND / 2)
+    }
+
+    #[test]
+    fn goes_from_one_state_to_the_other() {
+        let start = Instant::now();
+        let from = BulbState::color(true, 0.2, 0.2);
+        let to = BulbState::new(true, (0.4, 0.5, None), 1.0);
+        let transition = Transition::new(from, to, start, SECOND);
+        assert_eq!(transition.state_at(start), from);
+        assert!(!transition.is_finished(start + SECOND / 2));
+        let middle = transition.state_at(start + SECOND / 2);
+        assert!((middle.hue - 0.3).abs() < 1e-6);
+        assert!((middle.saturation - 0.75).abs() < 1e-6);
+        assert!((middle.brightness - 0.6).abs() < 1e-6);
+        assert!(transition.is_finished(start + SECOND));
+        assert_eq!(transition.state_at(start + SECOND), to);
+        assert_eq!(transition.state_at(start + SECOND * 5), to);
+    }
+
+    #[test]
+    fn hue_goes_the_short_way_around() {
+        let middle = halfway(
+            BulbState::color(true, 0.9, 1.0),
+            BulbState::color(true, 0.1, 1.0),
+        );
+        assert!(middle.hue < 1e-6 || middle.hue > 1.0 - 1e-6);
+        let middle = halfway(
+            BulbState::color(true, 0.1, 1.0),
+            BulbState::color(true, 0.8, 1.0),
+        );
+        assert!((middle.hue - 0.95).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fades_in_and_out_of_off() {
+        let off = BulbState::color(false, 0.1, 1.0);
+        let on = BulbState::color(true, 0.6, 0.8);
+        // Up from dark in the new color
+        let middle = halfway(off, on);
+        assert!(middle.on);
+        assert_eq!(middle.hue, 0.6);
+        assert!((middle.brightness - 0.4).abs() < 1e-6);
+        // Down in the old one, only going off at the end
+        let middle = halfway(on, off);
+        assert!(middle.on);
+        assert_eq!(middle.hue, 0.6);
+        assert!((middle.brightness - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stays_white_only_between_whites() {
+        let middle = halfway(
+            BulbState::white(true, 2000.0, 1.0),
+            BulbState::white(true, 4000.0, 1.0),
+        );
+        assert_eq!(middle.color_temp, Some(3000.0));
+        let middle = halfway(
+            BulbState::white(true, 2000.0, 1.0),
+            BulbState::color(true, 0.5, 1.0),
+        );
+        assert_eq!(middle.color_temp, None);
+    }
+}
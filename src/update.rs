@@ -0,0 +1,159 @@
+use minisign_verify::{PublicKey, Signature};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::error::Error;
+use std::io::Write;
+
+const RELEASES_URL: &str =
+    "https://api.github.com/repos/hrolfurgylfa/vrchat-light-sync/releases/latest";
+// Lists the SHA-256 of every asset in a release, like sha256sum prints them
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+// The minisign signature of the checksums, made with the release key
+const SIGNATURE_ASSET: &str = "SHA256SUMS.minisig";
+// The public half of the minisign key releases are signed with, given in
+// VRCHAT_LIGHT_SYNC_UPDATE_KEY when building. Builds without one can only
+// check for updates, there's no key they could trust downloads with.
+const PUBLIC_KEY: Option<&str> = option_env!("VRCHAT_LIGHT_SYNC_UPDATE_KEY");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+// Name of the release asset built for this platform
+fn asset_name() -> String {
+    let name = format!(
+        "vrchat-light-sync-{}-{}",
+        env::consts::OS,
+        env::consts::ARCH
+    );
+    if cfg!(windows) {
+        name + ".exe"
+    } else {
+        name
+    }
+}
+
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn download(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(client
+        .get(url)
+        .send()?
+        .error_for_status()?
+        .bytes()?
+        .to_vec())
+}
+
+// Checks that the checksums were signed with the release key, so a release
+// that was tampered with can't bring its own
+fn verify_checksums(key: &str, checksums: &[u8], signature: &str) -> Result<(), Box<dyn Error>> {
+    let key = PublicKey::from_base64(key)?;
+    let signature = Signature::decode(signature)?;
+    key.verify(checksums, &signature, false).map_err(|err| {
+        format!(
+            "{} isn't signed with the release key: {}",
+            CHECKSUMS_ASSET, err
+        )
+    })?;
+    Ok(())
+}
+
+fn expected_checksum(checksums: &str, name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let (hash, file) = line.split_once(char::is_whitespace)?;
+        // sha256sum marks binary files with a *
+        if file.trim().trim_start_matches('*') == name {
+            Some(hash.to_lowercase())
+        } else {
+            None
+        }
+    })
+}
+
+// Replaces this program with the newest release if there is one, returns
+// whether it was updated
+pub fn run(check_only: bool) -> Result<bool, Box<dyn Error>> {
+    let client = reqwest::blocking::Client::builder()
+        // GitHub's API turns away requests without one
+        .user_agent(concat!("vrchat-light-sync/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    // Only debug builds can be pointed at a fake release server for testing
+    let url = match env::var("VRCHAT_LIGHT_SYNC_RELEASES_URL") {
+        Ok(url) if cfg!(debug_assertions) => url,
+        _ => RELEASES_URL.to_owned(),
+    };
+    let release: Release =
+        serde_json::from_str(&client.get(url).send()?.error_for_status()?.text()?)?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    if parse_version(&release.tag_name) <= parse_version(current) {
+        println!("Already up to date with version {}", current);
+        return Ok(false);
+    }
+    println!("Version {} is out, this is {}", release.tag_name, current);
+    if check_only {
+        return Ok(false);
+    }
+    let key = PUBLIC_KEY.ok_or(
+        "This build has no release key to check downloads with, \
+         download the new version from the releases page instead",
+    )?;
+
+    let name = asset_name();
+    let find = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| format!("Release {} has no {}", release.tag_name, name))
+    };
+    let binary_asset = find(&name)?;
+    let checksums_asset = find(CHECKSUMS_ASSET)?;
+    let signature_asset = find(SIGNATURE_ASSET)?;
+
+    println!("Downloading {}", binary_asset.browser_download_url);
+    let binary = download(&client, &binary_asset.browser_download_url)?;
+    let checksums = download(&client, &checksums_asset.browser_download_url)?;
+    let signature = String::from_utf8(download(&client, &signature_asset.browser_download_url)?)?;
+    verify_checksums(key, &checksums, &signature)?;
+    let checksums = String::from_utf8(checksums)?;
+    let expected = expected_checksum(&checksums, &name)
+        .ok_or_else(|| format!("{} doesn't list {}", CHECKSUMS_ASSET, name))?;
+    let actual: String = Sha256::digest(&binary)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if actual != expected {
+        return Err(format!(
+            "The checksum of the download doesn't match, expected {} but got {}",
+            expected, actual
+        )
+        .into());
+    }
+
+    // A new file only we can open, so nobody else can swap it out before it's
+    // copied into place. It's removed when dropped.
+    let mut file = tempfile::Builder::new()
+        .prefix("vrchat-light-sync-")
+        .tempfile()?;
+    file.write_all(&binary)?;
+    file.flush()?;
+    self_replace::self_replace(file.path())?;
+    println!("Updated to version {}", release.tag_name);
+    Ok(true)
+}
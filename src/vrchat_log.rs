@@ -0,0 +1,190 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const JOINING_MARKER: &str = "[Behaviour] Joining wrld_";
+const LEFT_MARKER: &str = "[Behaviour] OnLeftRoom";
+
+// Where VRChat writes its output logs on this computer, None when it isn't
+// installed
+pub fn default_log_dir() -> Option<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(profile) = env::var("USERPROFILE") {
+        dirs.push(PathBuf::from(profile).join(r"AppData\LocalLow\VRChat\VRChat"));
+    }
+    // VRChat running through Proton
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(PathBuf::from(home).join(
+            ".steam/steam/steamapps/compatdata/438100/pfx/drive_c/users/steamuser/AppData/LocalLow/VRChat/VRChat",
+        ));
+    }
+    dirs.into_iter().find(|dir| dir.is_dir())
+}
+
+fn newest_log(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("output_log_") && name.ends_with(".txt")
+        })
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+        .map(|entry| entry.path())
+}
+
+// Follows VRChat's newest output log to find out which world the user is in
+pub struct WorldWatcher {
+    dir: PathBuf,
+    file: Option<PathBuf>,
+    offset: u64,
+    world: Option<String>,
+}
+
+impl WorldWatcher {
+    pub fn new(dir: PathBuf) -> WorldWatcher {
+        WorldWatcher {
+            dir,
+            file: None,
+            offset: 0,
+            world: None,
+        }
+    }
+
+    // The world ID the user is currently in, None when not in a world or
+    // VRChat isn't running.
+    pub fn world(&self) -> Option<&str> {
+        self.world.as_deref()
+    }
+
+    // Reads whatever VRChat has logged since the last call, returns true if
+    // the current world changed.
+    pub fn poll(&mut self) -> bool {
+        let old_world = self.world.clone();
+        let newest = newest_log(&self.dir);
+        if newest != self.file {
+            // VRChat was restarted, start over with the new log
+            self.file = newest;
+            self.offset = 0;
+            self.world = None;
+        }
+        if let Some(file) = &self.file {
+            if let Ok(new_lines) = read_from(file, &mut self.offset) {
+                for line in new_lines.lines() {
+                    self.read_line(line);
+                }
+            }
+        }
+        self.world != old_world
+    }
+
+    fn read_line(&mut self, line: &str) {
+        if let Some(pos) = line.find(JOINING_MARKER) {
+            // The world ID is followed by the instance ID, the "wrld_" is part of the ID
+            let id_start = pos + JOINING_MARKER.len() - "wrld_".len();
+            let id = line[id_start..].split(':').next().unwrap_or_default();
+            self.world = Some(id.trim().to_owned());
+        } else if line.contains(LEFT_MARKER) {
+            self.world = None;
+        }
+    }
+}
+
+// Reads the complete lines after offset and moves offset past them
+fn read_from(file: &Path, offset: &mut u64) -> std::io::Result<String> {
+    let mut file = File::open(file)?;
+    if file.metadata()?.len() < *offset {
+        // The log was truncated
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    // Leave a partially written last line for the next read
+    let complete = match buffer.iter().rposition(|b| *b == b'\n') {
+        Some(pos) => pos + 1,
+        None => 0,
+    };
+    *offset += complete as u64;
+    Ok(String::from_utf8_lossy(&buffer[..complete]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+
+    const JOINED: &str = "2024.05.01 20:00:00 Log        -  [Behaviour] Joining \
+                          wrld_4cf554b4-430c-4f8f-b53e-1f294eed230b:12345~private(usr_1)\n";
+
+    fn append(path: &Path, text: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn follows_the_world_through_the_newest_log() {
+        let dir = env::temp_dir().join(format!("vrchat-light-sync-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("output_log_2024-05-01_20-00-00.txt");
+        append(&first, "2024.05.01 20:00:00 Log        -  Starting\n");
+        let mut watcher = WorldWatcher::new(dir.clone());
+        assert!(!watcher.poll());
+        assert_eq!(watcher.world(), None);
+
+        append(&first, JOINED);
+        assert!(watcher.poll());
+        assert_eq!(
+            watcher.world(),
+            Some("wrld_4cf554b4-430c-4f8f-b53e-1f294eed230b")
+        );
+        assert!(!watcher.poll());
+
+        // A line VRChat is still writing is read once it's finished
+        append(
+            &first,
+            "2024.05.01 20:10:00 Log        -  [Behaviour] OnLeft",
+        );
+        assert!(!watcher.poll());
+        append(&first, "Room\n");
+        assert!(watcher.poll());
+        assert_eq!(watcher.world(), None);
+
+        append(&first, JOINED);
+        assert!(watcher.poll());
+        // VRChat was started again, the old log's world is gone
+        let second = dir.join("output_log_2024-05-02_20-00-00.txt");
+        append(&second, "2024.05.02 20:00:00 Log        -  Starting\n");
+        let later = SystemTime::now() + Duration::from_secs(60);
+        OpenOptions::new()
+            .write(true)
+            .open(&second)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(watcher.poll());
+        assert_eq!(watcher.world(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn starts_over_when_the_log_is_truncated() {
+        let path = env::temp_dir().join(format!("vrchat-light-sync-{}.txt", std::process::id()));
+        fs::write(&path, "first\nsecond\npartial").unwrap();
+        let mut offset = 0;
+        assert_eq!(read_from(&path, &mut offset).unwrap(), "first\nsecond\n");
+        assert_eq!(offset, 13);
+        fs::write(&path, "new\n").unwrap();
+        assert_eq!(read_from(&path, &mut offset).unwrap(), "new\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const VRCHAT_APP_ID: &str = "438100";
+
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Autodetect {
+    // Don't look at VRChat's settings at all
+    Off,
+    // Warn when VRChat listens on a different port than configured
+    #[default]
+    Warn,
+    // Send to whatever port VRChat is set up to listen on
+    Adjust,
+}
+
+// VRChat's OSC settings from its `--osc=inPort:outIP:outPort` launch option
+#[derive(Debug, PartialEq)]
+pub struct OscLaunchOption {
+    pub in_port: u16,
+    pub out_ip: String,
+    pub out_port: u16,
+}
+
+fn steam_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(program_files) = env::var("ProgramFiles(x86)") {
+        dirs.push(PathBuf::from(program_files).join("Steam"));
+    }
+    if cfg!(windows) {
+        dirs.push(PathBuf::from(r"C:\Program Files (x86)\Steam"));
+    }
+    if let Ok(home) = env::var("HOME") {
+        let home = PathBuf::from(home);
+        dirs.push(home.join(".steam/steam"));
+        dirs.push(home.join(".local/share/Steam"));
+        dirs.push(home.join(".var/app/com.valvesoftware.Steam/.local/share/Steam"));
+    }
+    dirs
+}
+
+// Reads the quoted string starting at the beginning of `text`, returns it and
+// the rest of the text after the closing quote.
+fn quoted_string(text: &str) -> Option<(String, &str)> {
+    let text = text.trim_start().strip_prefix('"')?;
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            '"' => return Some((value, &text[i + 1..])),
+            _ => value.push(c),
+        }
+    }
+    None
+}
+
+// Finds VRChat's launch options in the contents of a Steam localconfig.vdf
+fn launch_options(vdf: &str) -> Option<String> {
+    let app_start = vdf.find(&format!("\"{}\"", VRCHAT_APP_ID))?;
+    let mut rest = &vdf[app_start + VRCHAT_APP_ID.len() + 2..];
+    rest = rest.trim_start().strip_prefix('{')?;
+    // Walk the keys of the app block, skipping over nested blocks
+    let mut depth = 0;
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('{') {
+            depth += 1;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('}') {
+            if depth == 0 {
+                return None;
+            }
+            depth -= 1;
+            rest = after;
+        } else {
+            let (key, after) = quoted_string(rest)?;
+            rest = after;
+            if depth == 0 && key.eq_ignore_ascii_case("LaunchOptions") {
+                return quoted_string(rest).map(|(value, _)| value);
+            }
+        }
+    }
+}
+
+fn parse_osc_option(launch_options: &str) -> Option<OscLaunchOption> {
+    let value = launch_options
+        .split_whitespace()
+        .find_map(|option| option.strip_prefix("--osc="))?;
+    let mut parts = value.split(':');
+    let in_port = parts.next()?.parse().ok()?;
+    let out_ip = parts.next()?.to_owned();
+    let out_port = parts.next()?.parse().ok()?;
+    Some(OscLaunchOption {
+        in_port,
+        out_ip,
+        out_port,
+    })
+}
+
+// Looks through the launch options of every Steam user on this computer for
+// VRChat's OSC option.
+pub fn detect_osc_settings() -> Vec<OscLaunchOption> {
+    let mut found = Vec::new();
+    for steam_dir in steam_dirs() {
+        let users = match fs::read_dir(steam_dir.join("userdata")) {
+            Ok(users) => users,
+            Err(_) => continue,
+        };
+        for user in users.flatten() {
+            let vdf = match fs::read_to_string(user.path().join("config/localconfig.vdf")) {
+                Ok(vdf) => vdf,
+                Err(_) => continue,
+            };
+            if let Some(osc) = launch_options(&vdf).and_then(|opts| parse_osc_option(&opts)) {
+                if !found.contains(&osc) {
+                    found.push(osc);
+                }
+            }
+        }
+    }
+    found
+}
+
+// Works out which port to send to, given the configured port and what VRChat
+// is set up to listen on.
+pub fn resolve_port(configured: u16, autodetect: &Autodetect) -> u16 {
+    if *autodetect == Autodetect::Off {
+        return configured;
+    }
+    let detected = detect_osc_settings();
+    let osc = match detected.first() {
+        Some(osc) => osc,
+        None => return configured,
+    };
+    if detected.len() > 1 {
+        println!(
+            "Found {} different --osc launch options for VRChat, using the first one.",
+            detected.len()
+        );
+    }
+    if osc.in_port == configured {
+        return configured;
+    }
+    match autodetect {
+        Autodetect::Adjust => {
+            println!(
+                "VRChat is launched with --osc={}:{}:{}, sending to port {} instead of {}.",
+                osc.in_port, osc.out_ip, osc.out_port, osc.in_port, configured
+            );
+            osc.in_port
+        }
+        _ => {
+            println!(
+                "Warning: VRChat is launched with --osc={}:{}:{} so it listens on port {}, but \
+                 vrchat_port is set to {}. Set vrchat_autodetect to adjust to follow it.",
+                osc.in_port, osc.out_ip, osc.out_port, osc.in_port, configured
+            );
+            configured
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALCONFIG: &str = r#"
+"UserLocalConfigStore"
+{
+	"Software"
+	{
+		"Valve"
+		{
+			"Steam"
+			{
+				"apps"
+				{
+					"250820"
+					{
+						"LaunchOptions"		"--osc=1:2.2.2.2:3"
+					}
+					"438100"
+					{
+						"LastPlayed"		"1700000000"
+						"cloud"
+						{
+							"LaunchOptions"		"not this one"
+						}
+						"LaunchOptions"		"--no-vr --osc=9010:192.168.1.5:9011 \"--profile=0\""
+					}
+				}
+			}
+		}
+	}
+}
+"#;
+
+    #[test]
+    fn reads_quoted_strings() {
+        assert_eq!(
+            quoted_string(r#"  "a \"b\" \\c" rest"#),
+            Some((r#"a "b" \c"#.to_owned(), " rest"))
+        );
+        assert_eq!(quoted_string(r#""unterminated"#), None);
+        assert_eq!(quoted_string("not quoted"), None);
+    }
+
+    #[test]
+    fn finds_vrchats_launch_options() {
+        assert_eq!(
+            launch_options(LOCALCONFIG).as_deref(),
+            Some(r#"--no-vr --osc=9010:192.168.1.5:9011 "--profile=0""#)
+        );
+        let without = LOCALCONFIG.replace(r#""LaunchOptions"		"--no-vr"#, r#""Other"		"--no-vr"#);
+        assert_eq!(launch_options(&without), None);
+        assert_eq!(launch_options(r#""apps" { "250820" { } }"#), None);
+    }
+
+    #[test]
+    fn parses_the_osc_launch_option() {
+        assert_eq!(
+            parse_osc_option("--no-vr --osc=9010:192.168.1.5:9011"),
+            Some(OscLaunchOption {
+                in_port: 9010,
+                out_ip: "192.168.1.5".to_owned(),
+                out_port: 9011,
+            })
+        );
+        assert_eq!(parse_osc_option("--no-vr"), None);
+        assert_eq!(parse_osc_option("--osc=9010:127.0.0.1"), None);
+        assert_eq!(parse_osc_option("--osc=port:127.0.0.1:9001"), None);
+    }
+
+    #[test]
+    fn only_looks_at_the_settings_when_asked_to() {
+        assert_eq!(resolve_port(9123, &Autodetect::Off), 9123);
+    }
+}
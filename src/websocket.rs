@@ -0,0 +1,70 @@
+use crate::clock;
+use crate::control::{self, event_json, status_json, ControlCommand, ControlReply, ControlRequest};
+use serde::Deserialize;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use tungstenite::Message;
+
+fn default_port() -> u16 {
+    9125
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebSocketConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+// Sends the current status followed by every event as JSON until the client
+// goes away
+fn stream_events(stream: TcpStream, requests: mpsc::Sender<ControlRequest>) {
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+    let mut status = match control::request(&requests, ControlCommand::Status) {
+        ControlReply::Status(status) => status_json(&status),
+        _ => return,
+    };
+    status["type"] = "status".into();
+    if socket.send(Message::Text(status.to_string())).is_err() {
+        return;
+    }
+    let (sender, events) = mpsc::channel();
+    if let ControlReply::Error(_) = control::request(&requests, ControlCommand::Subscribe(sender)) {
+        return;
+    }
+    for event in events {
+        if socket
+            .send(Message::Text(event_json(&event).to_string()))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// Streams the synced lights to WebSocket clients on localhost, like overlays
+// in OBS browser sources
+pub fn start(
+    config: &WebSocketConfig,
+    requests: mpsc::Sender<ControlRequest>,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).map_err(|err| {
+        format!(
+            "Couldn't start the WebSocket stream on port {}: {}",
+            config.port, err
+        )
+    })?;
+    println!(
+        "WebSocket stream listening on ws://127.0.0.1:{}",
+        config.port
+    );
+    clock::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let requests = requests.clone();
+            clock::spawn(move || stream_events(stream, requests));
+        }
+    });
+    Ok(())
+}
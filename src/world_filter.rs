@@ -0,0 +1,92 @@
+use crate::vrchat_log::{default_log_dir, WorldWatcher};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    // Sync everywhere except the listed worlds
+    Blocklist,
+    // Only sync in the listed worlds
+    Allowlist,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorldFilterConfig {
+    pub mode: FilterMode,
+    pub worlds: Vec<String>,
+    // Folder containing VRChat's output_log files, found automatically if empty.
+    pub log_dir: Option<PathBuf>,
+}
+
+impl WorldFilterConfig {
+    pub fn allows(&self, world: Option<&str>) -> bool {
+        let listed = world.is_some_and(|world| self.worlds.iter().any(|w| w == world));
+        match self.mode {
+            FilterMode::Blocklist => !listed,
+            // Outside of any world there's nothing to allow
+            FilterMode::Allowlist => listed,
+        }
+    }
+}
+
+// Turns syncing on and off as the user moves between worlds
+pub struct WorldFilter<'a> {
+    config: &'a WorldFilterConfig,
+    watcher: WorldWatcher,
+}
+
+impl<'a> WorldFilter<'a> {
+    pub fn new(config: &'a WorldFilterConfig) -> Result<WorldFilter<'a>, String> {
+        let dir = config
+            .log_dir
+            .clone()
+            .or_else(default_log_dir)
+            .ok_or("Couldn't find VRChat's log folder, set log_dir in world_filter.")?;
+        Ok(WorldFilter {
+            config,
+            watcher: WorldWatcher::new(dir),
+        })
+    }
+
+    // Checks the log for world changes, returns whether syncing is allowed
+    pub fn poll(&mut self) -> bool {
+        let changed = self.watcher.poll();
+        let allowed = self.config.allows(self.watcher.world());
+        if changed {
+            println!(
+                "Now in world {}, syncing is {}",
+                self.watcher.world().unwrap_or("none"),
+                if allowed { "enabled" } else { "disabled" }
+            );
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mode: &str) -> WorldFilterConfig {
+        serde_yaml::from_str(&format!("mode: {}\nworlds: [wrld_home, wrld_club]\n", mode)).unwrap()
+    }
+
+    #[test]
+    fn blocks_only_the_listed_worlds() {
+        let config = filter("blocklist");
+        assert!(!config.allows(Some("wrld_club")));
+        assert!(config.allows(Some("wrld_other")));
+        assert!(config.allows(None));
+    }
+
+    #[test]
+    fn allows_only_the_listed_worlds() {
+        let config = filter("allowlist");
+        assert!(config.allows(Some("wrld_home")));
+        assert!(!config.allows(Some("wrld_other")));
+        assert!(!config.allows(None));
+        // World IDs have to match exactly
+        assert!(!config.allows(Some("wrld_home2")));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::config::{Config, LightConfig};
+use crate::light::Light;
+use crate::logging::{self, Category};
+use crate::output::derived::DerivedConfig;
+use crate::output::lut::LutConfig;
+use crate::output::packed::PackedConfig;
+use crate::output::parameters::ParametersConfig;
+use crate::output::vrchat::{HueOutput, VrchatOutput};
+use crate::vrchat_log::{default_log_dir, WorldWatcher};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// How a light's state is turned into avatar parameters, anything left out is
+// taken from the light's own settings
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MappingConfig {
+    pub parameter_prefix: Option<String>,
+    pub packed: Option<PackedConfig>,
+    pub hue_output: Option<HueOutput>,
+    pub last_color: Option<bool>,
+    pub full_color: Option<bool>,
+    pub lut: Option<LutConfig>,
+    pub smoothing: Option<HashMap<String, f32>>,
+    pub derived: Option<DerivedConfig>,
+    pub parameters: Option<ParametersConfig>,
+}
+
+impl MappingConfig {
+    // The light's settings with this mapping laid over them
+    fn apply(&self, light: &LightConfig) -> LightConfig {
+        LightConfig {
+            name: light.name.clone(),
+            parameter_prefix: self
+                .parameter_prefix
+                .clone()
+                .unwrap_or_else(|| light.parameter_prefix.clone()),
+            packed: self.packed.clone().or_else(|| light.packed.clone()),
+            hue_output: self.hue_output.unwrap_or(light.hue_output),
+            last_color: self.last_color.unwrap_or(light.last_color),
+            full_color: self.full_color.unwrap_or(light.full_color),
+            lut: self.lut.clone().or_else(|| light.lut.clone()),
+            smoothing: self
+                .smoothing
+                .clone()
+                .unwrap_or_else(|| light.smoothing.clone()),
+            derived: self.derived.clone().or_else(|| light.derived.clone()),
+            parameters: self.parameters.clone().or_else(|| light.parameters.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorldProfileConfig {
+    pub name: String,
+    pub worlds: Vec<String>,
+    // The lights to use the mapping for by name, every light if empty
+    #[serde(default)]
+    pub lights: Vec<String>,
+    #[serde(flatten)]
+    pub mapping: MappingConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorldProfilesConfig {
+    pub profiles: Vec<WorldProfileConfig>,
+    // Folder containing VRChat's output_log files, found automatically if empty.
+    pub log_dir: Option<PathBuf>,
+}
+
+impl WorldProfilesConfig {
+    // Mistakes in a profile show up now rather than when first joining one of
+    // its worlds
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        for profile in self.profiles.iter() {
+            if let Some(name) = profile
+                .lights
+                .iter()
+                .find(|name| !config.lights.iter().any(|light| light.name == **name))
+            {
+                return Err(format!(
+                    "The world profile {} is for {}, which isn't a light.",
+                    profile.name, name
+                ));
+            }
+            let used = config
+                .lights
+                .iter()
+                .filter(|light| profile.lights.is_empty() || profile.lights.contains(&light.name));
+            for light in used {
+                profile
+                    .mapping
+                    .apply(light)
+                    .validate_mapping(config)
+                    .map_err(|err| format!("{} in {}: {}", light.name, profile.name, err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Switches the lights over to other avatar parameters while the user is in
+// some worlds, and back to their own once they leave
+pub struct WorldProfiles<'a> {
+    config: &'a Config,
+    profiles: &'a [WorldProfileConfig],
+    vrc_addr: String,
+    watcher: WorldWatcher,
+    // Each light's settings under every profile, None for lights a profile
+    // leaves alone
+    mapped: Vec<Vec<Option<LightConfig>>>,
+    active: Option<usize>,
+}
+
+impl<'a> WorldProfiles<'a> {
+    pub fn new(
+        config: &'a Config,
+        profiles: &'a WorldProfilesConfig,
+        vrc_addr: &str,
+    ) -> Result<Self, String> {
+        let dir = profiles
+            .log_dir
+            .clone()
+            .or_else(default_log_dir)
+            .ok_or("Couldn't find VRChat's log folder, set log_dir in world_profiles.")?;
+        let mapped = profiles
+            .profiles
+            .iter()
+            .map(|profile| {
+                config
+                    .lights
+                    .iter()
+                    .map(|light| {
+                        let used =
+                            profile.lights.is_empty() || profile.lights.contains(&light.name);
+                        used.then(|| profile.mapping.apply(light))
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(WorldProfiles {
+            config,
+            profiles: &profiles.profiles,
+            vrc_addr: vrc_addr.to_owned(),
+            watcher: WorldWatcher::new(dir),
+            mapped,
+            active: None,
+        })
+    }
+
+    // Checks the log for world changes and moves the lights to the profile of
+    // the new world
+    pub fn poll(&mut self, lights: &mut [Light], syncing: bool) {
+        if !self.watcher.poll() {
+            return;
+        }
+        let world = self.watcher.world();
+        let active = self.profiles.iter().position(|profile| {
+            world.is_some_and(|world| profile.worlds.iter().any(|w| w == world))
+        });
+        if active == self.active {
+            return;
+        }
+        match (active, self.active) {
+            (Some(i), _) => println!(
+                "Now in world {}, using the {} profile",
+                world.unwrap_or("none"),
+                self.profiles[i].name
+            ),
+            (None, Some(i)) => println!(
+                "Left the worlds of the {} profile, back to the usual parameters",
+                self.profiles[i].name
+            ),
+            (None, None) => {}
+        }
+        for (i, light) in lights.iter_mut().enumerate() {
+            let old = self
+                .active
+                .and_then(|profile| self.mapped[profile][i].as_ref());
+            let new = active.and_then(|profile| self.mapped[profile][i].as_ref());
+            if old.is_none() && new.is_none() {
+                continue;
+            }
+            let settings = new.unwrap_or(&self.config.lights[i]);
+            // The light keeps going with the parameters it has
+            match VrchatOutput::new(&self.vrc_addr, self.config, settings) {
+                Ok(vrchat) => light.set_avatar_output(vrchat, syncing),
+                Err(err) => logging::error(
+                    Category::Output,
+                    format!(
+                        "Couldn't switch {} to the new parameters: {}",
+                        light.name, err
+                    ),
+                ),
+            }
+        }
+        self.active = active;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{parse_config, ConfigError};
+
+    fn invalid(profiles: &str) -> String {
+        let settings = "
+vrchat_ip: 127.0.0.1
+vrchat_port: 9000
+max_updates_per_second: 5
+lights:
+    - name: desk
+      bulb_service: push
+      push:
+          name: desk
+world_profiles:
+    log_dir: /nonexistent
+    profiles:
+"
+        .to_owned()
+            + profiles;
+        match parse_config(&settings) {
+            Err(ConfigError::Invalid(err)) => err,
+            other => panic!("expected invalid settings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn profiles_are_checked_with_the_settings() {
+        let err = invalid("        - name: club\n          worlds: []\n          lights: [lamp]\n");
+        assert_eq!(
+            err,
+            "The world profile club is for lamp, which isn't a light."
+        );
+        let err = invalid(
+            "        - name: club\n          worlds: []\n          parameters:\n              hue:\n                  - address: Hue\n                    gamma: 0\n",
+        );
+        assert_eq!(
+            err,
+            "desk in club: The gamma of the Hue parameter has to be above 0."
+        );
+        let err = invalid(
+            "        - name: club\n          worlds: []\n          lut:\n              hue: /nonexistent/hue.lut\n",
+        );
+        assert!(
+            err.starts_with("desk in club: Couldn't read the LUT /nonexistent/hue.lut: "),
+            "{}",
+            err
+        );
+    }
+}